@@ -0,0 +1,69 @@
+/*
+ * C API of Kun-peng, exported by libkun_peng (cargo build --release --lib).
+ *
+ *   KunPengClassifier *db = kun_peng_open("test_database", 0, 0.0, 2);
+ *   if (!db) { fprintf(stderr, "%s\n", kun_peng_last_error()); return 1; }
+ *   KunPengResults *res = kun_peng_classify(db, fastq, fastq_len, 0);
+ *   for (size_t i = 0; res && i < res->len; i++)
+ *       printf("%s\t%llu\n", res->calls[i].id, (unsigned long long)res->calls[i].taxid);
+ *   char *report = kun_peng_report(db, res, 0);
+ *   kun_peng_string_free(report);
+ *   kun_peng_results_free(res);
+ *   kun_peng_close(db);
+ *
+ * Functions that fail return NULL; kun_peng_last_error() then describes the failure
+ * until the next failing call on the same thread.
+ */
+#ifndef KUN_PENG_H
+#define KUN_PENG_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A database loaded in memory */
+typedef struct KunPengClassifier KunPengClassifier;
+
+/* The call of one read */
+typedef struct KunPengCall {
+    char *id;
+    int classified;   /* 1 if assigned to a taxon */
+    uint64_t taxid;   /* 0 if unclassified */
+    char *seq_len;
+    char *hits;       /* k-mer mapping, e.g. "562:13 561:4 A:31" */
+} KunPengCall;
+
+/* The calls and read counts of one classified buffer */
+typedef struct KunPengResults {
+    KunPengCall *calls;
+    size_t len;
+    uint64_t total_seqs;
+    uint64_t total_unclassified;
+    void *summary;    /* private */
+} KunPengResults;
+
+const char *kun_peng_last_error(void);
+
+/* num_threads 0 uses all CPUs */
+KunPengClassifier *kun_peng_open(const char *database, size_t num_threads,
+                                 double confidence_threshold, size_t minimum_hit_groups);
+void kun_peng_close(KunPengClassifier *classifier);
+
+/* data is an uncompressed FASTA or FASTQ text of len bytes */
+KunPengResults *kun_peng_classify(const KunPengClassifier *classifier, const uint8_t *data,
+                                  size_t len, int minimum_quality_score);
+void kun_peng_results_free(KunPengResults *results);
+
+/* Kraken-style report (kreport2) of the results */
+char *kun_peng_report(const KunPengClassifier *classifier, const KunPengResults *results,
+                      int report_zero_counts);
+void kun_peng_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KUN_PENG_H */
@@ -0,0 +1,195 @@
+//! Read-ID anonymization of the per-read outputs
+//!
+//! Read names often carry the instrument, run and flow cell IDs. With `--anonymize-ids`,
+//! `resolve` and `direct` write a salted hash instead of every read ID, and keep the pairs of
+//! hashes and read IDs in a mapping file that stays with the data owner. The salt is the first
+//! line of the mapping file, so runs appending to the same mapping file hash the same read IDs
+//! alike, while nobody without the file can recompute them.
+use seqkmer::{Base, Reader};
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Key of the salt line of a mapping file
+const SALT_KEY: &str = "#salt";
+
+/// The salted hash of a read ID, 32 hex digits
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::anonymize::anonymize_id;
+///
+/// let id = anonymize_id("3f1c", "A00123:8:H7KJ2DSXX:1:1101:10004:10019");
+/// assert_eq!(id.len(), 32);
+/// assert_eq!(id, anonymize_id("3f1c", "A00123:8:H7KJ2DSXX:1:1101:10004:10019"));
+/// assert_ne!(id, anonymize_id("9a07", "A00123:8:H7KJ2DSXX:1:1101:10004:10019"));
+/// ```
+pub fn anonymize_id(salt: &str, id: &str) -> String {
+    let mut hasher = md5::Context::new();
+    hasher.consume(salt.as_bytes());
+    hasher.consume(b"\t");
+    hasher.consume(id.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// A new random salt, 32 hex digits
+fn random_salt() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    // RandomState 的密钥来自操作系统的随机数
+    (0..2)
+        .map(|_| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            hasher.write_u32(std::process::id());
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+/// Creates a file only the owner can read, on Unix
+fn create_protected(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// The mapping file of anonymized read IDs to the original read IDs
+///
+/// The file starts with `#salt<TAB><salt>` and `#anonymous_id<TAB>read_id`, then has one line
+/// per read. A new file is created readable by its owner only; an existing file keeps its
+/// salt and is appended to.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::anonymize::{anonymize_id, IdMap};
+///
+/// let path = std::env::temp_dir().join("kun_peng_id_map_doctest.tsv");
+/// let _ = std::fs::remove_file(&path);
+/// let mut id_map = IdMap::open(&path).unwrap();
+/// let anonymous = id_map.anonymize("read_1").unwrap();
+/// assert_eq!(anonymous, anonymize_id(id_map.salt(), "read_1"));
+/// id_map.flush().unwrap();
+///
+/// // 再次打开沿用同一个 salt
+/// let id_map = IdMap::open(&path).unwrap();
+/// assert_eq!(anonymize_id(id_map.salt(), "read_1"), anonymous);
+/// drop(id_map);
+/// let text = std::fs::read_to_string(&path).unwrap();
+/// assert!(text.ends_with(&format!("{}\tread_1\n", anonymous)));
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct IdMap {
+    salt: String,
+    writer: BufWriter<File>,
+}
+
+impl IdMap {
+    /// Opens the mapping file, creating it with a new salt if it does not exist
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the mapping, an `InvalidData` error if an existing file does
+    /// not start with a salt line
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let mut line = String::new();
+            BufReader::new(File::open(path)?).read_line(&mut line)?;
+            let salt = match line.trim_end().split_once('\t') {
+                Some((SALT_KEY, salt)) if !salt.is_empty() => salt.to_string(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{:?} is not a read ID mapping file, it has no salt line",
+                            path
+                        ),
+                    ))
+                }
+            };
+            let file = OpenOptions::new().append(true).open(path)?;
+            return Ok(Self {
+                salt,
+                writer: BufWriter::new(file),
+            });
+        }
+
+        let salt = random_salt();
+        let mut writer = BufWriter::new(create_protected(path)?);
+        writeln!(writer, "{}\t{}", SALT_KEY, salt)?;
+        writeln!(writer, "#anonymous_id\tread_id")?;
+        Ok(Self { salt, writer })
+    }
+
+    /// The salt of the read ID hashes
+    pub fn salt(&self) -> &str {
+        &self.salt
+    }
+
+    /// Records a read ID and its anonymized ID, see [`anonymize_id`]
+    pub fn insert(&mut self, anonymous: &str, id: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}\t{}", anonymous, id)
+    }
+
+    /// Anonymizes a read ID and records it
+    pub fn anonymize(&mut self, id: &str) -> io::Result<String> {
+        let anonymous = anonymize_id(&self.salt, id);
+        self.insert(&anonymous, id)?;
+        Ok(anonymous)
+    }
+
+    /// Writes the buffered lines to the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A reader that anonymizes the read IDs of another reader and records them in an [`IdMap`]
+///
+/// Without a mapping it passes the reads through unchanged.
+pub struct AnonymizedReader<'a, R: Reader> {
+    inner: R,
+    id_map: Option<&'a mut IdMap>,
+}
+
+impl<'a, R: Reader> AnonymizedReader<'a, R> {
+    /// Wraps `inner`, anonymizing its read IDs if there is a mapping
+    pub fn new(inner: R, id_map: Option<&'a mut IdMap>) -> Self {
+        Self { inner, id_map }
+    }
+
+    fn anonymize(
+        &mut self,
+        records: Option<Vec<Base<Vec<u8>>>>,
+    ) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        let Some(id_map) = self.id_map.as_mut() else {
+            return Ok(records);
+        };
+        let Some(mut records) = records else {
+            return Ok(None);
+        };
+        for record in &mut records {
+            record.header.id = id_map.anonymize(&record.header.id)?;
+        }
+        Ok(Some(records))
+    }
+}
+
+impl<R: Reader> Reader for AnonymizedReader<'_, R> {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        let records = self.inner.next()?;
+        self.anonymize(records)
+    }
+}
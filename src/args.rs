@@ -1,3 +1,5 @@
+use crate::db::DEFAULT_MAX_PAGE_LOAD;
+use crate::input::InputOptions;
 use crate::utils::expand_spaced_seed_mask;
 use crate::{construct_seed_template, parse_binary};
 use clap::Parser;
@@ -6,7 +8,10 @@ use seqkmer::{
     BITS_PER_CHAR, DEFAULT_KMER_LENGTH, DEFAULT_MINIMIZER_LENGTH, DEFAULT_MINIMIZER_SPACES,
     DEFAULT_TOGGLE_MASK,
 };
-use std::path::PathBuf;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 pub const U32MAXPLUS: u64 = u32::MAX as u64;
 pub const ONEGB: u64 = 1073741824;
@@ -29,9 +34,80 @@ pub struct Build {
     #[clap(short, long, value_parser = clap::value_parser!(u8).range(0..31), default_value_t = 0)]
     pub requested_bits_for_taxid: u8,
 
-    /// Number of threads
-    #[clap(short = 'p', long, default_value_t = num_cpus::get())]
+    /// Number of worker threads, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = num_cpus::get())]
     pub threads: usize,
+
+    /// Produce byte-identical library and hash files from identical inputs (slower)
+    #[clap(long, default_value_t = false)]
+    pub deterministic: bool,
+
+    /// Store 64-bit hash cells (twice the size) for taxonomies that need more than 31 bits per taxid
+    #[clap(long, default_value_t = false)]
+    pub wide_cells: bool,
+
+    /// Grow hash pages that are fuller than this after the build, 0 keeps every page at its planned size
+    #[clap(long, default_value_t = DEFAULT_MAX_PAGE_LOAD)]
+    pub max_page_load: f64,
+
+    /// Compress the hash pages with zstd (hash_*.k2d.zst), they are decompressed when loaded
+    #[clap(long, default_value_t = false)]
+    pub compress_pages: bool,
+
+    /// Build each page from its externally sorted chunk file, sorting at most this much memory
+    /// of cells at a time (e.g. '512M') instead of holding the whole page in memory
+    #[clap(long, value_parser = parse_size)]
+    pub sort_buffer: Option<usize>,
+
+    /// Build each hash page in a memory mapping of its file, written back by the kernel as it
+    /// fills, instead of in memory and then serialized. Pages are not held in the memory budget
+    #[clap(long, default_value_t = false, conflicts_with = "sort_buffer")]
+    pub mmap_pages: bool,
+
+    /// Write a Bloom prefilter next to each hash page (hash_*.k2f), consulted before the page is
+    /// probed so that absent minimizers cost no page lookup. Takes about 10 bits per cell
+    #[clap(long, default_value_t = false)]
+    pub prefilter: bool,
+
+    /// Probe the hash pages by double hashing instead of linearly, recorded in hash_config.k2d.
+    /// Cuts the probe lengths of full pages; the default follows the `double_hashing` feature
+    #[clap(long, default_value_t = cfg!(feature = "double_hashing"))]
+    pub double_hashing: bool,
+
+    /// Print the progress of the hash page builds with an estimated time left to stderr
+    #[clap(long, default_value_t = false)]
+    pub progress: bool,
+}
+
+/// Largest hash file capacity chosen automatically (capacity 1G = file size 4G)
+pub const MAX_AUTO_HASH_CAPACITY: usize = 1 << 30;
+
+/// Smallest hash file capacity chosen automatically
+pub const MIN_AUTO_HASH_CAPACITY: usize = 1 << 20;
+
+/// Chooses the hash file capacity for a classification machine with `max_memory` bytes
+///
+/// `annotate` keeps one hash page in memory next to the buffered sample chunks, so a page is
+/// given at most a quarter of the memory. The capacity is a power of two between
+/// `MIN_AUTO_HASH_CAPACITY` and `MAX_AUTO_HASH_CAPACITY`.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::args::auto_hash_capacity;
+///
+/// // 16 GiB: the default 1G capacity (4 GiB pages)
+/// assert_eq!(auto_hash_capacity(16 << 30, false), 1 << 30);
+/// // 1 GiB: 256 MiB pages
+/// assert_eq!(auto_hash_capacity(1 << 30, false), 1 << 26);
+/// // 64-bit cells take twice the memory per cell
+/// assert_eq!(auto_hash_capacity(1 << 30, true), 1 << 25);
+/// ```
+pub fn auto_hash_capacity(max_memory: usize, wide_cells: bool) -> usize {
+    let cell_bytes = if wide_cells { 8 } else { 4 };
+    let cells = (max_memory / 4 / cell_bytes).max(1);
+    let capacity = 1usize << (usize::BITS - 1 - cells.leading_zeros());
+    capacity.clamp(MIN_AUTO_HASH_CAPACITY, MAX_AUTO_HASH_CAPACITY)
 }
 
 const BUFFER_SIZE: usize = 16 * 1024 * 1024;
@@ -54,11 +130,12 @@ pub struct ClassifyArgs {
     #[arg(long = "db", required = true)]
     pub database: PathBuf,
 
-    /// chunk directory
+    /// chunk directory. Default: a new directory under TMPDIR, removed when classify finishes
     #[clap(long)]
-    pub chunk_dir: PathBuf,
+    pub chunk_dir: Option<PathBuf>,
 
     /// File path for outputting normal Kraken output.
+    /// s3:// and gs:// URLs are uploaded with the aws or gcloud CLI as the files are written.
     #[clap(long = "output-dir", value_parser)]
     pub output_dir: Option<PathBuf>,
 
@@ -75,7 +152,7 @@ pub struct ClassifyArgs {
     )]
     pub minimum_quality_score: i32,
 
-    /// The number of threads to use.
+    /// The number of worker threads, capped by the global --threads.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
@@ -86,6 +163,16 @@ pub struct ClassifyArgs {
     #[clap(long, value_parser = clap::value_parser!(u32).range(1..=32), default_value_t = 4)]
     pub batch_size: u32,
 
+    /// Read the next hash page in the background while annotate looks up the current one.
+    /// Holds two pages in memory; turned off if they do not fit the memory budget
+    #[clap(long, default_value_t = false)]
+    pub prefetch: bool,
+
+    /// Write the slots of the chunk files as zstd frames, for a much smaller chunk directory on
+    /// shared scratch at the cost of some CPU in splitr and annotate
+    #[clap(long, default_value_t = false)]
+    pub compress_chunks: bool,
+
     /// Confidence score threshold
     #[clap(
         short = 'T',
@@ -112,14 +199,61 @@ pub struct ClassifyArgs {
     #[clap(short = 'z', long, value_parser, default_value_t = false)]
     pub report_zero_counts: bool,
 
-    // /// output file contains all unclassified sequence
-    // #[clap(long, value_parser, default_value_t = false)]
-    // pub full_output: bool,
+    /// Refine species-level calls to the best supported strain.
+    /// Appends the strain taxid and its support fraction as two extra output columns.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub strain_refinement: bool,
+
+    /// Window size in k-mer positions for chimera detection on long reads.
+    /// Reads whose windows resolve to incompatible taxa are written to chimera_{i}.txt in the output directory.
+    #[clap(long, value_parser)]
+    pub chimera_window: Option<usize>,
+
+    /// Stride between chimera detection windows, default is half the window size.
+    #[clap(long, value_parser)]
+    pub chimera_stride: Option<usize>,
+
+    /// Resume a run that was interrupted during annotate or resolve, keeping the files it left in
+    /// --chunk-dir and skipping the chunk files and partitions it completed.
+    #[clap(long, value_parser, default_value_t = false, requires_all = ["chunk_dir", "output_dir"])]
+    pub resume: bool,
+
+    /// Print the progress of each stage (reads split, chunk files annotated, samples resolved)
+    /// with an estimated time left to stderr
+    #[clap(long, value_parser, default_value_t = false)]
+    pub progress: bool,
+
+    /// Write the outputs of every sample into a directory named after its input files
+    /// (<sample>/output.txt, <sample>/report.kreport2) with a summary.tsv of all samples,
+    /// instead of output_{i} files. Needs --output-dir
+    #[clap(long, value_parser, default_value_t = false, requires = "output_dir")]
+    pub sample_dirs: bool,
+
+    /// Replace the read IDs of the per-read outputs with salted hashes and append the hashes
+    /// and read IDs to this mapping file, created readable by its owner only. Keep the file
+    /// private to share the outputs without the read names
+    #[clap(long, value_parser)]
+    pub anonymize_ids: Option<PathBuf>,
+
+    /// Print the expected memory of each stage, the chunk directory usage, the number of hash
+    /// pages and the output size, then exit without classifying
+    #[clap(long, value_parser, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Write a line for every read to the output, also a U line for each read without any hit
+    #[clap(long, value_parser, default_value_t = false)]
+    pub full_output: bool,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
+    /// s3:// and gs:// URLs are streamed with the aws or gcloud CLI.
     /// Can also be a single .txt file containing a list of input file paths, one per line.
     // #[clap(short = 'F', long = "files")]
     pub input_files: Vec<PathBuf>,
+
+    /// How the input files are read, set from the global options of `kun_peng`
+    #[clap(skip)]
+    pub input: InputOptions,
 }
 
 #[derive(Parser, Debug, Clone, Copy)]
@@ -149,18 +283,20 @@ pub struct KLMTArgs {
 }
 
 impl KLMTArgs {
-    pub fn as_meros(&self) -> Meros {
-        let seed = construct_seed_template(self.l_mer as usize, self.minimizer_spaces as usize);
-        let space_seed_mask = parse_binary(&seed).unwrap();
+    /// The minimizer settings, an `InvalidInput` error if the minimizer spaces do not fit
+    /// the minimizer length
+    pub fn as_meros(&self) -> io::Result<Meros> {
+        let seed = construct_seed_template(self.l_mer as usize, self.minimizer_spaces as usize)?;
+        let space_seed_mask = parse_binary(&seed).map_err(io::Error::other)?;
         let space_seed_mask = expand_spaced_seed_mask(space_seed_mask, BITS_PER_CHAR as u64);
 
-        Meros::new(
+        Ok(Meros::new(
             self.k_mer as usize,
             self.l_mer as usize,
             Some(space_seed_mask),
             Some(self.toggle_mask),
             self.min_clear_hash_value,
-        )
+        ))
     }
 }
 
@@ -191,3 +327,61 @@ pub fn parse_size(s: &str) -> Result<usize, String> {
         _ => Err("Invalid size suffix. Use 'G', 'M', or 'K'".to_string()),
     }
 }
+
+/// Part `index` of `count` of the work of a step, given as `i/N` on the command line
+///
+/// Units of work numbered from 1 (hash pages, samples) are dealt out round-robin, so each
+/// job of an array (e.g. SLURM `--array=1-N`) gets a share of them.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::args::Partition;
+///
+/// let partition: Partition = "2/3".parse().unwrap();
+/// assert_eq!(partition.to_string(), "2/3");
+/// let units: Vec<usize> = (1..=8).filter(|&n| partition.contains(n)).collect();
+/// assert_eq!(units, vec![2, 5, 8]);
+/// assert!("0/3".parse::<Partition>().is_err());
+/// assert!("4/3".parse::<Partition>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The partition, from 1 to `count`
+    pub index: usize,
+    /// The number of partitions
+    pub count: usize,
+}
+
+impl Partition {
+    /// Whether the unit `n`, numbered from 1, belongs to this partition
+    pub fn contains(&self, n: usize) -> bool {
+        n > 0 && (n - 1) % self.count == self.index - 1
+    }
+
+    /// The directory of the files kept by this partition in the chunk directory
+    pub fn dir<P: AsRef<Path>>(&self, chunk_dir: P) -> PathBuf {
+        chunk_dir.as_ref().join(format!("partition_{}", self.index))
+    }
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid partition '{}', expected i/N with 1 <= i <= N", s);
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index: usize = index.trim().parse().map_err(|_| invalid())?;
+        let count: usize = count.trim().parse().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(invalid());
+        }
+        Ok(Self { index, count })
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
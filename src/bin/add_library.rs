@@ -1,7 +1,17 @@
 use clap::Parser;
 use flate2::bufread::MultiGzDecoder; // 支持 .gz 和 .fna
 use kun_peng::args::parse_size;
-use kun_peng::utils::{find_files, read_id_to_taxon_map};
+use kun_peng::compact_hash::{read_page_from_file, HashConfig};
+use kun_peng::db::{convert_fna_to_k2_format, update_k2file, LIBRARY_PROVENANCE_FILENAME};
+use kun_peng::dust::mask_fasta_record;
+use kun_peng::manifest::DbManifest;
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::{
+    create_partition_files, create_partition_writers, file_md5, find_files, get_file_limit,
+    read_accession_to_taxid_map, read_id_to_taxon_map, set_fd_limit,
+};
+use kun_peng::IndexOptions;
+use log::{error, info, warn};
 use rayon::prelude::*;
 use regex::Regex;
 use std::collections::{HashMap, HashSet}; 
@@ -13,7 +23,6 @@ use std::sync::{Arc, Mutex};
 use std::time::{Instant, SystemTime, UNIX_EPOCH}; 
 use walkdir::WalkDir;
 
-use md5::Context; 
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, about = "Add new FASTA files to an existing Kun-Peng database library")]
@@ -29,6 +38,23 @@ pub struct Args {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// NCBI accession2taxid files (e.g. nucl_gb.accession2taxid.gz, nucl_wgs.accession2taxid.gz)
+    /// used to resolve FASTA headers without a taxid tag
+    #[arg(long = "accession2taxid", num_args = 1..)]
+    pub accession2taxid: Vec<PathBuf>,
+
+    /// Do not mask low-complexity regions of the library sequences
+    #[arg(long = "no-masking", default_value_t = false)]
+    pub no_masking: bool,
+
+    /// Only update the library, the hash tables have to be rebuilt with build-db
+    #[arg(long = "no-rebuild", default_value_t = false)]
+    pub no_rebuild: bool,
+
+    /// Number of threads used to update the hash tables, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = num_cpus::get())]
+    pub threads: usize,
 }
 
 // ... (SizedWriter 结构体保持不变) ...
@@ -152,7 +178,7 @@ fn find_fasta_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
 /// 解析 FASTA 标题以提取 seqid 和 taxid
 /// 稳健地查找 "taxid|TAXID" 或 "kraken:taxid|TAXID" 这样的模式
 /// 输出: "full_seq_id\tTAXID"
-fn parse_header_to_map_entry(header: &str) -> Option<String> {
+fn parse_header_to_map_entry(header: &str, accession_map: &HashMap<String, u64>) -> Option<String> {
     // 1. 去除 '>' 并获取第一个 "单词" (ID 部分)
     let id_part = header
         .strip_prefix('>')
@@ -182,7 +208,54 @@ fn parse_header_to_map_entry(header: &str) -> Option<String> {
             }
         }
     }
-    None
+
+    // 4. 没有 taxid 标记时, 按 accession (带或不带版本号) 查找
+    let accession = id_part.split('.').next().unwrap_or(id_part);
+    accession_map
+        .get(id_part)
+        .or_else(|| accession_map.get(accession))
+        .map(|taxid| format!("{}\t{}", id_part, taxid))
+}
+
+/// 收集所有没有 taxid 标记的 FASTA 标题中的 accession (带和不带版本号)
+fn collect_untagged_accessions(fasta_files: &[PathBuf]) -> Result<HashSet<String>> {
+    let no_accessions = HashMap::new();
+    let accessions = Mutex::new(HashSet::new());
+    fasta_files.par_iter().try_for_each(|fasta_file| -> Result<()> {
+        let mut found = Vec::new();
+        for line in open_fasta(fasta_file)?.lines() {
+            let line = line?;
+            if line.starts_with('>') && parse_header_to_map_entry(&line, &no_accessions).is_none() {
+                let id_part = line[1..].split_whitespace().next().unwrap_or("");
+                found.push(id_part.to_string());
+                found.push(id_part.split('.').next().unwrap_or(id_part).to_string());
+            }
+        }
+        accessions.lock().unwrap().extend(found);
+        Ok(())
+    })?;
+    Ok(accessions.into_inner().unwrap())
+}
+
+/// 打开 FASTA 文件 (gz 或 plain)
+fn open_fasta(fasta_file: &PathBuf) -> Result<Box<dyn BufRead>> {
+    let file = File::open(fasta_file)?;
+    let is_gzipped = fasta_file.extension().and_then(|s| s.to_str()) == Some("gz");
+    if is_gzipped {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(file)))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// 写入一个完整的 FASTA 记录, 可选地屏蔽低复杂度区域
+fn write_record(fna_writer: &mut SizedWriter, record: String, masking: bool) -> Result<()> {
+    let mut record = record.into_bytes();
+    if masking {
+        mask_fasta_record(&mut record);
+    }
+    fna_writer.write(&record)?;
+    Ok(())
 }
 
 // --- 已修改 ---
@@ -192,16 +265,11 @@ fn process_fasta_file(
     map_writer: &mut BufWriter<File>,
     fna_writer: &mut SizedWriter,
     fna_start: &Regex,
-) -> Result<()> { // <-- 这个 Result 可以是 Box<dyn Error>
-    let file = File::open(fasta_file)?;
-    let is_gzipped = fasta_file.extension().and_then(|s| s.to_str()) == Some("gz");
-    
-    let reader: Box<dyn BufRead> = if is_gzipped {
-        Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(file))))
-    } else {
-        Box::new(BufReader::new(file))
-    };
-    let mut reader = BufReader::new(reader);
+    accession_map: &HashMap<String, u64>,
+    masking: bool,
+) -> Result<Vec<String>> { // <-- 返回本文件加入的 seqid
+    let mut reader = BufReader::new(open_fasta(fasta_file)?);
+    let mut seq_ids = Vec::new();
 
     let mut line = String::new();
     let mut fna_buffer = String::new();
@@ -210,21 +278,22 @@ fn process_fasta_file(
         if fna_start.is_match(&line) {
             // 找到了一个新的 FASTA 记录头 (>)
             if !fna_buffer.is_empty() {
-                fna_writer.write(fna_buffer.as_bytes())?;
-                fna_buffer.clear();
+                write_record(fna_writer, std::mem::take(&mut fna_buffer), masking)?;
             }
 
             // --- 这是新的错误处理逻辑 ---
-            if let Some(map_entry) = parse_header_to_map_entry(&line) {
+            if let Some(map_entry) = parse_header_to_map_entry(&line, accession_map) {
                 // 成功: 写入 map, 准备 fna_buffer
                 map_writer.write_all(map_entry.as_bytes())?;
                 map_writer.write_all(b"\n")?;
+                seq_ids.push(map_entry.split('\t').next().unwrap_or("").to_string());
                 fna_buffer.push_str(&line);
             } else {
                 // 失败: 构造错误消息并返回 Err
                 let error_message = format!(
                     "Error in file '{}': Could not parse a valid taxid from FASTA header. \
-                     \nPlease ensure the header contains a 'taxid|123' format.\
+                     \nPlease ensure the header contains a 'taxid|123' format, \
+                     or pass --accession2taxid files that list its accession.\
                      \nProblematic header: \"{}\"",
                     fasta_file.display(),
                     line.trim()
@@ -245,12 +314,12 @@ fn process_fasta_file(
     }
 
     if !fna_buffer.is_empty() {
-        fna_writer.write(fna_buffer.as_bytes())?;
+        write_record(fna_writer, fna_buffer, masking)?;
     }
 
     fna_writer.flush()?;
     map_writer.flush()?;
-    Ok(())
+    Ok(seq_ids)
 }
 
 // ... (merge_files 保持不变) ...
@@ -282,7 +351,7 @@ fn merge_files(paths: &Vec<PathBuf>, output_path: &PathBuf) -> Result<()> {
 }
 
 
-// ... (load_processed_log 和 hash_file_content 保持不变) ...
+// ... (load_processed_log 保持不变) ...
 /// 从 added.md5 日志文件加载已处理文件的哈希值
 fn load_processed_log(log_path: &Path) -> Result<HashSet<String>> {
     if !log_path.exists() {
@@ -301,32 +370,23 @@ fn load_processed_log(log_path: &Path) -> Result<HashSet<String>> {
     Ok(hashes)
 }
 
-/// 计算文件的 md5 哈希值
-fn hash_file_content(file_path: &Path) -> Result<String> {
-    let mut file = File::open(file_path)?;
-    
-    // 使用 'Context::new()'
-    let mut hasher = Context::new(); 
-    std::io::copy(&mut file, &mut hasher)?;
-    let hash_bytes = hasher.finalize(); 
-    Ok(format!("{:x}", hash_bytes)) 
-}
-
-
 // --- 已修改 ---
 /// 并行添加 FASTA 文件，基于 merge_fna.rs::merge_fna_parallel
 fn add_fna_parallel(
-    fasta_files: &Vec<PathBuf>, // <-- 列表现在是预先过滤过的
+    fasta_files: &Vec<(PathBuf, String)>, // <-- 列表现在是预先过滤过的 (路径, 哈希)
     database: &PathBuf,
     library_dir: &PathBuf,
     max_file_size: u64,
     run_prefix: String, 
-) -> Result<()> { // <-- 这个 Result 会从 try_for_each 传播上来
+    accession_map: &HashMap<String, u64>,
+    masking: bool,
+) -> Result<Vec<(String, String)>> { // <-- 返回 (文件哈希, seqid), 用于记录来源
     let fna_start: Regex = Regex::new(r"^>").unwrap(); 
     let writers: Arc<Mutex<HashMap<usize, SizedWriter>>> = Arc::new(Mutex::new(HashMap::new()));
+    let provenance = Mutex::new(Vec::new());
 
     // --- 改为 .try_for_each() 以便能中途退出 ---
-    let result = fasta_files.par_iter().try_for_each(|fasta_file| -> Result<()> {
+    let result = fasta_files.par_iter().try_for_each(|(fasta_file, hash)| -> Result<()> {
         let thread_index = rayon::current_thread_index().unwrap_or(0);
         let mut writers = writers.lock().unwrap();
         
@@ -353,7 +413,12 @@ fn add_fna_parallel(
         );
 
         // --- '?' 将在出错时立即传播 Err, 停止 .try_for_each ---
-        process_fasta_file(&fasta_file, &mut map_writer, fna_writer, &fna_start)?;
+        let seq_ids = process_fasta_file(fasta_file, &mut map_writer, fna_writer, &fna_start, accession_map, masking)?;
+        drop(writers);
+        provenance
+            .lock()
+            .unwrap()
+            .extend(seq_ids.into_iter().map(|seq_id| (hash.clone(), seq_id)));
 
         Ok(()) // <-- 此文件成功
     });
@@ -368,12 +433,118 @@ fn add_fna_parallel(
     for (_, writer) in writers.iter_mut() {
         writer.flush()?;
     }
-    Ok(())
+    Ok(provenance.into_inner().unwrap())
+}
+
+/// 将本轮新增序列的 minimizer 合并到已有的哈希页中, 只重写收到新 minimizer 的页
+///
+/// # Returns
+///
+/// 哈希表是否已更新; 旧版 (Kraken 2 转换) 哈希表或 taxo.k2d 中缺少新的 taxid 时返回 false
+fn update_hash_tables(
+    args: &Args,
+    library_dir: &Path,
+    run_prefix: &str,
+    new_entries: &HashMap<String, u64>,
+) -> Result<bool> {
+    let k2d_dir = &args.database;
+    let hash_config_path = k2d_dir.join("hash_config.k2d");
+    if new_entries.is_empty() || !hash_config_path.exists() {
+        return Ok(false);
+    }
+    let mut hash_config = HashConfig::from_hash_header(&hash_config_path)?;
+    if hash_config.version < 1 {
+        warn!("Hash tables converted from Kraken 2 can not be updated incrementally.");
+        return Ok(false);
+    }
+    if hash_config.is_compressed() {
+        warn!("Compressed hash tables can not be updated incrementally.");
+        return Ok(false);
+    }
+
+    // 新的 taxid 会改变 taxo.k2d 的内部编号, 此时所有哈希页都必须重建
+    let taxonomy = Taxonomy::from_file(k2d_dir.join("taxo.k2d"))?;
+    let missing: HashSet<u64> = new_entries
+        .values()
+        .copied()
+        .filter(|&taxid| taxonomy.get_internal_id(taxid) == 0)
+        .collect();
+    if !missing.is_empty() {
+        warn!(
+            "{} new taxids are not in taxo.k2d, the hash tables can not be updated incrementally.",
+            missing.len()
+        );
+        return Ok(false);
+    }
+
+    let meros = IndexOptions::read_index_options(k2d_dir.join("opts.k2d"))?.as_meros();
+    let partition = hash_config.partition;
+    if partition >= get_file_limit() {
+        set_fd_limit(partition as u64 + 1)?;
+    }
+
+    // a. 只把新序列的 minimizer 写入 chunk 文件
+    let chunk_files = create_partition_files(partition, k2d_dir, "chunk")?;
+    for chunk_file in &chunk_files {
+        File::create(chunk_file)?;
+    }
+    let mut writers: Vec<_> = create_partition_writers(&chunk_files)?
+        .into_iter()
+        .map(Some)
+        .collect();
+    for fna_file in find_files(library_dir, run_prefix, ".fna") {
+        info!("convert fna file {:?}", fna_file);
+        convert_fna_to_k2_format(
+            fna_file,
+            meros,
+            &taxonomy,
+            new_entries,
+            hash_config,
+            &mut writers,
+            hash_config.hash_capacity,
+            args.threads,
+        );
+    }
+    for writer in writers.iter_mut().flatten() {
+        writer.flush()?;
+    }
+    drop(writers);
+
+    // b. 只合并收到新 minimizer 的哈希页
+    let mut updated_pages = 0;
+    for (i, chunk_file) in chunk_files.iter().enumerate() {
+        let page_index = i + 1;
+        if std::fs::metadata(chunk_file)?.len() > 0 {
+            let hash_file = k2d_dir.join(format!("hash_{}.k2d", page_index));
+            let old_count = read_page_from_file(&hash_file)?
+                .data
+                .iter()
+                .filter(|&&cell| cell != 0)
+                .count();
+            let new_count =
+                update_k2file(hash_config, k2d_dir, chunk_file, &taxonomy, page_index, false)?;
+            hash_config.size = hash_config.size + new_count - old_count;
+            updated_pages += 1;
+        }
+        std::fs::remove_file(chunk_file)?;
+    }
+    hash_config.write_to_file(&hash_config_path)?;
+    DbManifest::update(k2d_dir, library_dir)?;
+    info!("Updated {} of {} hash pages.", updated_pages, partition);
+
+    let load_factor = hash_config.size as f64 / hash_config.capacity as f64;
+    if load_factor > 0.9 {
+        warn!(
+            "Hash table load factor is {:.2}, run 'build-db' to rebuild with a larger capacity.",
+            load_factor
+        );
+    }
+    Ok(true)
 }
 
 pub fn run(args: Args) -> Result<()> {
     let start = Instant::now();
-    println!("Adding files to library...");
+    info!("Adding files to library...");
     let database = &args.database;
     let max_file_size = &args.max_file_size;
 
@@ -383,38 +554,38 @@ pub fn run(args: Args) -> Result<()> {
 
     // 3. 哈希校验和文件过滤
     let log_path = library_dir.join("added.md5");
-    println!("Loading processed file log from: {}", log_path.display());
+    info!("Loading processed file log from: {}", log_path.display());
 
     let processed_hashes_arc = Arc::new(load_processed_log(&log_path)?);
-    println!("Loaded {} previously processed file hashes.", processed_hashes_arc.len());
+    info!("Loaded {} previously processed file hashes.", processed_hashes_arc.len());
 
     let new_hashes_for_this_run_arc = Arc::new(Mutex::new(HashSet::new()));
 
     let all_fasta_files = find_fasta_files(&args.input_library)?;
-    println!("Found {} total FASTA files. Checking for duplicates...", all_fasta_files.len());
+    info!("Found {} total FASTA files. Checking for duplicates...", all_fasta_files.len());
 
     // (文件路径, 哈希值) 的 Vec
     let files_to_process_with_hash: Vec<(PathBuf, String)> = all_fasta_files
         .into_par_iter() 
         .filter_map(|file_path| {
-            let hash = match hash_file_content(&file_path) {
+            let hash = match file_md5(&file_path) {
                 Ok(h) => h,
                 Err(e) => {
-                    eprintln!("Error hashing file {}: {}. Skipping.", file_path.display(), e);
+                    error!("Error hashing file {}: {}. Skipping.", file_path.display(), e);
                     return None;
                 }
             };
 
             // 检查是否 *之前* 处理过
             if processed_hashes_arc.contains(&hash) {
-                println!("Skipping (already processed): {}", file_path.display());
+                info!("Skipping (already processed): {}", file_path.display());
                 return None;
             }
             
             // 检查是否在 *本轮* 运行中已经见过 (处理重复输入)
             let mut new_hashes = new_hashes_for_this_run_arc.lock().unwrap();
             if new_hashes.contains(&hash) {
-                println!("Skipping (duplicate in this run): {}", file_path.display());
+                info!("Skipping (duplicate in this run): {}", file_path.display());
                 return None;
             }
 
@@ -432,31 +603,45 @@ pub fn run(args: Args) -> Result<()> {
     
     // 4. 检查是否有新文件需要处理
     if files_to_process.is_empty() {
-        println!("No new files to add. Database is up-to-date.");
+        info!("No new files to add. Database is up-to-date.");
         return Ok(());
     }
-    println!("Processing {} new files...", files_to_process.len());
+    info!("Processing {} new files...", files_to_process.len());
 
 
+    // 4b. 通过 accession2taxid 解析没有 taxid 标记的标题
+    let accession_map = if args.accession2taxid.is_empty() {
+        HashMap::new()
+    } else {
+        let accessions = collect_untagged_accessions(&files_to_process)?;
+        info!("Resolving untagged FASTA headers against accession2taxid files...");
+        let accession_map = read_accession_to_taxid_map(&args.accession2taxid, &accessions)?;
+        info!("Found {} matching accession2taxid entries.", accession_map.len());
+        accession_map
+    };
+
     // 5. 生成唯一的运行前缀
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
         .as_secs();
     let run_prefix = format!("library_add_{}", timestamp);
-    println!("Using unique run prefix: {}", run_prefix);
+    info!("Using unique run prefix: {}", run_prefix);
 
     // 6. 传递 *过滤后* 的列表到并行处理器
     // --- '?' 将捕获来自 'add_fna_parallel' 的任何错误并停止 'run' ---
-    add_fna_parallel(
-        &files_to_process, // <-- 使用过滤后的列表
+    let provenance = add_fna_parallel(
+        &files_to_process_with_hash, // <-- 使用过滤后的列表
         &args.database,
         &library_dir,
         *max_file_size as u64,
-        run_prefix,
+        run_prefix.clone(),
+        &accession_map,
+        !args.no_masking,
     )?; 
 
     // 7. 合并并追加 map 文件
+    let mut new_entries: HashMap<String, u64> = HashMap::new();
     let add_map_files = find_files(database, "add_seqid2taxid_", "map");
     if !add_map_files.is_empty() {
         let temp_map_merge_path = database.join("add_seqid2taxid.map.tmp");
@@ -473,7 +658,7 @@ pub fn run(args: Args) -> Result<()> {
             HashMap::new()
         };
         
-        println!("De-duplicating and appending new seqid entries...");
+        info!("De-duplicating and appending new seqid entries...");
 
         // c. 打开主 map 文件用于追加
         let mut main_map_writer = BufWriter::new(OpenOptions::new()
@@ -511,6 +696,7 @@ pub fn run(args: Args) -> Result<()> {
                         if existing_map.insert(seq_id.clone(), taxid).is_none() {
                              // 写入主 map 文件
                             writeln!(main_map_writer, "{}\t{}", seq_id, taxid)?;
+                            new_entries.insert(seq_id, taxid);
                         }
                     }
                 }
@@ -520,12 +706,12 @@ pub fn run(args: Args) -> Result<()> {
         
         // e. 清理临时文件
         std::fs::remove_file(&temp_map_merge_path)?;
-        println!("Appended new entries to seqid2taxid.map");
+        info!("Appended new entries to seqid2taxid.map");
     }
 
     // 9. 成功后，将新哈希值写入日志 (TSV 格式)
     // --- 只有在前面所有步骤都成功后 (没有 Err) 才会执行到这里 ---
-    println!("Updating processed file log: {}", log_path.display());
+    info!("Updating processed file log: {}", log_path.display());
     let log_file = OpenOptions::new()
         .append(true)
         .create(true)
@@ -539,22 +725,31 @@ pub fn run(args: Args) -> Result<()> {
     }
     log_writer.flush()?;
 
-    // --- 10. 检查是否存在旧的哈希表并发出警告 ---
-    println!("\nChecking for existing hash tables...");
+    // 记录每个 seqid 来自哪个文件 (哈希), remove-library 按文件哈希删除时使用
+    let provenance_path = library_dir.join(LIBRARY_PROVENANCE_FILENAME);
+    let mut provenance_writer = BufWriter::new(
+        OpenOptions::new().append(true).create(true).open(&provenance_path)?,
+    );
+    for (hash, seq_id) in &provenance {
+        writeln!(provenance_writer, "{}\t{}", hash, seq_id)?;
+    }
+    provenance_writer.flush()?;
+
+    // --- 10. 增量更新哈希表, 无法更新时发出警告 ---
+    info!("Checking for existing hash tables...");
     let hash_files = find_files(database, "hash_", ".k2d");
     if !hash_files.is_empty() {
-        // 使用 eprintln! 将警告发送到标准错误流
-        eprintln!("\n---------------------------------------------------------------");
-        eprintln!("  [!] WARNING: DATABASE IS NOW OUT-OF-DATE");
-        eprintln!("\n  You have successfully added new sequences and regenerated the taxonomy (taxo.k2d).");
-        eprintln!("  However, old hash table files (e.g., 'hash_*.k2d') were detected.");
-        eprintln!("  These old hash files are now STALE and will produce incorrect results.");
-        eprintln!("\n  ==> You MUST run the 'build-db' command to rebuild the hash tables <==\n");
-        eprintln!("---------------------------------------------------------------");
+        let updated = !args.no_rebuild
+            && update_hash_tables(&args, &library_dir, &run_prefix, &new_entries)?;
+        if !updated {
+            warn!(
+                "DATABASE IS NOW OUT-OF-DATE: the existing hash tables (hash_*.k2d) do not contain the new sequences, run 'build-db' to rebuild them"
+            );
+        }
     }
 
     let duration = start.elapsed();
-    println!("Finished adding {} new files in: {:?}", files_to_process.len(), duration);
+    info!("Finished adding {} new files in: {:?}", files_to_process.len(), duration);
     Ok(())
 }
 
@@ -1,12 +1,30 @@
 use clap::Parser;
-use kun_peng::compact_hash::{read_next_page, Compact, HashConfig, Page, Row, Slot};
-use kun_peng::utils::{find_and_sort_files, open_file};
+use kun_peng::args::Partition;
+use kun_peng::checkpoint::{
+    remove_sentinel, write_sentinel, Checkpoint, ANNOTATE_DONE, CLASSIFY_CHECKPOINT_FILENAME,
+};
+use kun_peng::chunk_stream::{open_chunk_file, ChunkHeader};
+use kun_peng::compact_hash::{
+    page_file_bytes, read_next_page, Compact, HashConfig, Page, Row, Slot,
+};
+use kun_peng::error::{database_error, Error};
+use kun_peng::footer::seal_file;
+use kun_peng::progress::Progress;
+use kun_peng::read_ahead::will_need;
+use kun_peng::utils::{
+    find_and_sort_files, find_files, format_bytes, memory_budget, pipeline_threads,
+    pipeline_workers,
+};
+use log::{info, warn};
+use rayon::prelude::*;
 use seqkmer::buffer_read_parallel;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Result, Write};
+use std::io::{self, BufWriter, Read, Result, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::Instant;
 
 // 定义每批次处理的 Slot 数量
@@ -38,28 +56,34 @@ pub struct Args {
     #[clap(long, value_parser = clap::value_parser!(u32).range(1..=32), default_value_t = 4)]
     pub batch_size: u32,
 
-    /// The number of threads to use.
+    /// The number of worker threads, capped by the global --threads.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
-}
-
-fn read_chunk_header<R: Read>(reader: &mut R) -> io::Result<(usize, usize)> {
-    let mut buffer = [0u8; 16]; // u64 + u64 = 8 bytes + 8 bytes
 
-    reader.read_exact(&mut buffer)?;
+    /// Resume an interrupted run, skipping the chunk files it has already annotated
+    #[clap(long, default_value_t = false)]
+    pub resume: bool,
 
-    let index = u64::from_le_bytes(
-        buffer[0..8]
-            .try_into()
-            .expect("Failed to convert bytes to u64 for index"),
-    );
-    let chunk_size = u64::from_le_bytes(
-        buffer[8..16]
-            .try_into()
-            .expect("Failed to convert bytes to u64 for chunk size"),
-    );
+    /// Print the number of annotated chunk files with an estimated time left to stderr
+    #[clap(long, default_value_t = false)]
+    pub progress: bool,
 
-    Ok((index as usize, chunk_size as usize))
+    /// Annotate only the chunk files of the hash pages in partition i of N (e.g. 2/8), one
+    /// job of an array. The sample bin files go to <chunk_dir>/partition_i, where resolve finds them.
+    #[clap(long)]
+    pub partition: Option<Partition>,
+
+    /// Read the hash page of the next chunk file in the background while the current one is
+    /// annotated, so page loading overlaps with the lookups. Holds two pages in memory; turned
+    /// off if they do not fit the memory budget
+    #[clap(long, default_value_t = false)]
+    pub prefetch: bool,
+
+    /// Hash pages held in memory and annotated at once, each chunk file with a share of the
+    /// worker threads. Default: as many as the memory budget fits, up to one per worker
+    /// thread; 1 without a budget
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub concurrent_pages: Option<u32>,
 }
 
 fn _write_to_file(
@@ -100,16 +124,19 @@ fn write_to_file(
     chunk_dir: &PathBuf,
 ) -> io::Result<()> {
     // 检查是否已经有该文件的 writer，没有则创建一个新的
-    let writer = writers.entry((file_index, seq_id_mod)).or_insert_with(|| {
-        let file_name = format!("sample_file_{}_{}.bin", file_index, seq_id_mod);
-        let file_path = chunk_dir.join(file_name);
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&file_path)
-            .expect("failed to open file");
-        BufWriter::new(file)
-    });
+    let writer = match writers.entry((file_index, seq_id_mod)) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let file_name = format!("sample_file_{}_{}.bin", file_index, seq_id_mod);
+            let file_path = chunk_dir.join(file_name);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file_path)
+                .map_err(|e| Error::io(&file_path, e))?;
+            entry.insert(BufWriter::new(file))
+        }
+    };
 
     writer.write_all(bytes)?;
 
@@ -135,33 +162,91 @@ fn clean_up_writers(
     Ok(())
 }
 
+/// Appends the hits of a batch of slots to the sample bin files
+fn write_hits(
+    res: HashMap<(u64, u32), Vec<u8>>,
+    writers: &mut HashMap<(u64, u32), BufWriter<File>>,
+    current_file_index: &mut Option<u64>,
+    chunk_dir: &PathBuf,
+    write_lock: Option<&Mutex<()>>,
+) -> io::Result<()> {
+    let _guard = write_lock.map(|lock| lock.lock().unwrap());
+    let mut file_keys: Vec<_> = res.keys().cloned().collect();
+    file_keys.sort_unstable(); // 对 (file_index, seq_id_mod) 进行排序
+
+    for (file_index, seq_id_mod) in file_keys {
+        if let Some(bytes) = res.get(&(file_index, seq_id_mod)) {
+            // 如果当前处理的 file_index 改变了，清理非当前的 writers
+            if *current_file_index != Some(file_index) {
+                clean_up_writers(writers, file_index)?;
+                *current_file_index = Some(file_index);
+            }
+
+            write_to_file(file_index, seq_id_mod, bytes, writers, chunk_dir)?;
+        }
+    }
+    if write_lock.is_some() {
+        for writer in writers.values_mut() {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Pages with more cells than this have each batch of slots sorted by their index in the page
+/// before the lookups; smaller pages stay in the CPU caches, random lookups cost them nothing
+const SORT_SLOTS_MIN_PAGE_CELLS: usize = 1 << 22;
+
+/// Looks up the slots of a chunk file in its page and appends the hits to the sample bin files
+///
+/// On large pages the slots of a batch are looked up in the order of their index in the page,
+/// so the probes sweep through the page instead of jumping around in it. The rows are sorted
+/// by k-mer again in resolve.
+///
+/// Chunk files annotated at the same time share `write_lock`: each batch of hits is then
+/// written and flushed under the lock, so the rows of two chunk files never interleave.
+///
+/// The slots number the samples from `first_sample`, the sample bin files are named after
+/// their file index.
 fn process_batch<R>(
     reader: &mut R,
     hash_config: &HashConfig,
+    first_sample: u64,
     page: &Page,
     chunk_dir: PathBuf,
-    buffer_size: usize,
-    bin_threads: u32,
-    // page_index: usize,
-    num_threads: usize,
+    args: &Args,
+    write_lock: Option<&Mutex<()>>,
 ) -> std::io::Result<()>
 where
     R: Read + Send,
 {
-    let row_size = std::mem::size_of::<Row>();
     let mut writers: HashMap<(u64, u32), BufWriter<File>> = HashMap::new();
     let mut current_file_index: Option<u64> = None;
 
-    let value_mask = hash_config.get_value_mask();
-    let value_bits = hash_config.get_value_bits();
+    // 宽 cell 数据库中, 样本 slot 只保留 key 的高位, value 部分存放文件编号
+    let value_mask = hash_config.sample_value_mask();
+    let value_bits = hash_config.sample_value_bits();
+    let wide = hash_config.is_wide();
     let idx_mask = hash_config.get_idx_mask();
     let idx_bits = hash_config.get_idx_bits();
+    let sort_slots = page.size > SORT_SLOTS_MIN_PAGE_CELLS;
 
+    let bin_threads = args.batch_size;
+    // buffer_read_parallel 不返回汇总线程的结果, 由它写回这里
+    let mut written = Ok(());
     buffer_read_parallel(
         reader,
-        num_threads,
-        buffer_size,
-        |dataset: Vec<Slot<u64>>| {
+        pipeline_threads(args.num_threads),
+        args.buffer_size,
+        |mut dataset: Vec<Slot<u64>>| {
+            // slot 按文件中的字节读入, 在大端平台上换成本机字节序
+            for slot in dataset.iter_mut() {
+                *slot = Slot::from_le(*slot);
+            }
+            if sort_slots {
+                // 按页内下标排序, 查询顺序扫过页面而不是随机跳转
+                dataset.sort_unstable_by_key(|slot| slot.idx & idx_mask);
+            }
             let mut results: HashMap<(u64, u32), Vec<u8>> = HashMap::new();
             for slot in dataset {
                 let indx = slot.idx & idx_mask;
@@ -171,44 +256,42 @@ where
 
                 if taxid > 0 {
                     let kmer_id = slot.idx >> idx_bits;
-                    let file_index = slot.value.right(value_mask) >> 32;
+                    let file_index = (slot.value.right(value_mask) >> 32) + first_sample - 1;
                     let seq_id = slot.get_seq_id() as u32;
                     let left = slot.value.left(value_bits) as u32;
-                    let high = u32::combined(left, taxid, value_bits);
+                    let high = if wide {
+                        taxid
+                    } else {
+                        u32::combined(left, taxid, value_bits)
+                    };
                     let row = Row::new(high, seq_id, kmer_id as u32);
-                    let value_bytes = row.as_slice(row_size);
+                    let value_bytes = row.to_le_bytes();
                     let seq_id_mod = seq_id % bin_threads;
 
                     results
                         .entry((file_index, seq_id_mod))
                         .or_insert_with(Vec::new)
-                        .extend(value_bytes);
+                        .extend_from_slice(&value_bytes);
                 }
             }
             results
         },
         |result| {
             while let Some(data) = result.next() {
-                let res = data.unwrap();
-                let mut file_keys: Vec<_> = res.keys().cloned().collect();
-                file_keys.sort_unstable(); // 对 (file_index, seq_id_mod) 进行排序
-
-                for (file_index, seq_id_mod) in file_keys {
-                    if let Some(bytes) = res.get(&(file_index, seq_id_mod)) {
-                        // 如果当前处理的 file_index 改变了，清理非当前的 writers
-                        if current_file_index != Some(file_index) {
-                            clean_up_writers(&mut writers, file_index).expect("clean writer");
-                            current_file_index = Some(file_index);
-                        }
-
-                        write_to_file(file_index, seq_id_mod, bytes, &mut writers, &chunk_dir)
-                            .expect("write to file error");
-                    }
+                // 出错后仍取完剩余的批次, 工作线程才能结束
+                if written.is_ok() {
+                    written = write_hits(
+                        data.unwrap(),
+                        &mut writers,
+                        &mut current_file_index,
+                        &chunk_dir,
+                        write_lock,
+                    );
                 }
             }
         },
-    )
-    .expect("failed");
+    )?;
+    written?;
 
     // 最终批次处理完成后，刷新所有的 writer
     for writer in writers.values_mut() {
@@ -218,63 +301,383 @@ where
     Ok(())
 }
 
-fn process_chunk_file<P: AsRef<Path>>(
+/// Loads the hash page of a chunk file, with the overflow block of the next page
+fn load_chunk_page<P: AsRef<Path>>(
     args: &Args,
     chunk_file: P,
     hash_files: &Vec<PathBuf>,
     large_page: &mut Page,
 ) -> Result<()> {
-    let file = open_file(chunk_file)?;
-    let mut reader = BufReader::new(file);
-
-    let (page_index, _) = read_chunk_header(&mut reader)?;
+    let page_index = ChunkHeader::from_file(chunk_file)?.page_index;
 
     let start = Instant::now();
 
-    println!("start load table...");
+    info!("start load table...");
     let config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
 
     read_next_page(large_page, hash_files, page_index, config)?;
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("load table took: {:?}", duration);
+    info!("load table took: {:?}", duration);
+    Ok(())
+}
+
+/// Loads the hash pages of a group of chunk files, one thread per page
+fn load_chunk_pages(
+    args: &Args,
+    chunk_files: &[&PathBuf],
+    hash_files: &Vec<PathBuf>,
+    pages: &mut Vec<Page>,
+) -> Result<()> {
+    pages.resize_with(chunk_files.len(), Page::default);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunk_files
+            .iter()
+            .zip(pages.iter_mut())
+            .map(|(chunk_file, page)| {
+                scope.spawn(move || load_chunk_page(args, chunk_file, hash_files, page))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("page load panicked"))
+    })
+}
+
+/// Asks for the hash pages of a group of chunk files ahead of their load, see [`will_need`]
+fn will_need_chunk_pages(chunk_files: &[&PathBuf], hash_files: &[PathBuf]) {
+    for chunk_file in chunk_files {
+        if let Ok(header) = ChunkHeader::from_file(chunk_file) {
+            if let Some(hash_file) = hash_files.get(header.page_index) {
+                will_need(hash_file);
+            }
+        }
+    }
+}
+
+/// Annotates the slots of a chunk file with its hash page, loaded by [`load_chunk_page`]
+fn process_chunk_file<P: AsRef<Path>>(
+    args: &Args,
+    chunk_file: P,
+    large_page: &Page,
+    bin_dir: &Path,
+    write_lock: Option<&Mutex<()>>,
+) -> Result<()> {
+    // 压缩的分块文件在读取时解压
+    let (header, mut reader) = open_chunk_file(chunk_file)?;
+
+    let config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
     process_batch(
         &mut reader,
         &config,
-        &large_page,
-        args.chunk_dir.clone(),
-        args.buffer_size,
-        args.batch_size,
-        // page_index,
-        args.num_threads,
+        header.first_sample as u64,
+        large_page,
+        bin_dir.to_path_buf(),
+        args,
+        write_lock,
     )?;
 
     Ok(())
 }
 
+/// Annotates a group of chunk files at once, each with its own page and a share of the workers
+fn process_chunk_files(
+    args: &Args,
+    chunk_files: &[&PathBuf],
+    pages: &[Page],
+    bin_dir: &Path,
+) -> Result<()> {
+    if let ([chunk_file], [page]) = (chunk_files, pages) {
+        return process_chunk_file(args, chunk_file, page, bin_dir, None);
+    }
+    let args = &Args {
+        num_threads: (pipeline_workers(args.num_threads) / chunk_files.len()).max(1),
+        ..args.clone()
+    };
+    let write_lock = Mutex::new(());
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunk_files
+            .iter()
+            .zip(pages)
+            .map(|(chunk_file, page)| {
+                let write_lock = &write_lock;
+                scope.spawn(move || {
+                    process_chunk_file(args, chunk_file, page, bin_dir, Some(write_lock))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("chunk file annotation panicked"))
+    })
+}
+
+/// Smallest number of slots per batch chosen to fit the memory budget
+const MIN_BUFFER_SIZE: usize = 1 << 16;
+
+/// Fits the batches of slots next to the largest hash page into the memory budget
+///
+/// # Returns
+///
+/// The number of slots per batch, or an error if a hash page alone exceeds the budget
+fn fit_buffer_size(args: &Args, page_bytes: usize) -> Result<usize> {
+    let Some(budget) = memory_budget() else {
+        return Ok(args.buffer_size);
+    };
+    if page_bytes > budget {
+        return Err(Error::Resources(format!(
+            "hash pages of {} do not fit the memory budget of {}; rebuild the database with --max-memory {} or raise --max-memory",
+            format_bytes(page_bytes as f64),
+            format_bytes(budget as f64),
+            format_bytes(budget as f64)
+        ))
+        .into());
+    }
+    // 同时持有 concurrent_pages 页, 预读时加倍; 每个线程约有一批 Slot 在处理中
+    let pages = args.concurrent_pages.unwrap_or(1) as usize;
+    let pages_bytes = if args.prefetch { 2 * pages * page_bytes } else { pages * page_bytes };
+    let slot_size = std::mem::size_of::<Slot<u64>>();
+    let workers = pipeline_workers(args.num_threads);
+    let fitted = (budget.saturating_sub(pages_bytes) / slot_size / workers).max(MIN_BUFFER_SIZE);
+    if fitted >= args.buffer_size {
+        return Ok(args.buffer_size);
+    }
+    info!(
+        "buffer size reduced from {} to {} slots to fit the memory budget of {}",
+        args.buffer_size,
+        fitted,
+        format_bytes(budget as f64)
+    );
+    Ok(fitted)
+}
+
+/// Picks how many hash pages are held in memory and annotated at once
+///
+/// # Returns
+///
+/// `--concurrent-pages`, capped by the memory budget, or else as many pages as the budget fits
+/// next to the smallest batches of slots; one page without a budget. At most one page per
+/// worker thread.
+fn fit_concurrent_pages(args: &Args, page_bytes: usize) -> u32 {
+    let workers = pipeline_workers(args.num_threads) as u32;
+    let Some(budget) = memory_budget() else {
+        return args.concurrent_pages.unwrap_or(1).min(workers);
+    };
+    let slot_bytes = MIN_BUFFER_SIZE * std::mem::size_of::<Slot<u64>>() * workers as usize;
+    let copies = if args.prefetch { 2 } else { 1 };
+    let fitted = (budget.saturating_sub(slot_bytes) / (copies * page_bytes).max(1)).max(1) as u32;
+    match args.concurrent_pages {
+        Some(pages) if pages > fitted => {
+            warn!(
+                "{} hash pages do not fit the memory budget of {}, holding {}",
+                pages,
+                format_bytes(budget as f64),
+                fitted
+            );
+            fitted
+        }
+        Some(pages) => pages,
+        None => fitted.min(workers),
+    }
+}
+
+/// The size of the largest hash page when loaded
+fn largest_page_bytes(hash_files: &[PathBuf]) -> Result<usize> {
+    let mut page_bytes = 0;
+    for hash_file in hash_files {
+        page_bytes = page_bytes.max(page_file_bytes(hash_file)? as usize);
+    }
+    Ok(page_bytes)
+}
+
+/// The hash page of a chunk file written by splitr, `sample_{page}.k2`
+fn chunk_file_page(chunk_file: &Path) -> Option<usize> {
+    chunk_file
+        .file_name()?
+        .to_str()?
+        .strip_prefix("sample_")?
+        .strip_suffix(".k2")?
+        .parse()
+        .ok()
+}
+
+/// Sizes of the sample bin files, recorded as `name=size,...` after each annotated chunk file
+fn bin_file_sizes(chunk_dir: &Path) -> Result<String> {
+    let mut sizes = Vec::new();
+    for bin_file in find_files(chunk_dir, "sample_file_", ".bin") {
+        let name = bin_file.file_name().unwrap_or_default().to_string_lossy();
+        sizes.push(format!("{}={}", name, std::fs::metadata(&bin_file)?.len()));
+    }
+    Ok(sizes.join(","))
+}
+
+/// Cuts the sample bin files back to their sizes after the last annotated chunk file
+///
+/// An interrupted chunk file has appended part of its rows, which would be counted twice
+/// once the chunk file is annotated again.
+fn restore_bin_files(chunk_dir: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let recorded = checkpoint
+        .entries()
+        .filter(|(key, _)| key.starts_with(ANNOTATE_DONE))
+        .last()
+        .map_or("", |(_, sizes)| sizes);
+    let sizes: HashMap<&str, u64> = recorded
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(name, size)| Some((name, size.parse().ok()?)))
+        .collect();
+
+    for bin_file in find_files(chunk_dir, "sample_file_", ".bin") {
+        let name = bin_file.file_name().unwrap_or_default().to_string_lossy();
+        match sizes.get(name.as_ref()) {
+            Some(&size) => {
+                if std::fs::metadata(&bin_file)?.len() > size {
+                    OpenOptions::new().write(true).open(&bin_file)?.set_len(size)?;
+                }
+            }
+            None => std::fs::remove_file(&bin_file)?,
+        }
+    }
+    Ok(())
+}
+
 pub fn run(args: Args) -> Result<()> {
-    let chunk_files = find_and_sort_files(&args.chunk_dir, "sample", ".k2", true)?;
-    let hash_files = find_and_sort_files(
-        &args.database, "hash", ".k2d", true,
-    )
-    .expect("Invalid or incomplete database: missing hash files.");
+    // 续跑时已完成的分块文件已被删除, 编号不再连续; 分区运行时其他分区的作业也会删除分块文件
+    let check = !args.resume && args.partition.is_none();
+    let mut chunk_files = find_and_sort_files(&args.chunk_dir, "sample", ".k2", check)?;
+    // 各分区的样本 bin 文件, 断点和完成标记写在自己的目录, 互不干扰
+    let bin_dir = match &args.partition {
+        Some(partition) => {
+            chunk_files.retain(|file| chunk_file_page(file).is_some_and(|p| partition.contains(p)));
+            info!("partition {}: {} chunk files", partition, chunk_files.len());
+            let dir = partition.dir(&args.chunk_dir);
+            std::fs::create_dir_all(&dir)?;
+            dir
+        }
+        None => args.chunk_dir.clone(),
+    };
+    let hash_config_file = args.database.join("hash_config.k2d");
+    let config = HashConfig::from_hash_header(&hash_config_file)
+        .map_err(|e| database_error(&hash_config_file, e))?;
+    let hash_files = find_and_sort_files(&args.database, "hash", config.page_suffix(), true)
+        .map_err(|e| database_error(&args.database, e))?;
+    if hash_files.is_empty() {
+        return Err(Error::InvalidDatabase {
+            path: args.database.clone(),
+            reason: format!("no hash_*{} files", config.page_suffix()),
+        }
+        .into());
+    }
+    let page_bytes = largest_page_bytes(&hash_files)?;
+    let prefetch = args.prefetch && memory_budget().is_none_or(|budget| 2 * page_bytes <= budget);
+    if args.prefetch && !prefetch {
+        warn!(
+            "two hash pages of {} do not fit the memory budget, not prefetching",
+            format_bytes(page_bytes as f64)
+        );
+    }
+    let args = Args { prefetch, ..args };
+    let concurrent_pages = fit_concurrent_pages(&args, page_bytes);
+    if concurrent_pages > 1 {
+        info!("holding {} hash pages at once", concurrent_pages);
+    }
+    let args = Args {
+        concurrent_pages: Some(concurrent_pages),
+        ..args
+    };
+    let args = Args {
+        buffer_size: fit_buffer_size(&args, page_bytes)?,
+        ..args
+    };
 
     // 开始计时
     let start = Instant::now();
-    println!("annotate start...");
-    let config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))
-        .expect("Invalid or incomplete database: missing hash_config.k2d.");
-    let mut large_page = Page::with_capacity(0, config.hash_capacity);
-    for chunk_file in &chunk_files {
-        process_chunk_file(&args, chunk_file, &hash_files, &mut large_page)?;
-        let _ = std::fs::remove_file(chunk_file);
+    info!("annotate start...");
+    let mut checkpoint = Checkpoint::load_file(bin_dir.join(CLASSIFY_CHECKPOINT_FILENAME))?;
+    if args.resume {
+        restore_bin_files(&bin_dir, &checkpoint)?;
+    }
+    remove_sentinel(&bin_dir, "annotate")?;
+    let progress = Progress::new(
+        "annotate",
+        "chunk files",
+        chunk_files.len() as u64,
+        args.progress,
+    );
+    let keys: Vec<String> = chunk_files
+        .iter()
+        .map(|chunk_file| {
+            format!(
+                "{}{}",
+                ANNOTATE_DONE,
+                chunk_file.file_name().unwrap_or_default().to_string_lossy()
+            )
+        })
+        .collect();
+    let mut pending = Vec::new();
+    for (i, chunk_file) in chunk_files.iter().enumerate() {
+        if args.resume && checkpoint.is_done(&keys[i]) {
+            info!("{:?} already annotated, skipping", chunk_file);
+            let _ = std::fs::remove_file(chunk_file);
+            progress.inc(1);
+        } else {
+            pending.push(i);
+        }
     }
+    // 每组的分块文件同时加载各自的页并一起注释
+    let groups: Vec<Vec<&PathBuf>> = pending
+        .chunks(concurrent_pages as usize)
+        .map(|group| group.iter().map(|&i| &chunk_files[i]).collect())
+        .collect();
+    let mut pages = Vec::new();
+    // 预读用的第二组页缓冲, 与当前组轮换使用
+    let mut spare_pages = args.prefetch.then(Vec::new);
+    std::thread::scope(|scope| -> Result<()> {
+        let mut prefetched: Option<std::thread::ScopedJoinHandle<Result<Vec<Page>>>> = None;
+        for (g, (group, indices)) in groups
+            .iter()
+            .zip(pending.chunks(concurrent_pages as usize))
+            .enumerate()
+        {
+            match prefetched.take() {
+                Some(handle) => {
+                    let loaded = handle.join().expect("page prefetch panicked")?;
+                    spare_pages = Some(std::mem::replace(&mut pages, loaded));
+                }
+                None => load_chunk_pages(&args, group, &hash_files, &mut pages)?,
+            }
+            if let (Some(next_group), Some(mut spare)) = (groups.get(g + 1), spare_pages.take()) {
+                let (args, hash_files) = (&args, &hash_files);
+                prefetched = Some(scope.spawn(move || {
+                    load_chunk_pages(args, next_group, hash_files, &mut spare).map(|_| spare)
+                }));
+            } else if let Some(next_group) = groups.get(g + 1) {
+                // 不预读整页时, 让下一组的页文件先进入页缓存
+                will_need_chunk_pages(next_group, &hash_files);
+            }
+            process_chunk_files(&args, group, &pages, &bin_dir)?;
+            let sizes = bin_file_sizes(&bin_dir)?;
+            for (chunk_file, &i) in group.iter().zip(indices) {
+                checkpoint.mark(&keys[i], &sizes)?;
+                let _ = std::fs::remove_file(chunk_file);
+                progress.inc(1);
+            }
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    let bin_files = find_files(&bin_dir, "sample_file_", ".bin");
+    // 所有分块文件注释完后 bin 文件不再追加, 写入 footer 供 resolve 校验
+    bin_files
+        .par_iter()
+        .try_for_each(|bin_file| seal_file(bin_file, Row::BYTES))?;
+    write_sentinel(&bin_dir, "annotate", &bin_files)?;
 
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("annotate took: {:?}", duration);
+    info!("annotate took: {:?}", duration);
 
     Ok(())
 }
@@ -1,11 +1,21 @@
 // 使用时需要引用模块路径
 use clap::Parser;
-use kun_peng::compact_hash::HashConfig;
-use kun_peng::db::process_k2file;
+use kun_peng::args::parse_size;
+use kun_peng::checkpoint::{remove_sentinel, write_sentinel, Checkpoint};
+use kun_peng::compact_hash::{compress_page_file, HashConfig, DOUBLE_HASHING_FLAG, ZSTD_PAGE_FLAG};
+use kun_peng::db::{
+    process_k2file, process_sorted_k2file, read_build_stats, read_page_capacity,
+    BUILD_STATS_FILENAME, DEFAULT_MAX_PAGE_LOAD, PAGE_CAPACITY_FILENAME,
+};
+use kun_peng::manifest::DbManifest;
+use kun_peng::prefilter::{prefilter_path, write_prefilter};
+use kun_peng::progress::Progress;
 use kun_peng::taxonomy::Taxonomy;
-use kun_peng::utils::find_and_trans_files;
+use kun_peng::utils::{find_and_sort_files, find_and_trans_files, format_bytes, memory_budget};
+use log::{info, warn};
+use rayon::prelude::*;
 use std::fs::remove_file;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Parser, Debug, Clone)]
@@ -14,15 +24,164 @@ pub struct Args {
     /// database hash chunk directory and other files
     #[arg(long = "db", required = true)]
     pub database: PathBuf,
+
+    /// Insert the chunk cells in sorted order so that identical inputs produce byte-identical hash files
+    #[arg(long, default_value_t = false)]
+    pub deterministic: bool,
+
+    /// Grow hash pages that are fuller than this after the build, 0 keeps every page at its planned size
+    #[arg(long, default_value_t = DEFAULT_MAX_PAGE_LOAD)]
+    pub max_page_load: f64,
+
+    /// Compress the hash pages with zstd (hash_*.k2d.zst), they are decompressed when loaded
+    #[arg(long, default_value_t = false)]
+    pub compress_pages: bool,
+
+    /// Build each page from its externally sorted chunk file, sorting at most this much memory
+    /// of cells at a time (e.g. '512M') instead of holding the whole page in memory
+    #[arg(long, value_parser = parse_size)]
+    pub sort_buffer: Option<usize>,
+
+    /// Build each hash page in a memory mapping of its file, written back by the kernel as it
+    /// fills, instead of in memory and then serialized. Pages are not held in the memory budget
+    #[arg(long, default_value_t = false, conflicts_with = "sort_buffer")]
+    pub mmap_pages: bool,
+
+    /// Write a Bloom prefilter next to each hash page (hash_*.k2f), consulted before the page is
+    /// probed so that absent minimizers cost no page lookup. Takes about 10 bits per cell
+    #[arg(long, default_value_t = false)]
+    pub prefilter: bool,
+
+    /// Probe the hash pages by double hashing instead of linearly, recorded in hash_config.k2d.
+    /// Cuts the probe lengths of full pages; the default follows the `double_hashing` feature
+    #[arg(long, default_value_t = cfg!(feature = "double_hashing"))]
+    pub double_hashing: bool,
+
+    /// Print the progress of the hash page builds with an estimated time left to stderr
+    #[arg(long, default_value_t = false)]
+    pub progress: bool,
 }
 
-pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let k2d_dir = database;
+/// zstd level of compressed hash pages
+const ZSTD_LEVEL: i32 = 3;
+
+/// Prints the LCA promotions and probing collisions recorded for the pages
+fn print_build_stats(k2d_dir: &Path) -> std::io::Result<()> {
+    let stats = read_build_stats(k2d_dir)?;
+    let cells: usize = stats.iter().map(|item| item.cells).sum();
+    let lca_promotions: usize = stats.iter().map(|item| item.lca_promotions).sum();
+    let collisions: usize = stats.iter().map(|item| item.collisions).sum();
+    let per_cell = |value: usize| value as f64 / cells.max(1) as f64;
+    info!(
+        "{} cells, {} LCA promotions ({:.2} per cell), {} probing collisions ({:.2} per cell), see {}",
+        cells,
+        lca_promotions,
+        per_cell(lca_promotions),
+        collisions,
+        per_cell(collisions),
+        BUILD_STATS_FILENAME
+    );
+    Ok(())
+}
+
+/// Compresses the built hash pages, skipping those compressed by an interrupted run
+fn compress_pages(k2d_dir: &Path, partition: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let sizes = (1..=partition)
+        .into_par_iter()
+        .map(|i| {
+            let hash_file = k2d_dir.join(format!("hash_{}.k2d", i));
+            if !hash_file.exists() {
+                return Ok((0, 0));
+            }
+            let size = std::fs::metadata(&hash_file)?.len();
+            Ok((size, compress_page_file(&hash_file, ZSTD_LEVEL)?))
+        })
+        .collect::<std::io::Result<Vec<(u64, u64)>>>()?;
+    let (size, compressed): (u64, u64) = sizes
+        .iter()
+        .fold((0, 0), |acc, item| (acc.0 + item.0, acc.1 + item.1));
+    info!(
+        "compressed hash pages: {} -> {}",
+        format_bytes(size as f64),
+        format_bytes(compressed as f64)
+    );
+    Ok(())
+}
+
+/// Smallest sort buffer chosen to fit the memory budget
+const MIN_SORT_BUFFER: usize = 1 << 20;
+
+/// Chooses the sort buffer so that a page build fits the memory budget
+///
+/// Pages that would not fit are built from externally sorted chunk files, and a `--sort-buffer`
+/// larger than the budget allows is reduced. Pages probed by double hashing are always built
+/// in memory.
+fn fit_sort_buffer(
+    args: &Args,
+    hash_config: &HashConfig,
+    taxonomy_bytes: usize,
+) -> std::io::Result<Option<usize>> {
+    if hash_config.is_double_hashing() {
+        if args.sort_buffer.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--sort-buffer builds pages by linear probing, it cannot be combined with --double-hashing",
+            ));
+        }
+        // 双重哈希的页只能在内存中构建
+        return Ok(None);
+    }
+    if args.mmap_pages {
+        // 映射的页由内核回写, 不占用内存预算
+        return Ok(None);
+    }
+    let Some(budget) = memory_budget() else {
+        return Ok(args.sort_buffer);
+    };
+    let available = budget.saturating_sub(taxonomy_bytes);
+    let fitted = (available / 2).max(MIN_SORT_BUFFER);
+    match args.sort_buffer {
+        Some(sort_buffer) if sort_buffer > fitted => {
+            warn!(
+                "sort buffer reduced from {} to {} to fit the memory budget of {}",
+                format_bytes(sort_buffer as f64),
+                format_bytes(fitted as f64),
+                format_bytes(budget as f64)
+            );
+            Ok(Some(fitted))
+        }
+        Some(sort_buffer) => Ok(Some(sort_buffer)),
+        None => {
+            let cell_bytes = if hash_config.is_wide() { 8 } else { 4 };
+            let mut page_cells = hash_config.hash_capacity;
+            for page_index in 1..=hash_config.partition {
+                if let Some(capacity) = read_page_capacity(&args.database, page_index)? {
+                    page_cells = page_cells.max(capacity);
+                }
+            }
+            let page_bytes = page_cells * cell_bytes;
+            if page_bytes <= available {
+                return Ok(None);
+            }
+            info!(
+                "hash pages of {} do not fit the memory budget of {}, building them from sorted chunk files with a {} sort buffer",
+                format_bytes(page_bytes as f64),
+                format_bytes(budget as f64),
+                format_bytes(fitted as f64)
+            );
+            Ok(Some(fitted))
+        }
+    }
+}
+
+pub fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let k2d_dir = &args.database;
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
     let taxonomy = Taxonomy::from_file(taxonomy_filename)?;
     let hash_filename = k2d_dir.join("hash_config.k2d");
 
     let mut hash_config = HashConfig::from_hash_header(&hash_filename)?;
+    let taxonomy_bytes = std::fs::metadata(k2d_dir.join("taxo.k2d"))?.len() as usize;
 
     // 开始计时
     let start = Instant::now();
@@ -30,37 +189,120 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     let chunk_files = find_and_trans_files(&k2d_dir, "chunk", ".k2", true)?;
 
     let mut size: usize = 0;
+    let mut checkpoint = Checkpoint::load(k2d_dir)?;
+    remove_sentinel(k2d_dir, "build")?;
 
-    println!("start process k2 files...");
+    // 探测方式写入 hash_config.k2d, 续建时沿用已建页的探测方式
+    let resumed = chunk_files
+        .keys()
+        .any(|i| checkpoint.is_done(&format!("page {}", i)));
+    if !resumed {
+        if args.double_hashing {
+            hash_config.version |= DOUBLE_HASHING_FLAG;
+        } else {
+            hash_config.version &= !DOUBLE_HASHING_FLAG;
+        }
+        hash_config.write_to_file(&hash_filename)?;
+    } else if hash_config.is_double_hashing() != args.double_hashing {
+        warn!(
+            "resuming a build whose pages are probed {}, ignoring --double-hashing",
+            if hash_config.is_double_hashing() {
+                "by double hashing"
+            } else {
+                "linearly"
+            }
+        );
+    }
+    let sort_buffer = fit_sort_buffer(&args, &hash_config, taxonomy_bytes)?;
+
+    info!("start process k2 files...");
+    // 已构建的页不计入进度, 以免低估剩余时间
+    let pending = chunk_files
+        .keys()
+        .filter(|i| !checkpoint.is_done(&format!("page {}", i)))
+        .count();
+    let progress = Progress::new("build", "pages", pending as u64, args.progress);
     for (i, chunk_file) in &chunk_files {
+        let checkpoint_key = format!("page {}", i);
+        if let Some(count) = checkpoint.get(&checkpoint_key) {
+            size += count.parse::<usize>()?;
+            info!("skip built hash page {}/{}", i, hash_config.partition);
+            continue;
+        }
         // 计算持续时间
-        let count = process_k2file(
-            hash_config,
-            &k2d_dir,
-            &chunk_file,
-            &taxonomy,
-            hash_config.hash_capacity,
-            *i,
-        )?;
+        let count = match sort_buffer {
+            Some(sort_buffer) => process_sorted_k2file(
+                hash_config,
+                k2d_dir,
+                chunk_file,
+                &taxonomy,
+                *i,
+                args.max_page_load,
+                sort_buffer,
+            )?,
+            None => process_k2file(
+                hash_config,
+                k2d_dir,
+                chunk_file,
+                &taxonomy,
+                *i,
+                args.deterministic,
+                args.max_page_load,
+                args.mmap_pages,
+            )?,
+        };
+        if args.prefilter {
+            let page_file = k2d_dir.join(format!("hash_{}.k2d", i));
+            let bytes = write_prefilter(&hash_config, chunk_file, &page_file, count)?;
+            info!(
+                "prefilter of hash page {}: {}",
+                i,
+                format_bytes(bytes as f64)
+            );
+        }
         size += count;
+        checkpoint.mark(&checkpoint_key, &count.to_string())?;
         let duration = start.elapsed();
-        println!(
+        info!(
             "process chunk file {:?}/{:}: duration: {:?}",
             i, hash_config.partition, duration
         );
+        progress.inc(1);
     }
+    progress.finish();
 
     hash_config.size = size;
+    print_build_stats(k2d_dir)?;
+    if args.compress_pages {
+        compress_pages(k2d_dir, hash_config.partition)?;
+        hash_config.version |= ZSTD_PAGE_FLAG;
+    }
     hash_config.write_to_file(&hash_filename)?;
 
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("build k2 db took: {:?}", duration);
+    info!("build k2 db took: {:?}", duration);
 
     for (_, chunk_file) in &chunk_files {
         remove_file(chunk_file)?;
     }
+    let plan_file = k2d_dir.join(PAGE_CAPACITY_FILENAME);
+    if plan_file.exists() {
+        remove_file(plan_file)?;
+    }
+    checkpoint.remove()?;
+    DbManifest::update(k2d_dir, &k2d_dir.join("library"))?;
+
+    let mut artifacts = find_and_sort_files(k2d_dir, "hash", hash_config.page_suffix(), true)?;
+    let prefilters: Vec<PathBuf> = artifacts
+        .iter()
+        .map(|hash_file| prefilter_path(hash_file))
+        .filter(|prefilter| prefilter.exists())
+        .collect();
+    artifacts.extend(prefilters);
+    artifacts.extend(["hash_config.k2d", "taxo.k2d", "opts.k2d"].map(|name| k2d_dir.join(name)));
+    write_sentinel(k2d_dir, "build", &artifacts)?;
 
     Ok(())
 }
@@ -68,7 +310,7 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
 #[allow(dead_code)]
 fn main() {
     let args = Args::parse();
-    if let Err(e) = run(&args.database) {
+    if let Err(e) = run(args) {
         eprintln!("Application error: {}", e);
     }
 }
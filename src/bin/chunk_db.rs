@@ -1,14 +1,26 @@
 // 使用时需要引用模块路径
 use clap::Parser;
 use kun_peng::args::{parse_size, Build};
-use kun_peng::compact_hash::HashConfig;
-use kun_peng::db::{convert_fna_to_k2_format, get_bits_for_taxid, generate_taxonomy};
-use kun_peng::taxonomy::Taxonomy;
+use kun_peng::compact_hash::{HashConfig, WIDE_CELL_VERSION};
+use kun_peng::db::{
+    convert_fna_to_k2_format, get_bits_for_taxid, generate_taxonomy, write_page_capacities,
+    MinimizerSample, MINIMIZER_SAMPLE_FILENAME, PAGE_CAPACITY_FILENAME,
+};
+use kun_peng::error::Error;
+use kun_peng::taxonomy::{find_gtdb_taxonomy_files, Taxonomy};
 use kun_peng::utils::{
-    create_partition_files, create_partition_writers, find_files, get_file_limit,
-    read_id_to_taxon_map, set_fd_limit,
+    create_partition_files, create_partition_writers, find_files, find_library_fna_files,
+    get_file_limit,
+    read_id_to_taxon_map, set_fd_limit, summary_prelim_map_files,
+};
+use kun_peng::checkpoint::{
+    remove_sentinel, write_sentinel, Checkpoint, CHECKPOINT_FILENAME, CHUNK_DONE,
 };
 use kun_peng::IndexOptions;
+use log::{info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Parser, Debug, Clone)]
@@ -22,26 +34,124 @@ pub struct Args {
     pub build: Build,
 }
 
+/// Checkpoint key prefix of a library file whose minimizers are in the chunk files
+const CHUNK_FILE_PREFIX: &str = "chunk_file ";
+
+/// Restores the chunk files to the lengths recorded after the last converted library file
+fn truncate_chunk_files(
+    chunk_files: &[PathBuf],
+    lengths: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let lengths: Vec<u64> = lengths
+        .split(',')
+        .filter(|len| !len.is_empty())
+        .map(|len| len.parse())
+        .collect::<Result<_, _>>()?;
+    for (i, chunk_file) in chunk_files.iter().enumerate() {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(chunk_file)?
+            .set_len(lengths.get(i).copied().unwrap_or(0))?;
+    }
+    Ok(())
+}
+
+/// Plans the capacity of every page from the minimizer sample written by `estimate`
+///
+/// Without a sample all pages keep the uniform size `--hash-capacity`.
+fn plan_page_capacities(
+    k2d_dir: &Path,
+    hash_config: &HashConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sample_file = k2d_dir.join(MINIMIZER_SAMPLE_FILENAME);
+    let plan_file = k2d_dir.join(PAGE_CAPACITY_FILENAME);
+    if !sample_file.exists() {
+        if plan_file.exists() {
+            std::fs::remove_file(plan_file)?;
+        }
+        return Ok(());
+    }
+    let capacities = MinimizerSample::from_file(&sample_file)?.page_capacities(hash_config);
+    let grown = capacities
+        .iter()
+        .filter(|&&capacity| capacity > hash_config.hash_capacity)
+        .count();
+    info!(
+        "planned {} hash pages, {} of them larger than {} cells",
+        capacities.len(),
+        grown,
+        hash_config.hash_capacity
+    );
+    write_page_capacities(k2d_dir, &capacities)?;
+    Ok(())
+}
+
 pub fn run(args: Args, required_capacity: usize) -> Result<(), Box<dyn std::error::Error>> {
     let file_num_limit = get_file_limit();
-    let meros = args.build.klmt.as_meros();
+    let meros = args.build.klmt.as_meros()?;
     let k2d_dir = &args.build.database;
 
+    let mut checkpoint = Checkpoint::load(k2d_dir)?;
+    if checkpoint.is_done(CHUNK_DONE) {
+        info!("chunk files already written, skipping (checkpoint {})", CHECKPOINT_FILENAME);
+        return Ok(());
+    }
+    remove_sentinel(k2d_dir, "chunk")?;
+    remove_sentinel(k2d_dir, "build")?;
+
     let id_to_taxon_map_filename = k2d_dir.join("seqid2taxid.map");
-    let id_to_taxon_map = read_id_to_taxon_map(&id_to_taxon_map_filename)?;
+    let ncbi_taxonomy_directory = k2d_dir.join("taxonomy");
+    let library_dir = &args.build.database.join("library");
+    if !id_to_taxon_map_filename.exists()
+        && !find_files(library_dir, "prelim_map", ".txt").is_empty()
+    {
+        // kraken2-build 的 library 只有 prelim_map.txt,
+        // ACCNUM 条目通过 taxonomy/ 中的 accession2taxid 文件解析
+        let accession2taxid: Vec<PathBuf> =
+            find_files(&ncbi_taxonomy_directory, "", ".accession2taxid")
+                .into_iter()
+                .chain(find_files(&ncbi_taxonomy_directory, "", ".accession2taxid.gz"))
+                .collect();
+        let (count, unresolved) = summary_prelim_map_files(
+            library_dir,
+            &accession2taxid,
+            &id_to_taxon_map_filename,
+        )?;
+        info!(
+            "seqid2taxid.map generated from prelim_map.txt files: {} entries",
+            count
+        );
+        if unresolved > 0 {
+            warn!(
+                "{} accessions not found in taxonomy/*.accession2taxid, their sequences are skipped",
+                unresolved
+            );
+        }
+    }
+    let mut id_to_taxon_map = read_id_to_taxon_map(&id_to_taxon_map_filename)?;
 
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
-    let ncbi_taxonomy_directory = k2d_dir.join("taxonomy");
 
     let names_file = ncbi_taxonomy_directory.join("names.dmp");
     let nodes_file = ncbi_taxonomy_directory.join("nodes.dmp");
-    assert!(names_file.exists(), "names.dmp not found in taxonomy directory");
-    assert!(nodes_file.exists(), "nodes.dmp not found in taxonomy directory");
+    if find_gtdb_taxonomy_files(&ncbi_taxonomy_directory)?.is_empty() {
+        for file in [&names_file, &nodes_file] {
+            if !file.exists() {
+                return Err(Error::InvalidInput(format!(
+                    "{:?} not found; download the taxonomy into {:?} or run merge-fna",
+                    file, ncbi_taxonomy_directory
+                ))
+                .into());
+            }
+        }
+    }
 
     let _ = generate_taxonomy(
         &ncbi_taxonomy_directory,
         &taxonomy_filename,
-        &id_to_taxon_map,
+        &mut id_to_taxon_map,
     )?;
 
     let taxonomy = Taxonomy::from_file(taxonomy_filename)?;
@@ -50,30 +160,82 @@ pub fn run(args: Args, required_capacity: usize) -> Result<(), Box<dyn std::erro
         args.build.requested_bits_for_taxid as usize,
         taxonomy.node_count() as f64,
     )
-    .expect("more bits required for storing taxid");
+    .map_err(|e| {
+        Error::InvalidInput(format!(
+            "{} for the {} taxonomy nodes; raise --requested-bits-for-taxid or leave it at 0",
+            e,
+            taxonomy.node_count()
+        ))
+    })?;
+    if value_bits > 31 && !args.build.wide_cells {
+        return Err(Error::InvalidInput(format!(
+            "{} bits required for storing taxid, rebuild with --wide-cells",
+            value_bits
+        ))
+        .into());
+    }
+    let version = if args.build.wide_cells {
+        WIDE_CELL_VERSION
+    } else {
+        1
+    };
 
-    let capacity = required_capacity;
-    let partition = (capacity + args.hash_capacity - 1) / args.hash_capacity;
-    let hash_config = HashConfig::new(1, capacity, value_bits, 0, partition, args.hash_capacity);
+    // 恢复中断的构建时, 沿用中断前写入的 hash_config.k2d, 否则重新计算并立即写入
+    let hash_filename = k2d_dir.join("hash_config.k2d");
+    let last_chunk = checkpoint
+        .entries()
+        .filter(|(key, _)| key.starts_with(CHUNK_FILE_PREFIX))
+        .last()
+        .map(|(_, lengths)| lengths.to_string());
+    let hash_config = if last_chunk.is_some() {
+        HashConfig::from_hash_header(&hash_filename)?
+    } else {
+        let capacity = required_capacity;
+        let partition = capacity.div_ceil(args.hash_capacity);
+        let hash_config =
+            HashConfig::new(version, capacity, value_bits, 0, partition, args.hash_capacity);
+        hash_config.write_to_file(&hash_filename)?;
+        plan_page_capacities(k2d_dir, &hash_config)?;
+        hash_config
+    };
+    let partition = hash_config.partition;
 
     // 开始计时
     let start = Instant::now();
 
-    let chunk_size = args.hash_capacity as usize;
+    let chunk_size = hash_config.hash_capacity;
 
     if partition >= file_num_limit {
-        set_fd_limit(partition as u64 + 1).expect("Failed to set file descriptor limit");
+        set_fd_limit(partition as u64 + 1)?;
         // panic!("Exceeds File Number Limit");
     }
 
-    let chunk_files = create_partition_files(partition, &k2d_dir, "chunk");
-    let mut writers = create_partition_writers(&chunk_files);
+    let chunk_files = create_partition_files(partition, k2d_dir, "chunk")?;
+    match &last_chunk {
+        Some(lengths) => {
+            info!("resuming chunk db from checkpoint {}", CHECKPOINT_FILENAME);
+            truncate_chunk_files(&chunk_files, lengths)?;
+        }
+        None => truncate_chunk_files(&chunk_files, "")?,
+    }
+    let mut writers: Vec<_> = create_partition_writers(&chunk_files)?
+        .into_iter()
+        .map(Some)
+        .collect();
 
-    let library_dir = &args.build.database.join("library");
-    let fna_files = find_files(&library_dir, "library", ".fna");
+    let fna_files = find_library_fna_files(library_dir);
 
     for fna_file in fna_files {
-        println!("convert fna file {:?}", fna_file);
+        let checkpoint_key = format!(
+            "{}{}",
+            CHUNK_FILE_PREFIX,
+            fna_file.file_name().unwrap_or_default().to_string_lossy()
+        );
+        if checkpoint.is_done(&checkpoint_key) {
+            info!("skip converted fna file {:?}", fna_file);
+            continue;
+        }
+        info!("convert fna file {:?}", fna_file);
         convert_fna_to_k2_format(
             fna_file,
             meros,
@@ -84,18 +246,29 @@ pub fn run(args: Args, required_capacity: usize) -> Result<(), Box<dyn std::erro
             chunk_size,
             args.build.threads,
         );
-    }
 
-    let hash_filename = k2d_dir.join("hash_config.k2d");
-    hash_config.write_to_file(&hash_filename)?;
+        for writer in writers.iter_mut().flatten() {
+            writer.flush()?;
+        }
+        let lengths = chunk_files
+            .iter()
+            .map(|chunk_file| Ok(std::fs::metadata(chunk_file)?.len().to_string()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        checkpoint.mark(&checkpoint_key, &lengths.join(","))?;
+    }
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("chunk db took: {:?}", duration);
+    info!("chunk db took: {:?}", duration);
 
     let options_filename = k2d_dir.join("opts.k2d");
     let idx_opts = IndexOptions::from_meros(meros);
     idx_opts.write_to_file(options_filename)?;
+    checkpoint.mark(CHUNK_DONE, "")?;
+
+    let mut artifacts = chunk_files;
+    artifacts.extend(["hash_config.k2d", "taxo.k2d", "opts.k2d"].map(|name| k2d_dir.join(name)));
+    write_sentinel(k2d_dir, "chunk", &artifacts)?;
 
     Ok(())
 }
@@ -0,0 +1,271 @@
+use clap::Parser;
+use kun_peng::compact_hash::{read_page_from_file, Compact, HashConfig, Page};
+use kun_peng::db::DEFAULT_MAX_PAGE_LOAD;
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::{find_and_sort_files, format_bytes};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Report the occupancy, probe runs and value distribution of the hash pages",
+    long_about = "Walk all hash pages of a database and report, per page, how full it is and how long the runs of occupied cells that linear probing walks are.
+The runs decide the cost of a lookup, above all of the minimizers that are not in the database: a lookup whose home cell falls into a run probes to its end.
+Histograms of the run lengths, of the ranks of the stored taxa and of the cells per taxon follow, all as TSV sections starting with '#'."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Pages fuller than this are reported with the capacity that would bring them under it
+    #[clap(long, default_value_t = DEFAULT_MAX_PAGE_LOAD)]
+    pub max_load: f64,
+}
+
+/// Occupancy and runs of occupied cells of one page
+#[derive(Debug, Default)]
+struct PageStats {
+    capacity: usize,
+    cells: usize,
+    longest_run: usize,
+    runs: usize,
+    /// Probes of a miss summed over all home cells
+    miss_probes: usize,
+}
+
+impl PageStats {
+    fn load(&self) -> f64 {
+        self.cells as f64 / self.capacity.max(1) as f64
+    }
+
+    /// Expected probes of a lookup of an absent key at a random home cell
+    fn mean_miss_probes(&self) -> f64 {
+        self.miss_probes as f64 / self.capacity.max(1) as f64
+    }
+}
+
+/// Bucket of a log2 histogram: 1, 2-3, 4-7, ...
+fn log2_bucket(value: usize) -> usize {
+    (usize::BITS - value.max(1).leading_zeros() - 1) as usize
+}
+
+fn bucket_label(bucket: usize) -> String {
+    let low = 1usize << bucket;
+    let high = (low << 1) - 1;
+    if low == high {
+        low.to_string()
+    } else {
+        format!("{}-{}", low, high)
+    }
+}
+
+/// The taxid of a cell, 0 if it is empty
+fn cell_taxid(page: &Page, idx: usize, value_mask: usize) -> u32 {
+    if page.is_wide() {
+        page.data[idx]
+    } else {
+        page.data[idx].right(value_mask)
+    }
+}
+
+/// Measures the runs of occupied cells of a page and adds their lengths to `run_lengths`
+///
+/// The run at the end of the page continues with the cells at its start, as lookups do with
+/// the overflow block. A lookup of an absent key whose home cell is at position `j` of a run
+/// of length `L` probes `L - j + 1` cells, one if the home cell is empty.
+fn page_runs(
+    page: &Page,
+    value_mask: usize,
+    run_lengths: &mut BTreeMap<usize, usize>,
+) -> PageStats {
+    let capacity = page.size;
+    let occupied = |idx: usize| cell_taxid(page, idx, value_mask) != 0;
+    let mut stats = PageStats {
+        capacity,
+        ..Default::default()
+    };
+    // 从一个空单元开始扫描, 页尾的 run 就与页首的相连
+    let Some(first_empty) = (0..capacity).find(|&idx| !occupied(idx)) else {
+        stats.cells = capacity;
+        stats.longest_run = capacity;
+        if capacity > 0 {
+            stats.runs = 1;
+            stats.miss_probes = capacity * (capacity + 1) / 2 + capacity;
+            *run_lengths.entry(log2_bucket(capacity)).or_insert(0) += 1;
+        }
+        return stats;
+    };
+    let mut add_run = |stats: &mut PageStats, run: usize| {
+        stats.runs += 1;
+        stats.longest_run = stats.longest_run.max(run);
+        stats.miss_probes += run * (run + 1) / 2 + run;
+        *run_lengths.entry(log2_bucket(run)).or_insert(0) += 1;
+    };
+    let mut run = 0;
+    for offset in 1..=capacity {
+        let idx = (first_empty + offset) % capacity;
+        if occupied(idx) {
+            stats.cells += 1;
+            run += 1;
+        } else {
+            stats.miss_probes += 1;
+            if run > 0 {
+                add_run(&mut stats, run);
+                run = 0;
+            }
+        }
+    }
+    stats
+}
+
+/// Counts the occupied cells of a page per internal taxon ID
+fn count_page_taxa(page: &Page, value_mask: usize, counts: &mut HashMap<u32, u64>) {
+    let page_counts = (0..page.size)
+        .into_par_iter()
+        .fold(HashMap::new, |mut counts, idx| {
+            let taxid = cell_taxid(page, idx, value_mask);
+            if taxid != 0 {
+                *counts.entry(taxid).or_insert(0u64) += 1;
+            }
+            counts
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (taxid, count) in b {
+                *a.entry(taxid).or_insert(0) += count;
+            }
+            a
+        });
+    for (taxid, count) in page_counts {
+        *counts.entry(taxid).or_insert(0) += count;
+    }
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let taxonomy = Taxonomy::from_file(args.database.join("taxo.k2d"))?;
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let hash_files = find_and_sort_files(&args.database, "hash", hash_config.page_suffix(), true)?;
+    if hash_files.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no hash pages in {:?}", args.database),
+        ));
+    }
+    let value_mask = hash_config.value_mask;
+    // 双重哈希的探测序列不沿 run 走, run 只反映单元分布
+    let linear = !hash_config.is_double_hashing();
+    let cell_bytes = if hash_config.is_wide() { 8 } else { 4 };
+
+    let mut run_lengths = BTreeMap::new();
+    let mut taxon_cells: HashMap<u32, u64> = HashMap::new();
+    let mut total = PageStats::default();
+    let mut full_pages = 0;
+
+    println!("# pages");
+    println!("page\tcapacity\tcells\tload\tlongest run\tmean run\tmiss probes\tsuggested capacity");
+    for hash_file in &hash_files {
+        let page = read_page_from_file(hash_file)?;
+        let stats = page_runs(&page, value_mask, &mut run_lengths);
+        count_page_taxa(&page, value_mask, &mut taxon_cells);
+
+        let suggested = if stats.load() > args.max_load && args.max_load > 0.0 {
+            full_pages += 1;
+            ((stats.cells as f64 / args.max_load).ceil() as usize).to_string()
+        } else {
+            "-".to_string()
+        };
+        let (longest_run, mean_run, miss_probes) = if linear {
+            (
+                stats.longest_run.to_string(),
+                format!("{:.2}", stats.cells as f64 / stats.runs.max(1) as f64),
+                format!("{:.3}", stats.mean_miss_probes()),
+            )
+        } else {
+            ("-".to_string(), "-".to_string(), "-".to_string())
+        };
+        println!(
+            "{}\t{}\t{}\t{:.3}\t{}\t{}\t{}\t{}",
+            page.index,
+            stats.capacity,
+            stats.cells,
+            stats.load(),
+            longest_run,
+            mean_run,
+            miss_probes,
+            suggested
+        );
+        total.capacity += stats.capacity;
+        total.cells += stats.cells;
+        total.runs += stats.runs;
+        total.longest_run = total.longest_run.max(stats.longest_run);
+        total.miss_probes += stats.miss_probes;
+    }
+
+    println!(
+        "# database: {} pages, {} cells of {} ({}), load {:.3}, {} pages fuller than {}",
+        hash_files.len(),
+        total.cells,
+        total.capacity,
+        format_bytes((total.capacity * cell_bytes) as f64),
+        total.load(),
+        full_pages,
+        args.max_load
+    );
+    if linear {
+        println!(
+            "# longest run {}, mean miss probes {:.3}",
+            total.longest_run,
+            total.mean_miss_probes()
+        );
+        println!("# run lengths");
+        println!("run\truns");
+        for (&bucket, &runs) in &run_lengths {
+            println!("{}\t{}", bucket_label(bucket), runs);
+        }
+    } else {
+        println!("# pages probed by double hashing, see probe-bench for their probe lengths");
+    }
+
+    // 存储 LCA 的 rank 越高, 命中这些单元的 read 越难分到种
+    let mut rank_cells: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut taxa_per_bucket: BTreeMap<usize, usize> = BTreeMap::new();
+    for (&taxid, &cells) in &taxon_cells {
+        let rank = match taxonomy.rank_of(taxid) {
+            "" => "unknown",
+            rank => rank,
+        };
+        *rank_cells.entry(rank).or_insert(0) += cells;
+        *taxa_per_bucket
+            .entry(log2_bucket(cells as usize))
+            .or_insert(0) += 1;
+    }
+    let mut rank_cells: Vec<(&str, u64)> = rank_cells.into_iter().collect();
+    rank_cells.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("# cells per rank of the stored taxon");
+    println!("rank\tcells\tfraction");
+    for (rank, cells) in rank_cells {
+        println!(
+            "{}\t{}\t{:.4}",
+            rank,
+            cells,
+            cells as f64 / total.cells.max(1) as f64
+        );
+    }
+    println!("# taxa by number of cells");
+    println!("cells\ttaxa");
+    for (&bucket, &taxa) in &taxa_per_bucket {
+        println!("{}\t{}", bucket_label(bucket), taxa);
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
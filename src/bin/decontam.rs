@@ -0,0 +1,159 @@
+use clap::Parser;
+use kun_peng::readcounts::{ReadCounter, TaxonCounters};
+use kun_peng::report::{read_kraken_report, report_kraken_style, ReportEntry};
+use kun_peng::taxonomy::Taxonomy;
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Subtract a negative control from a sample report",
+    long_about = "Compare a sample report against a negative control report.
+Taxa that are present in the control and whose abundance in the sample is not significantly above the control are removed,
+the expected background is subtracted from all other control taxa, and a decontaminated report is written."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Kraken-style report (kreport2) of the sample.
+    #[clap(long, required = true)]
+    pub sample: PathBuf,
+
+    /// Kraken-style report (kreport2) of the negative control.
+    #[clap(long, required = true)]
+    pub control: PathBuf,
+
+    /// Directory for decontaminated.kreport2 and contaminants.tsv.
+    #[clap(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+
+    /// Significance level of the one-sided test sample > control.
+    #[clap(long, default_value_t = 0.05)]
+    pub alpha: f64,
+
+    /// Minimum fold change of the sample abundance over the control abundance.
+    #[clap(long, default_value_t = 2.0)]
+    pub min_fold: f64,
+
+    /// In comb. w/ -R, report taxa w/ 0 count
+    #[clap(short = 'z', long, value_parser, default_value_t = false)]
+    pub report_zero_counts: bool,
+}
+
+/// Upper tail probability of the standard normal distribution
+fn normal_sf(z: f64) -> f64 {
+    // Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erfc = poly * (-x * x).exp();
+    if z >= 0.0 {
+        0.5 * erfc
+    } else {
+        1.0 - 0.5 * erfc
+    }
+}
+
+/// One-sided two-proportion z-test of `s / s_total > c / c_total`
+fn proportion_p_value(s: u64, s_total: u64, c: u64, c_total: u64) -> f64 {
+    let (s, s_total, c, c_total) = (s as f64, s_total as f64, c as f64, c_total as f64);
+    let pooled = (s + c) / (s_total + c_total);
+    let se = (pooled * (1.0 - pooled) * (1.0 / s_total + 1.0 / c_total)).sqrt();
+    if se == 0.0 {
+        return if s / s_total > c / c_total { 0.0 } else { 1.0 };
+    }
+    normal_sf((s / s_total - c / c_total) / se)
+}
+
+fn total_reads(entries: &[ReportEntry]) -> u64 {
+    entries.iter().map(|entry| entry.taxon_reads).sum()
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let taxonomy_filename = args.database.join("taxo.k2d");
+    let taxo = Taxonomy::from_file(taxonomy_filename)?;
+
+    let sample = read_kraken_report(&args.sample)?;
+    let control = read_kraken_report(&args.control)?;
+    let sample_total = total_reads(&sample);
+    let control_total = total_reads(&control).max(1);
+
+    let control_reads: HashMap<u64, u64> = control
+        .iter()
+        .map(|entry| (entry.taxid, entry.taxon_reads))
+        .collect();
+
+    create_dir_all(&args.output_dir)?;
+    let mut flags = BufWriter::new(File::create(args.output_dir.join("contaminants.tsv"))?);
+    writeln!(
+        flags,
+        "taxid\tname\tsample_reads\tcontrol_reads\texpected_reads\tfold\tp_value\taction"
+    )?;
+
+    let mut call_counters = TaxonCounters::new();
+    let mut classified = 0u64;
+    for entry in sample.iter().filter(|entry| entry.taxid != 0) {
+        let internal_id = taxo.get_internal_id(entry.taxid);
+        if internal_id == 0 {
+            warn!(
+                "taxid {} not found in database taxonomy, skipping",
+                entry.taxid
+            );
+            continue;
+        }
+
+        let mut reads = entry.taxon_reads;
+        let c = *control_reads.get(&entry.taxid).unwrap_or(&0);
+        if c > 0 && reads > 0 {
+            let expected = c as f64 / control_total as f64 * sample_total as f64;
+            let fold = reads as f64 / expected;
+            let p_value = proportion_p_value(reads, sample_total, c, control_total);
+            let action = if p_value > args.alpha || fold < args.min_fold {
+                reads = 0;
+                "removed"
+            } else {
+                reads = reads.saturating_sub(expected.round() as u64);
+                "subtracted"
+            };
+            writeln!(
+                flags,
+                "{}\t{}\t{}\t{}\t{:.2}\t{:.3}\t{:.3e}\t{}",
+                entry.taxid, entry.name, entry.taxon_reads, c, expected, fold, p_value, action
+            )?;
+        }
+
+        if reads > 0 {
+            classified += reads;
+            call_counters.insert(internal_id as u64, ReadCounter::new(reads, 0));
+        }
+    }
+    flags.flush()?;
+
+    report_kraken_style(
+        args.output_dir.join("decontaminated.kreport2"),
+        args.report_zero_counts,
+        false,
+        &taxo,
+        &call_counters,
+        sample_total,
+        sample_total - classified,
+    )?;
+
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -1,14 +1,29 @@
 use clap::Parser;
-use kun_peng::classify::process_hitgroup;
-use kun_peng::compact_hash::{CHTable, Compact, HashConfig, Row};
+use kun_peng::anonymize::{AnonymizedReader, IdMap};
+use kun_peng::classifier::{SampleChain, SampleProgress};
+use kun_peng::classify::{detect_chimera, format_chimera, process_hitgroup, refine_strain};
+use kun_peng::compact_hash::{page_file_bytes, CHTable, Compact, HashConfig, Row};
+use kun_peng::error;
+use kun_peng::input::{open_fastx, CheckedReader, InputOptions};
+use kun_peng::page_cache::PageCache;
 use kun_peng::readcounts::{TaxonCounters, TaxonCountersDash};
-use kun_peng::report::report_kraken_style;
+use kun_peng::remote::{check_uploads, create_output, is_remote};
+use kun_peng::report::{
+    read_pseudo_taxa_expectations, report_kraken_style, report_pseudo_taxa, sample_dir_names,
+    write_sample_summary, SampleOutputs, SampleSummary, COMBINED_REPORT_FILENAME,
+    SAMPLE_SUMMARY_FILENAME,
+};
 use kun_peng::taxonomy::Taxonomy;
-use kun_peng::utils::{create_sample_file, find_and_sort_files, get_lastest_file_index};
+use kun_peng::utils::{
+    create_sample_file, find_and_sort_files, format_bytes, get_lastest_file_index, memory_budget,
+    pipeline_threads, BufferPool,
+};
 use kun_peng::{HitGroup, IndexOptions};
-use seqkmer::{read_parallel, Base, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
-use std::collections::HashMap;
-use std::fs::File;
+use log::{debug, info, warn};
+use seqkmer::{read_parallel, Base, Meros, MinimizerIterator, OptionPair, Reader};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
 use std::io::{self, BufWriter, Write};
 use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
@@ -27,6 +42,7 @@ pub struct Args {
     pub database: PathBuf,
 
     /// File path for outputting normal Kraken output.
+    /// s3:// and gs:// URLs are uploaded with the aws or gcloud CLI as the files are written.
     #[clap(long = "output-dir", value_parser)]
     pub output_dir: Option<PathBuf>,
 
@@ -69,14 +85,67 @@ pub struct Args {
     )]
     pub minimum_hit_groups: usize,
 
-    /// The number of threads to use.
+    /// The number of worker threads, capped by the global --threads.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
+    /// Refine species-level calls to the best supported strain.
+    /// Appends the strain taxid and its support fraction as two extra output columns.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub strain_refinement: bool,
+
+    /// Window size in k-mer positions for chimera detection on long reads.
+    /// Reads whose windows resolve to incompatible taxa are written to chimera_{i}.txt in the output directory.
+    #[clap(long, value_parser)]
+    pub chimera_window: Option<usize>,
+
+    /// Stride between chimera detection windows, default is half the window size.
+    #[clap(long, value_parser)]
+    pub chimera_stride: Option<usize>,
+
+    /// Write the outputs of every sample into a directory named after its input files
+    /// (<sample>/output.txt, <sample>/report.kreport2) with a summary.tsv of all samples,
+    /// instead of output_{i} files. Needs --output-dir
+    #[clap(long, value_parser, default_value_t = false, requires = "output_dir")]
+    pub sample_dirs: bool,
+
+    /// Replace the read IDs of the per-read outputs with salted hashes and append the hashes
+    /// and read IDs to this mapping file, created readable by its owner only. Keep the file
+    /// private to share the outputs without the read names
+    #[clap(long, value_parser)]
+    pub anonymize_ids: Option<PathBuf>,
+
+    /// Map the hash pages into memory instead of reading them, leaving it to the OS page cache
+    /// which parts of the table stay in memory. Starts at once when an earlier run left the
+    /// pages cached, and works with databases somewhat larger than the memory at the cost of
+    /// disk reads. zstd-compressed pages are read as usual
+    #[clap(long, value_parser, default_value_t = false)]
+    pub mmap: bool,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
+    /// s3:// and gs:// URLs are streamed with the aws or gcloud CLI.
     // #[clap(short = 'F', long = "files")]
     pub input_files: Vec<String>,
+    /// How the input files are read, set from the global options of `kun_peng`
+    #[clap(skip)]
+    pub input: InputOptions,
+}
+
+/// The hash pages the minimizers are looked up in
+enum HashTable {
+    /// All pages, read or mapped into memory
+    Resident(CHTable),
+    /// The pages that fit the memory budget, see [`PageCache`]
+    Cached(PageCache<PathBuf>),
+}
+
+/// The database the samples are classified against, loaded once for all of them
+struct Database<'a> {
+    meros: Meros,
+    hash_config: HashConfig,
+    table: &'a HashTable,
+    taxonomy: &'a Taxonomy,
 }
 
 fn process_seq(
@@ -88,15 +157,18 @@ fn process_seq(
 ) -> usize {
     let chunk_size = hash_config.hash_capacity;
     let value_bits = hash_config.value_bits;
-    let data: Vec<(usize, u64)> = m_iter.collect();
-    for (sort, hash_key) in data {
+    for (sort, hash_key) in m_iter.by_ref() {
         let (idx, compacted) = hash_config.compact(hash_key);
         let partition_index = idx / chunk_size;
         let index = idx % chunk_size;
 
         let taxid = chtable.get_from_page(index, compacted, partition_index);
         if taxid > 0 {
-            let high = u32::combined(compacted, taxid, value_bits);
+            let high = if hash_config.is_wide() {
+                taxid
+            } else {
+                u32::combined(compacted, taxid, value_bits)
+            };
             let row = Row::new(high, 0, sort as u32 + 1 + offset as u32);
             rows.push(row);
         }
@@ -104,20 +176,120 @@ fn process_seq(
     m_iter.size + offset
 }
 
-fn process_record(
-    marker: &mut Base<MinimizerIterator>,
+/// A minimizer of a batch of reads to look up in the [`PageCache`]
+struct PageQuery {
+    read: u32,
+    page: u32,
+    index: u32,
+    compacted: u32,
+    kmer_id: u32,
+}
+
+/// The buffers of a batch of reads, reused by the following batches
+#[derive(Default)]
+struct ClassifyBuffers {
+    /// The hits of the read being classified
+    rows: Vec<Row>,
+    output: String,
+    chimera: String,
+    /// The minimizers of the batch, with a [`PageCache`]
+    queries: Vec<PageQuery>,
+    /// The hits of the reads of the batch, with a [`PageCache`]
+    read_rows: Vec<(u32, Row)>,
+}
+
+impl ClassifyBuffers {
+    fn clear(&mut self) {
+        self.rows.clear();
+        self.output.clear();
+        self.chimera.clear();
+        self.queries.clear();
+        self.read_rows.clear();
+    }
+}
+
+/// Looks up the minimizers of a batch of reads in the cached pages, page by page
+///
+/// The pages that are cached are probed first, so that loading the others evicts none of
+/// them before the batch used it. The hits are left in `buffers.read_rows`, ordered by read
+/// and position.
+fn cached_batch_hits(
+    seqs: &mut [Base<MinimizerIterator>],
+    buffers: &mut ClassifyBuffers,
+    hash_config: &HashConfig,
+    cache: &PageCache<PathBuf>,
+) -> io::Result<()> {
+    let chunk_size = hash_config.hash_capacity;
+    let queries = &mut buffers.queries;
+    for (read, record) in seqs.iter_mut().enumerate() {
+        let mut offset = 0;
+        record.body.apply_mut(|m_iter| {
+            for (sort, hash_key) in m_iter.by_ref() {
+                let (idx, compacted) = hash_config.compact(hash_key);
+                queries.push(PageQuery {
+                    read: read as u32,
+                    page: (idx / chunk_size) as u32,
+                    index: (idx % chunk_size) as u32,
+                    compacted,
+                    kmer_id: (sort + 1 + offset) as u32,
+                });
+            }
+            offset += m_iter.size;
+        });
+    }
+    queries.sort_unstable_by_key(|query| query.page);
+
+    let mut groups: Vec<&[PageQuery]> = queries
+        .chunk_by(|a, b| a.page == b.page)
+        .filter(|group| (group[0].page as usize) < cache.len())
+        .collect();
+    groups.sort_by_cached_key(|group| !cache.contains(group[0].page as usize));
+
+    // `compact` returns the whole 32-bit key for wide cells
+    let value_bits = if hash_config.is_wide() {
+        0
+    } else {
+        hash_config.value_bits
+    };
+    for group in groups {
+        let page = cache.get(group[0].page as usize)?;
+        for query in group {
+            let taxid = page.find_index(
+                query.index as usize,
+                query.compacted,
+                value_bits,
+                hash_config.value_mask,
+            );
+            if taxid > 0 {
+                let high = if hash_config.is_wide() {
+                    taxid
+                } else {
+                    u32::combined(query.compacted, taxid, hash_config.value_bits)
+                };
+                let row = Row::new(high, 0, query.kmer_id);
+                buffers.read_rows.push((query.read, row));
+            }
+        }
+    }
+    buffers
+        .read_rows
+        .sort_unstable_by_key(|(read, row)| (*read, row.kmer_id));
+    Ok(())
+}
+
+/// Classifies a read from its hits and appends its output line and chimera line to `buffers`
+#[allow(clippy::too_many_arguments)]
+fn classify_hits(
+    marker: &Base<MinimizerIterator>,
+    hits: HitGroup,
+    buffers: &mut ClassifyBuffers,
     args: &Args,
     taxonomy: &Taxonomy,
-    chtable: &CHTable,
     hash_config: &HashConfig,
     cur_taxon_counts: &TaxonCountersDash,
     classify_counter: &AtomicUsize,
-) -> String {
-    let id = &marker.header.id.clone();
-    let rows: Vec<Row> = marker
-        .fold(|rows, m_iter, offset| process_seq(rows, m_iter, &hash_config, chtable, offset));
-
-    let hits = HitGroup::new(rows, marker.range());
+) {
+    let id = &marker.header.id;
     let seq_len_str = marker.fmt_seq_size();
 
     let required_score = hits.required_score(args.confidence_threshold);
@@ -137,70 +309,95 @@ fn process_record(
             .merge(value)
             .unwrap();
     });
-    format!(
-        "{}\t{}\t{}\t{}\t{}\n",
+    let output = &mut buffers.output;
+    let _ = write!(
+        output,
+        "{}\t{}\t{}\t{}\t{}",
         hit_data.0, id, hit_data.1, seq_len_str, hit_data.2
-    )
+    );
+    if args.strain_refinement {
+        let call = taxonomy.get_internal_id(hit_data.1);
+        let (strain, support) = refine_strain(&hits, taxonomy, call, hash_config.value_mask)
+            .map(|(strain, support)| (taxonomy.nodes[strain as usize].external_id, support))
+            .unwrap_or((0, 0.0));
+        let _ = write!(output, "\t{}\t{:.3}", strain, support);
+    }
+    output.push('\n');
+    let chimera_line = args.chimera_window.and_then(|window| {
+        let stride = args.chimera_stride.unwrap_or((window / 2).max(1));
+        detect_chimera(
+            &hits,
+            taxonomy,
+            hash_config.value_mask,
+            window,
+            stride,
+            args.minimum_hit_groups,
+        )
+        .map(|(windows, breakpoints)| format_chimera(id, taxonomy, &windows, &breakpoints))
+    });
+    if let Some(chimera_line) = chimera_line {
+        buffers.chimera.push_str(&chimera_line);
+    }
+    // 命中行的缓冲区留给下一个 read
+    buffers.rows = hits.rows;
 }
 
-fn process_fastx_file<R>(
-    args: &Args,
-    meros: Meros,
-    hash_config: HashConfig,
-    file_index: usize,
-    reader: &mut R,
-    chtable: &CHTable,
-    taxonomy: &Taxonomy,
-    total_taxon_counts: &mut TaxonCounters,
-) -> io::Result<(usize, usize)>
-where
-    R: Reader,
-{
-    let mut writer: Box<dyn Write + Send> = match &args.output_dir {
-        Some(ref file_path) => {
-            let filename = file_path.join(format!("output_{}.txt", file_index));
-            let file = File::create(filename)?;
-            Box::new(BufWriter::new(file)) as Box<dyn Write + Send>
-        }
-        None => Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>,
-    };
-
-    let cur_taxon_counts = TaxonCountersDash::new();
+/// The read counts of a sample while it is classified
+#[derive(Default)]
+struct SampleCounts {
+    taxon_counts: TaxonCountersDash,
+    seqs: AtomicUsize,
+    classified: AtomicUsize,
+}
 
-    let seq_counter = AtomicUsize::new(0);
-    let classify_counter = AtomicUsize::new(0);
+/// The open outputs of a sample and the number of batches written to them
+struct SampleWriters {
+    writer: Box<dyn Write + Send>,
+    chimera_writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    batches: usize,
+}
 
-    let _ = read_parallel(
-        reader,
-        args.num_threads,
-        &meros,
-        |seqs| {
-            let mut buffer = String::new();
-            for record in seqs {
-                seq_counter.fetch_add(1, Ordering::SeqCst);
-                let output_line = process_record(
-                    record,
-                    args,
-                    taxonomy,
-                    chtable,
-                    &hash_config,
-                    &cur_taxon_counts,
-                    &classify_counter,
-                );
-                buffer.push_str(&output_line);
+impl SampleWriters {
+    fn create(args: &Args, outputs: Option<&SampleOutputs>) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match outputs {
+            Some(outputs) => {
+                outputs.create_dir()?;
+                let file = create_output(outputs.output())?;
+                Box::new(BufWriter::new(file)) as Box<dyn Write + Send>
             }
+            None => Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>,
+        };
+
+        let chimera_writer = match (outputs, args.chimera_window) {
+            (Some(outputs), Some(_)) => Some(BufWriter::new(create_output(outputs.chimera())?)),
+            _ => None,
+        };
+        Ok(Self {
+            writer,
+            chimera_writer,
+            batches: 0,
+        })
+    }
+}
 
-            buffer
-        },
-        |dataset| {
-            while let Some(data) = dataset.next() {
-                let res = data.unwrap();
-                writer
-                    .write_all(res.as_bytes())
-                    .expect("Failed to write date to file");
-            }
-        },
-    );
+/// Flushes the outputs of a classified sample and writes its reports
+///
+/// # Returns
+///
+/// The number of reads and unclassified reads of the sample
+fn finish_sample(
+    args: &Args,
+    taxonomy: &Taxonomy,
+    outputs: Option<&SampleOutputs>,
+    mut writers: SampleWriters,
+    counts: &SampleCounts,
+    expected_reads: &HashMap<u64, u64>,
+    total_taxon_counts: &mut TaxonCounters,
+) -> io::Result<(usize, usize)> {
+    writers.writer.flush()?;
+    if let Some(mut chimera_writer) = writers.chimera_writer {
+        chimera_writer.flush()?;
+    }
 
     let mut sample_taxon_counts: HashMap<
         u64,
@@ -208,98 +405,306 @@ where
             hyperloglogplus::HyperLogLogPlus<u64, kun_peng::KBuildHasher>,
         >,
     > = HashMap::new();
-    cur_taxon_counts.iter().for_each(|entry| {
+    counts.taxon_counts.iter().for_each(|entry| {
         total_taxon_counts
             .entry(*entry.key())
             .or_default()
-            .merge(&entry.value())
+            .merge(entry.value())
             .unwrap();
         sample_taxon_counts
             .entry(*entry.key())
             .or_default()
-            .merge(&entry.value())
+            .merge(entry.value())
             .unwrap();
     });
+    // 样本已写完, 释放它的计数
+    counts.taxon_counts.clear();
 
-    let thread_sequences = seq_counter.load(Ordering::SeqCst);
-    let thread_classified = classify_counter.load(Ordering::SeqCst);
-    if let Some(output) = &args.output_dir {
-        let filename = output.join(format!("output_{}.kreport2", file_index));
+    let thread_sequences = counts.seqs.load(Ordering::SeqCst);
+    let thread_classified = counts.classified.load(Ordering::SeqCst);
+    if let Some(outputs) = outputs {
         report_kraken_style(
-            filename,
+            outputs.report(),
             args.report_zero_counts,
             args.report_kmer_data,
-            &taxonomy,
+            taxonomy,
             &sample_taxon_counts,
             thread_sequences as u64,
             (thread_sequences - thread_classified) as u64,
         )?;
+        report_pseudo_taxa(
+            outputs.spikein(),
+            taxonomy,
+            &sample_taxon_counts,
+            thread_sequences as u64,
+            expected_reads,
+        )?;
     }
 
     Ok((thread_sequences, thread_sequences - thread_classified))
 }
 
-fn process_files(
-    args: Args,
-    meros: Meros,
-    hash_config: HashConfig,
-    chtable: &CHTable,
-    taxonomy: &Taxonomy,
-) -> Result<()> {
-    let (mut file_index, mut file_writer) = if let Some(out_dir) = &args.output_dir {
-        let file_path = out_dir.join("sample_file.map");
-        let file_writer = create_sample_file(&file_path);
-        let file_index = get_lastest_file_index(&file_path)?;
-        (
-            file_index,
-            Box::new(BufWriter::new(file_writer)) as Box<dyn Write + Send>,
+/// Classifies the samples of a [`SampleChain`] through one read pipeline
+///
+/// The batches are written to the outputs of their sample, which is finished as soon as it
+/// has been read in full and all its batches are written.
+///
+/// # Returns
+///
+/// The number of reads and unclassified reads of every sample, and the read counts of all
+/// samples
+fn process_samples<R>(
+    args: &Args,
+    database: &Database,
+    outputs: &[Option<SampleOutputs>],
+    reader: &mut R,
+    progress: &SampleProgress,
+) -> io::Result<(Vec<(usize, usize)>, TaxonCounters)>
+where
+    R: Reader,
+{
+    let Database {
+        meros,
+        hash_config,
+        table,
+        taxonomy,
+    } = *database;
+    let counts: Vec<SampleCounts> = (0..progress.len())
+        .map(|_| SampleCounts::default())
+        .collect();
+    let expected_reads = read_pseudo_taxa_expectations(&args.database)?;
+    let pool: BufferPool<ClassifyBuffers> = BufferPool::default();
+    let classify = |record: &Base<MinimizerIterator>,
+                    hits: HitGroup,
+                    buffers: &mut ClassifyBuffers,
+                    sample_counts: &SampleCounts| {
+        classify_hits(
+            record,
+            hits,
+            buffers,
+            args,
+            taxonomy,
+            &hash_config,
+            &sample_counts.taxon_counts,
+            &sample_counts.classified,
         )
-    } else {
-        (
+    };
+
+    // read_parallel 不返回汇总线程的结果, 由它写回这里
+    let mut written = None;
+    let mut reader = CheckedReader::new(reader);
+    read_parallel(
+        &mut reader,
+        pipeline_threads(args.num_threads),
+        &meros,
+        |seqs| -> io::Result<(usize, ClassifyBuffers)> {
+            let sample = progress.sample(seqs[0].header.file_index);
+            let sample_counts = &counts[sample];
+            // 缓冲区来自已写出的批次, 每个 read 不再单独分配
+            let mut buffers = pool.take();
+            sample_counts.seqs.fetch_add(seqs.len(), Ordering::SeqCst);
+            match table {
+                HashTable::Resident(chtable) => {
+                    for record in seqs.iter_mut() {
+                        let mut rows = std::mem::take(&mut buffers.rows);
+                        rows.clear();
+                        let mut offset = 0;
+                        record.body.apply_mut(|m_iter| {
+                            offset = process_seq(&mut rows, m_iter, &hash_config, chtable, offset);
+                        });
+                        let hits = HitGroup::new(rows, record.range());
+                        classify(record, hits, &mut buffers, sample_counts);
+                    }
+                }
+                HashTable::Cached(cache) => {
+                    cached_batch_hits(seqs, &mut buffers, &hash_config, cache)?;
+                    let read_rows = std::mem::take(&mut buffers.read_rows);
+                    let mut read_rows_iter = read_rows.iter().peekable();
+                    for (read, record) in seqs.iter().enumerate() {
+                        let mut rows = std::mem::take(&mut buffers.rows);
+                        rows.clear();
+                        while let Some((_, row)) =
+                            read_rows_iter.next_if(|(hit_read, _)| *hit_read as usize == read)
+                        {
+                            rows.push(*row);
+                        }
+                        let hits = HitGroup::new(rows, record.range());
+                        classify(record, hits, &mut buffers, sample_counts);
+                    }
+                    buffers.read_rows = read_rows;
+                }
+            }
+
+            Ok((sample, buffers))
+        },
+        |dataset| {
+            let mut write_batches = || -> io::Result<(Vec<(usize, usize)>, TaxonCounters)> {
+                let mut total_taxon_counts = TaxonCounters::new();
+                let mut results: Vec<Option<(usize, usize)>> = vec![None; progress.len()];
+                let mut open: BTreeMap<usize, SampleWriters> = BTreeMap::new();
+                while let Some(data) = dataset.next() {
+                    let (sample, mut buffers) = data.unwrap()?;
+                    let writers = match open.entry(sample) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            entry.insert(SampleWriters::create(args, outputs[sample].as_ref())?)
+                        }
+                    };
+                    writers.writer.write_all(buffers.output.as_bytes())?;
+                    if let Some(chimera_writer) = writers.chimera_writer.as_mut() {
+                        chimera_writer.write_all(buffers.chimera.as_bytes())?;
+                    }
+                    writers.batches += 1;
+                    buffers.clear();
+                    pool.give(buffers);
+
+                    // 读完且批次全部写出的样本立即收尾, 释放文件句柄
+                    let complete: Vec<usize> = open
+                        .iter()
+                        .filter(|(sample, writers)| progress.is_complete(**sample, writers.batches))
+                        .map(|(sample, _)| *sample)
+                        .collect();
+                    for sample in complete {
+                        if let Some(writers) = open.remove(&sample) {
+                            results[sample] = Some(finish_sample(
+                                args,
+                                taxonomy,
+                                outputs[sample].as_ref(),
+                                writers,
+                                &counts[sample],
+                                &expected_reads,
+                                &mut total_taxon_counts,
+                            )?);
+                        }
+                    }
+                }
+
+                // 没有读数的样本也写出空的输出和报告
+                for (sample, result) in results.iter_mut().enumerate() {
+                    if result.is_some() {
+                        continue;
+                    }
+                    let writers = match open.remove(&sample) {
+                        Some(writers) => writers,
+                        None => SampleWriters::create(args, outputs[sample].as_ref())?,
+                    };
+                    *result = Some(finish_sample(
+                        args,
+                        taxonomy,
+                        outputs[sample].as_ref(),
+                        writers,
+                        &counts[sample],
+                        &expected_reads,
+                        &mut total_taxon_counts,
+                    )?);
+                }
+                Ok((results.into_iter().flatten().collect(), total_taxon_counts))
+            };
+            let result = write_batches();
+            // 出错后仍取完剩余的批次, 工作线程才能结束
+            while dataset.next().is_some() {}
+            written = Some(result);
+        },
+    )?;
+    reader.finish()?;
+    written.unwrap_or_else(|| Err(Error::other("the classify pipeline stopped early")))
+}
+
+fn process_files(args: Args, database: &Database) -> Result<()> {
+    let (mut file_index, mut file_writer) = match &args.output_dir {
+        // 对象存储上的 sample_file.map 不能追加, 每次运行从 1 开始编号
+        Some(out_dir) if is_remote(out_dir) => (
+            0,
+            Box::new(BufWriter::new(create_output(
+                out_dir.join("sample_file.map"),
+            )?)) as Box<dyn Write + Send>,
+        ),
+        Some(out_dir) => {
+            let file_path = out_dir.join("sample_file.map");
+            let file_writer = create_sample_file(&file_path)?;
+            let file_index = get_lastest_file_index(&file_path)?;
+            (
+                file_index,
+                Box::new(BufWriter::new(file_writer)) as Box<dyn Write + Send>,
+            )
+        }
+        None => (
             0,
             Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>,
-        )
+        ),
     };
 
+    let mut id_map = args.anonymize_ids.as_ref().map(IdMap::open).transpose()?;
+
+    // 样本编号随 read 传递, 不占哈希值的位, 样本数不受 value_bits 限制
     let mut process_funcs = |files: Vec<&[String]>| -> Result<()> {
-        let file_bits = (((files.len() + file_index) as f64).log2().ceil() as usize).max(1);
-        if file_bits > hash_config.value_bits {
-            panic!("The number of files is too large to process.");
+        let samples: BTreeMap<usize, String> = if args.sample_dirs {
+            (file_index + 1..)
+                .zip(files.iter().map(|file_pair| file_pair.join(",")))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+        let sample_dir_names = sample_dir_names(&samples);
+        let mut summaries = Vec::new();
+
+        let first_index = file_index;
+        for file_pair in &files {
+            file_index += 1;
+            writeln!(file_writer, "{}\t{}", file_index, file_pair.join(","))?;
         }
+        file_writer.flush()?;
+
+        let outputs: Vec<Option<SampleOutputs>> = (first_index + 1..=file_index)
+            .map(|index| {
+                args.output_dir.as_ref().map(|output| {
+                    SampleOutputs::new(
+                        output,
+                        index,
+                        sample_dir_names.get(&index).map(String::as_str),
+                    )
+                })
+            })
+            .collect();
+        // 所有样本共用同一条读取流水线, 数据库只加载一次
+        let progress = SampleProgress::new(first_index, files.len());
+        let score = args.minimum_quality_score;
+        let chain = SampleChain::new(&progress, |sample| {
+            let paths = OptionPair::from_slice(files[sample]);
+            open_fastx(paths, progress.file_index(sample), score, &args.input)
+        });
+        let mut reader = AnonymizedReader::new(chain, id_map.as_mut());
+        let (sample_counts, total_taxon_counts) =
+            process_samples(&args, database, &outputs, &mut reader, &progress)?;
 
-        let mut total_taxon_counts = TaxonCounters::new();
         let mut total_seqs: usize = 0;
         let mut total_unclassified: usize = 0;
-        for file_pair in files {
-            file_index += 1;
-
-            writeln!(file_writer, "{}\t{}", file_index, file_pair.join(","))?;
-            file_writer.flush().unwrap();
-
-            let score = args.minimum_quality_score;
-            let paths = OptionPair::from_slice(file_pair);
-            let mut reader = FastxReader::from_paths(paths, file_index, score)?;
-            // let mut reader = create_reader(file_pair, file_index, score)?;
-            let (thread_sequences, thread_unclassified) = process_fastx_file(
-                &args,
-                meros,
-                hash_config,
-                file_index,
-                &mut reader,
-                chtable,
-                taxonomy,
-                &mut total_taxon_counts,
-            )?;
-            total_seqs += thread_sequences;
-            total_unclassified += thread_unclassified;
+        for (sample, (file_pair, &(seqs, unclassified))) in
+            files.iter().zip(&sample_counts).enumerate()
+        {
+            if let Some(name) = sample_dir_names.get(&progress.file_index(sample)) {
+                summaries.push(SampleSummary {
+                    name: name.clone(),
+                    files: file_pair.join(","),
+                    reads: seqs as u64,
+                    unclassified: unclassified as u64,
+                });
+            }
+            total_seqs += seqs;
+            total_unclassified += unclassified;
         }
         if let Some(output) = &args.output_dir {
-            let filename = output.join("output.kreport2");
+            let filename = if args.sample_dirs {
+                write_sample_summary(output.join(SAMPLE_SUMMARY_FILENAME), &summaries)?;
+                output.join(COMBINED_REPORT_FILENAME)
+            } else {
+                output.join("output.kreport2")
+            };
             report_kraken_style(
                 filename,
                 args.report_zero_counts,
                 args.report_kmer_data,
-                &taxonomy,
+                database.taxonomy,
                 &total_taxon_counts,
                 total_seqs as u64,
                 total_unclassified as u64,
@@ -317,10 +722,34 @@ fn process_files(
         let files = args.input_files.chunks(1).collect();
         process_funcs(files)?;
     }
+    if let Some(id_map) = id_map.as_mut() {
+        id_map.flush()?;
+    }
 
     Ok(())
 }
 
+/// Reads all hash pages into memory, or caches as many as fit the memory budget
+fn load_hash_table(hash_config: HashConfig, hash_files: Vec<PathBuf>) -> Result<HashTable> {
+    if let Some(budget) = memory_budget() {
+        let mut table_bytes = 0;
+        for hash_file in &hash_files {
+            table_bytes += page_file_bytes(hash_file)?;
+        }
+        if table_bytes as usize > budget {
+            info!(
+                "hash pages ({}) exceed the memory budget of {}, caching the recently used pages",
+                format_bytes(table_bytes as f64),
+                format_bytes(budget as f64)
+            );
+            let cache = PageCache::new(hash_config, hash_files, budget)?;
+            return Ok(HashTable::Cached(cache));
+        }
+    }
+    let chtable = CHTable::from_hash_files(hash_config, &hash_files)?;
+    Ok(HashTable::Resident(chtable))
+}
+
 pub fn run(args: Args) -> Result<()> {
     let options_filename = &args.database.join("opts.k2d");
     let idx_opts = IndexOptions::read_index_options(options_filename)?;
@@ -333,24 +762,56 @@ pub fn run(args: Args) -> Result<()> {
         ));
     }
 
+    if args.chimera_window.is_some() && args.output_dir.is_none() {
+        warn!("chimera detection requires --output-dir, skipping");
+    }
+
     let taxonomy_filename = args.database.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
 
     let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
 
-    println!("{:?}", hash_config);
+    debug!("{:?}", hash_config);
     if hash_config.hash_capacity == 0 {
-        panic!("`hash_capacity` can't be zero!");
+        return Err(error::Error::InvalidDatabase {
+            path: args.database.join("hash_config.k2d"),
+            reason: "hash_capacity is 0".to_string(),
+        }
+        .into());
     }
-    println!("classify start...");
+    info!("classify start...");
     let start = Instant::now();
     let meros = idx_opts.as_meros();
-    let hash_files = find_and_sort_files(&args.database, "hash", ".k2d", true)?;
-    let chtable = CHTable::from_hash_files(hash_config, &hash_files)?;
+    let hash_files = find_and_sort_files(&args.database, "hash", hash_config.page_suffix(), true)?;
+    let table = if args.mmap && !hash_config.is_compressed() {
+        // 映射的页由页缓存管理, 不受内存预算限制
+        HashTable::Resident(CHTable::map_hash_files(hash_config, &hash_files)?)
+    } else {
+        if args.mmap {
+            warn!("zstd-compressed hash pages cannot be mapped, reading them into memory");
+        }
+        load_hash_table(hash_config, hash_files)?
+    };
 
-    process_files(args, meros, hash_config, &chtable, &taxo)?;
+    let database = Database {
+        meros,
+        hash_config,
+        table: &table,
+        taxonomy: &taxo,
+    };
+    process_files(args, &database)?;
+    if let HashTable::Cached(cache) = &table {
+        let (loads, evictions) = cache.stats();
+        info!(
+            "page cache: {} page loads, {} evictions of {} pages",
+            loads,
+            evictions,
+            cache.len()
+        );
+    }
+    check_uploads()?;
     let duration = start.elapsed();
-    println!("classify took: {:?}", duration);
+    info!("classify took: {:?}", duration);
     Ok(())
 }
 
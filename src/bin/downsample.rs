@@ -0,0 +1,197 @@
+use clap::Parser;
+use kun_peng::args::parse_size;
+use kun_peng::compact_hash::HashConfig;
+use kun_peng::db::{convert_fna_to_k2_format, process_k2file, DEFAULT_MAX_PAGE_LOAD};
+use kun_peng::manifest::DbManifest;
+use kun_peng::taxonomy::{Taxonomy, PSEUDO_TAXA_FILENAME};
+use kun_peng::utils::{
+    create_partition_files, create_partition_writers, find_files, get_file_limit,
+    read_id_to_taxon_map, set_fd_limit,
+};
+use kun_peng::IndexOptions;
+use log::info;
+use std::fs::{self, create_dir_all};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Subsample an existing database into a smaller one",
+    long_about = "Rebuild the hash tables of an existing database from its library, keeping only a fraction of the minimizers.
+Like Kraken 2's --max-db-size, minimizers are selected by a hash threshold that is stored in opts.k2d, so classification
+skips the dropped minimizers as well. The new hash tables are written at a smaller capacity to --output-dir."
+)]
+pub struct Args {
+    /// Database to subsample (must contain library/, seqid2taxid.map, taxo.k2d and opts.k2d)
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory for the subsampled database
+    #[arg(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+
+    /// Fraction of the minimizers to keep (0 < fraction <= 1)
+    #[arg(long, conflicts_with = "every")]
+    pub fraction: Option<f64>,
+
+    /// Keep about one in every N minimizers
+    #[arg(long)]
+    pub every: Option<u64>,
+
+    /// Set the hash table capacity of the subsampled database (number of slots),
+    /// by default it is derived from the current size and --load-factor
+    #[arg(short = 'c', long, value_name = "EXACT_SLOT_COUNT")]
+    pub required_capacity: Option<usize>,
+
+    /// Proportion of the subsampled hash table to be populated
+    #[clap(long, default_value_t = 0.7)]
+    pub load_factor: f64,
+
+    #[clap(long, value_parser = parse_size, default_value = "1G", help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nDefault: 1G (capacity 1G = file size 4G)")]
+    pub hash_capacity: usize,
+
+    /// Number of worker threads, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = num_cpus::get())]
+    pub threads: usize,
+}
+
+/// Hash threshold keeping `fraction` of the minimizers that pass the `current` threshold
+fn min_clear_hash_value(current: u64, fraction: f64) -> u64 {
+    let kept = (u64::MAX - current) as f64;
+    current.saturating_add((kept * (1.0 - fraction)) as u64)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let fraction = match (args.fraction, args.every) {
+        (Some(fraction), _) => fraction,
+        (None, Some(every)) if every > 0 => 1.0 / every as f64,
+        _ => 1.0,
+    };
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--fraction must be in (0, 1]",
+        ));
+    }
+
+    let database = &args.database;
+    let output_dir = &args.output_dir;
+    let hash_config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    if hash_config.version < 1 {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "databases converted from Kraken 2 have no library to subsample",
+        ));
+    }
+    create_dir_all(output_dir)?;
+    let output_config = output_dir.join("hash_config.k2d");
+    if output_config.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} already exists", output_config),
+        ));
+    }
+
+    // 保留 hash >= 阈值的 minimizer, 分类时按 opts.k2d 中相同的阈值跳过其余 minimizer
+    let mut idx_opts = IndexOptions::read_index_options(database.join("opts.k2d"))?;
+    idx_opts.minimum_acceptable_hash_value =
+        min_clear_hash_value(idx_opts.minimum_acceptable_hash_value, fraction);
+    let meros = idx_opts.as_meros();
+
+    let capacity = args.required_capacity.unwrap_or_else(|| {
+        ((hash_config.size as f64 * fraction / args.load_factor).ceil() as usize).max(1)
+    });
+    let partition = capacity.div_ceil(args.hash_capacity);
+    let mut new_config = HashConfig::new(
+        hash_config.base_version(),
+        capacity,
+        hash_config.value_bits,
+        0,
+        partition,
+        args.hash_capacity,
+    );
+    info!(
+        "keeping {:.2}% of the minimizers, capacity {} -> {} ({} pages)",
+        fraction * 100.0,
+        hash_config.capacity,
+        capacity,
+        partition
+    );
+
+    let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    let id_to_taxon_map = read_id_to_taxon_map(database.join("seqid2taxid.map"))?;
+
+    if partition >= get_file_limit() {
+        set_fd_limit(partition as u64 + 1)?;
+    }
+    let chunk_files = create_partition_files(partition, output_dir, "chunk")?;
+    for chunk_file in &chunk_files {
+        fs::File::create(chunk_file)?;
+    }
+    let mut writers: Vec<_> = create_partition_writers(&chunk_files)?
+        .into_iter()
+        .map(Some)
+        .collect();
+    for fna_file in find_files(database.join("library"), "library", ".fna") {
+        info!("convert fna file {:?}", fna_file);
+        convert_fna_to_k2_format(
+            fna_file,
+            meros,
+            &taxonomy,
+            &id_to_taxon_map,
+            new_config,
+            &mut writers,
+            args.hash_capacity,
+            args.threads,
+        );
+    }
+    for writer in writers.iter_mut().flatten() {
+        writer.flush()?;
+    }
+    drop(writers);
+
+    for (i, chunk_file) in chunk_files.iter().enumerate() {
+        let page_index = i + 1;
+        new_config.size += process_k2file(
+            new_config,
+            output_dir,
+            chunk_file,
+            &taxonomy,
+            page_index,
+            false,
+            DEFAULT_MAX_PAGE_LOAD,
+            false,
+        )?;
+        fs::remove_file(chunk_file)?;
+        info!("built hash page {}/{}", page_index, partition);
+    }
+
+    idx_opts.write_to_file(output_dir.join("opts.k2d"))?;
+    fs::copy(database.join("taxo.k2d"), output_dir.join("taxo.k2d"))?;
+    let pseudo_taxa = database.join(PSEUDO_TAXA_FILENAME);
+    if pseudo_taxa.exists() {
+        fs::copy(pseudo_taxa, output_dir.join(PSEUDO_TAXA_FILENAME))?;
+    }
+    new_config.write_to_file(&output_config)?;
+    DbManifest::update(output_dir, &database.join("library"))?;
+    info!(
+        "subsampled database: {} of {} minimizers, load factor {:.2}",
+        new_config.size,
+        hash_config.size,
+        new_config.size as f64 / capacity as f64
+    );
+
+    info!("downsample took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
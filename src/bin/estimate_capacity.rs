@@ -1,12 +1,14 @@
-use clap::{error::ErrorKind, Error, Parser};
+use clap::Parser;
 use hyperloglogplus::{HyperLogLog, HyperLogLogPlus};
 use kun_peng::args::KLMTArgs;
-use kun_peng::utils::{find_files, format_bytes, open_file};
+use kun_peng::db::{MinimizerSample, DEFAULT_SAMPLE_LIMIT, MINIMIZER_SAMPLE_FILENAME};
+use kun_peng::error::Error;
+use kun_peng::utils::{find_library_fna_files, format_bytes, open_file, pipeline_threads};
 use kun_peng::KBuildHasher;
+use log::{error, info};
 
 use seqkmer::{read_parallel, BufferFastaReader};
 use serde_json;
-use std::collections::HashSet;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -40,8 +42,8 @@ pub struct Args {
     #[clap(long, default_value_t = 0.7)]
     pub load_factor: f64,
 
-    /// Number of threads
-    #[clap(short = 'p', long, default_value_t = 10)]
+    /// Number of worker threads, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = 10)]
     pub threads: usize,
 }
 
@@ -59,62 +61,72 @@ fn build_output_path<P: AsRef<Path>>(input_path: &P, extension: &str) -> String
     output_path.to_str().unwrap().to_owned()
 }
 
+/// Estimates the distinct minimizers of a fna file and samples their hashes
+///
+/// Every batch of records is reduced to its sorted distinct minimizers before it reaches the
+/// sketches, and both the HyperLogLog sketch and the bottom-k sample have a fixed size, so
+/// the memory does not depend on the size of the file.
 fn process_sequence<P: AsRef<Path>>(
     fna_file: &P,
     // hllp: &mut HyperLogLogPlus<u64, KBuildHasher>,
     args: Args,
-) -> HyperLogLogPlus<u64, KBuildHasher> {
+) -> std::io::Result<(HyperLogLogPlus<u64, KBuildHasher>, MinimizerSample)> {
     // 构建预期的 JSON 文件路径
     let json_path = build_output_path(fna_file, &format!("hllp_{}.json", args.n));
+    let sample_path = build_output_path(fna_file, &format!("sample_{}.json", args.n));
     // 检查是否存在 JSON 文件
-    if args.cache && Path::new(&json_path).exists() {
+    if args.cache && Path::new(&json_path).exists() && Path::new(&sample_path).exists() {
         // 如果存在，从文件读取并反序列化
-        let mut file = open_file(json_path).unwrap();
+        let mut file = open_file(&json_path)?;
         let mut serialized_hllp = String::new();
-        file.read_to_string(&mut serialized_hllp).unwrap();
-        let hllp: HyperLogLogPlus<u64, KBuildHasher> =
-            serde_json::from_str(&serialized_hllp).unwrap();
-
-        return hllp;
+        file.read_to_string(&mut serialized_hllp)?;
+        let hllp: HyperLogLogPlus<u64, KBuildHasher> = serde_json::from_str(&serialized_hllp)
+            .map_err(|e| {
+                Error::InvalidInput(format!("{}: {}; rerun without --cache", json_path, e))
+            })?;
+        let sample = MinimizerSample::from_file(&sample_path)?;
+
+        return Ok((hllp, sample));
     }
 
-    let meros = args.klmt.as_meros();
+    let meros = args.klmt.as_meros()?;
 
     let mut hllp: HyperLogLogPlus<u64, _> =
         HyperLogLogPlus::new(16, KBuildHasher::default()).unwrap();
+    let mut sample = MinimizerSample::new(DEFAULT_SAMPLE_LIMIT);
 
-    let mut reader = BufferFastaReader::from_path(fna_file, 1)
-        .expect("Failed to open the FASTA file with FastaReader");
+    let mut reader = BufferFastaReader::from_path(fna_file, 1)?;
     let range_n = args.n as u64;
     read_parallel(
         &mut reader,
-        args.threads,
+        pipeline_threads(args.threads),
         &meros,
         |record_set| {
-            let mut minimizer_set = HashSet::new();
+            let mut minimizers = Vec::new();
 
             for record in record_set {
                 record.body.apply_mut(|m_iter| {
-                    let kmer_iter: HashSet<u64> = m_iter
-                        .filter(|(_, hash_key)| *hash_key & RANGE_MASK < range_n)
-                        .map(|(_, hash_key)| hash_key)
-                        .collect();
-
-                    minimizer_set.extend(kmer_iter);
+                    minimizers.extend(
+                        m_iter
+                            .filter(|(_, hash_key)| *hash_key & RANGE_MASK < range_n)
+                            .map(|(_, hash_key)| hash_key),
+                    );
                 });
             }
-            minimizer_set
+            minimizers.sort_unstable();
+            minimizers.dedup();
+            minimizers
         },
         |record_sets| {
             while let Some(data) = record_sets.next() {
-                let m_set = data.unwrap();
-                for minimizer in m_set {
+                let minimizers = data.unwrap();
+                for minimizer in minimizers {
                     hllp.insert(&minimizer);
+                    sample.insert(minimizer);
                 }
             }
         },
-    )
-    .expect("read parallel error");
+    )?;
 
     // 序列化 hllp 对象并将其写入文件
     let serialized_hllp = serde_json::to_string(&hllp).unwrap();
@@ -122,64 +134,81 @@ fn process_sequence<P: AsRef<Path>>(
     if let Ok(mut file) = File::create(&json_path) {
         // 尝试写入数据
         if let Err(e) = file.write_all(serialized_hllp.as_bytes()) {
-            eprintln!("Failed to write to file: {}", e);
+            error!("Failed to write to file: {}", e);
         }
     } else {
-        eprintln!("Failed to create file: {}", json_path);
+        error!("Failed to create file: {}", json_path);
+    }
+    if let Err(e) = sample.write_to_file(&sample_path) {
+        error!("Failed to write {}: {}", sample_path, e);
     }
 
-    hllp
+    Ok((hllp, sample))
 }
 
-pub fn run(args: Args) -> usize {
-    let meros = args.klmt.as_meros();
+pub fn run(args: Args) -> std::io::Result<usize> {
+    let meros = args.klmt.as_meros()?;
 
     if meros.k_mer < meros.l_mer {
-        let err = Error::raw(ErrorKind::ValueValidation, "k cannot be less than l");
-        err.exit();
+        return Err(Error::InvalidInput("k cannot be less than l".to_string()).into());
     }
 
     let mut hllp: HyperLogLogPlus<u64, KBuildHasher> =
         HyperLogLogPlus::new(16, KBuildHasher::default()).unwrap();
+    let mut sample = MinimizerSample::new(DEFAULT_SAMPLE_LIMIT);
 
     let source: PathBuf = args.database.clone();
     let fna_files = if source.is_file() {
         vec![source.clone()]
     } else {
         let library_dir = &args.database.join("library");
-        find_files(library_dir, "library", ".fna")
+        find_library_fna_files(library_dir)
     };
 
     if fna_files.is_empty() {
-        panic!("Error: No library.fna files found in the specified directory. Please ensure that the directory contains at least one library.fna file and try again.");
+        return Err(Error::InvalidInput(format!(
+            "no library.fna files found in {:?}; run merge-fna or add-library first",
+            args.database.join("library")
+        ))
+        .into());
     }
 
-    println!("estimate start... ");
+    info!("estimate start... ");
 
     for fna_file in fna_files {
         let args_clone = Args {
             database: source.clone(),
             ..args
         };
-        let local_hllp = process_sequence(&fna_file, args_clone);
+        let (local_hllp, local_sample) = process_sequence(&fna_file, args_clone)?;
         if let Err(e) = hllp.merge(&local_hllp) {
-            println!("hllp merge err {:?}", e);
+            error!("hllp merge err {:?}", e);
+        }
+        sample.merge(&local_sample);
+    }
+
+    // chunk 按这份样本估计每个 hash page 的基数, 单独规划每个 page 的容量
+    if source.is_dir() {
+        if let Err(e) = sample.write_to_file(source.join(MINIMIZER_SAMPLE_FILENAME)) {
+            error!("Failed to write {}: {}", MINIMIZER_SAMPLE_FILENAME, e);
         }
     }
 
     let hllp_count = (hllp.count() * RANGE_SECTIONS as f64 / args.n as f64).round() as u64;
     let required_capacity = (hllp_count + 8192) as f64 / args.load_factor;
-    println!(
+    info!(
         "estimate count: {:?}, required capacity: {:?}, Estimated hash table requirement: {:}",
         hllp_count,
         required_capacity.ceil(),
         format_bytes(required_capacity * 4f64)
     );
-    required_capacity.ceil() as usize
+    Ok(required_capacity.ceil() as usize)
 }
 
 #[allow(dead_code)]
 fn main() {
     let args = Args::parse();
-    run(args);
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
 }
@@ -0,0 +1,172 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::Parser;
+use kun_peng::compact_hash::{read_page_from_file, HashConfig};
+use kun_peng::format::export_kraken2_files;
+use kun_peng::utils::find_and_sort_files;
+use log::{debug, info, warn};
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Export a Kun-peng database to Kraken 2 format",
+    long_about = "Reassemble the hash_*.k2d pages into a single Kraken 2 hash.k2d and write taxo.k2d and opts.k2d next to it in the layouts of Kraken 2.
+The resulting directory can be used directly with kraken2 --db."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory for the Kraken 2 index files (hash.k2d, opts.k2d, taxo.k2d).
+    #[clap(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+}
+
+/// Leading run of a page, i.e. all cells before the first empty cell
+fn leading_run(data: &[u32]) -> Vec<u32> {
+    data.iter()
+        .take_while(|&&cell| cell != 0)
+        .copied()
+        .collect()
+}
+
+/// Put cells into the empty slots of a page, starting at its first cell
+///
+/// # Returns
+///
+/// The cells that did not fit into the page
+fn place_cells(data: &mut [u32], cells: Vec<u32>) -> Vec<u32> {
+    let mut cells = cells.into_iter();
+    for slot in data.iter_mut().filter(|slot| **slot == 0) {
+        match cells.next() {
+            Some(cell) => *slot = cell,
+            None => break,
+        }
+    }
+    cells.collect()
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let hash_files = find_and_sort_files(&args.database, "hash", hash_config.page_suffix(), true)?;
+    debug!("{:?}", hash_config);
+    if hash_config.is_wide() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "databases with 64-bit cells (--wide-cells) have no Kraken 2 equivalent",
+        ));
+    }
+    if hash_config.is_double_hashing() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Kraken 2 probes linearly, databases built with --double-hashing cannot be exported",
+        ));
+    }
+    if hash_files.len() != hash_config.partition {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "expected {} hash files, found {}",
+                hash_config.partition,
+                hash_files.len()
+            ),
+        ));
+    }
+
+    info!("export start...");
+    let start = Instant::now();
+    create_dir_all(&args.output_dir)?;
+
+    let hash_filename = args.output_dir.join("hash.k2d");
+    let mut writer = BufWriter::new(File::create(&hash_filename)?);
+    writer.write_u64::<LittleEndian>(hash_config.capacity as u64)?;
+    // size is rewritten once all cells are known
+    writer.write_u64::<LittleEndian>(0)?;
+    writer.write_u64::<LittleEndian>((32 - hash_config.value_bits) as u64)?;
+    writer.write_u64::<LittleEndian>(hash_config.value_bits as u64)?;
+
+    // Kun-peng pages (version >= 1) wrap their probe sequences around to their own first cell,
+    // whereas Kraken 2 continues into the next page. The leading run of a full page is therefore
+    // copied into the free slots after the leading run of the following page.
+    let native = hash_config.version >= 1;
+    let mut carry = Vec::new();
+    if native {
+        let last_page = read_page_from_file(&hash_files[hash_files.len() - 1])?;
+        if last_page.data[last_page.size - 1] != 0 {
+            carry = leading_run(&last_page.data[..last_page.size]);
+        }
+    }
+
+    let mut capacity = 0;
+    let mut size = 0;
+    for hash_file in &hash_files {
+        let mut page = read_page_from_file(hash_file)?;
+        let expected = hash_config
+            .hash_capacity
+            .min(hash_config.capacity - capacity);
+        if page.size != expected {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{:?} was grown to {} cells by the build, only pages of {} cells can be exported",
+                    hash_file, page.size, expected
+                ),
+            ));
+        }
+        let data = &mut page.data[..page.size];
+        let next_carry = if native && data[data.len() - 1] != 0 {
+            leading_run(data)
+        } else {
+            Vec::new()
+        };
+
+        let mut leftover = place_cells(data, carry);
+        for &cell in data.iter() {
+            writer.write_u32::<LittleEndian>(cell)?;
+        }
+        size += data.iter().filter(|&&cell| cell != 0).count();
+        capacity += data.len();
+
+        leftover.extend(next_carry);
+        carry = leftover;
+        info!("export page {:?}: {:?}", hash_file, start.elapsed());
+    }
+    if !carry.is_empty() {
+        warn!("{} wrapped cells could not be placed", carry.len());
+    }
+
+    if capacity != hash_config.capacity {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "hash pages hold {} cells, expected {}",
+                capacity, hash_config.capacity
+            ),
+        ));
+    }
+    let mut file = writer.into_inner()?;
+    file.seek(SeekFrom::Start(8))?;
+    file.write_u64::<LittleEndian>(size as u64)?;
+    file.flush()?;
+
+    if fs::canonicalize(&args.output_dir)? != fs::canonicalize(&args.database)? {
+        export_kraken2_files(&args.database, &args.output_dir)?;
+    } else {
+        warn!("taxo.k2d and opts.k2d of the database are left in the format of kun_peng");
+    }
+
+    info!("export took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,77 @@
+use clap::Parser;
+use kun_peng::taxonomy::{NCBITaxonomy, GTDB_ACCESSION_MAP_FILENAME};
+use log::info;
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Use GTDB taxonomy files as the database taxonomy",
+    long_about = "Copy GTDB taxonomy files (e.g. bac120_taxonomy.tsv, ar53_taxonomy.tsv) into the taxonomy directory of a database.
+taxo.k2d is then built from the GTDB lineages instead of an NCBI taxdump.
+The taxids assigned to the GTDB genomes are written to taxonomy/gtdb.accession2taxid, for labelling the library sequences."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// GTDB taxonomy files, e.g. bac120_taxonomy.tsv ar53_taxonomy.tsv
+    #[arg(required = true)]
+    pub taxonomy_files: Vec<PathBuf>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let taxonomy_dir = args.database.join("taxonomy");
+    create_dir_all(&taxonomy_dir)?;
+    if taxonomy_dir.join("nodes.dmp").exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "nodes.dmp found in taxonomy directory, remove the NCBI taxdump to use the GTDB taxonomy",
+        ));
+    }
+
+    let mut copied = Vec::new();
+    for filename in &args.taxonomy_files {
+        let name = filename
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid taxonomy file name"))?;
+        if !name.contains("_taxonomy") || !name.ends_with(".tsv") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a GTDB *_taxonomy*.tsv file", name),
+            ));
+        }
+        let dest = taxonomy_dir.join(name);
+        fs::copy(filename, &dest)?;
+        copied.push(dest);
+    }
+
+    let (_, accession_map) = NCBITaxonomy::from_gtdb(&copied)?;
+    let mut accessions: Vec<_> = accession_map.into_iter().collect();
+    accessions.sort_unstable();
+
+    let mut writer = BufWriter::new(File::create(taxonomy_dir.join(GTDB_ACCESSION_MAP_FILENAME))?);
+    for (accession, taxid) in &accessions {
+        writeln!(writer, "{}\t{}", accession, taxid)?;
+    }
+    writer.flush()?;
+
+    info!(
+        "{} GTDB genomes written to {:?}",
+        accessions.len(),
+        taxonomy_dir.join(GTDB_ACCESSION_MAP_FILENAME)
+    );
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -1,46 +1,59 @@
 use clap::Parser;
 use kun_peng::args::parse_size;
 use kun_peng::compact_hash::HashConfig;
+use kun_peng::error::Error;
+use kun_peng::format::migrate_database;
+use log::info;
 // use memmap2::MmapOptions;
 use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::BufWriter;
-use std::io::{self, BufReader, Read, Result as IOResult, Seek, Write};
+use std::io::{self, Read, Result as IOResult, Seek, Write};
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::Instant;
 
-fn mmap_read_write<P: AsRef<Path>, Q: AsRef<Path>>(
+/// Copy one page of the Kraken 2 hash table into a hash_{i}.k2d file
+///
+/// The data is streamed through a buffer of `buffer_size` bytes and written to a temporary
+/// file first, so an interrupted run never leaves a truncated page behind.
+fn copy_page<P: AsRef<Path>, Q: AsRef<Path>>(
     source_path: P,
     dest_path: Q,
     partition: usize,
     cap: usize,
     offset: u64,
     length: usize,
+    buffer_size: usize,
 ) -> IOResult<()> {
-    // 打开目标文件，准备写入数据
-    let mut dest_file = BufWriter::new(File::create(dest_path)?);
-    dest_file
-        .write_all(&partition.to_le_bytes())
-        .expect("Failed to write capacity");
-    dest_file
-        .write_all(&cap.to_le_bytes())
-        .expect("Failed to write capacity");
-
-    // 打开源文件，并创建一个缓冲读取器
+    let dest_path = dest_path.as_ref();
+    let tmp_path = dest_path.with_extension("k2d.tmp");
+    let mut dest_file = BufWriter::new(File::create(&tmp_path)?);
+    dest_file.write_all(&partition.to_le_bytes())?;
+    dest_file.write_all(&cap.to_le_bytes())?;
+
     let mut file = OpenOptions::new().read(true).open(&source_path)?;
     file.seek(io::SeekFrom::Start(offset))?;
-    let mut reader = BufReader::new(file);
-
-    // 创建一个缓冲区，用于存储读取的数据
-    let mut buffer = vec![0; length];
-    reader.read_exact(&mut buffer)?;
 
-    // 将读取的数据写入目标文件
-    dest_file.write_all(&buffer)?;
+    let mut buffer = vec![0; buffer_size.min(length).max(1)];
+    let mut remaining = length;
+    while remaining > 0 {
+        let n = remaining.min(buffer.len());
+        file.read_exact(&mut buffer[..n])?;
+        dest_file.write_all(&buffer[..n])?;
+        remaining -= n;
+    }
+    dest_file.flush()?;
+    drop(dest_file);
 
+    fs::rename(tmp_path, dest_path)?;
     Ok(())
 }
 
+/// Check whether a page was completely written by an earlier run
+fn page_is_complete(path: &Path, cap: usize) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.len() == (16 + cap * 4) as u64)
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(
     version,
@@ -51,14 +64,21 @@ pub struct Args {
     #[clap(long = "db", value_parser, required = true)]
     database: PathBuf,
 
-    // /// database hash chunk directory and other files
-    // #[clap(long)]
-    // k2d_dir: Option<PathBuf>,
+    /// Directory of the converted database: hash_config.k2d, the hash pages and versioned copies of taxo.k2d and opts.k2d.
+    /// Defaults to the Kraken 2 database directory, whose taxo.k2d and opts.k2d are then left as they are
+    #[clap(long)]
+    k2d_dir: Option<PathBuf>,
+
     /// Specifies the hash file capacity. Acceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').
     /// Note: The specified capacity affects the index size, with a factor of 4 applied. For example, specifying '1G' results in an index size of '4G'.
     /// Default: 1G (capacity 1G = file size 4G)
     #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G", help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nNote: The specified capacity affects the index size, with a factor of 4 applied.\nFor example, specifying '1G' results in an index size of '4G'.\nDefault: 1G (capacity 1G = file size 4G)")]
     hash_capacity: usize,
+
+    /// Size of the read buffer used while copying the hash table.
+    /// Memory use of the conversion is bounded by this value.
+    #[clap(long = "buffer-size", value_parser = parse_size, default_value = "64M")]
+    buffer_size: usize,
 }
 
 pub fn run(args: Args) -> IOResult<()> {
@@ -69,24 +89,26 @@ pub fn run(args: Args) -> IOResult<()> {
     hash_config.partition = partition;
     hash_config.hash_capacity = args.hash_capacity;
 
-    println!("hashshard start...");
+    info!("hashshard start...");
     // 开始计时
     let start = Instant::now();
 
     let file_len = hash_config.capacity * 4 + 32;
     let b_size = std::mem::size_of::<u32>();
 
-    let k2d_dir = args.database.clone();
+    let k2d_dir = args
+        .k2d_dir
+        .clone()
+        .unwrap_or_else(|| args.database.clone());
 
-    create_dir_all(&k2d_dir).expect(&format!("create hash dir error {:?}", k2d_dir));
+    create_dir_all(&k2d_dir).map_err(|e| Error::io(&k2d_dir, e))?;
 
+    // the config is written last and marks a finished conversion
     let config_file = k2d_dir.join("hash_config.k2d");
     if config_file.exists() {
-        panic!("hash config is exists!!!");
+        return Err(Error::AlreadyExists(config_file).into());
     }
 
-    hash_config.write_to_file(config_file)?;
-
     for i in 1..=partition {
         let chunk_file = k2d_dir.join(format!("hash_{}.k2d", i));
         let offset = (32 + args.hash_capacity * (i - 1) * b_size) as u64;
@@ -95,14 +117,35 @@ pub fn run(args: Args) -> IOResult<()> {
             length = file_len - offset as usize;
         }
         let cap = length / b_size;
-        mmap_read_write(&index_filename, chunk_file, i, cap, offset, length)?
+        if page_is_complete(&chunk_file, cap) {
+            info!("hash page {}/{} exists, skipping", i, partition);
+            continue;
+        }
+        copy_page(
+            index_filename,
+            &chunk_file,
+            i,
+            cap,
+            offset,
+            length,
+            args.buffer_size,
+        )?;
+        info!(
+            "hash page {}/{} done ({:.1}%), elapsed: {:?}",
+            i,
+            partition,
+            (offset as usize + length) as f64 * 100.0 / file_len as f64,
+            start.elapsed()
+        );
     }
 
+    hash_config.write_to_file(config_file)?;
+
     // 计算持续时间
     let duration = start.elapsed();
 
     // 打印运行时间
-    println!("hashshard took: {:?}", duration);
+    info!("hashshard took: {:?}", duration);
 
     let source_taxo_file = &args.database.join("taxo.k2d");
     let dst_tax_file = k2d_dir.join("taxo.k2d");
@@ -116,6 +159,16 @@ pub fn run(args: Args) -> IOResult<()> {
         fs::copy(source_opts_file, dst_opts_file)?;
     }
 
+    // Kraken 2 的 taxo.k2d 和 opts.k2d 没有版本号, 只升级输出目录里的副本, 不改动源目录
+    if fs::canonicalize(&k2d_dir)? != fs::canonicalize(&args.database)? {
+        for version in migrate_database(&k2d_dir)? {
+            info!(
+                "{:?} upgraded from format version {} to {}",
+                version.path, version.found, version.supported
+            );
+        }
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,147 @@
+use clap::Parser;
+use kun_peng::compact_hash::{read_page_from_file, Compact, HashConfig};
+use kun_peng::readcounts::{ReadCounter, TaxonCounters};
+use kun_peng::report::report_kraken_style;
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::find_and_sort_files;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Inspect the contents of the hash tables",
+    long_about = "Walk all hash_*.k2d pages and count the minimizers stored for each taxon.
+The counts are written as a Kraken-style report (inspect.kreport2), optionally together with a dump of all occupied cells (cells.tsv)."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory for inspect.kreport2 and cells.tsv.
+    #[clap(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+
+    /// Also dump every occupied cell as index, compacted key and taxid.
+    #[clap(long, default_value_t = false)]
+    pub raw: bool,
+
+    /// In comb. w/ -R, report taxa w/ 0 count
+    #[clap(short = 'z', long, value_parser, default_value_t = false)]
+    pub report_zero_counts: bool,
+}
+
+/// Count the occupied cells of a page per internal taxon ID
+fn count_page_cells(data: &[u32], value_mask: usize) -> HashMap<u32, u64> {
+    data.par_chunks(1 << 20)
+        .fold(HashMap::new, |mut counts, chunk| {
+            for cell in chunk {
+                let taxid = cell.right(value_mask);
+                if taxid != 0 {
+                    *counts.entry(taxid).or_insert(0u64) += 1;
+                }
+            }
+            counts
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (taxid, count) in b {
+                *a.entry(taxid).or_insert(0) += count;
+            }
+            a
+        })
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let taxonomy_filename = args.database.join("taxo.k2d");
+    let taxo = Taxonomy::from_file(taxonomy_filename)?;
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let hash_files = find_and_sort_files(&args.database, "hash", hash_config.page_suffix(), true)?;
+    debug!("{:?}", hash_config);
+
+    create_dir_all(&args.output_dir)?;
+    let mut raw_writer = if args.raw {
+        let file = File::create(args.output_dir.join("cells.tsv"))?;
+        Some(BufWriter::new(file))
+    } else {
+        None
+    };
+
+    let start = Instant::now();
+    let value_bits = hash_config.value_bits;
+    let value_mask = hash_config.value_mask;
+    let mut minimizer_counts: HashMap<u32, u64> = HashMap::new();
+
+    for hash_file in &hash_files {
+        let page = read_page_from_file(hash_file)?;
+        let offset = (page.index - 1) * hash_config.hash_capacity;
+
+        for (taxid, count) in count_page_cells(&page.data[..page.size], value_mask) {
+            *minimizer_counts.entry(taxid).or_insert(0) += count;
+        }
+
+        if let Some(writer) = raw_writer.as_mut() {
+            for (i, cell) in page.data[..page.size].iter().enumerate() {
+                let taxid = cell.right(value_mask);
+                if taxid != 0 {
+                    let external_id = taxo
+                        .nodes
+                        .get(taxid as usize)
+                        .map_or(0, |node| node.external_id);
+                    let key = if page.is_wide() {
+                        page.keys[i]
+                    } else {
+                        cell.left(value_bits)
+                    };
+                    writeln!(writer, "{}\t{:#x}\t{}", offset + i, key, external_id)?;
+                }
+            }
+        }
+        info!("inspect page {:?}: {:?}", hash_file, start.elapsed());
+    }
+    if let Some(mut writer) = raw_writer {
+        writer.flush()?;
+    }
+
+    let mut call_counters = TaxonCounters::new();
+    let mut total_minimizers = 0u64;
+    for (&taxid, &count) in &minimizer_counts {
+        if (taxid as usize) >= taxo.node_count() {
+            warn!("taxid {} not found in database taxonomy, skipping", taxid);
+            continue;
+        }
+        total_minimizers += count;
+        call_counters.insert(taxid as u64, ReadCounter::new(count, 0));
+    }
+    info!(
+        "{} minimizers assigned to {} taxa",
+        total_minimizers,
+        call_counters.len()
+    );
+
+    report_kraken_style(
+        args.output_dir.join("inspect.kreport2"),
+        args.report_zero_counts,
+        false,
+        &taxo,
+        &call_counters,
+        total_minimizers,
+        0,
+    )?;
+
+    info!("inspect took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
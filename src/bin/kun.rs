@@ -2,19 +2,52 @@ use clap::{Parser, Subcommand};
 mod annotate;
 mod build_db;
 mod chunk_db;
+mod db_stats;
+mod decontam;
 mod direct;
+mod downsample;
 mod estimate_capacity;
+mod export;
+mod gtdb_taxonomy;
 mod hashshard;
+mod inspect;
 mod merge_fna;
+mod migrate_db;
+mod probe_bench;
 mod resolve;
+mod serve;
+mod watch;
+mod special;
 mod splitr;
+mod subset;
+mod opts;
+mod taxonomy;
 mod add_library;
+mod remove_library;
 
 use kun_peng::args::ClassifyArgs;
-use kun_peng::args::{parse_size, Build};
-use kun_peng::utils::find_files;
+use kun_peng::args::{auto_hash_capacity, parse_size, Build, MAX_AUTO_HASH_CAPACITY};
+use kun_peng::checkpoint::{Checkpoint, CHUNK_DONE, CLASSIFY_CHECKPOINT_FILENAME, SPLITR_DONE};
+use kun_peng::compact_hash::HashConfig;
+use kun_peng::error::{exit_code, Error};
+use kun_peng::input::{InputOptions, MismatchedPairs};
+use kun_peng::logging;
+use kun_peng::manifest::validate_manifest;
+use kun_peng::numa::{set_numa_policy, NumaPolicy};
+use kun_peng::plan::{
+    estimated_chunk_bytes, estimated_file_bytes, list_input_files, plan_build, plan_classify,
+};
+use kun_peng::read_ahead::{set_read_ahead, ReadAhead};
+use kun_peng::summary::RunSummary;
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::{
+    find_files, find_library_fna_files, format_bytes, get_available_disk_space,
+    get_available_memory, import_library_files, memory_budget, set_memory_budget,
+    set_thread_budget, ChunkDirLock, TempChunkDir,
+};
+use log::{debug, error, info, warn, LevelFilter};
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
@@ -59,8 +92,30 @@ struct BuildArgs {
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
 
-    #[clap(long, value_parser = parse_size, default_value = "1G", help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nNote: The specified capacity affects the index size, with a factor of 4 applied.\nFor example, specifying '1G' results in an index size of '4G'.\nDefault: 1G (capacity 1G = file size 4G)")]
-    pub hash_capacity: usize,
+    /// Do not mask low-complexity regions of the library sequences
+    #[arg(long = "no-masking", default_value_t = false)]
+    pub no_masking: bool,
+
+    /// Only merge assemblies of these RefSeq categories (comma separated: reference, representative, na).
+    /// Default: all assemblies
+    #[arg(long, value_delimiter = ',')]
+    pub category: Vec<String>,
+
+    /// Skip metagenome-assembled genomes (excluded_from_refseq "derived from metagenome")
+    #[arg(long = "exclude-mag", default_value_t = false)]
+    pub exclude_mag: bool,
+
+    /// Skip atypical assemblies, i.e. those with any excluded_from_refseq reason (includes MAGs)
+    #[arg(long = "exclude-atypical", default_value_t = false)]
+    pub exclude_atypical: bool,
+
+    #[clap(long, value_parser = parse_size, help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nNote: The specified capacity affects the index size, with a factor of 4 applied.\nFor example, specifying '1G' results in an index size of '4G'.\nDefault: chosen from --max-memory, at most 1G (capacity 1G = file size 4G)")]
+    pub hash_capacity: Option<usize>,
+
+    /// Print the expected memory of each stage, the temporary disk usage, the number of hash
+    /// pages and the database size, then exit without building
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -97,17 +152,115 @@ It is highly recommended to run the 'estimate_capacity' command first to determi
     #[clap(long, default_value_t = 0.7)]
     load_factor: f64,
 
-    #[clap(long, value_parser = parse_size, default_value = "1G", help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nNote: The specified capacity affects the index size, with a factor of 4 applied.\nFor example, specifying '1G' results in an index size of '4G'.\nDefault: 1G (capacity 1G = file size 4G)")]
-    pub hash_capacity: usize,
+    #[clap(long, value_parser = parse_size, help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nNote: The specified capacity affects the index size, with a factor of 4 applied.\nFor example, specifying '1G' results in an index size of '4G'.\nDefault: chosen from --max-memory, at most 1G (capacity 1G = file size 4G)")]
+    pub hash_capacity: Option<usize>,
+
+    /// Plain multi-FASTA files to build from, linked into library/ as library_<name>.fna.
+    /// Sequences are looked up in --seqid2taxid by the first word of their headers
+    #[clap(long, num_args = 1.., requires = "seqid2taxid")]
+    pub library_fna: Vec<PathBuf>,
+
+    /// Sequence id to taxid map (e.g. of a Kraken 2 custom database), copied to seqid2taxid.map
+    #[clap(long)]
+    pub seqid2taxid: Option<PathBuf>,
+
+    /// Print the expected memory of each stage, the temporary disk usage, the number of hash
+    /// pages and the database size, then exit without building
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Most verbose log level written: off, error, warn, info, debug or trace
+    #[clap(long, global = true, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Append the log to this file instead of writing it to stderr
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Write the log as JSON lines (ts, level, target, msg)
+    #[clap(long, global = true, default_value_t = false)]
+    log_json: bool,
+
+    /// Print nothing but errors: no log, progress or run summary. Overrides --log-level
+    #[clap(short, long, global = true, default_value_t = false, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print the diagnostics of every stage (debug log). Overrides --log-level
+    #[clap(short, long, global = true, default_value_t = false)]
+    verbose: bool,
+
+    /// Memory budget of the run (e.g. '16G'), honored by splitr, annotate, build and direct.
+    /// build also sizes the hash pages so that the machine classifying with the database fits them.
+    /// Default: the memory limit of the cgroup, if any
+    #[clap(long, global = true, value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// Most threads the run uses at once. Sizes the rayon pool and caps the --num-threads
+    /// workers of every command, so that workers plus the reader and writer threads fit.
+    /// Default: one rayon thread per CPU and --num-threads workers
+    #[clap(
+        long = "threads",
+        value_name = "THREADS",
+        global = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_threads: Option<u32>,
+
+    /// NUMA placement of the threads and hash pages: off, local (pin the run to one node and
+    /// allocate the pages there) or interleave (spread the pages over all nodes). Linux only
+    #[clap(long, global = true, default_value = "off")]
+    numa: NumaPolicy,
+
+    /// How the hash pages are read: off, sequential (read-ahead hints and the next page
+    /// fetched into the page cache ahead of use) or direct (O_DIRECT, past the page cache).
+    /// Helps cold reads from Lustre or NFS. Linux only
+    #[clap(long, global = true, default_value = "off")]
+    db_read_ahead: ReadAhead,
+
+    /// What to do when the mates of paired-end files are out of sync (IDs that differ apart
+    /// from a /1 or /2 suffix, or one file ending first): error, naming the read, or warn
+    /// and classify the pairs as they are
+    #[clap(long, global = true, default_value = "error")]
+    mismatched_pairs: MismatchedPairs,
+
+    /// Keep the reads before a truncated or corrupt input file (e.g. a gzip file that ends
+    /// mid-record) and go on with the next sample, instead of failing the run
+    #[clap(long, global = true, default_value_t = false)]
+    keep_truncated_input: bool,
+
+    /// Fail on malformed FASTQ records (a header without '@', a separator without '+' or a
+    /// quality line of another length than the sequence), naming the file, record and line,
+    /// instead of skipping them and counting them in a warning
+    #[clap(long, global = true, default_value_t = false)]
+    strict: bool,
+
     #[clap(subcommand)]
     cmd: Commands,
 }
 
+/// Resolves --hash-capacity, chosen from the memory budget or the available memory if not set
+fn resolve_hash_capacity(hash_capacity: Option<usize>, wide_cells: bool) -> usize {
+    if let Some(hash_capacity) = hash_capacity {
+        return hash_capacity;
+    }
+    match memory_budget().or_else(get_available_memory) {
+        Some(memory) => {
+            let hash_capacity = auto_hash_capacity(memory, wide_cells);
+            info!(
+                "hash capacity {} chosen for {} of memory",
+                hash_capacity,
+                format_bytes(memory as f64)
+            );
+            hash_capacity
+        }
+        None => MAX_AUTO_HASH_CAPACITY,
+    }
+}
+
 impl From<ClassifyArgs> for splitr::Args {
     fn from(item: ClassifyArgs) -> Self {
         Self {
@@ -115,8 +268,13 @@ impl From<ClassifyArgs> for splitr::Args {
             paired_end_processing: item.paired_end_processing,
             minimum_quality_score: item.minimum_quality_score,
             num_threads: item.num_threads,
-            chunk_dir: item.chunk_dir,
+            chunk_dir: item
+                .chunk_dir
+                .expect("classify sets the chunk directory first"),
             input_files: item.input_files,
+            progress: item.progress,
+            compress_chunks: item.compress_chunks,
+            input: item.input,
         }
     }
 }
@@ -125,10 +283,17 @@ impl From<ClassifyArgs> for annotate::Args {
     fn from(item: ClassifyArgs) -> Self {
         Self {
             database: item.database,
-            chunk_dir: item.chunk_dir,
+            chunk_dir: item
+                .chunk_dir
+                .expect("classify sets the chunk directory first"),
             batch_size: item.batch_size,
             buffer_size: item.buffer_size,
             num_threads: item.num_threads,
+            resume: item.resume,
+            progress: item.progress,
+            partition: None,
+            prefetch: item.prefetch,
+            concurrent_pages: None,
         }
     }
 }
@@ -137,13 +302,25 @@ impl From<ClassifyArgs> for resolve::Args {
     fn from(item: ClassifyArgs) -> Self {
         Self {
             database: item.database,
-            chunk_dir: item.chunk_dir,
+            chunk_dir: item
+                .chunk_dir
+                .expect("classify sets the chunk directory first"),
             num_threads: item.num_threads,
             confidence_threshold: item.confidence_threshold,
             minimum_hit_groups: item.minimum_hit_groups,
+            full_output: item.full_output,
             output_dir: item.output_dir,
             report_kmer_data: item.report_kmer_data,
             report_zero_counts: item.report_zero_counts,
+            strain_refinement: item.strain_refinement,
+            chimera_window: item.chimera_window,
+            chimera_stride: item.chimera_stride,
+            resume: item.resume,
+            progress: item.progress,
+            sample_dirs: item.sample_dirs,
+            anonymize_ids: item.anonymize_ids,
+            partition: None,
+            merge_partitions: false,
         }
     }
 }
@@ -164,8 +341,8 @@ impl From<BuildArgs> for estimate_capacity::Args {
 impl From<BuildArgs> for chunk_db::Args {
     fn from(item: BuildArgs) -> Self {
         Self {
+            hash_capacity: resolve_hash_capacity(item.hash_capacity, item.build.wide_cells),
             build: item.build,
-            hash_capacity: item.hash_capacity,
         }
     }
 }
@@ -176,6 +353,27 @@ impl From<BuildArgs> for merge_fna::Args {
             download_dir: item.download_dir,
             database: item.build.database,
             max_file_size: item.max_file_size,
+            no_masking: item.no_masking,
+            deterministic: item.build.deterministic,
+            category: item.category,
+            exclude_mag: item.exclude_mag,
+            exclude_atypical: item.exclude_atypical,
+        }
+    }
+}
+
+impl From<BuildArgs> for build_db::Args {
+    fn from(item: BuildArgs) -> Self {
+        Self {
+            database: item.build.database,
+            deterministic: item.build.deterministic,
+            max_page_load: item.build.max_page_load,
+            compress_pages: item.build.compress_pages,
+            sort_buffer: item.build.sort_buffer,
+            mmap_pages: item.build.mmap_pages,
+            prefilter: item.build.prefilter,
+            double_hashing: item.build.double_hashing,
+            progress: item.build.progress,
         }
     }
 }
@@ -196,8 +394,24 @@ impl From<BuildDBArgs> for estimate_capacity::Args {
 impl From<BuildDBArgs> for chunk_db::Args {
     fn from(item: BuildDBArgs) -> Self {
         Self {
+            hash_capacity: resolve_hash_capacity(item.hash_capacity, item.build.wide_cells),
             build: item.build,
-            hash_capacity: item.hash_capacity,
+        }
+    }
+}
+
+impl From<BuildDBArgs> for build_db::Args {
+    fn from(item: BuildDBArgs) -> Self {
+        Self {
+            database: item.build.database,
+            deterministic: item.build.deterministic,
+            max_page_load: item.build.max_page_load,
+            compress_pages: item.build.compress_pages,
+            sort_buffer: item.build.sort_buffer,
+            mmap_pages: item.build.mmap_pages,
+            prefilter: item.build.prefilter,
+            double_hashing: item.build.double_hashing,
+            progress: item.build.progress,
         }
     }
 }
@@ -214,57 +428,238 @@ enum Commands {
     Resolve(resolve::Args),
     Classify(ClassifyArgs),
     Direct(direct::Args),
+    Serve(serve::Args),
+    Watch(watch::Args),
     MergeFna(merge_fna::Args),
     AddLibrary(add_library::Args),
+    RemoveLibrary(remove_library::Args),
+    Decontam(decontam::Args),
+    Inspect(inspect::Args),
+    DbStats(db_stats::Args),
+    Export(export::Args),
+    ProbeBench(probe_bench::Args),
+    GtdbTaxonomy(gtdb_taxonomy::Args),
+    Special(special::Args),
+    Downsample(downsample::Args),
+    Subset(subset::Args),
+    Opts(opts::Args),
+    Taxonomy(taxonomy::Args),
+    MigrateDb(migrate_db::Args),
+}
+
+/// Checks that the chunk directory has room for classifying the input files
+///
+/// # Arguments
+///
+/// * `args` - The splitr arguments with the chunk directory and the input files
+/// * `strict` - Fail when the space is short instead of printing a warning
+fn check_chunk_dir_space(args: &splitr::Args, strict: bool) -> std::io::Result<()> {
+    let Some(available) = get_available_disk_space(&args.chunk_dir) else {
+        return Ok(());
+    };
+    let required = estimated_chunk_bytes(&list_input_files(&args.input_files)?);
+    if available >= required {
+        return Ok(());
+    }
+    let message = format!(
+        "chunk directory {:?} has {} free, classifying the input needs about {}",
+        args.chunk_dir,
+        format_bytes(available as f64),
+        format_bytes(required as f64)
+    );
+    if strict {
+        return Err(Error::Resources(format!(
+            "{}; use --chunk-dir on a larger file system",
+            message
+        ))
+        .into());
+    }
+    warn!("{}", message);
+    Ok(())
+}
+
+/// Splits the input files of classify into batches of samples that the chunk files can tell
+/// apart, one batch unless there are more samples than the slots can number
+fn sample_batches(args: &ClassifyArgs) -> std::io::Result<Vec<Vec<PathBuf>>> {
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let files_per_sample = if args.paired_end_processing { 2 } else { 1 };
+    let batch_files = hash_config.max_chunk_samples().max(1) * files_per_sample;
+    let input_files = list_input_files(&args.input_files)?;
+    if input_files.len() <= batch_files {
+        return Ok(vec![args.input_files.clone()]);
+    }
+    Ok(input_files
+        .chunks(batch_files)
+        .map(<[PathBuf]>::to_vec)
+        .collect())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Whether an interrupted build has already written all chunk files
+fn chunk_done(database: &PathBuf) -> std::io::Result<bool> {
+    Ok(Checkpoint::load(database)?.is_done(CHUNK_DONE))
+}
+
+/// The hash table capacity recorded by an interrupted build that has written all chunk files
+fn chunk_capacity(database: &PathBuf) -> std::io::Result<Option<usize>> {
+    if !chunk_done(database)? {
+        return Ok(None);
+    }
+    Ok(Some(
+        HashConfig::from_hash_header(database.join("hash_config.k2d"))?.capacity,
+    ))
+}
+
+fn main() {
     let args = Args::parse();
+    let log_level = if args.quiet {
+        LevelFilter::Error
+    } else if args.verbose {
+        args.log_level.max(LevelFilter::Debug)
+    } else {
+        args.log_level
+    };
+    if let Err(e) = logging::init(log_level, args.log_file.as_deref(), args.log_json) {
+        eprintln!("failed to open the log: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(max_memory) = args.max_memory {
+        set_memory_budget(max_memory);
+    }
+    set_read_ahead(args.db_read_ahead);
+    // 在任何工作线程启动前绑定, 之后的线程继承 CPU 亲和性
+    if let Err(e) = set_numa_policy(args.numa, args.max_threads.map(|t| t as usize)) {
+        error!("failed to apply --numa {}: {}", args.numa, e);
+        std::process::exit(1);
+    }
+    if let Some(max_threads) = args.max_threads {
+        if let Err(e) = set_thread_budget(max_threads as usize) {
+            error!("failed to size the thread pool: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let input = InputOptions {
+        mismatched_pairs: args.mismatched_pairs,
+        keep_truncated: args.keep_truncated_input,
+        strict: args.strict,
+    };
+    if let Err(e) = run(args.cmd, input) {
+        error!("{}", e);
+        log::logger().flush();
+        std::process::exit(exit_code(e.as_ref()));
+    }
+}
 
-    match args.cmd {
+fn run(cmd: Commands, input: InputOptions) -> Result<(), Box<dyn std::error::Error>> {
+    match cmd {
         Commands::MergeFna(cmd_args) => {
             merge_fna::run(cmd_args)?;
         }
         Commands::Estimate(cmd_args) => {
-            estimate_capacity::run(cmd_args);
+            estimate_capacity::run(cmd_args)?;
         }
         Commands::AddLibrary(cmd_args) => {
             add_library::run(cmd_args)?;
         }
+        Commands::RemoveLibrary(cmd_args) => {
+            remove_library::run(cmd_args)?;
+        }
         Commands::Build(cmd_args) => {
+            if cmd_args.dry_run {
+                // 下载目录中的基因组合并前按解压后的大小估算 library
+                let library_bytes = walkdir::WalkDir::new(&cmd_args.download_dir)
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.file_name().to_string_lossy().ends_with(".fna.gz"))
+                    .map(|entry| estimated_file_bytes(entry.path()))
+                    .sum();
+                let hash_capacity =
+                    resolve_hash_capacity(cmd_args.hash_capacity, cmd_args.build.wide_cells);
+                let plan = plan_build(
+                    &cmd_args.build,
+                    library_bytes,
+                    chunk_capacity(&cmd_args.build.database)?,
+                    hash_capacity,
+                    cmd_args.load_factor,
+                    true,
+                );
+                print!("{}", plan);
+                return Ok(());
+            }
             let fna_args = merge_fna::Args::from(cmd_args.clone());
             merge_fna::run(fna_args)?;
-            let ec_args = estimate_capacity::Args::from(cmd_args.clone());
-            let required_capacity = estimate_capacity::run(ec_args);
+            // the capacity is already recorded in hash_config.k2d when chunking has finished
+            let required_capacity = if chunk_done(&cmd_args.build.database)? {
+                0
+            } else {
+                let ec_args = estimate_capacity::Args::from(cmd_args.clone());
+                estimate_capacity::run(ec_args)?
+            };
 
             let build_args = chunk_db::Args::from(cmd_args.clone());
-            let database = &build_args.build.database.clone();
             chunk_db::run(build_args, required_capacity)?;
-            build_db::run(database)?;
+            build_db::run(build_db::Args::from(cmd_args))?;
         }
         Commands::BuildDB(cmd_args) => {
-            println!("Running: BuildDB (Building from existing library)");
+            if cmd_args.dry_run {
+                let library_bytes = find_library_fna_files(cmd_args.build.database.join("library"))
+                    .iter()
+                    .chain(&cmd_args.library_fna)
+                    .map(|file| estimated_file_bytes(file))
+                    .sum();
+                let capacity = match cmd_args.required_capacity {
+                    Some(cap) => Some(cap),
+                    None => chunk_capacity(&cmd_args.build.database)?,
+                };
+                let hash_capacity =
+                    resolve_hash_capacity(cmd_args.hash_capacity, cmd_args.build.wide_cells);
+                let plan = plan_build(
+                    &cmd_args.build,
+                    library_bytes,
+                    capacity,
+                    hash_capacity,
+                    cmd_args.load_factor,
+                    false,
+                );
+                print!("{}", plan);
+                return Ok(());
+            }
+            info!("Running: BuildDB (Building from existing library)");
+            if !cmd_args.library_fna.is_empty() || cmd_args.seqid2taxid.is_some() {
+                let (sequences, unmapped) = import_library_files(
+                    &cmd_args.build.database,
+                    &cmd_args.library_fna,
+                    cmd_args.seqid2taxid.as_deref(),
+                )?;
+                info!("{} sequences imported into the library", sequences);
+                if unmapped > 0 {
+                    warn!(
+                        "{} sequences are not in the seqid2taxid map and are skipped",
+                        unmapped
+                    );
+                }
+            }
             let required_capacity = match cmd_args.required_capacity {
                 Some(cap) => {
-                    println!("Using user-provided capacity: {}", cap);
+                    info!("Using user-provided capacity: {}", cap);
                     cap
                 }
+                None if chunk_done(&cmd_args.build.database)? => 0,
                 None => {
-                    println!("Estimating capacity...");
+                    info!("Estimating capacity...");
                     let ec_args = estimate_capacity::Args::from(cmd_args.clone());
-                    estimate_capacity::run(ec_args)
+                    estimate_capacity::run(ec_args)?
                 }
             };
 
             let build_args = chunk_db::Args::from(cmd_args.clone());
-            let database = &build_args.build.database.clone();
             chunk_db::run(build_args, required_capacity)?;
-            build_db::run(database)?;
+            build_db::run(build_db::Args::from(cmd_args))?;
         }
         Commands::Hashshard(cmd_args) => {
             hashshard::run(cmd_args)?;
         }
-        Commands::Splitr(cmd_args) => {
+        Commands::Splitr(mut cmd_args) => {
+            cmd_args.input = input;
             splitr::run(cmd_args)?;
         }
         Commands::Annotate(cmd_args) => {
@@ -273,34 +668,164 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Commands::Resolve(cmd_args) => {
             resolve::run(cmd_args)?;
         }
-        Commands::Classify(cmd_args) => {
+        Commands::Classify(mut cmd_args) => {
+            cmd_args.input = input;
+            if cmd_args.dry_run {
+                let chunk_dir = cmd_args.chunk_dir.clone().unwrap_or_else(std::env::temp_dir);
+                print!("{}", plan_classify(&cmd_args, &chunk_dir)?);
+                return Ok(());
+            }
             let start = Instant::now();
 
+            validate_manifest(&cmd_args.database)?;
+            // 未指定 --chunk-dir 时使用临时目录, 无论成功或失败, 返回时都会删除
+            let temp_chunk_dir = match cmd_args.chunk_dir {
+                Some(_) => None,
+                None => Some(TempChunkDir::create()?),
+            };
+            if let Some(temp_chunk_dir) = &temp_chunk_dir {
+                info!("chunk directory: {}", temp_chunk_dir.path().display());
+                cmd_args.chunk_dir = Some(temp_chunk_dir.path().to_path_buf());
+            }
+            let mut summary_stages = Vec::new();
             let splitr_args = splitr::Args::from(cmd_args.clone());
-            let chunk_files = find_files(&splitr_args.chunk_dir, "sample", ".k2");
-            let sample_files = find_files(&splitr_args.chunk_dir, "sample_id", ".map");
-            let bin_files = find_files(&splitr_args.chunk_dir, "sample", ".bin");
-            if !chunk_files.is_empty() || !sample_files.is_empty() || !bin_files.is_empty() {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!(
-                        "The directory '{}' must not contain files with extensions '.k2', '.map', or '.bin' for 'sample' and 'sample_id'",
+            debug!("{:?}", splitr_args);
+            std::fs::create_dir_all(&splitr_args.chunk_dir)?;
+            // 被杀掉的 classify 会留下锁文件, 据此判断分块文件是否为残留
+            let chunk_dir_lock = ChunkDirLock::acquire(&splitr_args.chunk_dir)?;
+            let checkpoint =
+                Checkpoint::load_file(splitr_args.chunk_dir.join(CLASSIFY_CHECKPOINT_FILENAME))?;
+            // 样本多于 slot 能区分的数目时, 每批样本先 splitr 再 annotate, 最后一起 resolve
+            let batches = sample_batches(&cmd_args)?;
+            let resume = cmd_args.resume && batches.len() == 1;
+            if batches.len() > 1 {
+                info!(
+                    "classifying the samples in {} batches, each split and annotated before the next",
+                    batches.len()
+                );
+                if cmd_args.resume {
+                    warn!("a run of several batches cannot be resumed, classifying all batches again");
+                }
+            }
+            let mut annotate_time = Duration::ZERO;
+            if resume && checkpoint.is_done(SPLITR_DONE) {
+                info!("splitr already completed, resuming from annotate");
+            } else {
+                let chunk_files = find_files(&splitr_args.chunk_dir, "sample", ".k2");
+                let sample_files = find_files(&splitr_args.chunk_dir, "sample_id", ".map");
+                let bin_files = find_files(&splitr_args.chunk_dir, "sample", ".bin");
+                let leftovers =
+                    !chunk_files.is_empty() || !sample_files.is_empty() || !bin_files.is_empty();
+                if leftovers && !cmd_args.resume && chunk_dir_lock.stale() {
+                    warn!(
+                        "removing the chunk files of an interrupted run in {}",
+                        splitr_args.chunk_dir.display()
+                    );
+                }
+                if cmd_args.resume || chunk_dir_lock.stale() {
+                    // splitr 未完成: 清除不完整的分块文件, 从头开始
+                    for file in find_files(&splitr_args.chunk_dir, "sample", "") {
+                        std::fs::remove_file(file)?;
+                    }
+                } else if leftovers {
+                    return Err(Box::new(std::io::Error::other(format!(
+                        "The directory '{}' must not contain files with extensions '.k2', '.map', or '.bin' for 'sample' and 'sample_id'; pass --resume to continue an interrupted run",
                         &splitr_args.chunk_dir.display()
-                    ),
-                )));
+                    ))));
+                }
+                cmd_args.resume = resume;
+                let mut splitr_time = Duration::ZERO;
+                for (batch, input_files) in batches.into_iter().enumerate() {
+                    if batch > 0 {
+                        // annotate 删除上一批的分块文件, 下一批的 slot 从新的分块文件重新编号
+                        let stage_start = Instant::now();
+                        annotate::run(annotate::Args::from(cmd_args.clone()))?;
+                        annotate_time += stage_start.elapsed();
+                        info!("batch {} split and annotated", batch);
+                    }
+                    let splitr_args = splitr::Args {
+                        input_files,
+                        ..splitr_args.clone()
+                    };
+                    check_chunk_dir_space(&splitr_args, temp_chunk_dir.is_some())?;
+                    let stage_start = Instant::now();
+                    splitr::run(splitr_args)?;
+                    splitr_time += stage_start.elapsed();
+                }
+                summary_stages.push(("splitr", splitr_time));
             }
-            splitr::run(splitr_args)?;
             let annotate_args = annotate::Args::from(cmd_args.clone());
+            debug!("{:?}", annotate_args);
+            let stage_start = Instant::now();
             annotate::run(annotate_args)?;
+            summary_stages.push(("annotate", annotate_time + stage_start.elapsed()));
             let resolve_args = resolve::Args::from(cmd_args.clone());
-            resolve::run(resolve_args)?;
+            debug!("{:?}", resolve_args);
+            let stage_start = Instant::now();
+            let counts = resolve::run(resolve_args)?;
+            summary_stages.push(("resolve", stage_start.elapsed()));
 
             let duration = start.elapsed();
-            println!("Classify took: {:?}", duration);
+            info!("Classify took: {:?}", duration);
+            if logging::human_output() {
+                let taxonomy = Taxonomy::from_file(cmd_args.database.join("taxo.k2d"))?;
+                let mut summary = RunSummary::new(&counts, &taxonomy);
+                for (stage, duration) in summary_stages {
+                    summary.add_stage(stage, duration);
+                }
+                summary.print();
+            }
         }
-        Commands::Direct(cmd_args) => {
+        Commands::Direct(mut cmd_args) => {
+            cmd_args.input = input;
+            validate_manifest(&cmd_args.database)?;
             direct::run(cmd_args)?;
         }
+        Commands::Serve(mut cmd_args) => {
+            cmd_args.input = input;
+            validate_manifest(&cmd_args.database)?;
+            serve::run(cmd_args)?;
+        }
+        Commands::Watch(cmd_args) => {
+            validate_manifest(&cmd_args.database)?;
+            watch::run(cmd_args)?;
+        }
+        Commands::Decontam(cmd_args) => {
+            decontam::run(cmd_args)?;
+        }
+        Commands::Inspect(cmd_args) => {
+            inspect::run(cmd_args)?;
+        }
+        Commands::DbStats(cmd_args) => {
+            db_stats::run(cmd_args)?;
+        }
+        Commands::Export(cmd_args) => {
+            export::run(cmd_args)?;
+        }
+        Commands::ProbeBench(cmd_args) => {
+            probe_bench::run(cmd_args)?;
+        }
+        Commands::GtdbTaxonomy(cmd_args) => {
+            gtdb_taxonomy::run(cmd_args)?;
+        }
+        Commands::Special(cmd_args) => {
+            special::run(cmd_args)?;
+        }
+        Commands::Downsample(cmd_args) => {
+            downsample::run(cmd_args)?;
+        }
+        Commands::Subset(cmd_args) => {
+            subset::run(cmd_args)?;
+        }
+        Commands::Opts(cmd_args) => {
+            opts::run(cmd_args)?;
+        }
+        Commands::Taxonomy(cmd_args) => {
+            taxonomy::run(cmd_args)?;
+        }
+        Commands::MigrateDb(cmd_args) => {
+            migrate_db::run(cmd_args)?;
+        }
     }
 
     Ok(())
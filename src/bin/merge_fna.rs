@@ -1,7 +1,11 @@
 use clap::Parser;
 use flate2::read::GzDecoder;
 use kun_peng::args::parse_size;
+use kun_peng::checkpoint::{Checkpoint, CHECKPOINT_FILENAME, MERGE_FNA_DONE};
+use kun_peng::dust::mask_fasta_record;
+use kun_peng::error::Error;
 use kun_peng::utils::{find_files, open_file};
+use log::{error, info, warn};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fs::{create_dir_all, File, OpenOptions};
@@ -25,6 +29,78 @@ pub struct Args {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// Do not mask low-complexity regions of the library sequences
+    #[arg(long = "no-masking", default_value_t = false)]
+    pub no_masking: bool,
+
+    /// Process the genome files one by one in assembly summary order, so that identical downloads give byte-identical library files
+    #[arg(long, default_value_t = false)]
+    pub deterministic: bool,
+
+    /// Only merge assemblies of these RefSeq categories (comma separated: reference, representative, na).
+    /// Default: all assemblies
+    #[arg(long, value_delimiter = ',')]
+    pub category: Vec<String>,
+
+    /// Skip metagenome-assembled genomes (excluded_from_refseq "derived from metagenome")
+    #[arg(long = "exclude-mag", default_value_t = false)]
+    pub exclude_mag: bool,
+
+    /// Skip atypical assemblies, i.e. those with any excluded_from_refseq reason (includes MAGs)
+    #[arg(long = "exclude-atypical", default_value_t = false)]
+    pub exclude_atypical: bool,
+}
+
+/// Selects the assembly_summary rows whose genomes are merged
+#[derive(Debug, Clone)]
+struct AssemblyFilter {
+    /// Accepted refseq_category prefixes, lower case; empty accepts every row
+    categories: Vec<String>,
+    /// Reject metagenome-assembled genomes
+    exclude_mag: bool,
+    /// Reject assemblies with any excluded_from_refseq reason
+    exclude_atypical: bool,
+}
+
+impl AssemblyFilter {
+    fn new(args: &Args) -> Self {
+        Self {
+            categories: args
+                .category
+                .iter()
+                .map(|c| c.trim().to_lowercase())
+                .collect(),
+            exclude_mag: args.exclude_mag,
+            exclude_atypical: args.exclude_atypical,
+        }
+    }
+
+    /// Whether an assembly_summary row passes the filter
+    fn accepts(&self, fields: &[&str]) -> bool {
+        // refseq_category 为 "reference genome", "representative genome" 或 "na"
+        let refseq_category = fields[4].to_lowercase();
+        if !self.categories.is_empty()
+            && !self
+                .categories
+                .iter()
+                .any(|c| refseq_category.starts_with(c.as_str()))
+        {
+            return false;
+        }
+
+        // excluded_from_refseq (第 21 列) 以 "; " 分隔列出原因, 旧格式没有该列
+        let excluded = fields.get(20).map_or("", |f| f.trim());
+        let excluded = if excluded.eq_ignore_ascii_case("na") {
+            ""
+        } else {
+            excluded
+        };
+        if self.exclude_atypical && !excluded.is_empty() {
+            return false;
+        }
+        !(self.exclude_mag && excluded.to_lowercase().contains("derived from metagenome"))
+    }
 }
 
 struct SizedWriter {
@@ -91,7 +167,11 @@ impl SizedWriter {
     }
 }
 
-fn parse_assembly_fna(assembly_file: &PathBuf, site: &str) -> Result<Vec<(String, String)>> {
+fn parse_assembly_fna(
+    assembly_file: &PathBuf,
+    site: &str,
+    filter: &AssemblyFilter,
+) -> Result<Vec<(String, String)>> {
     let mut gz_files = Vec::new();
     let file = open_file(&assembly_file)?;
     let reader = BufReader::new(file);
@@ -110,7 +190,7 @@ fn parse_assembly_fna(assembly_file: &PathBuf, site: &str) -> Result<Vec<(String
         if fields.len() > 19 {
             let (taxid, _, ftp_path) = (fields[5], fields[11], fields[19]);
 
-            if ftp_path == "na" {
+            if ftp_path == "na" || !filter.accepts(&fields) {
                 continue;
             }
 
@@ -131,12 +211,23 @@ fn parse_assembly_fna(assembly_file: &PathBuf, site: &str) -> Result<Vec<(String
     Ok(gz_files)
 }
 
+/// 写入一个完整的 FASTA 记录, 可选地屏蔽低复杂度区域
+fn write_record(fna_writer: &mut SizedWriter, record: String, masking: bool) -> Result<()> {
+    let mut record = record.into_bytes();
+    if masking {
+        mask_fasta_record(&mut record);
+    }
+    fna_writer.write(&record)?;
+    Ok(())
+}
+
 fn process_gz_file(
     gz_file: &PathBuf,
     map_writer: &mut BufWriter<File>,
     fna_writer: &mut SizedWriter,
     fna_start: &regex::Regex,
     taxid: &str,
+    masking: bool,
 ) -> Result<()> {
     let file = open_file(gz_file)?;
     let decompressor = GzDecoder::new(BufReader::new(file));
@@ -155,8 +246,7 @@ fn process_gz_file(
             // SizedWriter 现在会接收一个以 ">taxid" 开头的完整记录，
             // 它的文件分割逻辑可以正确运行了。
             if !fna_buffer.is_empty() {
-                fna_writer.write(fna_buffer.as_bytes())?;
-                fna_buffer.clear();
+                write_record(fna_writer, std::mem::take(&mut fna_buffer), masking)?;
             }
 
             // 2. 为 *新* 记录处理 map 和 fna 头
@@ -179,7 +269,7 @@ fn process_gz_file(
 
     // After the loop ends, don't forget to write out the last accumulated FASTA record
     if !fna_buffer.is_empty() {
-        fna_writer.write(fna_buffer.as_bytes())?;
+        write_record(fna_writer, fna_buffer, masking)?;
     }
 
     // Flush once at the end of the function
@@ -197,6 +287,9 @@ fn merge_fna_parallel(
     database: &PathBuf,
     library_dir: &PathBuf,
     max_file_size: u64,
+    masking: bool,
+    deterministic: bool,
+    filter: &AssemblyFilter,
 ) -> Result<()> {
     let pattern = format!(r"{}_(\S+)\.{}", PREFIX, SUFFIX);
     let file_site = regex::Regex::new(&pattern).unwrap();
@@ -208,16 +301,20 @@ fn merge_fna_parallel(
     for assembly_file in assembly_files {
         if let Some(caps) = file_site.captures(assembly_file.to_string_lossy().as_ref()) {
             if let Some(matched) = caps.get(1) {
-                let gz_files = parse_assembly_fna(assembly_file, matched.as_str())?;
+                let gz_files = parse_assembly_fna(assembly_file, matched.as_str(), filter)?;
 
-                gz_files.par_iter().for_each(|(gz_path, taxid)| {
+                let process = |(gz_path, taxid): &(String, String)| {
                     let gz_file = PathBuf::from(&gz_path);
                     if !gz_file.exists() {
                         // eprintln!("{} does not exist", gz_file.to_string_lossy());
                         return;
                     }
 
-                    let thread_index = rayon::current_thread_index().unwrap_or(0);
+                    let thread_index = if deterministic {
+                        0
+                    } else {
+                        rayon::current_thread_index().unwrap_or(0)
+                    };
                     let mut writers = writers.lock().unwrap();
                     let mut fna_writer = writers.entry(thread_index).or_insert_with(|| {
                         SizedWriter::new(&library_dir, thread_index, max_file_size).unwrap()
@@ -239,14 +336,20 @@ fn merge_fna_parallel(
                         &mut fna_writer,
                         &fna_start,
                         &taxid,
+                        masking,
                     ) {
-                        eprintln!("process_gz_file error: {}", e);
+                        error!("process_gz_file error: {}", e);
                     } else {
                         fna_writer.flush().unwrap();
                         map_writer.flush().unwrap();
                         is_empty.fetch_and(false, Ordering::Relaxed);
                     }
-                });
+                };
+                if deterministic {
+                    gz_files.iter().for_each(process);
+                } else {
+                    gz_files.par_iter().for_each(process);
+                }
             }
         }
     }
@@ -255,7 +358,10 @@ fn merge_fna_parallel(
     let seqid2taxid_path = database.join("seqid2taxid.map");
     merge_files(&seqid_files, &seqid2taxid_path)?;
     if is_empty.load(Ordering::Relaxed) {
-        panic!("genimics fna files is empty! please check download dir");
+        return Err(Error::InvalidInput(
+            "no genomic .fna files were merged; check that the download directory holds the genomes of its assembly_summary files".to_string(),
+        )
+        .into());
     }
     Ok(())
 }
@@ -291,7 +397,8 @@ fn merge_files(paths: &Vec<PathBuf>, output_path: &PathBuf) -> Result<()> {
 pub fn run(args: Args) -> Result<()> {
     // 开始计时
     let start = Instant::now();
-    println!("merge fna start...");
+    info!("merge fna start...");
+    let filter = AssemblyFilter::new(&args);
     let download_dir = args.download_dir;
     let database = &args.database;
     let max_file_size = &args.max_file_size;
@@ -303,25 +410,60 @@ pub fn run(args: Args) -> Result<()> {
     // create_dir_all(&library_dir)?;
 
     let source_names_file = &download_dir.join("taxonomy").join("names.dmp");
-    assert!(source_names_file.exists());
+    if !source_names_file.exists() {
+        return Err(Error::InvalidInput(format!(
+            "{:?} not found; download the taxonomy into the download directory first",
+            source_names_file
+        ))
+        .into());
+    }
     let dst_name_file = &dst_tax_dir.join("names.dmp");
     if !dst_name_file.exists() {
         std::fs::copy(source_names_file, dst_name_file)?;
     }
 
     let source_nodes_file = &download_dir.join("taxonomy").join("nodes.dmp");
-    assert!(source_nodes_file.exists());
+    if !source_nodes_file.exists() {
+        return Err(Error::InvalidInput(format!(
+            "{:?} not found; download the taxonomy into the download directory first",
+            source_nodes_file
+        ))
+        .into());
+    }
     let dst_nodes_file = &dst_tax_dir.join("nodes.dmp");
     if !dst_nodes_file.exists() {
         std::fs::copy(source_nodes_file, dst_nodes_file)?;
     }
 
+    // 生成 taxonomy 时用于重映射 seqid2taxid.map 中已合并或已删除的 taxid
+    for filename in ["merged.dmp", "delnodes.dmp"] {
+        let source_file = download_dir.join("taxonomy").join(filename);
+        let dst_file = dst_tax_dir.join(filename);
+        if dst_file.exists() {
+            continue;
+        }
+        if source_file.exists() {
+            std::fs::copy(&source_file, &dst_file)?;
+        } else {
+            warn!(
+                "{:?} not found; taxids that NCBI has since merged or deleted are not remapped",
+                source_file
+            );
+        }
+    }
+
+    let mut checkpoint = Checkpoint::load(database)?;
+    if checkpoint.is_done(MERGE_FNA_DONE) {
+        info!("merge fna already completed, skipping (checkpoint {})", CHECKPOINT_FILENAME);
+        return Ok(());
+    }
+
     let seqid2taxid_path = database.join("seqid2taxid.map");
     if seqid2taxid_path.exists() {
         if let Ok(mut entries) = std::fs::read_dir(&library_dir) {
             if entries.next().is_some() {
                 // 如果 library 目录至少有一个文件，我们就认为构建已完成
-                println!("Build appears complete (seqid2taxid.map, taxo.k2d, and library files exist). Skipping.");
+                info!("Build appears complete (seqid2taxid.map, taxo.k2d, and library files exist). Skipping.");
                 return Ok(());
             }
         }
@@ -342,11 +484,15 @@ pub fn run(args: Args) -> Result<()> {
         &args.database,
         &library_dir,
         *max_file_size as u64,
+        !args.no_masking,
+        args.deterministic,
+        &filter,
     )?;
+    checkpoint.mark(MERGE_FNA_DONE, "")?;
 
     // 计算持续时间
     let duration = start.elapsed();
-    println!("merge fna took: {:?}", duration);
+    info!("merge fna took: {:?}", duration);
     Ok(())
 }
 
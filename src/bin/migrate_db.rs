@@ -0,0 +1,66 @@
+use clap::Parser;
+use kun_peng::format::{database_versions, migrate_database};
+use log::info;
+use std::io::Result;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Upgrade the files of a database built by an older kun_peng in place",
+    long_about = "Rewrite taxo.k2d and opts.k2d of a database in the formats of this kun_peng, e.g. of a database built by an older kun_peng or converted from Kraken 2.
+kun_peng reads the files of older versions as they are, so the migration is optional.
+Every file is written next to the old one and renamed over it, so an interrupted migration can simply be run again. The hash pages are not rewritten.
+Databases of a newer kun_peng cannot be migrated back; use that kun_peng or a newer one."
+)]
+pub struct Args {
+    /// database directory holding taxo.k2d, opts.k2d and hash_config.k2d
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Only print the format versions of the files and what would be upgraded
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    if args.dry_run {
+        for version in database_versions(&args.database)? {
+            let state = if version.is_older() {
+                "upgrade"
+            } else if version.is_newer() {
+                "newer"
+            } else {
+                "current"
+            };
+            println!(
+                "{}\t{}\t{}\t{}",
+                version.path.display(),
+                version.found,
+                version.supported,
+                state
+            );
+        }
+        return Ok(());
+    }
+
+    let upgraded = migrate_database(&args.database)?;
+    if upgraded.is_empty() {
+        info!("{:?} is up to date, nothing to migrate", args.database);
+    }
+    for version in upgraded {
+        info!(
+            "{:?} upgraded from format version {} to {}",
+            version.path, version.found, version.supported
+        );
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,150 @@
+use clap::Parser;
+use kun_peng::args::KLMTArgs;
+use kun_peng::manifest::{DbManifest, MANIFEST_FILENAME};
+use kun_peng::IndexOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Print or regenerate the minimizer options (opts.k2d) of a database",
+    long_about = "Print k, l, the spaced seed mask and the toggle mask stored in opts.k2d.
+With --write, opts.k2d is generated from the KLMT options instead, e.g. for a database directory assembled by hand.
+The options must be the ones the hash tables were built with, otherwise classification finds no hits."
+)]
+pub struct Args {
+    /// database directory holding opts.k2d
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Write opts.k2d from the KLMT options below
+    #[clap(long, default_value_t = false)]
+    pub write: bool,
+
+    /// With --write, replace an existing opts.k2d
+    #[clap(long, default_value_t = false, requires = "write")]
+    pub force: bool,
+
+    #[clap(flatten)]
+    pub klmt: KLMTArgs,
+}
+
+/// Seed template of an expanded spaced seed mask, '0' marking the ignored minimizer positions
+fn seed_template(spaced_seed_mask: u64, l: usize) -> String {
+    (0..l.min(32))
+        .rev()
+        .map(|i| {
+            if (spaced_seed_mask >> (2 * i)) & 3 == 3 {
+                '1'
+            } else {
+                '0'
+            }
+        })
+        .collect()
+}
+
+fn print_index_options(idx_opts: &IndexOptions) {
+    println!("k: {}", idx_opts.k);
+    println!("l: {}", idx_opts.l);
+    if idx_opts.spaced_seed_mask == 0 {
+        println!("spaced seed mask: none (0 minimizer spaces)");
+    } else {
+        let template = seed_template(idx_opts.spaced_seed_mask, idx_opts.l);
+        println!(
+            "spaced seed mask: {:#018x} (template {}, {} minimizer spaces)",
+            idx_opts.spaced_seed_mask,
+            template,
+            template.matches('0').count()
+        );
+    }
+    println!("toggle mask: {:#018x}", idx_opts.toggle_mask);
+    println!("dna db: {}", idx_opts.dna_db);
+    println!(
+        "minimum acceptable hash value: {}",
+        idx_opts.minimum_acceptable_hash_value
+    );
+    println!("revcom version: {}", idx_opts.revcom_version);
+    println!("db version: {}", idx_opts.db_version);
+    println!("db type: {}", idx_opts.db_type);
+}
+
+/// Checks the options against those recorded in the database manifest
+fn check_manifest(manifest: &DbManifest, idx_opts: &IndexOptions) -> Result<()> {
+    let mut mismatches = Vec::new();
+    let mut check = |name: &str, expected: u64, found: u64| {
+        if expected != found {
+            mismatches.push(format!("{}: manifest {}, given {}", name, expected, found));
+        }
+    };
+    check("k", manifest.k as u64, idx_opts.k as u64);
+    check("l", manifest.l as u64, idx_opts.l as u64);
+    check(
+        "spaced_seed_mask",
+        manifest.spaced_seed_mask,
+        idx_opts.spaced_seed_mask,
+    );
+    check("toggle_mask", manifest.toggle_mask, idx_opts.toggle_mask);
+    check(
+        "minimum_acceptable_hash_value",
+        manifest.minimum_acceptable_hash_value,
+        idx_opts.minimum_acceptable_hash_value,
+    );
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "the options differ from those in {}: {}",
+                MANIFEST_FILENAME,
+                mismatches.join("; ")
+            ),
+        ))
+    }
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let options_filename = args.database.join("opts.k2d");
+    if !args.write {
+        if !options_filename.exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{:?} does not exist, use --write to generate it",
+                    options_filename
+                ),
+            ));
+        }
+        let idx_opts = IndexOptions::read_index_options(&options_filename)?;
+        print_index_options(&idx_opts);
+        return Ok(());
+    }
+
+    if options_filename.exists() && !args.force {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "{:?} already exists, use --force to replace it",
+                options_filename
+            ),
+        ));
+    }
+    let idx_opts = IndexOptions::from_meros(args.klmt.as_meros()?);
+    // 数据库有 manifest 时, 参数必须与构建时一致
+    if args.database.join(MANIFEST_FILENAME).exists() {
+        check_manifest(&DbManifest::from_file(&args.database)?, &idx_opts)?;
+    }
+    idx_opts.write_to_file(&options_filename)?;
+    println!("wrote {:?}", options_filename);
+    print_index_options(&idx_opts);
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,273 @@
+use clap::Parser;
+use kun_peng::compact_hash::{
+    probe_step, read_page_from_file, Compact, HashConfig, Page, WIDE_SAMPLE_VALUE_BITS,
+};
+use kun_peng::utils::find_and_sort_files;
+use std::hint::black_box;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Compare the probe lengths of linear probing and double hashing on a database",
+    long_about = "Load sampled hash pages of a database and measure how many cells a lookup probes.
+For each page the keys it stores are inserted again at random home cells into a page of the same capacity, once probing linearly and once by double hashing, and looked up as hits and as misses.
+The 'database' row probes the page as built, with random keys that miss; its hits cannot be measured as the home cells of the stored keys are not known."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Number of hash pages to sample, spread evenly over the database
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub pages: u32,
+
+    /// Lookups per page and probing scheme
+    #[clap(long, default_value_t = 1_000_000)]
+    pub lookups: usize,
+}
+
+/// SplitMix64, the random home cells and keys of the benchmark
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Probe lengths and lookup time of one kind of lookup
+#[derive(Debug, Default)]
+struct ProbeStats {
+    lookups: usize,
+    probes: usize,
+    max_probes: usize,
+    nanos: u128,
+}
+
+impl ProbeStats {
+    fn mean(&self) -> f64 {
+        self.probes as f64 / self.lookups.max(1) as f64
+    }
+
+    fn nanos_per_lookup(&self) -> f64 {
+        self.nanos as f64 / self.lookups.max(1) as f64
+    }
+}
+
+/// How the lookups of the benchmark address a page
+struct Lookup {
+    wide: bool,
+    value_bits: usize,
+    value_mask: usize,
+}
+
+impl Lookup {
+    /// The key of a cell as it is looked up, the whole 32-bit key for wide cells
+    fn stored_keys(&self, page: &Page) -> Vec<u32> {
+        if self.wide {
+            (0..page.size)
+                .filter(|&idx| page.data[idx] != 0)
+                .map(|idx| page.keys[idx])
+                .collect()
+        } else {
+            page.data[..page.size]
+                .iter()
+                .filter(|cell| cell.right(self.value_mask) != 0)
+                .map(|cell| cell.left(self.value_bits))
+                .collect()
+        }
+    }
+
+    /// A random key as it is looked up
+    fn random_key(&self, rng: &mut SplitMix64) -> u32 {
+        let key = (rng.next() >> 32) as u32;
+        if self.wide {
+            key
+        } else {
+            key >> self.value_bits
+        }
+    }
+
+    /// Probes `queries` in `page` and times them
+    fn run(&self, page: &Page, queries: &[(usize, u32)]) -> ProbeStats {
+        let mut stats = ProbeStats {
+            lookups: queries.len(),
+            ..Default::default()
+        };
+        for &(index, key) in queries {
+            let (_, probes) = page.probe(index, key, self.value_bits, self.value_mask);
+            stats.probes += probes;
+            stats.max_probes = stats.max_probes.max(probes);
+        }
+        let start = Instant::now();
+        for &(index, key) in queries {
+            black_box(page.find_index(index, key, self.value_bits, self.value_mask));
+        }
+        stats.nanos = start.elapsed().as_nanos();
+        stats
+    }
+
+    /// Inserts `keys` at random home cells into a new page of `capacity` cells
+    ///
+    /// # Returns
+    ///
+    /// The page and the home cell and key of every inserted key
+    fn simulate(
+        &self,
+        keys: &[u32],
+        capacity: usize,
+        double_hashing: bool,
+        rng: &mut SplitMix64,
+    ) -> (Page, Vec<(usize, u32)>) {
+        let mut cells: Vec<Option<u32>> = vec![None; capacity];
+        let mut inserted = Vec::with_capacity(keys.len());
+        for &key in keys {
+            let home = rng.next() as usize % capacity;
+            let step = if !double_hashing {
+                1
+            } else if self.wide {
+                probe_step(key >> WIDE_SAMPLE_VALUE_BITS, capacity)
+            } else {
+                probe_step(key, capacity)
+            };
+            let mut idx = home;
+            for _ in 0..capacity {
+                match cells[idx] {
+                    None => {
+                        cells[idx] = Some(key);
+                        inserted.push((home, key));
+                        break;
+                    }
+                    Some(stored) if stored == key => break,
+                    Some(_) => {}
+                }
+                idx = (idx + step) % capacity;
+            }
+        }
+
+        // 单元中的 taxid 统一为 1
+        let mut page = if self.wide {
+            let data = cells.iter().map(|cell| cell.is_some() as u32).collect();
+            let keys = cells.iter().map(|cell| cell.unwrap_or(0)).collect();
+            Page::new_wide(0, capacity, data, keys)
+        } else {
+            let data = cells
+                .iter()
+                .map(|cell| cell.map_or(0, |key| u32::combined(key, 1, self.value_bits)))
+                .collect();
+            Page::new(0, capacity, data)
+        };
+        page.double_hashing = double_hashing;
+        if !double_hashing {
+            // 线性探测越过页尾时接着查页首, 同加载数据库时的溢出块
+            let run = page.data.iter().take_while(|&&cell| cell != 0).count();
+            let run = (run + 1).min(capacity);
+            let overflow = if self.wide {
+                Page::new_wide(0, run, page.data[..run].to_vec(), page.keys[..run].to_vec())
+            } else {
+                Page::new(0, run, page.data[..run].to_vec())
+            };
+            page.merge(overflow);
+        }
+        (page, inserted)
+    }
+}
+
+fn print_row(scheme: &str, hits: Option<&ProbeStats>, misses: &ProbeStats) {
+    let hits = hits.map_or("-\t-\t-".to_string(), |hits| {
+        format!(
+            "{:.3}\t{}\t{:.1}",
+            hits.mean(),
+            hits.max_probes,
+            hits.nanos_per_lookup()
+        )
+    });
+    println!(
+        "{}\t{}\t{:.3}\t{}\t{:.1}",
+        scheme,
+        hits,
+        misses.mean(),
+        misses.max_probes,
+        misses.nanos_per_lookup()
+    );
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let hash_files = find_and_sort_files(&args.database, "hash", hash_config.page_suffix(), true)?;
+    if hash_files.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no hash pages in {:?}", args.database),
+        ));
+    }
+    let lookup = Lookup {
+        wide: hash_config.is_wide(),
+        // `direct` 查宽 cell 时比较整个 32 位 key
+        value_bits: if hash_config.is_wide() {
+            0
+        } else {
+            hash_config.value_bits
+        },
+        value_mask: hash_config.value_mask,
+    };
+    let database_scheme = if hash_config.is_double_hashing() {
+        "database (double hashing)"
+    } else {
+        "database (linear)"
+    };
+
+    let mut rng = SplitMix64(0x4B756E2D70656E67);
+    let pages = (args.pages as usize).min(hash_files.len());
+    for sample in 0..pages {
+        let page_index = sample * hash_files.len() / pages;
+        let hash_file = &hash_files[page_index];
+        let mut page = read_page_from_file(hash_file)?;
+        page.double_hashing = hash_config.is_double_hashing();
+        let capacity = page.size;
+        let keys = lookup.stored_keys(&page);
+        println!(
+            "# {:?}: {} cells, {} keys, load {:.3}",
+            hash_file,
+            capacity,
+            keys.len(),
+            keys.len() as f64 / capacity.max(1) as f64
+        );
+        println!("scheme\thit probes\tmax\tns\tmiss probes\tmax\tns");
+        if capacity == 0 {
+            continue;
+        }
+
+        let misses: Vec<(usize, u32)> = (0..args.lookups)
+            .map(|_| (rng.next() as usize % capacity, lookup.random_key(&mut rng)))
+            .collect();
+        // 读入的页不含溢出块, 越过页尾的线性探测在页尾结束
+        print_row(database_scheme, None, &lookup.run(&page, &misses));
+        drop(page);
+
+        for (scheme, double_hashing) in [("linear", false), ("double hashing", true)] {
+            let (page, inserted) = lookup.simulate(&keys, capacity, double_hashing, &mut rng);
+            let stride = inserted.len().div_ceil(args.lookups.max(1)).max(1);
+            let hits: Vec<(usize, u32)> = inserted.iter().step_by(stride).copied().collect();
+            let hit_stats = lookup.run(&page, &hits);
+            print_row(scheme, Some(&hit_stats), &lookup.run(&page, &misses));
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
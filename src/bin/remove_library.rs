@@ -0,0 +1,313 @@
+use clap::Parser;
+use kun_peng::compact_hash::{read_page_from_file, HashConfig};
+use kun_peng::db::{
+    collect_partitions, convert_fna_to_k2_format, process_k2file, DEFAULT_MAX_PAGE_LOAD,
+    LIBRARY_PROVENANCE_FILENAME,
+};
+use kun_peng::manifest::DbManifest;
+use kun_peng::prefilter::{prefilter_path, write_prefilter};
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::{find_files, read_id_to_taxon_map};
+use kun_peng::IndexOptions;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Remove genomes from an existing Kun-Peng database library",
+    long_about = "Remove sequences from the library by accession, taxid or the md5 hash of the file they were added from.
+seqid2taxid.map is updated and only the hash pages that held minimizers of the removed sequences are rebuilt.
+taxo.k2d is kept unchanged, so the remaining hash pages stay valid."
+)]
+pub struct Args {
+    /// Main database directory (must contain existing library/ dir)
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Accessions of the sequences to remove, with or without version
+    #[arg(long, num_args = 1..)]
+    pub accession: Vec<String>,
+
+    /// Remove all sequences assigned to these taxids
+    #[arg(long, num_args = 1..)]
+    pub taxid: Vec<u64>,
+
+    /// Remove all sequences of a file added by add-library, given its md5 hash from library/added.md5
+    #[arg(long = "file-hash", num_args = 1..)]
+    pub file_hash: Vec<String>,
+
+    /// Only update the library, the hash tables have to be rebuilt with build-db
+    #[arg(long = "no-rebuild", default_value_t = false)]
+    pub no_rebuild: bool,
+
+    /// Number of worker threads, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = num_cpus::get())]
+    pub threads: usize,
+}
+
+/// Check whether a seqid such as `taxid|562|NC_000913.3` belongs to an accession
+fn matches_accession(seq_id: &str, accessions: &HashSet<String>) -> bool {
+    let accession = seq_id.rsplit('|').next().unwrap_or(seq_id);
+    let unversioned = accession.split('.').next().unwrap_or(accession);
+    accessions.contains(seq_id)
+        || accessions.contains(accession)
+        || accessions.contains(unversioned)
+}
+
+/// Seqids recorded for the given file hashes
+fn read_provenance(path: &Path, hashes: &HashSet<String>) -> Result<HashSet<String>> {
+    let mut seq_ids = HashSet::new();
+    if hashes.is_empty() || !path.exists() {
+        return Ok(seq_ids);
+    }
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Some((hash, seq_id)) = line.split_once('\t') {
+            if hashes.contains(hash) {
+                seq_ids.insert(seq_id.to_string());
+            }
+        }
+    }
+    Ok(seq_ids)
+}
+
+/// Rewrite a tab separated file, keeping the lines for which `keep` returns true
+fn filter_lines<F: Fn(&str) -> bool>(path: &Path, keep: F) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if keep(&line) {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writer.flush()?;
+    fs::rename(tmp_path, path)
+}
+
+/// Write the records of a library file that are not removed to `<file>.tmp`
+///
+/// # Returns
+///
+/// Whether any record was removed
+fn filter_library_file(path: &Path, removed: &HashMap<String, u64>) -> Result<bool> {
+    let tmp_path = path.with_extension("fna.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut changed = false;
+    let mut keep = true;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            let seq_id = header.split_whitespace().next().unwrap_or("");
+            keep = !removed.contains_key(seq_id);
+            changed |= !keep;
+        }
+        if keep {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writer.flush()?;
+    Ok(changed)
+}
+
+/// Rebuild the given hash pages (0-based partitions) from the remaining library
+fn rebuild_pages(
+    args: &Args,
+    hash_config: &mut HashConfig,
+    partitions: &[usize],
+    id_to_taxon_map: &HashMap<String, u64>,
+    library_files: &[PathBuf],
+) -> Result<()> {
+    let k2d_dir = &args.database;
+    let taxonomy = Taxonomy::from_file(k2d_dir.join("taxo.k2d"))?;
+    let meros = IndexOptions::read_index_options(k2d_dir.join("opts.k2d"))?.as_meros();
+    let chunk_size = hash_config.hash_capacity;
+
+    let mut writers: Vec<Option<BufWriter<File>>> =
+        (0..hash_config.partition).map(|_| None).collect();
+    for &partition in partitions {
+        let chunk_file = k2d_dir.join(format!("chunk_{}.k2", partition + 1));
+        writers[partition] = Some(BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(chunk_file)?,
+        ));
+    }
+    for library_file in library_files {
+        info!("convert fna file {:?}", library_file);
+        convert_fna_to_k2_format(
+            library_file,
+            meros,
+            &taxonomy,
+            id_to_taxon_map,
+            *hash_config,
+            &mut writers,
+            chunk_size,
+            args.threads,
+        );
+    }
+    for writer in writers.iter_mut().flatten() {
+        writer.flush()?;
+    }
+    drop(writers);
+
+    for &partition in partitions {
+        let page_index = partition + 1;
+        let chunk_file = k2d_dir.join(format!("chunk_{}.k2", page_index));
+        let hash_file = k2d_dir.join(format!("hash_{}.k2d", page_index));
+        let old_count = read_page_from_file(&hash_file)?
+            .data
+            .iter()
+            .filter(|&&cell| cell != 0)
+            .count();
+        // 重建的页没有 prefilter, 原来有的话一并重建
+        let had_prefilter = prefilter_path(&hash_file).exists();
+        let new_count = process_k2file(
+            *hash_config,
+            k2d_dir,
+            &chunk_file,
+            &taxonomy,
+            page_index,
+            false,
+            DEFAULT_MAX_PAGE_LOAD,
+            false,
+        )?;
+        if had_prefilter {
+            write_prefilter(hash_config, &chunk_file, &hash_file, new_count)?;
+        }
+        hash_config.size = hash_config.size + new_count - old_count;
+        fs::remove_file(chunk_file)?;
+        info!("rebuilt hash page {}", page_index);
+    }
+    hash_config.write_to_file(k2d_dir.join("hash_config.k2d"))?;
+    DbManifest::update(k2d_dir, &k2d_dir.join("library"))?;
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = &args.database;
+    let library_dir = database.join("library");
+    let map_path = database.join("seqid2taxid.map");
+    let provenance_path = library_dir.join(LIBRARY_PROVENANCE_FILENAME);
+
+    let id_to_taxon_map = read_id_to_taxon_map(&map_path)?;
+    let accessions: HashSet<String> = args.accession.iter().cloned().collect();
+    let taxids: HashSet<u64> = args.taxid.iter().copied().collect();
+    let file_hashes: HashSet<String> = args.file_hash.iter().cloned().collect();
+    let file_seq_ids = read_provenance(&provenance_path, &file_hashes)?;
+
+    let removed: HashMap<String, u64> = id_to_taxon_map
+        .iter()
+        .filter(|(seq_id, taxid)| {
+            taxids.contains(taxid)
+                || file_seq_ids.contains(seq_id.as_str())
+                || matches_accession(seq_id, &accessions)
+        })
+        .map(|(seq_id, taxid)| (seq_id.clone(), *taxid))
+        .collect();
+    if removed.is_empty() {
+        info!("No matching sequences found in seqid2taxid.map. Nothing to remove.");
+        return Ok(());
+    }
+    info!("Removing {} sequences...", removed.len());
+
+    let hash_config_path = database.join("hash_config.k2d");
+    let mut hash_config = if !args.no_rebuild && hash_config_path.exists() {
+        let hash_config = HashConfig::from_hash_header(&hash_config_path)?;
+        if hash_config.version < 1 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "hash tables converted from Kraken 2 can not be rebuilt, use --no-rebuild",
+            ));
+        }
+        if hash_config.is_compressed() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "compressed hash tables can not be rebuilt page by page, use --no-rebuild",
+            ));
+        }
+        Some(hash_config)
+    } else {
+        None
+    };
+
+    // partitions holding minimizers of the removed sequences, scanned before they are dropped
+    let library_files = find_files(&library_dir, "library", ".fna");
+    let meros = match &hash_config {
+        Some(_) => Some(IndexOptions::read_index_options(database.join("opts.k2d"))?.as_meros()),
+        None => None,
+    };
+    let mut partitions = HashSet::new();
+    for library_file in &library_files {
+        let tmp_path = library_file.with_extension("fna.tmp");
+        if filter_library_file(library_file, &removed)? {
+            if let (Some(hash_config), Some(meros)) = (&hash_config, meros) {
+                partitions.extend(collect_partitions(
+                    library_file,
+                    meros,
+                    &removed,
+                    *hash_config,
+                    hash_config.hash_capacity,
+                    args.threads,
+                ));
+            }
+            fs::rename(tmp_path, library_file)?;
+            info!("removed sequences from {:?}", library_file);
+        } else {
+            fs::remove_file(tmp_path)?;
+        }
+    }
+
+    let seq_id_of = |line: &str| line.split_whitespace().next().unwrap_or("").to_string();
+    filter_lines(&map_path, |line| !removed.contains_key(&seq_id_of(line)))?;
+    filter_lines(&provenance_path, |line| {
+        !removed.contains_key(line.split('\t').nth(1).unwrap_or(""))
+    })?;
+    filter_lines(&library_dir.join("added.md5"), |line| {
+        !file_hashes.contains(line.split('\t').nth(1).unwrap_or(""))
+    })?;
+
+    match hash_config.as_mut() {
+        Some(hash_config) => {
+            let mut partitions: Vec<usize> = partitions.into_iter().collect();
+            partitions.sort_unstable();
+            info!(
+                "Rebuilding {} of {} hash pages...",
+                partitions.len(),
+                hash_config.partition
+            );
+            let remaining: HashMap<String, u64> = id_to_taxon_map
+                .into_iter()
+                .filter(|(seq_id, _)| !removed.contains_key(seq_id))
+                .collect();
+            rebuild_pages(&args, hash_config, &partitions, &remaining, &library_files)?;
+        }
+        None => {
+            if hash_config_path.exists() {
+                warn!("Hash tables are now out-of-date, run 'build-db' to rebuild them.");
+            }
+        }
+    }
+
+    info!("remove library took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
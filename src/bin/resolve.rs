@@ -1,15 +1,33 @@
 use clap::Parser;
-use kun_peng::classify::process_hitgroup;
+use kun_peng::anonymize::IdMap;
+use kun_peng::args::Partition;
+use kun_peng::checkpoint::{
+    remove_sentinel, write_sentinel, Checkpoint, CLASSIFY_CHECKPOINT_FILENAME, RESOLVE_DONE,
+    SENTINEL_SUFFIX,
+};
+use kun_peng::classifier::ClassifySummary;
+use kun_peng::classify::{detect_chimera, format_chimera, process_hitgroup, refine_strain};
 use kun_peng::compact_hash::{HashConfig, Row};
+use kun_peng::error::Error;
+use kun_peng::footer::{damaged, open_with_footer};
+use kun_peng::progress::Progress;
 use kun_peng::readcounts::{TaxonCounters, TaxonCountersDash};
-use kun_peng::report::report_kraken_style;
+use kun_peng::remote::{check_uploads, copy_to_output, create_output, is_remote};
+use kun_peng::report::{
+    read_pseudo_taxa_expectations, report_kraken_style, report_pseudo_taxa, sample_dir_names,
+    write_sample_summary, SampleOutputs, SampleSummary, COMBINED_REPORT_FILENAME,
+    SAMPLE_SUMMARY_FILENAME,
+};
 use kun_peng::taxonomy::Taxonomy;
-use kun_peng::utils::{find_and_trans_bin_files, find_and_trans_files, open_file};
+use kun_peng::utils::{
+    find_and_trans_bin_files, find_and_trans_files, find_files, open_file, pipeline_threads,
+};
 use kun_peng::HitGroup;
+use log::{info, warn};
 // use rayon::prelude::*;
 use seqkmer::{buffer_map_parallel, trim_pair_info, OptionPair};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::create_dir_all;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Result, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -62,16 +80,19 @@ pub struct Args {
     pub chunk_dir: PathBuf,
 
     /// File path for outputting normal Kraken output.
+    /// s3:// and gs:// URLs are uploaded with the aws or gcloud CLI as the files are written.
     #[clap(long = "output-dir", value_parser)]
     pub output_dir: Option<PathBuf>,
 
-    /// The number of threads to use.
+    /// The number of worker threads, capped by the global --threads.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
-    // /// output file contains all unclassified sequence
-    // #[clap(long, value_parser, default_value_t = false)]
-    // pub full_output: bool,
+    /// Write a line for every read to the output, also a U line for each read without any hit.
+    /// Reads without a hit are left out of the sample bin files, and by default out of the output
+    #[clap(long, value_parser, default_value_t = false)]
+    pub full_output: bool,
+
     /// Confidence score threshold, default is 0.0.
     #[clap(
         short = 'T',
@@ -97,103 +118,398 @@ pub struct Args {
         default_value_t = 2
     )]
     pub minimum_hit_groups: usize,
+
+    /// Refine species-level calls to the best supported strain.
+    /// Appends the strain taxid and its support fraction as two extra output columns.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub strain_refinement: bool,
+
+    /// Window size in k-mer positions for chimera detection on long reads.
+    /// Reads whose windows resolve to incompatible taxa are written to chimera_{i}.txt in the output directory.
+    #[clap(long, value_parser)]
+    pub chimera_window: Option<usize>,
+
+    /// Stride between chimera detection windows, default is half the window size.
+    #[clap(long, value_parser)]
+    pub chimera_stride: Option<usize>,
+
+    /// Resume an interrupted run, skipping the partitions whose output files it has written.
+    /// Needs --output-dir
+    #[clap(long, value_parser, default_value_t = false, requires = "output_dir")]
+    pub resume: bool,
+
+    /// Print the number of resolved samples with an estimated time left to stderr
+    #[clap(long, value_parser, default_value_t = false)]
+    pub progress: bool,
+
+    /// Write the outputs of every sample into a directory named after its input files
+    /// (<sample>/output.txt, <sample>/report.kreport2) with a summary.tsv of all samples,
+    /// instead of output_{i} files. Needs --output-dir
+    #[clap(long, value_parser, default_value_t = false, requires = "output_dir")]
+    pub sample_dirs: bool,
+
+    /// Replace the read IDs of the per-read outputs with salted hashes and append the hashes
+    /// and read IDs to this mapping file, created readable by its owner only. Keep the file
+    /// private to share the outputs without the read names
+    #[clap(long, value_parser)]
+    pub anonymize_ids: Option<PathBuf>,
+
+    /// Resolve only the samples in partition i of N (e.g. 2/8), one job of an array.
+    /// Writes the outputs of each sample; run `resolve --merge-partitions` afterwards for the combined report
+    #[clap(long, value_parser, conflicts_with = "merge_partitions")]
+    pub partition: Option<Partition>,
+
+    /// Write the combined report (and summary.tsv with --sample-dirs) from the sample reports
+    /// of `resolve --partition` jobs instead of resolving. Needs --output-dir
+    #[clap(long, value_parser, default_value_t = false, requires = "output_dir")]
+    pub merge_partitions: bool,
+}
+
+/// Reads the input files of every sample from sample_file.map
+fn read_sample_files<P: AsRef<Path>>(filename: P) -> Result<BTreeMap<usize, String>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut samples = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((index, files)) = line.trim().split_once('\t') {
+            if let Ok(index) = index.parse::<usize>() {
+                samples.insert(index, files.to_string());
+            }
+        }
+    }
+    Ok(samples)
 }
 
-fn read_rows_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<HashMap<u32, Vec<Row>>> {
-    let file = File::open(file_path)?;
-    let mut reader = BufReader::new(file);
-    let mut buffer = [0u8; std::mem::size_of::<Row>()]; // 确保buffer的大小与Row结构体的大小一致
+/// Reads the rows of sample bin files, checked against the footers annotate wrote
+fn read_rows_from_files(file_paths: &[&Path]) -> io::Result<HashMap<u32, Vec<Row>>> {
+    let mut buffer = [0u8; Row::BYTES];
     let mut map: HashMap<u32, Vec<Row>> = HashMap::new();
 
-    while reader.read_exact(&mut buffer).is_ok() {
-        let row: Row = unsafe { std::mem::transmute(buffer) }; // 将读取的字节直接转换为Row结构体
-        map.entry(row.seq_id).or_default().push(row); // 插入到HashMap中
+    for file_path in file_paths {
+        let (footer, mut reader) = open_with_footer(file_path)?;
+        let rows = footer.records;
+        if reader.data_len() != rows * buffer.len() as u64 {
+            return Err(damaged(
+                file_path,
+                format!(
+                    "{} bytes do not hold the {} rows of its footer",
+                    reader.data_len(),
+                    rows
+                ),
+            ));
+        }
+        for _ in 0..rows {
+            reader.read_exact(&mut buffer)?;
+            let row = Row::from_le_bytes(&buffer);
+            map.entry(row.seq_id).or_default().push(row); // 插入到HashMap中
+        }
+        // 读到末尾时核对校验和
+        if reader.read(&mut buffer)? != 0 {
+            return Err(damaged(
+                file_path,
+                "it has bytes after its rows".to_string(),
+            ));
+        }
     }
 
     Ok(map)
 }
 
+/// Finds the sample bin files of the chunk directory and of its `annotate --partition` directories
+///
+/// # Returns
+///
+/// The bin files of every sample, by sample number
+fn find_sample_bin_files(chunk_dir: &Path) -> Result<BTreeMap<usize, Vec<PathBuf>>> {
+    let mut sample_files = find_and_trans_bin_files(chunk_dir, "sample_file", ".bin", false)?;
+    for entry in std::fs::read_dir(chunk_dir)? {
+        let dir = entry?.path();
+        let is_partition = dir.is_dir()
+            && dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("partition_"));
+        if is_partition {
+            for (i, files) in find_and_trans_bin_files(&dir, "sample_file", ".bin", false)? {
+                sample_files.entry(i).or_default().extend(files);
+            }
+        }
+    }
+    Ok(sample_files)
+}
+
+/// The output line of a read and its chimera line, if any, from the rows of its hits
+///
+/// A read without hits, with no rows, gets a U line whose hit list counts all its k-mers as
+/// unhit.
+fn resolve_read(
+    rows: Vec<Row>,
+    item: &(String, String, usize, Option<usize>),
+    args: &Args,
+    taxonomy: &Taxonomy,
+    classify_counter: &AtomicUsize,
+    cur_taxon_counts: &TaxonCountersDash,
+    value_mask: usize,
+) -> (String, Option<String>) {
+    let dna_id = trim_pair_info(&item.0);
+    let range = OptionPair::from(((0, item.2), item.3.map(|size| (item.2, size + item.2))));
+    let hits = HitGroup::new(rows, range);
+
+    let hit_data = process_hitgroup(
+        &hits,
+        taxonomy,
+        classify_counter,
+        hits.required_score(args.confidence_threshold),
+        args.minimum_hit_groups,
+        value_mask,
+    );
+
+    hit_data.3.iter().for_each(|(key, value)| {
+        cur_taxon_counts
+            .entry(*key)
+            .or_default()
+            .merge(value)
+            .unwrap();
+    });
+
+    let strain_data = if args.strain_refinement {
+        let call = taxonomy.get_internal_id(hit_data.1);
+        let (strain, support) = refine_strain(&hits, taxonomy, call, value_mask)
+            .map(|(strain, support)| (taxonomy.nodes[strain as usize].external_id, support))
+            .unwrap_or((0, 0.0));
+        format!("\t{}\t{:.3}", strain, support)
+    } else {
+        String::new()
+    };
+
+    let output_line = format!(
+        "{}\t{}\t{}\t{}\t{}{}\n",
+        hit_data.0, dna_id, hit_data.1, item.1, hit_data.2, strain_data
+    );
+    let chimera_line = args.chimera_window.and_then(|window| {
+        let stride = args.chimera_stride.unwrap_or((window / 2).max(1));
+        detect_chimera(
+            &hits,
+            taxonomy,
+            value_mask,
+            window,
+            stride,
+            args.minimum_hit_groups,
+        )
+        .map(|(windows, breakpoints)| format_chimera(&dna_id, taxonomy, &windows, &breakpoints))
+    });
+    (output_line, chimera_line)
+}
+
+/// Resolves the reads of a sample from its bin files and writes their output lines
+///
+/// With `--full-output` the reads of `id_map` without any row in the bin files follow as U
+/// lines, in the order of their sequence IDs.
+///
+/// # Returns
+///
+/// The taxon counts of the sample and the number of classified reads
 fn process_batch<P: AsRef<Path>>(
     sample_files: &Vec<P>,
     args: &Args,
     taxonomy: &Taxonomy,
     id_map: &HashMap<u32, (String, String, usize, Option<usize>)>,
     writer: &mut Box<dyn Write + Send>,
+    chimera_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
     value_mask: usize,
 ) -> Result<(TaxonCountersDash, usize)> {
-    let confidence_threshold = args.confidence_threshold;
-    let minimum_hit_groups = args.minimum_hit_groups;
-
     let classify_counter = AtomicUsize::new(0);
     let cur_taxon_counts = TaxonCountersDash::new();
 
+    // annotate --partition 的每个分区目录都有同名的 bin 文件, 同一条序列的行分散在其中, 要一起读取
+    let mut bin_groups: BTreeMap<&std::ffi::OsStr, Vec<&Path>> = BTreeMap::new();
     for sample_file in sample_files {
-        let hit_counts: HashMap<u32, Vec<Row>> = read_rows_from_file(sample_file)?;
+        let sample_file = sample_file.as_ref();
+        let name = sample_file.file_name().unwrap_or_default();
+        bin_groups.entry(name).or_default().push(sample_file);
+    }
 
+    // 有命中的 read, 其余的 read 没有写入 bin 文件
+    let mut hit_seq_ids: HashSet<u32> = HashSet::new();
+    for bin_files in bin_groups.values() {
+        let hit_counts: HashMap<u32, Vec<Row>> = read_rows_from_files(bin_files)?;
+        if args.full_output {
+            hit_seq_ids.extend(hit_counts.keys());
+        }
+
+        let mut write_result = Ok(());
         buffer_map_parallel(
             &hit_counts,
-            args.num_threads,
+            pipeline_threads(args.num_threads),
             |(k, rows)| {
                 if let Some(item) = id_map.get(&k) {
                     let mut rows = rows.to_owned();
                     rows.sort_unstable();
-
-                    let dna_id = trim_pair_info(&item.0);
-                    let range =
-                        OptionPair::from(((0, item.2), item.3.map(|size| (item.2, size + item.2))));
-                    let hits = HitGroup::new(rows, range);
-
-                    let hit_data = process_hitgroup(
-                        &hits,
+                    Some(resolve_read(
+                        rows,
+                        item,
+                        args,
                         taxonomy,
                         &classify_counter,
-                        hits.required_score(confidence_threshold),
-                        minimum_hit_groups,
+                        &cur_taxon_counts,
                         value_mask,
-                    );
-
-                    hit_data.3.iter().for_each(|(key, value)| {
-                        cur_taxon_counts
-                            .entry(*key)
-                            .or_default()
-                            .merge(value)
-                            .unwrap();
-                    });
-
-                    // 使用锁来同步写入
-                    let output_line = format!(
-                        "{}\t{}\t{}\t{}\t{}\n",
-                        hit_data.0, dna_id, hit_data.1, item.1, hit_data.2
-                    );
-                    Some(output_line)
+                    ))
                 } else {
-                    eprintln!("can't find {} in sample_id map file", k);
+                    warn!("can't find {} in sample_id map file", k);
                     None
                 }
             },
             |result| {
                 while let Some(output) = result.next() {
-                    if let Some(res) = output.unwrap() {
-                        writer
-                            .write_all(res.as_bytes())
-                            .expect("write output content error");
+                    if let Some((res, chimera)) = output.unwrap() {
+                        // 记下第一个写入错误, 读完结果后返回
+                        if write_result.is_err() {
+                            continue;
+                        }
+                        write_result = writer.write_all(res.as_bytes());
+                        if let (Some(chimera_writer), Some(chimera), Ok(())) =
+                            (chimera_writer.as_mut(), chimera, &write_result)
+                        {
+                            write_result = chimera_writer.write_all(chimera.as_bytes());
+                        }
                     }
                 }
             },
-        )
-        .expect("failed");
+        )?;
+        write_result?;
+    }
+
+    if args.full_output {
+        // 没有命中的 read 按序号补写 U 行, 它们已计入未分类的 read 数
+        let mut unhit: Vec<_> = id_map
+            .iter()
+            .filter(|(seq_id, _)| !hit_seq_ids.contains(seq_id))
+            .collect();
+        unhit.sort_unstable_by_key(|(seq_id, _)| **seq_id);
+        for (_, item) in unhit {
+            let (output_line, _) = resolve_read(
+                Vec::new(),
+                item,
+                args,
+                taxonomy,
+                &classify_counter,
+                &cur_taxon_counts,
+                value_mask,
+            );
+            writer.write_all(output_line.as_bytes())?;
+        }
     }
 
     Ok((cur_taxon_counts, classify_counter.load(Ordering::SeqCst)))
 }
 
-pub fn run(args: Args) -> Result<()> {
+/// Writes the combined report and summary of the samples resolved by `resolve --partition` jobs
+///
+/// The read counts come from the sample reports, so the combined report has no minimizer data.
+fn merge_partitions(args: &Args, taxo: &Taxonomy) -> Result<ClassifySummary> {
+    let output = args.output_dir.as_ref().ok_or_else(|| {
+        Error::InvalidInput("--merge-partitions requires --output-dir".to_string())
+    })?;
+    if is_remote(output) {
+        return Err(Error::InvalidInput(
+            "--merge-partitions reads the sample reports back and needs a local --output-dir"
+                .to_string(),
+        )
+        .into());
+    }
+    remove_sentinel(output, "resolve")?;
+    let sample_names = read_sample_files(args.chunk_dir.join("sample_file.map"))?;
+    let sample_dir_names = if args.sample_dirs {
+        sample_dir_names(&sample_names)
+    } else {
+        HashMap::new()
+    };
+    if args.report_kmer_data {
+        warn!("sample reports have no minimizer data to merge, the combined report has none");
+    }
+
+    let mut total = ClassifySummary::default();
+    let mut summaries = Vec::new();
+    let mut artifacts = Vec::new();
+    for (i, files) in &sample_names {
+        let outputs = SampleOutputs::new(output, *i, sample_dir_names.get(i).map(String::as_str));
+        let report = outputs.report();
+        if !report.exists() {
+            return Err(Error::InvalidInput(format!(
+                "{:?} not found; run resolve --partition for every partition before --merge-partitions",
+                report
+            ))
+            .into());
+        }
+        let summary = ClassifySummary::read_report(&report, taxo)?;
+        if let Some(name) = sample_dir_names.get(i) {
+            summaries.push(SampleSummary {
+                name: name.clone(),
+                files: files.clone(),
+                reads: summary.total_seqs,
+                unclassified: summary.total_unclassified,
+            });
+        }
+        total.merge(&summary);
+        artifacts.extend(outputs.files());
+    }
+
+    if args.sample_dirs {
+        let filename = output.join(SAMPLE_SUMMARY_FILENAME);
+        write_sample_summary(&filename, &summaries)?;
+        artifacts.push(filename);
+    }
+    if let (Some(min), Some(max)) = (sample_names.keys().min(), sample_names.keys().max()) {
+        if max > min {
+            let filename = if args.sample_dirs {
+                output.join(COMBINED_REPORT_FILENAME)
+            } else {
+                output.join(format!("output_{}-{}.kreport2", min, max))
+            };
+            report_kraken_style(
+                &filename,
+                args.report_zero_counts,
+                false,
+                taxo,
+                &total.taxon_counts,
+                total.total_seqs,
+                total.total_unclassified,
+            )?;
+            artifacts.push(filename);
+        }
+    }
+    write_sentinel(output, "resolve", &artifacts)?;
+    info!(
+        "merged the reports of {} samples: {} reads, {} classified",
+        sample_names.len(),
+        total.total_seqs,
+        total.total_seqs - total.total_unclassified
+    );
+    Ok(total)
+}
+
+/// Resolves the sample bin files of the chunk directory into the outputs
+///
+/// # Returns
+///
+/// An io::Result containing the read counts of the samples resolved by this run
+pub fn run(args: Args) -> Result<ClassifySummary> {
     let k2d_dir = &args.database;
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
+    let pseudo_taxa_expected = read_pseudo_taxa_expectations(k2d_dir)?;
+
+    if args.merge_partitions {
+        return merge_partitions(&args, &taxo);
+    }
 
-    let sample_files = find_and_trans_bin_files(&args.chunk_dir, "sample_file", ".bin", false)?;
-    let sample_id_files = find_and_trans_files(&args.chunk_dir, "sample_id", ".map", false)?;
+    let mut sample_files = find_sample_bin_files(&args.chunk_dir)?;
+    let mut sample_id_files = find_and_trans_files(&args.chunk_dir, "sample_id", ".map", false)?;
+    if let Some(partition) = &args.partition {
+        sample_files.retain(|i, _| partition.contains(*i));
+        sample_id_files.retain(|i, _| partition.contains(*i));
+        info!("partition {}: {} samples", partition, sample_files.len());
+    }
 
     // let partition = sample_files.len();
     let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
@@ -203,34 +519,112 @@ pub fn run(args: Args) -> Result<()> {
     let mut total_seqs = 0;
     let mut total_unclassified = 0;
 
-    if let Some(output) = &args.output_dir {
-        create_dir_all(output)?;
+    match &args.output_dir {
+        Some(output) if is_remote(output) => {}
+        Some(output) => create_dir_all(output)?,
+        None if args.chimera_window.is_some() => {
+            warn!("chimera detection requires --output-dir, skipping")
+        }
+        None => {}
     }
+    // 没有本地输出目录时 (stdout 或对象存储), 完成标记写在分块目录
+    let sentinel_dir = args
+        .output_dir
+        .as_ref()
+        .filter(|output| !is_remote(output))
+        .unwrap_or(&args.chunk_dir);
+    let stage = match &args.partition {
+        Some(partition) => format!("resolve.partition_{}", partition.index),
+        None => "resolve".to_string(),
+    };
+    remove_sentinel(sentinel_dir, &stage)?;
+
+    let sample_names = if args.sample_dirs {
+        read_sample_files(args.chunk_dir.join("sample_file.map"))?
+    } else {
+        BTreeMap::new()
+    };
+    let sample_dir_names = sample_dir_names(&sample_names);
+    let mut summaries = Vec::new();
+
+    // 分区运行的断点写在分区自己的目录, 同时运行的作业互不干扰
+    let checkpoint_dir = match &args.partition {
+        Some(partition) => partition.dir(&args.chunk_dir),
+        None => args.chunk_dir.clone(),
+    };
+    create_dir_all(&checkpoint_dir)?;
+    let mut checkpoint = Checkpoint::load_file(checkpoint_dir.join(CLASSIFY_CHECKPOINT_FILENAME))?;
+    // 多个样本的合并报告和汇总需要所有分区的计数, 已完成的分区仍要重新计数;
+    // 分区运行只写各样本的输出, 合并报告由 --merge-partitions 写
+    let combined_report = args.output_dir.is_some()
+        && args.partition.is_none()
+        && (sample_files.len() > 1 || args.sample_dirs);
+    let mut sample_outputs = Vec::new();
+    let mut id_map = args.anonymize_ids.as_ref().map(IdMap::open).transpose()?;
 
     // 开始计时
     let start = Instant::now();
-    println!("resolve start...");
+    info!("resolve start...");
+    let progress = Progress::new(
+        "resolve",
+        "samples",
+        sample_files.len() as u64,
+        args.progress,
+    );
 
     for (i, sam_files) in &sample_files {
-        let sample_id_map = read_id_to_seq_map(&sample_id_files[i])?;
+        let key = format!("{}{}", RESOLVE_DONE, i);
+        let resolved = args.resume && checkpoint.is_done(&key);
+        if resolved && !combined_report {
+            info!("partition {} already resolved, skipping", i);
+            if let Some(output) = &args.output_dir {
+                let name = sample_dir_names.get(i).map(String::as_str);
+                sample_outputs.extend(SampleOutputs::new(output, *i, name).files());
+            }
+            progress.inc(1);
+            continue;
+        }
+        let mut sample_id_map = read_id_to_seq_map(&sample_id_files[i])?;
+        if let (Some(id_map), false) = (id_map.as_mut(), resolved) {
+            // 输出和嵌合体文件只写匿名 ID, 成对序列的 /1 /2 后缀先去掉
+            for item in sample_id_map.values_mut() {
+                item.0 = id_map.anonymize(&trim_pair_info(&item.0))?;
+            }
+            id_map.flush()?;
+        }
 
         let thread_sequences = sample_id_map.len();
-        let mut writer: Box<dyn Write + Send> = match &args.output_dir {
-            Some(ref file_path) => {
-                let filename = file_path.join(format!("output_{}.txt", i));
-                let file = File::create(filename)?;
+        let outputs = args.output_dir.as_ref().map(|output| {
+            SampleOutputs::new(output, *i, sample_dir_names.get(i).map(String::as_str))
+        });
+        if let Some(outputs) = &outputs {
+            outputs.create_dir()?;
+        }
+        let mut writer: Box<dyn Write + Send> = match &outputs {
+            _ if resolved => Box::new(io::sink()),
+            Some(outputs) => {
+                let file = create_output(outputs.output())?;
                 Box::new(BufWriter::new(file)) as Box<dyn Write + Send>
             }
             None => Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>,
         };
+        let mut chimera_writer = match (&outputs, args.chimera_window) {
+            _ if resolved => None,
+            (Some(outputs), Some(_)) => Some(BufWriter::new(create_output(outputs.chimera())?)),
+            _ => None,
+        };
         let (thread_taxon_counts, thread_classified) = process_batch::<PathBuf>(
             sam_files,
             &args,
             &taxo,
             &sample_id_map,
             &mut writer,
+            &mut chimera_writer,
             value_mask,
         )?;
+        if let Some(mut chimera_writer) = chimera_writer {
+            chimera_writer.flush()?;
+        }
 
         let mut sample_taxon_counts: HashMap<
             u64,
@@ -250,10 +644,12 @@ pub fn run(args: Args) -> Result<()> {
                 .merge(&entry.value())
                 .unwrap();
         });
-        if let Some(output) = &args.output_dir {
-            let filename = output.join(format!("output_{}.kreport2", i));
+        writer.flush()?;
+        if resolved {
+            // 输出文件已在上次运行中写好, 只累计合并报告的计数
+        } else if let Some(outputs) = &outputs {
             report_kraken_style(
-                filename,
+                outputs.report(),
                 args.report_zero_counts,
                 args.report_kmer_data,
                 &taxo,
@@ -261,19 +657,49 @@ pub fn run(args: Args) -> Result<()> {
                 thread_sequences as u64,
                 (thread_sequences - thread_classified) as u64,
             )?;
+            report_pseudo_taxa(
+                outputs.spikein(),
+                &taxo,
+                &sample_taxon_counts,
+                thread_sequences as u64,
+                &pseudo_taxa_expected,
+            )?;
+            checkpoint.mark(&key, "")?;
+        }
+
+        if let Some(outputs) = &outputs {
+            sample_outputs.extend(outputs.files());
+        }
+
+        if let Some(name) = sample_dir_names.get(i) {
+            summaries.push(SampleSummary {
+                name: name.clone(),
+                files: sample_names[i].clone(),
+                reads: thread_sequences as u64,
+                unclassified: (thread_sequences - thread_classified) as u64,
+            });
         }
 
         total_seqs += thread_sequences;
         total_unclassified += thread_sequences - thread_classified;
+        progress.inc(1);
     }
+    progress.finish();
 
     if let Some(output) = &args.output_dir {
-        if !sample_files.is_empty() {
+        if !sample_files.is_empty() && args.partition.is_none() {
             let min = &sample_files.keys().min().cloned().unwrap();
             let max = &sample_files.keys().max().cloned().unwrap();
 
+            if args.sample_dirs {
+                write_sample_summary(output.join(SAMPLE_SUMMARY_FILENAME), &summaries)?;
+            }
             if max > min {
-                let filename = output.join(format!("output_{}-{}.kreport2", min, max));
+                let filename = if args.sample_dirs {
+                    output.join(COMBINED_REPORT_FILENAME)
+                } else {
+                    output.join(format!("output_{}-{}.kreport2", min, max))
+                };
                 report_kraken_style(
                     filename,
                     args.report_zero_counts,
@@ -287,14 +713,30 @@ pub fn run(args: Args) -> Result<()> {
 
             let source_sample_file = args.chunk_dir.join("sample_file.map");
             let to_sample_file = output.join("sample_file.txt");
-            std::fs::copy(source_sample_file, to_sample_file)?;
+            copy_to_output(source_sample_file, to_sample_file)?;
         };
     }
+    if let (Some(output), Some(_)) = (&args.output_dir, &args.partition) {
+        copy_to_output(
+            args.chunk_dir.join("sample_file.map"),
+            output.join("sample_file.txt"),
+        )?;
+    }
+    let artifacts: Vec<PathBuf> = match &args.output_dir {
+        Some(_) if args.partition.is_some() => sample_outputs,
+        Some(output) if is_remote(output) => Vec::new(),
+        Some(output) => find_files(output, "", "")
+            .into_iter()
+            .filter(|path| path.is_file() && !path.to_string_lossy().ends_with(SENTINEL_SUFFIX))
+            .collect(),
+        None => Vec::new(),
+    };
+    write_sentinel(sentinel_dir, &stage, &artifacts)?;
 
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("resolve took: {:?}", duration);
+    info!("resolve took: {:?}", duration);
 
     for (_, sam_files) in &sample_files {
         for sample_file in sam_files {
@@ -305,9 +747,15 @@ pub fn run(args: Args) -> Result<()> {
     for (_, sample_file) in sample_id_files {
         let _ = std::fs::remove_file(sample_file);
     }
+    checkpoint.remove()?;
+    check_uploads()?;
     // let source_sample_file = args.chunk_dir.join("sample_file.map");
     // let _ = std::fs::remove_file(source_sample_file);
-    Ok(())
+    Ok(ClassifySummary {
+        taxon_counts: total_taxon_counts,
+        total_seqs: total_seqs as u64,
+        total_unclassified: total_unclassified as u64,
+    })
 }
 
 #[allow(dead_code)]
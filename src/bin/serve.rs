@@ -0,0 +1,386 @@
+use clap::Parser;
+use flate2::read::GzDecoder;
+use kun_peng::args::parse_size;
+use kun_peng::classifier::{Classifier, ClassifierOptions, ClassifySummary};
+use kun_peng::input::{parse_fastx, InputOptions};
+use kun_peng::utils::{format_bytes, thread_budget};
+use log::{debug, info, warn};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Load a database once and classify sequences sent over HTTP",
+    long_about = "Load all hash tables and the taxonomy of a database once and classify sequences sent over HTTP.
+
+Endpoints:
+  GET  /health    returns 'ok' once the database is loaded
+  POST /classify  FASTA/FASTQ body (optionally gzip with 'Content-Encoding: gzip', other encodings get 415), returns the Kraken output of every read
+  POST /report    FASTA/FASTQ body, returns the Kraken-style report (kreport2) of the reads
+
+Example: curl --data-binary @reads.fq http://127.0.0.1:8080/classify"
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Address to listen on.
+    #[clap(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    /// Port to listen on.
+    #[clap(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Largest request body accepted, e.g. 512M.
+    #[clap(long, value_parser = parse_size, default_value = "1G")]
+    pub max_body_size: usize,
+
+    /// Seconds a connection may wait for the next bytes of the request or for the client to
+    /// take the response before it is closed.
+    #[clap(long, default_value_t = 30)]
+    pub timeout: u64,
+
+    /// Minimum quality score for FASTQ data.
+    #[clap(
+        short = 'Q',
+        long = "minimum-quality-score",
+        value_parser,
+        default_value_t = 0
+    )]
+    pub minimum_quality_score: i32,
+
+    /// Confidence score threshold.
+    #[clap(
+        short = 'T',
+        long = "confidence-threshold",
+        value_parser,
+        default_value_t = 0.0
+    )]
+    pub confidence_threshold: f64,
+
+    /// Provide minimizer information in /report responses
+    #[clap(short = 'K', long, value_parser, default_value_t = false)]
+    pub report_kmer_data: bool,
+
+    /// Report taxa w/ 0 count in /report responses
+    #[clap(short = 'z', long, value_parser, default_value_t = false)]
+    pub report_zero_counts: bool,
+
+    /// The minimum number of hit groups needed for a call.
+    #[clap(
+        short = 'g',
+        long = "minimum-hit-groups",
+        value_parser,
+        default_value_t = 2
+    )]
+    pub minimum_hit_groups: usize,
+
+    /// The number of worker threads used by each request, capped by the global --threads.
+    /// Also the number of connections handled at once: the global --threads if set, and then
+    /// one request is classified at a time.
+    #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
+    pub num_threads: usize,
+
+    /// How the request bodies are read, set from the global options of `kun_peng`
+    #[clap(skip)]
+    pub input: InputOptions,
+}
+
+/// The loaded database shared by all requests
+struct Server<'a> {
+    args: &'a Args,
+    classifier: Classifier,
+    /// Held while classifying when the global --threads is set, so that concurrent
+    /// requests do not exceed it: the requests are then classified one at a time, each
+    /// with all threads of the budget, however many handlers read and answer them
+    classify_lock: Mutex<()>,
+}
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// An HTTP response with a status and a text body
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl Server<'_> {
+    /// Classifies the records of a request body
+    ///
+    /// # Returns
+    ///
+    /// The Kraken output lines and the read counts
+    fn classify(&self, body: &[u8]) -> Result<(String, ClassifySummary)> {
+        let records = parse_fastx(
+            body,
+            self.args.minimum_quality_score,
+            self.args.input.strict,
+        )?;
+        let _guard = thread_budget().map(|_| self.classify_lock.lock().unwrap());
+        let (calls, summary) = self.classifier.classify_records(records)?;
+        let mut output = String::new();
+        for call in calls {
+            output.push_str(&call.to_string());
+            output.push('\n');
+        }
+        Ok((output, summary))
+    }
+
+    fn report(&self, body: &[u8]) -> Result<String> {
+        let (_, summary) = self.classify(body)?;
+        let mut report = Vec::new();
+        summary.write_report(
+            &mut report,
+            self.classifier.taxonomy(),
+            self.args.report_zero_counts,
+            self.args.report_kmer_data,
+        )?;
+        Ok(String::from_utf8_lossy(&report).into_owned())
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => return Response::text(200, "ok\n".to_string()),
+            ("POST", "/classify") => self.classify(&request.body).map(|(output, _)| output),
+            ("POST", "/report") => self.report(&request.body),
+            (_, "/health" | "/classify" | "/report") => {
+                return Response::text(405, "method not allowed\n".to_string())
+            }
+            _ => return Response::text(404, "not found\n".to_string()),
+        };
+        match result {
+            Ok(body) => Response::text(200, body),
+            Err(e) if e.kind() == ErrorKind::InvalidData => Response::text(400, format!("{}\n", e)),
+            Err(e) => Response::text(500, format!("{}\n", e)),
+        }
+    }
+}
+
+/// Reads one HTTP/1.1 request with a `Content-Length` body
+fn read_request(
+    stream: &TcpStream,
+    max_body_size: usize,
+) -> std::result::Result<Request, Response> {
+    let bad_request = |message: &str| Response::text(400, format!("{}\n", message));
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(|e| bad_request(&e.to_string()))?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad_request("malformed request line"));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = None;
+    let mut gzip = false;
+    loop {
+        line.clear();
+        reader
+            .read_line(&mut line)
+            .map_err(|e| bad_request(&e.to_string()))?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| bad_request("invalid Content-Length"))?,
+                )
+            }
+            "content-encoding" => match value.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => gzip = true,
+                "identity" => gzip = false,
+                _ => {
+                    return Err(Response::text(
+                        415,
+                        format!("Content-Encoding {} is not supported, send gzip\n", value),
+                    ))
+                }
+            },
+            "transfer-encoding" if !value.eq_ignore_ascii_case("identity") => {
+                return Err(Response::text(
+                    411,
+                    "chunked bodies are not supported, send Content-Length\n".to_string(),
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    let mut body = Vec::new();
+    if method == "POST" {
+        let Some(length) = content_length else {
+            return Err(Response::text(411, "Content-Length required\n".to_string()));
+        };
+        if length > max_body_size {
+            return Err(Response::text(
+                413,
+                format!("body larger than {}\n", format_bytes(max_body_size as f64)),
+            ));
+        }
+        let raw = reader.take(length as u64);
+        let limit = max_body_size as u64 + 1;
+        let read = if gzip {
+            GzDecoder::new(raw).take(limit).read_to_end(&mut body)
+        } else {
+            raw.take(limit).read_to_end(&mut body)
+        };
+        read.map_err(|e| bad_request(&e.to_string()))?;
+        if body.len() > max_body_size {
+            return Err(Response::text(
+                413,
+                format!(
+                    "decompressed body larger than {}\n",
+                    format_bytes(max_body_size as f64)
+                ),
+            ));
+        }
+    }
+
+    Ok(Request { method, path, body })
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+fn handle_connection(server: &Server, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    // 空闲或过慢的客户端不能一直占用处理线程
+    let timeout = Some(Duration::from_secs(server.args.timeout.max(1)));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    let start = Instant::now();
+    let (request_line, response) = match read_request(&stream, server.args.max_body_size) {
+        Ok(request) => (
+            format!("{} {}", request.method, request.path),
+            server.handle(&request),
+        ),
+        Err(response) => ("-".to_string(), response),
+    };
+    info!(
+        "{} {} {} {} bytes in {:?}",
+        peer,
+        request_line,
+        response.status,
+        response.body.len(),
+        start.elapsed()
+    );
+    write_response(&stream, &response)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let options = ClassifierOptions {
+        confidence_threshold: args.confidence_threshold,
+        minimum_hit_groups: args.minimum_hit_groups,
+        num_threads: args.num_threads,
+    };
+    let classifier = Classifier::load(&args.database, options)?;
+    debug!("{:?}", classifier.hash_config());
+    info!("database loaded in {:?}", start.elapsed());
+
+    let listener = TcpListener::bind((args.host.as_str(), args.port))?;
+    info!("listening on http://{}", listener.local_addr()?);
+
+    let server = Server {
+        args: &args,
+        classifier,
+        classify_lock: Mutex::new(()),
+    };
+    // 固定数量的连接处理线程; 全部忙碌时新连接留在监听队列中
+    let handlers = thread_budget().unwrap_or(args.num_threads).max(1);
+    info!("handling up to {} connections at once", handlers);
+    let (sender, receiver) = sync_channel::<TcpStream>(0);
+    let receiver = Mutex::new(receiver);
+    std::thread::scope(|scope| {
+        for _ in 0..handlers {
+            let (server, receiver) = (&server, &receiver);
+            scope.spawn(move || loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                if let Err(e) = handle_connection(server, stream) {
+                    warn!("connection failed: {}", e);
+                }
+            });
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("failed to accept a connection: {}", e),
+            }
+        }
+        drop(sender);
+    });
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
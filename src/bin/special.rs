@@ -0,0 +1,318 @@
+use clap::Parser;
+use flate2::bufread::MultiGzDecoder;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Prepare a 16S database from downloaded SILVA, Greengenes or RDP files",
+    long_about = "Prepare a 16S database from SILVA, Greengenes or RDP release files already downloaded from the source's website; nothing is downloaded.
+A taxonomy (taxonomy/nodes.dmp, taxonomy/names.dmp) is generated from the source's own lineages, the sequences are written to library/ and seqid2taxid.map maps them to the generated taxids.
+Run build-db afterwards to build the hash tables.
+
+Expected input files:
+  silva:      SILVA_*_SSURef_Nr99_tax_silva.fasta(.gz) and tax_slv_ssu_*.txt
+  greengenes: gg_13_5.fasta(.gz) and gg_13_5_taxonomy.txt
+  rdp:        current_Bacteria_unaligned.fa(.gz) (lineages are read from the headers)"
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Source of the 16S sequences and taxonomy.
+    #[arg(long, value_parser = ["silva", "greengenes", "rdp"])]
+    pub source: String,
+
+    /// FASTA file with the 16S sequences.
+    #[arg(long, required = true)]
+    pub sequences: PathBuf,
+
+    /// Taxonomy file of the release (required for silva and greengenes).
+    #[arg(long)]
+    pub taxonomy: Option<PathBuf>,
+}
+
+/// Taxonomy tree keyed by lineage path, written out as an NCBI-style taxdump
+struct LineageTree {
+    ids: HashMap<String, u64>,
+    // id, parent id, name, rank
+    nodes: Vec<(u64, u64, String, String)>,
+    next_id: u64,
+}
+
+impl LineageTree {
+    fn new() -> Self {
+        let mut ids = HashMap::new();
+        ids.insert(String::new(), 1);
+        Self {
+            ids,
+            nodes: vec![(1, 1, "root".to_string(), "no rank".to_string())],
+            next_id: 2,
+        }
+    }
+
+    /// Add a node below the node of `parent_path` and return its id
+    ///
+    /// Nodes without an `id` of their own are numbered sequentially.
+    fn add(
+        &mut self,
+        path: &str,
+        parent_path: &str,
+        name: &str,
+        rank: &str,
+        id: Option<u64>,
+    ) -> u64 {
+        if let Some(&id) = self.ids.get(path) {
+            return id;
+        }
+        let parent_id = *self.ids.get(parent_path).unwrap_or(&1);
+        let id = id.unwrap_or_else(|| {
+            self.next_id += 1;
+            self.next_id - 1
+        });
+        self.ids.insert(path.to_string(), id);
+        self.nodes
+            .push((id, parent_id, name.to_string(), rank.to_string()));
+        id
+    }
+
+    fn get(&self, path: &str) -> Option<u64> {
+        self.ids.get(path).copied()
+    }
+
+    fn write_taxdump(&self, directory: &Path) -> Result<()> {
+        create_dir_all(directory)?;
+        let mut nodes = BufWriter::new(File::create(directory.join("nodes.dmp"))?);
+        let mut names = BufWriter::new(File::create(directory.join("names.dmp"))?);
+        for (id, parent_id, name, rank) in &self.nodes {
+            writeln!(nodes, "{}\t|\t{}\t|\t{}\t|", id, parent_id, rank)?;
+            writeln!(names, "{}\t|\t{}\t|\t\t|\tscientific name\t|", id, name)?;
+        }
+        nodes.flush()?;
+        names.flush()
+    }
+}
+
+fn open_text<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    if path.extension().and_then(|s| s.to_str()) == Some("gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(
+            BufReader::new(file),
+        ))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Parent of a SILVA path such as `Bacteria;Firmicutes;`
+fn silva_parent(path: &str) -> &str {
+    match path.trim_end_matches(';').rfind(';') {
+        Some(pos) => &path[..pos + 1],
+        None => "",
+    }
+}
+
+/// Build the tree from a SILVA tax_slv_ssu file, keeping the SILVA taxids
+fn silva_taxonomy(filename: &Path) -> Result<LineageTree> {
+    let mut entries = Vec::new();
+    for line in open_text(filename)?.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        let taxid = fields[1].trim().parse::<u64>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid SILVA line: {}", line),
+            )
+        })?;
+        entries.push((fields[0].to_string(), taxid, fields[2].trim().to_string()));
+    }
+    // parents first
+    entries.sort_by_key(|(path, _, _)| path.matches(';').count());
+
+    let mut tree = LineageTree::new();
+    for (path, taxid, rank) in &entries {
+        let name = path.trim_end_matches(';').rsplit(';').next().unwrap_or("");
+        tree.add(path, silva_parent(path), name, rank, Some(*taxid));
+    }
+    Ok(tree)
+}
+
+/// Taxid of a SILVA sequence, i.e. of the deepest known prefix of its lineage
+fn silva_taxid(tree: &LineageTree, description: &str) -> Option<u64> {
+    let taxa: Vec<&str> = description.split(';').collect();
+    (1..=taxa.len()).rev().find_map(|n| {
+        let path = format!("{};", taxa[..n].join(";"));
+        tree.get(&path)
+    })
+}
+
+/// Greengenes rank prefixes and the ranks they stand for
+const GREENGENES_RANKS: [(&str, &str); 7] = [
+    ("k__", "superkingdom"),
+    ("p__", "phylum"),
+    ("c__", "class"),
+    ("o__", "order"),
+    ("f__", "family"),
+    ("g__", "genus"),
+    ("s__", "species"),
+];
+
+/// Build the tree from a Greengenes taxonomy file and map its sequence IDs to taxids
+fn greengenes_taxonomy(filename: &Path) -> Result<(LineageTree, HashMap<String, u64>)> {
+    let mut tree = LineageTree::new();
+    let mut seq_map = HashMap::new();
+    for line in open_text(filename)?.lines() {
+        let line = line?;
+        let Some((seq_id, lineage)) = line.split_once('\t') else {
+            continue;
+        };
+        let mut path = String::new();
+        let mut taxid = 1;
+        let mut genus = "";
+        for taxon in lineage.split(';').map(str::trim) {
+            let Some(&(prefix, rank)) = GREENGENES_RANKS
+                .iter()
+                .find(|(prefix, _)| taxon.starts_with(prefix))
+            else {
+                continue;
+            };
+            let name = &taxon[prefix.len()..];
+            if name.is_empty() {
+                break;
+            }
+            // species are only given as epithets
+            let name = if rank == "species" && !genus.is_empty() {
+                format!("{} {}", genus, name)
+            } else {
+                name.to_string()
+            };
+            if rank == "genus" {
+                genus = &taxon[prefix.len()..];
+            }
+            let parent_path = path.clone();
+            path.push_str(taxon);
+            path.push(';');
+            taxid = tree.add(&path, &parent_path, &name, rank, None);
+        }
+        seq_map.insert(seq_id.trim().to_string(), taxid);
+    }
+    Ok((tree, seq_map))
+}
+
+/// Taxid of an RDP sequence, adding its `Lineage=Root;rootrank;Bacteria;domain;...` to the tree
+fn rdp_taxid(tree: &mut LineageTree, header: &str) -> Option<u64> {
+    let lineage = &header[header.find("Lineage=")? + "Lineage=".len()..];
+    let fields: Vec<&str> = lineage.trim().trim_end_matches(';').split(';').collect();
+    let mut path = String::new();
+    let mut taxid = 1;
+    for pair in fields.chunks(2) {
+        let (name, rank) = match pair {
+            [name, rank] => (name.trim(), rank.trim()),
+            _ => break,
+        };
+        if rank == "rootrank" || name.is_empty() {
+            continue;
+        }
+        let parent_path = path.clone();
+        path.push_str(name);
+        path.push(';');
+        taxid = tree.add(&path, &parent_path, name.trim_matches('"'), rank, None);
+    }
+    Some(taxid)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let taxonomy_dir = args.database.join("taxonomy");
+    let map_filename = args.database.join("seqid2taxid.map");
+    if taxonomy_dir.join("nodes.dmp").exists() || map_filename.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "taxonomy/nodes.dmp or seqid2taxid.map already exists, use an empty database directory",
+        ));
+    }
+    let taxonomy_file = || {
+        args.taxonomy.as_deref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("--taxonomy is required for {}", args.source),
+            )
+        })
+    };
+
+    let (mut tree, seq_map) = match args.source.as_str() {
+        "silva" => (silva_taxonomy(taxonomy_file()?)?, HashMap::new()),
+        "greengenes" => greengenes_taxonomy(taxonomy_file()?)?,
+        _ => (LineageTree::new(), HashMap::new()),
+    };
+
+    let library_dir = args.database.join("library");
+    create_dir_all(&library_dir)?;
+    let mut fna_writer = BufWriter::new(File::create(
+        library_dir.join(format!("library_16s_{}.fna", args.source)),
+    )?);
+    let mut map_writer = BufWriter::new(File::create(&map_filename)?);
+
+    let mut total = 0;
+    let mut skipped = 0;
+    let mut keep = false;
+    for line in open_text(&args.sequences)?.lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            total += 1;
+            let (seq_id, description) = header
+                .split_once(char::is_whitespace)
+                .unwrap_or((header, ""));
+            let taxid = match args.source.as_str() {
+                "silva" => silva_taxid(&tree, description.trim()),
+                "greengenes" => seq_map.get(seq_id).copied(),
+                _ => rdp_taxid(&mut tree, header),
+            };
+            keep = matches!(taxid, Some(taxid) if taxid != 1);
+            if let (true, Some(taxid)) = (keep, taxid) {
+                writeln!(fna_writer, ">{}", seq_id)?;
+                writeln!(map_writer, "{}\t{}", seq_id, taxid)?;
+            } else {
+                skipped += 1;
+            }
+        } else if keep {
+            // SILVA sequences are RNA
+            writeln!(fna_writer, "{}", line.trim_end().replace(['U', 'u'], "T"))?;
+        }
+    }
+    fna_writer.flush()?;
+    map_writer.flush()?;
+    tree.write_taxdump(&taxonomy_dir)?;
+
+    if skipped > 0 {
+        warn!(
+            "{} of {} sequences without a known lineage skipped",
+            skipped, total
+        );
+    }
+    info!(
+        "{} sequences, {} taxa, took: {:?}",
+        total - skipped,
+        tree.nodes.len(),
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
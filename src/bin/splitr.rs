@@ -1,15 +1,28 @@
 use clap::Parser;
+use kun_peng::checkpoint::{
+    remove_sentinel, write_sentinel, Checkpoint, CLASSIFY_CHECKPOINT_FILENAME, SPLITR_DONE,
+};
+use kun_peng::chunk_stream::{encode_slots, ChunkHeader};
 use kun_peng::compact_hash::{HashConfig, Slot};
+use kun_peng::error;
+use kun_peng::footer::FooterWriter;
+use kun_peng::input::{open_fastx, CheckedReader, InputOptions};
+use kun_peng::progress::Progress;
+use kun_peng::remote::is_remote;
 use kun_peng::utils::{
     create_partition_files, create_partition_writers, create_sample_file, get_file_limit,
-    get_lastest_file_index, set_fd_limit,
+    find_files, get_lastest_file_index, memory_budget, pipeline_threads, set_fd_limit,
+    BufferPool,
 };
 use kun_peng::IndexOptions;
-use seqkmer::{read_parallel, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
+use log::{debug, info, warn};
+use seqkmer::{read_parallel, Meros, MinimizerIterator, OptionPair, Reader};
+use std::fmt::Write as _;
 use std::fs;
 use std::io::{BufWriter, Write};
 use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
 use std::time::Instant;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -45,7 +58,7 @@ pub struct Args {
     )]
     pub minimum_quality_score: i32,
 
-    /// The number of threads to use.
+    /// The number of worker threads, capped by the global --threads.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
@@ -55,9 +68,22 @@ pub struct Args {
 
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
+    /// s3:// and gs:// URLs are streamed with the aws or gcloud CLI.
     /// Can also be a single .txt file containing a list of input file paths, one per line.
     #[clap(required = true)]
     pub input_files: Vec<PathBuf>,
+
+    /// Print the number of reads split with an estimated time left to stderr
+    #[clap(long, default_value_t = false)]
+    pub progress: bool,
+
+    /// Write the slots of the chunk files as zstd frames, for a much smaller chunk directory on
+    /// shared scratch at the cost of some CPU in splitr and annotate
+    #[clap(long, default_value_t = false)]
+    pub compress_chunks: bool,
+    /// How the input files are read, set from the global options of `kun_peng`
+    #[clap(skip)]
+    pub input: InputOptions,
 }
 
 impl Args {
@@ -91,7 +117,7 @@ impl Args {
         // Final check for all input files
         let mut missing_files = Vec::new();
         for file in &self.input_files {
-            if !file.exists() {
+            if !is_remote(file) && !file.exists() {
                 missing_files.push(file.clone());
             }
         }
@@ -102,60 +128,122 @@ impl Args {
             return Err(Error::new(ErrorKind::NotFound, error_msg));
         }
 
-        // Print the list of valid input files
-        println!("Input files:");
+        // Log the list of valid input files
         for (index, file) in self.input_files.iter().enumerate() {
-            println!("  {}: {}", index + 1, file.display());
+            info!("input file {}: {}", index + 1, file.display());
         }
 
         Ok(self)
     }
 }
 
+/// Buffer of a chunk writer without a memory budget, the `BufWriter` default
+const DEFAULT_WRITER_CAPACITY: usize = 8 * 1024;
+
+/// Largest buffer of a chunk writer chosen from the memory budget
+const MAX_WRITER_CAPACITY: usize = 1024 * 1024;
+
+/// Buffer of every chunk writer: the writers of all pages share an eighth of the memory budget
+fn chunk_writer_capacity(partition: usize) -> usize {
+    memory_budget().map_or(DEFAULT_WRITER_CAPACITY, |budget| {
+        (budget / 8 / partition.max(1)).clamp(DEFAULT_WRITER_CAPACITY, MAX_WRITER_CAPACITY)
+    })
+}
+
+/// A chunk file being written, ended with the footer of its slots by [`FooterWriter::finish`]
+type ChunkWriter = FooterWriter<BufWriter<fs::File>>;
+
+/// Opens the chunk files for appending, writing the header of new ones
+///
+/// # Returns
+///
+/// The writers, whether the slots are written compressed and the file index of the first
+/// sample of the chunk files: as `--compress-chunks` asks for and the next sample for new chunk
+/// files, as the first header says for chunk files that already have slots
 fn init_chunk_writers(
     args: &Args,
     partition: usize,
     chunk_size: usize,
-) -> Vec<BufWriter<fs::File>> {
-    let chunk_files = create_partition_files(partition, &args.chunk_dir, "sample");
+) -> Result<(Vec<ChunkWriter>, bool, usize)> {
+    let chunk_files = create_partition_files(partition, &args.chunk_dir, "sample")?;
+
+    let capacity = chunk_writer_capacity(partition);
+    let mut writers = Vec::with_capacity(partition);
+    for (writer, chunk_file) in create_partition_writers(&chunk_files)?
+        .into_iter()
+        .zip(&chunk_files)
+    {
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        writers.push((BufWriter::with_capacity(capacity, file), chunk_file));
+    }
 
-    let mut writers = create_partition_writers(&chunk_files);
+    // 已有的分块文件沿用其压缩方式和样本起点, 同一文件里不能混用
+    let mut compressed = args.compress_chunks;
+    let sample_file = args.chunk_dir.join("sample_file.map");
+    let mut first_sample = if sample_file.exists() {
+        get_lastest_file_index(&sample_file)? + 1
+    } else {
+        1
+    };
+    if let Some((_, chunk_file)) = writers
+        .iter()
+        .find(|(writer, _)| writer.get_ref().metadata().is_ok_and(|m| m.len() > 0))
+    {
+        let header = ChunkHeader::from_file(chunk_file)?;
+        compressed = header.compressed;
+        first_sample = header.first_sample;
+        if compressed != args.compress_chunks {
+            warn!(
+                "appending to the chunk files in {:?}, which are {}compressed",
+                args.chunk_dir,
+                if compressed { "" } else { "not " }
+            );
+        }
+    }
 
-    writers.iter_mut().enumerate().for_each(|(index, writer)| {
+    let mut chunk_writers = Vec::with_capacity(partition);
+    for (index, (writer, chunk_file)) in writers.into_iter().enumerate() {
         // 获取对应的文件大小
-        let file_size = writer
-            .get_ref()
-            .metadata()
-            .expect("Failed to get file metadata")
-            .len();
+        let file_size = writer.get_ref().metadata()?.len();
 
         if file_size == 0 {
+            let header = ChunkHeader {
+                page_index: index,
+                chunk_size,
+                compressed,
+                first_sample,
+            };
+            let mut header_bytes = Vec::new();
+            header.write(&mut header_bytes)?;
+            let mut writer = FooterWriter::new(writer);
             writer
-                .write_all(&index.to_le_bytes())
-                .expect("Failed to write partition");
-
-            let chunk_size_bytes = chunk_size.to_le_bytes();
-            writer
-                .write_all(&chunk_size_bytes)
-                .expect("Failed to write chunk size");
-
-            writer.flush().expect("Failed to flush writer");
+                .write_records(&header_bytes, 0)
+                .and_then(|_| writer.get_mut().flush())
+                .map_err(|e| error::Error::io(chunk_file, e))?;
+            chunk_writers.push(writer);
+        } else {
+            // 追加前校验已有的 slot, 并去掉旧的 footer
+            chunk_writers.push(FooterWriter::append(chunk_file, writer)?);
         }
-    });
-
-    writers
+    }
+    Ok((chunk_writers, compressed, first_sample))
 }
 
 /// 处理record
+///
+/// Appends the slots of a read, or of one of its mates, to `k2_slot_list`, whose slots of the
+/// read start at `read_start`.
 fn process_record(
     k2_slot_list: &mut Vec<(usize, Slot<u64>)>,
+    read_start: usize,
     marker: &mut MinimizerIterator,
     hash_config: &HashConfig,
     chunk_size: usize,
     seq_id: u64,
     idx_bits: usize,
 ) {
-    let offset = k2_slot_list.len();
+    // 第二个 mate 的序号接在第一个之后
+    let offset = k2_slot_list.len() - read_start;
     for (sort, hash_key) in marker {
         let mut slot = hash_config.slot_u64(hash_key, seq_id);
         let seq_sort = sort + offset;
@@ -166,92 +254,190 @@ fn process_record(
     }
 }
 
-fn write_data_to_file(
+/// The output of a batch of reads: the lines of the sample id map and the slots of every
+/// chunk file, as zstd frames if the chunk files are compressed
+#[derive(Default)]
+struct SplitBatch {
     k2_map: String,
-    k2_slot_list: Vec<(usize, Slot<u64>)>,
-    writers: &mut Vec<BufWriter<fs::File>>,
-    slot_size: usize,
-    sample_writer: &mut BufWriter<fs::File>,
-) {
-    for slot in k2_slot_list {
-        let partition_index = slot.0;
-        if let Some(writer) = writers.get_mut(partition_index) {
-            writer.write_all(slot.1.as_slice(slot_size)).unwrap();
+    chunk_batches: Vec<Vec<u8>>,
+    /// The number of slots of every chunk file
+    chunk_slots: Vec<u64>,
+    /// The slots of the batch and their chunk files, before they are grouped by chunk file
+    slots: Vec<(usize, Slot<u64>)>,
+}
+
+impl SplitBatch {
+    /// Groups the slots of the batch by the chunk file they belong to
+    fn group_slots(&mut self, partition: usize, compressed: bool) -> Result<()> {
+        self.chunk_batches.resize_with(partition, Vec::new);
+        self.chunk_slots.resize(partition, 0);
+        for (partition_index, slot) in &self.slots {
+            if let Some(batch) = self.chunk_batches.get_mut(*partition_index) {
+                batch.extend_from_slice(&slot.to_le_bytes());
+                self.chunk_slots[*partition_index] += 1;
+            }
+        }
+        if compressed {
+            for batch in self.chunk_batches.iter_mut() {
+                let slots = std::mem::take(batch);
+                *batch = encode_slots(slots, compressed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the batch to the chunk files and the sample id map
+    fn write_to(
+        &self,
+        writers: &mut [ChunkWriter],
+        sample_writer: &mut BufWriter<fs::File>,
+    ) -> Result<()> {
+        for ((writer, batch), &slots) in writers
+            .iter_mut()
+            .zip(&self.chunk_batches)
+            .zip(&self.chunk_slots)
+        {
+            if !batch.is_empty() {
+                writer.write_records(batch, slots)?;
+            }
         }
+        sample_writer.write_all(self.k2_map.as_bytes())
     }
 
-    sample_writer.write_all(k2_map.as_bytes()).unwrap();
+    /// Empties the batch, keeping its buffers for the next one
+    fn clear(&mut self) {
+        self.k2_map.clear();
+        self.chunk_batches.iter_mut().for_each(Vec::clear);
+        self.chunk_slots.iter_mut().for_each(|slots| *slots = 0);
+        self.slots.clear();
+    }
 }
 
+/// Batches queued for the writer thread before the pipeline waits for it
+const WRITER_QUEUE_BATCHES: usize = 16;
+
+/// Splits the reads of an input file into the chunk files
+///
+/// The workers compute the slots and the sample id lines of a batch of reads, and a dedicated
+/// writer thread appends them to the files. The pipeline only hands the batches over, so the
+/// workers keep running while the writer waits on the disk, until `WRITER_QUEUE_BATCHES`
+/// batches are queued. The slots number the sample `sample`, counted from the first sample of
+/// the chunk files.
+#[allow(clippy::too_many_arguments)]
 fn process_fastx_file<R>(
     args: &Args,
     meros: Meros,
     hash_config: HashConfig,
-    file_index: usize,
+    sample: usize,
     reader: &mut R,
-    writers: &mut Vec<BufWriter<fs::File>>,
+    writers: &mut [ChunkWriter],
     sample_writer: &mut BufWriter<fs::File>,
+    progress: &Progress,
 ) -> Result<()>
 where
     R: Reader,
 {
     let chunk_size = hash_config.hash_capacity;
     let idx_bits = ((chunk_size as f64).log2().ceil() as usize).max(1);
-    let slot_size = std::mem::size_of::<Slot<u64>>();
-
-    read_parallel(
-        reader,
-        args.num_threads as usize,
-        &meros,
-        |seqs| {
-            let mut buffer = String::new();
-            let mut k2_slot_list = Vec::new();
-            for seq in seqs {
-                let mut init: Vec<(usize, Slot<u64>)> = Vec::new();
-                let header = &seq.header;
-                let index = header.reads_index;
-                let dna_id = header.id.trim();
-                let seq_id = (file_index << 32 | index) as u64;
-
-                seq.body.apply_mut(|m_iter| {
-                    process_record(
-                        &mut init,
-                        m_iter,
-                        &hash_config,
-                        chunk_size,
-                        seq_id,
-                        idx_bits,
-                    );
-                });
-                k2_slot_list.extend_from_slice(&init);
-
-                let size_str = seq.fmt_size();
-                let seq_size_str = seq.fmt_seq_size();
-                buffer.push_str(
-                    format!("{}\t{}\t{}\t{}\n", index, dna_id, seq_size_str, size_str).as_str(),
-                );
-            }
-            (buffer, k2_slot_list)
-        },
-        |dataset| {
-            while let Some(data) = dataset.next() {
-                let (buffer, k2_slot_list) = data.unwrap();
-                write_data_to_file(buffer, k2_slot_list, writers, slot_size, sample_writer);
+    let partition = writers.len();
+    let pool: BufferPool<SplitBatch> = BufferPool::default();
+    let (sender, receiver) = sync_channel::<SplitBatch>(WRITER_QUEUE_BATCHES);
+    let mut reader = CheckedReader::new(reader);
+
+    std::thread::scope(|scope| {
+        let pool = &pool;
+        let writer = scope.spawn(move || -> Result<()> {
+            for mut batch in receiver {
+                batch.write_to(writers, sample_writer)?;
+                batch.clear();
+                pool.give(batch);
             }
-        },
-    )
-    .expect("failed");
+            Ok(())
+        });
 
-    Ok(())
+        // read_parallel 不返回汇总线程的结果, 由它写回这里
+        let mut grouped = Ok(());
+        read_parallel(
+            &mut reader,
+            pipeline_threads(args.num_threads),
+            &meros,
+            |seqs| -> Result<(SplitBatch, u64)> {
+                // 缓冲区来自已写出的批次, 每个 read 不再单独分配
+                let mut batch = pool.take();
+                let mut reads = 0;
+                for seq in seqs {
+                    reads += 1;
+                    let header = &seq.header;
+                    let index = header.reads_index;
+                    let dna_id = header.id.trim();
+                    let seq_id = (sample << 32 | index) as u64;
+
+                    let read_start = batch.slots.len();
+                    seq.body.apply_mut(|m_iter| {
+                        process_record(
+                            &mut batch.slots,
+                            read_start,
+                            m_iter,
+                            &hash_config,
+                            chunk_size,
+                            seq_id,
+                            idx_bits,
+                        );
+                    });
+
+                    let size_str = seq.fmt_size();
+                    let seq_size_str = seq.fmt_seq_size();
+                    let _ = writeln!(
+                        batch.k2_map,
+                        "{}\t{}\t{}\t{}",
+                        index, dna_id, seq_size_str, size_str
+                    );
+                }
+                // 压缩在工作线程中完成, 写线程只追加字节
+                batch.group_slots(partition, args.compress_chunks)?;
+                Ok((batch, reads))
+            },
+            |dataset| {
+                let mut queued = true;
+                while let Some(data) = dataset.next() {
+                    // 出错后仍取完剩余的批次, 工作线程才能结束
+                    if !queued || grouped.is_err() {
+                        continue;
+                    }
+                    match data.unwrap() {
+                        Ok((batch, reads)) => {
+                            // 写线程出错退出后不再排队, 错误由 join 返回
+                            queued = sender.send(batch).is_ok();
+                            progress.inc(reads);
+                        }
+                        Err(e) => grouped = Err(e),
+                    }
+                }
+                drop(sender);
+            },
+        )?;
+
+        let written = writer.join().expect("chunk writer thread panicked");
+        reader.finish()?;
+        grouped.and(written)
+    })
 }
 
 /// 处理样本文件
-fn process_files<F>(args: &Args, hash_config: HashConfig, mut action: F) -> Result<()>
+///
+/// Fails if the samples of the chunk files, from `first_sample` on, would be more than their
+/// slots can tell apart.
+fn process_files<F>(
+    args: &Args,
+    hash_config: HashConfig,
+    first_sample: usize,
+    mut action: F,
+) -> Result<()>
 where
     F: FnMut(usize, OptionPair<PathBuf>) -> Result<()>,
 {
     let file_path = args.chunk_dir.join("sample_file.map");
-    let mut file_writer = create_sample_file(&file_path);
+    let mut file_writer = create_sample_file(&file_path)?;
     let mut file_index = get_lastest_file_index(&file_path)?;
 
     let chunk_size = if args.paired_end_processing {
@@ -261,9 +447,21 @@ where
     };
     let files = args.input_files.chunks(chunk_size).collect::<Vec<_>>();
 
-    let file_bits = (((files.len() + file_index) as f64).log2().ceil() as usize).max(1);
-    if file_bits > hash_config.value_bits {
-        panic!("The number of files is too large to process.");
+    if first_sample > file_index + 1 {
+        return Err(error::Error::InvalidInput(format!(
+            "the chunk files in {:?} start at sample {} but {:?} ends at sample {}",
+            args.chunk_dir, first_sample, file_path, file_index
+        ))
+        .into());
+    }
+    // slot 中的样本编号从分块文件的第一个样本数起
+    let samples = file_index + files.len() + 1 - first_sample;
+    if samples > hash_config.max_chunk_samples() {
+        return Err(error::Error::TooManyFiles {
+            files: samples,
+            max: hash_config.max_chunk_samples(),
+        }
+        .into());
     }
 
     for file_pair in files {
@@ -273,9 +471,9 @@ where
             file_writer,
             "{}\t{}",
             file_index,
-            path_pair.reduce_str(",", |a| a.to_str().unwrap().to_string())
+            path_pair.reduce_str(",", |a| a.to_string_lossy().into_owned())
         )?;
-        file_writer.flush().unwrap();
+        file_writer.flush()?;
 
         action(file_index, path_pair)?;
     }
@@ -284,7 +482,7 @@ where
 }
 
 pub fn run(args: Args) -> Result<()> {
-    let args = args.process_input_files()?;
+    let mut args = args.process_input_files()?;
     let options_filename = &args.database.join("opts.k2d");
     let idx_opts = IndexOptions::read_index_options(options_filename)?;
 
@@ -297,48 +495,85 @@ pub fn run(args: Args) -> Result<()> {
     }
     let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
 
-    println!("{:?}", hash_config);
+    debug!("{:?}", hash_config);
     if hash_config.hash_capacity == 0 {
-        panic!("`hash_capacity` can't be zero!");
+        return Err(error::Error::InvalidDatabase {
+            path: args.database.join("hash_config.k2d"),
+            reason: "hash_capacity is 0".to_string(),
+        }
+        .into());
     }
-    println!("splitr start...");
+    info!("splitr start...");
     let file_num_limit = get_file_limit();
     if hash_config.partition >= file_num_limit {
-        eprintln!(
+        warn!(
             "file num limit {:?}, need: {:?}",
             file_num_limit, hash_config.partition
         );
-        set_fd_limit(hash_config.partition as u64 + 1)
-            .expect("Failed to set file descriptor limit, please run this operation with administrative/root privileges.");
+        set_fd_limit(hash_config.partition as u64 + 1).map_err(|e| {
+            error::Error::Resources(format!(
+                "failed to raise the open file limit to {}: {}; raise it with `ulimit -n`",
+                hash_config.partition + 1,
+                e
+            ))
+        })?;
         // panic!("Exceeds File Number Limit");
     }
 
+    // 新的 splitr 开始新一轮 classify, 旧的断点记录作废
+    let checkpoint_file = args.chunk_dir.join(CLASSIFY_CHECKPOINT_FILENAME);
+    if checkpoint_file.exists() {
+        fs::remove_file(&checkpoint_file)?;
+    }
+    remove_sentinel(&args.chunk_dir, "splitr")?;
+    remove_sentinel(&args.chunk_dir, "annotate")?;
+    // 上一轮 annotate/resolve --partition 留下的分区目录
+    if args.chunk_dir.is_dir() {
+        for entry in fs::read_dir(&args.chunk_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() && name.starts_with("partition_") {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+
     let meros = idx_opts.as_meros();
     let start = Instant::now();
     let partition = hash_config.partition;
-    let mut writers: Vec<BufWriter<fs::File>> =
-        init_chunk_writers(&args, partition, hash_config.hash_capacity);
+    let (mut writers, compressed, first_sample) =
+        init_chunk_writers(&args, partition, hash_config.hash_capacity)?;
+    args.compress_chunks = compressed;
+    let progress = Progress::new("splitr", "reads", 0, args.progress);
 
-    process_files(&args, hash_config, |file_index, path_pair| {
+    process_files(&args, hash_config, first_sample, |file_index, path_pair| {
         let mut sample_writer =
-            create_sample_file(args.chunk_dir.join(format!("sample_id_{}.map", file_index)));
+            create_sample_file(args.chunk_dir.join(format!("sample_id_{}.map", file_index)))?;
 
         let score = args.minimum_quality_score;
-        let mut reader = FastxReader::from_paths(path_pair, file_index, score)?;
+        let mut reader = open_fastx(path_pair, file_index, score, &args.input)?;
         process_fastx_file(
             &args,
             meros,
             hash_config,
-            file_index,
+            file_index + 1 - first_sample,
             &mut reader,
             &mut writers,
             &mut sample_writer,
+            &progress,
         )
-        .expect("process fastx file error");
-        Ok(())
     })?;
+    for writer in writers {
+        writer.finish()?;
+    }
+    progress.finish();
+    Checkpoint::load_file(&checkpoint_file)?.mark(SPLITR_DONE, "")?;
+    let mut artifacts = find_files(&args.chunk_dir, "sample_", ".k2");
+    artifacts.extend(find_files(&args.chunk_dir, "sample_id_", ".map"));
+    artifacts.push(args.chunk_dir.join("sample_file.map"));
+    write_sentinel(&args.chunk_dir, "splitr", &artifacts)?;
     let duration = start.elapsed();
-    println!("splitr took: {:?}", duration);
+    info!("splitr took: {:?}", duration);
 
     Ok(())
 }
@@ -0,0 +1,262 @@
+use clap::Parser;
+use kun_peng::args::parse_size;
+use kun_peng::compact_hash::HashConfig;
+use kun_peng::db::{
+    convert_fna_to_k2_format, generate_taxonomy, get_bits_for_taxid, process_k2file,
+    DEFAULT_MAX_PAGE_LOAD,
+};
+use kun_peng::manifest::DbManifest;
+use kun_peng::taxonomy::{TaxidChanges, Taxonomy};
+use kun_peng::utils::{
+    create_partition_files, create_partition_writers, find_library_fna_files, get_file_limit,
+    open_file, read_id_to_taxon_map, set_fd_limit,
+};
+use kun_peng::IndexOptions;
+use log::info;
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Extract the database of a taxonomic subtree from an existing database",
+    long_about = "Write a smaller database holding only the genomes of the given taxa and their descendants.
+The hash tables are rebuilt from the library sequences of the subtree, so every cell maps into the subtree,
+and taxo.k2d is pruned to the subtree and its ancestors."
+)]
+pub struct Args {
+    /// Database to extract from (must contain library/, taxonomy/, seqid2taxid.map, taxo.k2d and opts.k2d)
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory for the subset database
+    #[arg(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+
+    /// Taxids of the subtrees to keep, e.g. 10239 for Viruses
+    #[arg(long, required = true, num_args = 1..)]
+    pub taxid: Vec<u64>,
+
+    /// Set the hash table capacity of the subset database (number of slots),
+    /// by default it is derived from the share of the library in the subtree and --load-factor
+    #[arg(short = 'c', long, value_name = "EXACT_SLOT_COUNT")]
+    pub required_capacity: Option<usize>,
+
+    /// Proportion of the subset hash table to be populated
+    #[clap(long, default_value_t = 0.7)]
+    pub load_factor: f64,
+
+    #[clap(long, value_parser = parse_size, default_value = "1G", help = "Specifies the hash file capacity.\nAcceptable formats include numeric values followed by 'K', 'M', or 'G' (e.g., '1.5G', '250M', '1024K').\nDefault: 1G (capacity 1G = file size 4G)")]
+    pub hash_capacity: usize,
+
+    /// Number of worker threads, capped by the global --threads
+    #[clap(short = 'p', long = "num-threads", default_value_t = num_cpus::get())]
+    pub threads: usize,
+}
+
+/// Counts the bases of the kept sequences and of the whole library
+fn count_bases(fna_files: &[PathBuf], kept: &HashMap<String, u64>) -> Result<(u64, u64)> {
+    let mut kept_bases = 0;
+    let mut total_bases = 0;
+    for fna_file in fna_files {
+        let mut keep = false;
+        for line in BufReader::new(open_file(fna_file)?).lines() {
+            let line = line?;
+            if let Some(header) = line.strip_prefix('>') {
+                let seq_id = header.split_whitespace().next().unwrap_or("");
+                keep = kept.contains_key(seq_id);
+            } else {
+                let bases = line.trim_end().len() as u64;
+                total_bases += bases;
+                if keep {
+                    kept_bases += bases;
+                }
+            }
+        }
+    }
+    Ok((kept_bases, total_bases))
+}
+
+fn write_id_to_taxon_map(path: &Path, id_to_taxon_map: &HashMap<String, u64>) -> Result<()> {
+    let mut entries: Vec<_> = id_to_taxon_map.iter().collect();
+    entries.sort();
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (seq_id, taxid) in entries {
+        writeln!(writer, "{}\t{}", seq_id, taxid)?;
+    }
+    writer.flush()
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = &args.database;
+    let output_dir = &args.output_dir;
+    let hash_config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    if hash_config.version < 1 {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "databases converted from Kraken 2 have no library to extract a subset from",
+        ));
+    }
+    create_dir_all(output_dir)?;
+    let output_config = output_dir.join("hash_config.k2d");
+    if output_config.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} already exists", output_config),
+        ));
+    }
+
+    let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    let mut clades = Vec::new();
+    for &taxid in &args.taxid {
+        match taxonomy.get_internal_id(taxid) {
+            0 => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("taxid {} is not in taxo.k2d", taxid),
+                ))
+            }
+            internal_id => clades.push(internal_id),
+        }
+    }
+
+    // 只保留属于指定分支的序列
+    let mut id_to_taxon_map = read_id_to_taxon_map(database.join("seqid2taxid.map"))?;
+    // 构建时已合并的 taxid 在 taxo.k2d 中是新 taxid
+    TaxidChanges::from_directory(&database.join("taxonomy"))?
+        .remap(&mut id_to_taxon_map, |taxid| {
+            taxonomy.get_internal_id(taxid) != 0
+        })
+        .log_summary();
+    let mut subset_map: HashMap<String, u64> = id_to_taxon_map
+        .into_iter()
+        .filter(|(_, taxid)| {
+            let internal_id = taxonomy.get_internal_id(*taxid);
+            clades
+                .iter()
+                .any(|&clade| taxonomy.is_a_ancestor_of_b(clade, internal_id))
+        })
+        .collect();
+    if subset_map.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "no library sequence belongs to the given taxa",
+        ));
+    }
+    info!("{} sequences in the subtree", subset_map.len());
+
+    // taxo.k2d 只保留子树及其祖先, internal id 随之重新编号
+    let subset_taxonomy = generate_taxonomy(
+        &database.join("taxonomy"),
+        &output_dir.join("taxo.k2d"),
+        &mut subset_map,
+    )?;
+    write_id_to_taxon_map(&output_dir.join("seqid2taxid.map"), &subset_map)?;
+
+    let fna_files = find_library_fna_files(database.join("library"));
+    let capacity = match args.required_capacity {
+        Some(capacity) => capacity,
+        None => {
+            let (kept_bases, total_bases) = count_bases(&fna_files, &subset_map)?;
+            let share = kept_bases as f64 / total_bases.max(1) as f64;
+            info!(
+                "the subtree holds {:.2}% of the library bases",
+                share * 100.0
+            );
+            ((hash_config.size as f64 * share / args.load_factor).ceil() as usize).max(1)
+        }
+    };
+    let value_bits = if hash_config.is_wide() {
+        hash_config.value_bits
+    } else {
+        get_bits_for_taxid(0, subset_taxonomy.node_count() as f64)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+    };
+    let partition = capacity.div_ceil(args.hash_capacity);
+    let mut new_config = HashConfig::new(
+        hash_config.base_version(),
+        capacity,
+        value_bits,
+        0,
+        partition,
+        args.hash_capacity,
+    );
+    info!(
+        "subset capacity {} ({} pages), {} taxonomy nodes",
+        capacity,
+        partition,
+        subset_taxonomy.node_count()
+    );
+
+    let options_filename = database.join("opts.k2d");
+    let meros = IndexOptions::read_index_options(&options_filename)?.as_meros();
+    if partition >= get_file_limit() {
+        set_fd_limit(partition as u64 + 1)?;
+    }
+    let chunk_files = create_partition_files(partition, output_dir, "chunk")?;
+    for chunk_file in &chunk_files {
+        File::create(chunk_file)?;
+    }
+    let mut writers: Vec<_> = create_partition_writers(&chunk_files)?
+        .into_iter()
+        .map(Some)
+        .collect();
+    for fna_file in &fna_files {
+        info!("convert fna file {:?}", fna_file);
+        convert_fna_to_k2_format(
+            fna_file,
+            meros,
+            &subset_taxonomy,
+            &subset_map,
+            new_config,
+            &mut writers,
+            args.hash_capacity,
+            args.threads,
+        );
+    }
+    for writer in writers.iter_mut().flatten() {
+        writer.flush()?;
+    }
+    drop(writers);
+
+    for (i, chunk_file) in chunk_files.iter().enumerate() {
+        let page_index = i + 1;
+        new_config.size += process_k2file(
+            new_config,
+            output_dir,
+            chunk_file,
+            &subset_taxonomy,
+            page_index,
+            false,
+            DEFAULT_MAX_PAGE_LOAD,
+            false,
+        )?;
+        fs::remove_file(chunk_file)?;
+        info!("built hash page {}/{}", page_index, partition);
+    }
+
+    fs::copy(&options_filename, output_dir.join("opts.k2d"))?;
+    new_config.write_to_file(&output_config)?;
+    DbManifest::update(output_dir, &database.join("library"))?;
+    info!(
+        "subset database: {} of {} minimizers, load factor {:.2}",
+        new_config.size,
+        hash_config.size,
+        new_config.size as f64 / capacity as f64
+    );
+
+    info!("subset took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
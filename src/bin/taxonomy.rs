@@ -0,0 +1,143 @@
+use clap::{Parser, Subcommand};
+use kun_peng::taxonomy::Taxonomy;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+/// Most taxa listed when a name matches several of them
+const MAX_LISTED_MATCHES: usize = 20;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Query the taxonomy (taxo.k2d) of a database",
+    long_about = "Look up taxa in taxo.k2d by taxid or name and print their lineage, rank, children and internal id.
+Hash cells and the inspect dump store internal ids, which are mapped back to taxids here."
+)]
+pub struct Args {
+    /// database directory holding taxo.k2d
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    #[clap(subcommand)]
+    pub cmd: Query,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Query {
+    /// Print the lineage, rank, children and ids of a taxon
+    Lookup {
+        /// Taxid or scientific name (case-insensitive) of the taxon
+        query: String,
+
+        /// Read the query as an internal id of taxo.k2d instead of a taxid
+        #[clap(long, default_value_t = false)]
+        internal: bool,
+    },
+}
+
+/// Internal ids of the taxa matching a taxid, internal id or name
+fn find_taxa(taxo: &Taxonomy, query: &str, internal: bool) -> Vec<u32> {
+    if let Ok(id) = query.parse::<u64>() {
+        let internal_id = if internal {
+            if id > 0 && (id as usize) < taxo.node_count() {
+                id as u32
+            } else {
+                0
+            }
+        } else {
+            taxo.get_internal_id(id)
+        };
+        return if internal_id == 0 {
+            Vec::new()
+        } else {
+            vec![internal_id]
+        };
+    }
+
+    let query = query.to_lowercase();
+    let exact: Vec<u32> = (1..taxo.node_count() as u32)
+        .filter(|&id| taxo.name_of(id).to_lowercase() == query)
+        .collect();
+    if !exact.is_empty() {
+        return exact;
+    }
+    (1..taxo.node_count() as u32)
+        .filter(|&id| taxo.name_of(id).to_lowercase().contains(&query))
+        .collect()
+}
+
+/// Formats a taxon as `taxid name (rank)`
+fn describe(taxo: &Taxonomy, internal_id: u32) -> String {
+    format!(
+        "{} {} ({})",
+        taxo.nodes[internal_id as usize].external_id,
+        taxo.name_of(internal_id),
+        taxo.rank_of(internal_id)
+    )
+}
+
+fn print_taxon(taxo: &Taxonomy, internal_id: u32) {
+    let node = &taxo.nodes[internal_id as usize];
+    println!("taxid: {}", node.external_id);
+    println!("internal id: {}", internal_id);
+    println!("name: {}", taxo.name_of(internal_id));
+    println!("rank: {}", taxo.rank_of(internal_id));
+    if node.parent_id != 0 {
+        println!("parent: {}", describe(taxo, node.parent_id as u32));
+    }
+
+    // 沿 parent 回溯到根
+    let mut lineage = Vec::new();
+    let mut id = internal_id;
+    while id != 0 && lineage.len() < taxo.node_count() {
+        lineage.push(id);
+        id = taxo.nodes[id as usize].parent_id as u32;
+    }
+    let lineage: Vec<String> = lineage
+        .iter()
+        .rev()
+        .map(|&id| format!("{} ({})", taxo.name_of(id), taxo.rank_of(id)))
+        .collect();
+    println!("lineage: {}", lineage.join(" > "));
+
+    let children = taxo.children_of(internal_id);
+    println!("children: {}", children.len());
+    for child in children {
+        println!("  {}", describe(taxo, child));
+    }
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let taxo = Taxonomy::from_file(args.database.join("taxo.k2d"))?;
+    match args.cmd {
+        Query::Lookup { query, internal } => {
+            let taxa = find_taxa(&taxo, &query, internal);
+            if taxa.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no taxon matches {:?} in taxo.k2d", query),
+                ));
+            }
+            if taxa.len() == 1 {
+                print_taxon(&taxo, taxa[0]);
+                return Ok(());
+            }
+            println!("{} taxa match {:?}:", taxa.len(), query);
+            for &id in taxa.iter().take(MAX_LISTED_MATCHES) {
+                println!("  {}", describe(&taxo, id));
+            }
+            if taxa.len() > MAX_LISTED_MATCHES {
+                println!("  ...");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,287 @@
+use clap::Parser;
+use kun_peng::classifier::{Classifier, ClassifierOptions, ClassifySummary};
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::create_sample_file;
+use log::{debug, info, warn};
+use seqkmer::{FastxReader, OptionPair};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+
+/// Name of the report of all files classified so far
+const CUMULATIVE_REPORT_FILENAME: &str = "cumulative.kreport2";
+
+/// Extensions of the files picked up in the watched directory
+const FASTX_EXTENSIONS: [&str; 5] = [".fastq", ".fq", ".fasta", ".fa", ".fna"];
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Watch a directory and classify new FASTA/FASTQ files as they appear",
+    long_about = "Load all hash tables once, watch a directory (e.g. a MinION output folder) and classify every new FASTA/FASTQ file as it appears.
+
+A file is classified once its size stays the same between two scans. Every file gets output_{i}.txt and output_{i}.kreport2,
+and cumulative.kreport2 is rewritten after each file with all reads classified so far.
+Files listed in sample_file.map of the output directory are skipped, so a restarted watch continues the same run."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory for the per-file outputs and the cumulative report.
+    #[clap(long = "output-dir", required = true)]
+    pub output_dir: PathBuf,
+
+    /// Seconds between two scans of the watched directory.
+    #[clap(long, default_value_t = 10)]
+    pub interval: u64,
+
+    /// Classify the files present now and exit instead of watching.
+    #[clap(long, default_value_t = false)]
+    pub once: bool,
+
+    /// Minimum quality score for FASTQ data.
+    #[clap(
+        short = 'Q',
+        long = "minimum-quality-score",
+        value_parser,
+        default_value_t = 0
+    )]
+    pub minimum_quality_score: i32,
+
+    /// Confidence score threshold.
+    #[clap(
+        short = 'T',
+        long = "confidence-threshold",
+        value_parser,
+        default_value_t = 0.0
+    )]
+    pub confidence_threshold: f64,
+
+    /// Provide minimizer information in the reports
+    #[clap(short = 'K', long, value_parser, default_value_t = false)]
+    pub report_kmer_data: bool,
+
+    /// Report taxa w/ 0 count in the reports
+    #[clap(short = 'z', long, value_parser, default_value_t = false)]
+    pub report_zero_counts: bool,
+
+    /// The minimum number of hit groups needed for a call.
+    #[clap(
+        short = 'g',
+        long = "minimum-hit-groups",
+        value_parser,
+        default_value_t = 2
+    )]
+    pub minimum_hit_groups: usize,
+
+    /// The number of worker threads, capped by the global --threads.
+    #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
+    pub num_threads: usize,
+
+    /// Directory to watch, searched recursively (e.g. fastq_pass/ with barcode subdirectories).
+    pub input_dir: PathBuf,
+}
+
+fn is_fastx(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    FASTX_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Lists the FASTA/FASTQ files of the watched directory that are ready to be classified
+///
+/// # Arguments
+///
+/// * `input_dir` - The watched directory
+/// * `processed` - The files classified already
+/// * `sizes` - The file sizes seen by the previous scan, updated by this scan
+/// * `once` - Take every non-empty file instead of waiting for its size to settle
+///
+/// # Returns
+///
+/// The ready files, oldest first
+fn scan_ready_files(
+    input_dir: &Path,
+    processed: &HashSet<String>,
+    sizes: &mut HashMap<PathBuf, u64>,
+    once: bool,
+) -> Vec<PathBuf> {
+    let mut ready = Vec::new();
+    for entry in WalkDir::new(input_dir).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !entry.file_type().is_file()
+            || !is_fastx(path)
+            || processed.contains(&path.to_string_lossy().to_string())
+        {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let size = metadata.len();
+        let settled = sizes.insert(path.to_path_buf(), size) == Some(size);
+        if size > 0 && (once || settled) {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            ready.push((modified, path.to_path_buf()));
+        }
+    }
+    ready.sort();
+    ready.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Classifies one file into output_{i}.txt and output_{i}.kreport2 and adds it to the run
+fn process_file(
+    args: &Args,
+    classifier: &Classifier,
+    file_index: usize,
+    path: &Path,
+    cumulative: &mut ClassifySummary,
+) -> Result<()> {
+    let mut reader = FastxReader::from_paths(
+        OptionPair::Single(path),
+        file_index,
+        args.minimum_quality_score,
+    )?;
+    let filename = args.output_dir.join(format!("output_{}.txt", file_index));
+    let mut writer = BufWriter::new(File::create(filename)?);
+    // 写出失败后不再写, 错误在分类结束后返回
+    let mut written = Ok(());
+    let summary = classifier.classify_reader(&mut reader, |calls| {
+        for call in calls {
+            if written.is_ok() {
+                written = writeln!(writer, "{}", call);
+            }
+        }
+    })?;
+    written?;
+    writer.flush()?;
+
+    let mut report = BufWriter::new(File::create(
+        args.output_dir
+            .join(format!("output_{}.kreport2", file_index)),
+    )?);
+    summary.write_report(
+        &mut report,
+        classifier.taxonomy(),
+        args.report_zero_counts,
+        args.report_kmer_data,
+    )?;
+    report.flush()?;
+
+    cumulative.merge(&summary);
+    info!(
+        "{:?}: {} reads, {} classified; run total {} reads, {} classified",
+        path,
+        summary.total_seqs,
+        summary.total_seqs - summary.total_unclassified,
+        cumulative.total_seqs,
+        cumulative.total_seqs - cumulative.total_unclassified
+    );
+    Ok(())
+}
+
+/// Rewrites the cumulative report, replacing the previous one only once it is complete
+fn write_cumulative_report(
+    args: &Args,
+    taxonomy: &Taxonomy,
+    cumulative: &ClassifySummary,
+) -> Result<()> {
+    let filename = args.output_dir.join(CUMULATIVE_REPORT_FILENAME);
+    let tmp_filename = filename.with_extension("kreport2.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_filename)?);
+    cumulative.write_report(
+        &mut writer,
+        taxonomy,
+        args.report_zero_counts,
+        args.report_kmer_data,
+    )?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(tmp_filename, filename)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let options = ClassifierOptions {
+        confidence_threshold: args.confidence_threshold,
+        minimum_hit_groups: args.minimum_hit_groups,
+        num_threads: args.num_threads,
+    };
+    let classifier = Classifier::load(&args.database, options)?;
+    let taxo = classifier.taxonomy();
+    debug!("{:?}", classifier.hash_config());
+    info!("database loaded in {:?}", start.elapsed());
+
+    fs::create_dir_all(&args.output_dir)?;
+    let sample_file = args.output_dir.join("sample_file.map");
+    let mut cumulative = ClassifySummary::default();
+    let mut processed = HashSet::new();
+    let mut file_index = 0;
+    if sample_file.exists() {
+        for line in fs::read_to_string(&sample_file)?.lines() {
+            let Some((index, path)) = line.trim().split_once('\t') else {
+                continue;
+            };
+            let Ok(index) = index.parse::<usize>() else {
+                continue;
+            };
+            let report = args.output_dir.join(format!("output_{}.kreport2", index));
+            if report.exists() {
+                // 之前文件的 minimizer 计数不在报告中, 无法恢复
+                cumulative.merge(&ClassifySummary::read_report(report, taxo)?);
+                processed.insert(path.to_string());
+            }
+            file_index = file_index.max(index);
+        }
+        if !processed.is_empty() {
+            info!(
+                "continuing the run in {:?}: {} files, {} reads classified before",
+                args.output_dir,
+                processed.len(),
+                cumulative.total_seqs
+            );
+        }
+    }
+    let mut sample_writer = create_sample_file(&sample_file)?;
+    if !args.once {
+        info!("watching {:?} every {}s", args.input_dir, args.interval);
+    }
+
+    let mut sizes = HashMap::new();
+    loop {
+        let ready = scan_ready_files(&args.input_dir, &processed, &mut sizes, args.once);
+        for path in ready {
+            file_index += 1;
+            let path_str = path.to_string_lossy().to_string();
+            writeln!(sample_writer, "{}\t{}", file_index, path_str)?;
+            sample_writer.flush()?;
+
+            if let Err(e) = process_file(&args, &classifier, file_index, &path, &mut cumulative) {
+                warn!("failed to classify {:?}: {}", path, e);
+            }
+            processed.insert(path_str);
+            write_cumulative_report(&args, taxo, &cumulative)?;
+        }
+        if args.once {
+            break;
+        }
+        std::thread::sleep(Duration::from_secs(args.interval));
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
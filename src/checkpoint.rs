@@ -0,0 +1,205 @@
+use crate::utils::file_md5;
+use rayon::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the checkpoint file in the database directory
+pub const CHECKPOINT_FILENAME: &str = "build.checkpoint";
+
+/// Checkpoint key written when merge-fna has finished
+pub const MERGE_FNA_DONE: &str = "merge_fna";
+
+/// Checkpoint key written when all chunk files have been written
+pub const CHUNK_DONE: &str = "chunk";
+
+/// Name of the checkpoint file of a classify run in the chunk directory
+pub const CLASSIFY_CHECKPOINT_FILENAME: &str = "classify.checkpoint";
+
+/// Classify checkpoint key written when splitr has finished
+pub const SPLITR_DONE: &str = "splitr";
+
+/// Classify checkpoint key prefix of an annotated chunk file, e.g. `annotate:sample_3.k2`
+pub const ANNOTATE_DONE: &str = "annotate:";
+
+/// Classify checkpoint key prefix of a resolved partition, e.g. `resolve:1`
+pub const RESOLVE_DONE: &str = "resolve:";
+
+/// Suffix of the sentinel file written when a stage has finished, e.g. `splitr.done`
+pub const SENTINEL_SUFFIX: &str = ".done";
+
+/// Completed units of an interrupted database build
+///
+/// Every completed unit is appended to `build.checkpoint` as a `key\tvalue` line,
+/// so a rerun of `build`/`build-db` can skip it. The file is removed once the hash
+/// tables are complete. A classify run keeps the same kind of file,
+/// `classify.checkpoint`, in its chunk directory.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::checkpoint::{Checkpoint, CLASSIFY_CHECKPOINT_FILENAME, SPLITR_DONE};
+///
+/// let dir = std::env::temp_dir().join("kun_peng_checkpoint_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let path = dir.join(CLASSIFY_CHECKPOINT_FILENAME);
+/// let mut checkpoint = Checkpoint::load_file(&path).unwrap();
+/// checkpoint.mark(SPLITR_DONE, "").unwrap();
+/// checkpoint.mark("annotate:sample_1.k2", "sample_file_1_0.bin=32").unwrap();
+///
+/// let checkpoint = Checkpoint::load_file(&path).unwrap();
+/// assert!(checkpoint.is_done(SPLITR_DONE));
+/// assert_eq!(checkpoint.get("annotate:sample_1.k2"), Some("sample_file_1_0.bin=32"));
+/// checkpoint.remove().unwrap();
+/// assert!(!path.exists());
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct Checkpoint {
+    path: PathBuf,
+    entries: Vec<(String, String)>,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint of a database, empty if no build was interrupted
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - The database directory
+    ///
+    /// # Returns
+    ///
+    /// The checkpoint with the units completed so far
+    pub fn load<P: AsRef<Path>>(database: P) -> Result<Self> {
+        Self::load_file(database.as_ref().join(CHECKPOINT_FILENAME))
+    }
+
+    /// Loads a checkpoint file, empty if it does not exist
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = Vec::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let (key, value) = line.split_once('\t').unwrap_or((&line, ""));
+                entries.push((key.to_string(), value.to_string()));
+            }
+        }
+        Ok(Self { path, entries })
+    }
+
+    /// Whether the unit `key` has been completed
+    pub fn is_done(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The value recorded for the unit `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All recorded units in completion order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Records a completed unit and syncs the checkpoint file to disk
+    pub fn mark(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}\t{}", key, value)?;
+        file.sync_data()?;
+        self.entries.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Removes the checkpoint file after the build or classify run has finished
+    pub fn remove(self) -> Result<()> {
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sentinel file of `stage` in `dir`
+pub fn sentinel_path<P: AsRef<Path>>(dir: P, stage: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}{}", stage, SENTINEL_SUFFIX))
+}
+
+/// Removes the sentinel of a stage that is about to run again
+///
+/// A rerun that is interrupted must not leave the sentinel of the previous run behind.
+pub fn remove_sentinel<P: AsRef<Path>>(dir: P, stage: &str) -> Result<()> {
+    let path = sentinel_path(dir, stage);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Writes the sentinel file of a finished stage for workflow engines
+///
+/// The sentinel `<stage>.done` lists the md5 of every artifact of the stage in the
+/// format of `md5sum`, with paths relative to `dir`, so `md5sum -c <stage>.done` run in
+/// `dir` verifies them. Snakemake or Nextflow rules can depend on the sentinel instead of
+/// the artifacts. The checksums describe the artifacts when the stage finished; the next
+/// stage may consume them (annotate removes the splitr chunk files).
+///
+/// The sentinel is written to a temporary file and renamed, so it only exists complete.
+///
+/// # Arguments
+///
+/// * `dir` - The directory of the stage outputs, where the sentinel is written
+/// * `stage` - The stage name, e.g. `splitr`
+/// * `artifacts` - The files produced by the stage
+///
+/// # Returns
+///
+/// The path of the sentinel file
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::checkpoint::write_sentinel;
+///
+/// let dir = std::env::temp_dir().join("kun_peng_sentinel_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let artifact = dir.join("output_1.txt");
+/// std::fs::write(&artifact, "hello\n").unwrap();
+///
+/// let sentinel = write_sentinel(&dir, "resolve", &[artifact]).unwrap();
+/// assert_eq!(sentinel, dir.join("resolve.done"));
+/// assert_eq!(
+///     std::fs::read_to_string(&sentinel).unwrap(),
+///     "b1946ac92492d2347c6235b4d2611184  output_1.txt\n"
+/// );
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn write_sentinel<P: AsRef<Path>>(
+    dir: P,
+    stage: &str,
+    artifacts: &[PathBuf],
+) -> Result<PathBuf> {
+    let dir = dir.as_ref();
+    let checksums = artifacts
+        .par_iter()
+        .map(|artifact| file_md5(artifact))
+        .collect::<Result<Vec<String>>>()?;
+
+    let path = sentinel_path(dir, stage);
+    let tmp_path = path.with_extension("done.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for (artifact, md5) in artifacts.iter().zip(checksums) {
+        let name = artifact.strip_prefix(dir).unwrap_or(artifact);
+        writeln!(writer, "{}  {}", md5, name.display())?;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
@@ -0,0 +1,241 @@
+//! The slot streams of the chunk files `sample_{i}.k2` written by splitr and read by annotate
+//!
+//! A chunk file starts with a header of five little-endian u64: [`CHUNK_MAGIC`], the format
+//! version of the file, the index of the hash page its slots belong to, the chunk size and the
+//! file index of the first sample of its slots, which number the samples from there. The `Slot<u64>` records follow, 16 bytes each, which
+//! for short reads adds up to more than the input FASTQ. `splitr --compress-chunks` writes
+//! every batch of slots as a zstd frame instead and marks the header with
+//! [`CHUNK_ZSTD_FLAG`], so the chunk files of a run are read the way they were written.
+//! A [`Footer`] with the number of slots ends the file.
+use crate::compact_hash::Slot;
+use crate::error::Error;
+use crate::footer::{damaged, open_with_footer, Footer};
+use crate::format::{check_format_version, CHUNK_FORMAT_VERSION};
+use crate::utils::open_file;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The first word of the header of a chunk file
+pub const CHUNK_MAGIC: u64 = u64::from_le_bytes(*b"KPCHUNK\0");
+
+/// Bytes of the header of a chunk file
+pub const CHUNK_HEADER_BYTES: usize = 40;
+
+/// Flag in the page index word of the header of a chunk file whose slots are zstd frames
+pub const CHUNK_ZSTD_FLAG: u64 = 1 << 63;
+
+/// zstd level of compressed chunk files, the fastest: splitr writes them as fast as it reads
+pub const CHUNK_ZSTD_LEVEL: i32 = 1;
+
+/// The header of a chunk file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    /// The 0-based index of the hash page of the slots
+    pub page_index: usize,
+    pub chunk_size: usize,
+    /// Whether the slots are written as zstd frames
+    pub compressed: bool,
+    /// The file index of the sample numbered 1 in the slots
+    pub first_sample: usize,
+}
+
+impl ChunkHeader {
+    /// Writes the header
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::chunk_stream::ChunkHeader;
+    ///
+    /// let header = ChunkHeader {
+    ///     page_index: 3,
+    ///     chunk_size: 1 << 20,
+    ///     compressed: true,
+    ///     first_sample: 1001,
+    /// };
+    /// let mut bytes = Vec::new();
+    /// header.write(&mut bytes).unwrap();
+    /// assert_eq!(bytes.len(), 40);
+    /// let path = std::path::Path::new("sample_4.k2");
+    /// assert_eq!(ChunkHeader::read(&mut bytes.as_slice(), path).unwrap(), header);
+    ///
+    /// // 旧版本的头部没有 magic 和版本号, 其后紧跟 slot
+    /// let old: Vec<u8> = [3u64, 1 << 20, 1001, 7, 7].iter().flat_map(|w| w.to_le_bytes()).collect();
+    /// let error = ChunkHeader::read(&mut old.as_slice(), path).unwrap_err();
+    /// assert!(error.to_string().contains("older kun_peng"));
+    /// ```
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut index = self.page_index as u64;
+        if self.compressed {
+            index |= CHUNK_ZSTD_FLAG;
+        }
+        writer.write_all(&CHUNK_MAGIC.to_le_bytes())?;
+        writer.write_all(&CHUNK_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&index.to_le_bytes())?;
+        writer.write_all(&(self.chunk_size as u64).to_le_bytes())?;
+        writer.write_all(&(self.first_sample as u64).to_le_bytes())
+    }
+
+    /// Reads the header of the chunk file `path`
+    ///
+    /// Fails if the file was written by an older or a newer kun_peng.
+    pub fn read<R: Read>(reader: &mut R, path: &Path) -> io::Result<Self> {
+        let mut buffer = [0u8; CHUNK_HEADER_BYTES];
+        reader.read_exact(&mut buffer)?;
+        let word = |i: usize| u64::from_le_bytes(buffer[i * 8..i * 8 + 8].try_into().unwrap());
+        // 旧版本的头部直接从页编号开始
+        let version = if word(0) == CHUNK_MAGIC { word(1) } else { 0 };
+        // 旧版本的头部布局不同, 不能照常读取
+        if version < CHUNK_FORMAT_VERSION {
+            return Err(Error::FormatVersion {
+                path: path.to_path_buf(),
+                found: version,
+                supported: CHUNK_FORMAT_VERSION,
+                upgrade: "rerun splitr into an empty chunk directory".to_string(),
+            }
+            .into());
+        }
+        check_format_version(path, version, CHUNK_FORMAT_VERSION)?;
+        let index = word(2);
+        let chunk_size = word(3);
+        let first_sample = word(4);
+        Ok(Self {
+            page_index: (index & !CHUNK_ZSTD_FLAG) as usize,
+            chunk_size: chunk_size as usize,
+            compressed: index & CHUNK_ZSTD_FLAG != 0,
+            first_sample: first_sample as usize,
+        })
+    }
+
+    /// Reads the header of a chunk file
+    pub fn from_file<P: AsRef<Path>>(chunk_file: P) -> io::Result<Self> {
+        let path = chunk_file.as_ref();
+        Self::read(&mut open_file(path)?, path)
+    }
+}
+
+/// Encodes a batch of slot bytes to be appended to a chunk file
+///
+/// # Returns
+///
+/// The bytes as they are, or one zstd frame of them if `compressed`
+pub fn encode_slots(bytes: Vec<u8>, compressed: bool) -> io::Result<Vec<u8>> {
+    if compressed && !bytes.is_empty() {
+        zstd::bulk::compress(&bytes, CHUNK_ZSTD_LEVEL)
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// Fills the whole buffer of every read unless the stream ends, so that a batch reader never
+/// gets a slot split over two reads from a decoder
+struct FullReader<R>(R);
+
+impl<R: Read> Read for FullReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.0.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+/// Counts the slot bytes read from a chunk file and checks them against its footer at the end
+struct SlotCounter<R> {
+    inner: R,
+    bytes: u64,
+    footer: Footer,
+    path: PathBuf,
+}
+
+impl<R: Read> Read for SlotCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf).map_err(|e| {
+            // footer 的错误已带文件名, 其余多为 zstd 帧损坏
+            if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+                e
+            } else {
+                damaged(&self.path, format!("its slots cannot be read: {}", e))
+            }
+        })?;
+        self.bytes += n as u64;
+        let slot_size = Slot::<u64>::BYTES as u64;
+        if n == 0 && !buf.is_empty() && self.bytes != self.footer.records * slot_size {
+            return Err(damaged(
+                &self.path,
+                format!(
+                    "it holds {} slots but its footer counts {}",
+                    self.bytes / slot_size,
+                    self.footer.records
+                ),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Opens a chunk file for reading its slots, decompressing them if they are zstd frames
+///
+/// # Returns
+///
+/// The header and a reader positioned at the first slot. The reader fails at the end of the
+/// slots if they do not match the footer of the file.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::chunk_stream::{encode_slots, open_chunk_file, ChunkHeader};
+/// use kun_peng::footer::FooterWriter;
+/// use std::io::Read;
+///
+/// let chunk_file = std::env::temp_dir().join("kun_peng_chunk_stream_doctest.k2");
+/// let mut writer = FooterWriter::new(std::fs::File::create(&chunk_file).unwrap());
+/// let header = ChunkHeader {
+///     page_index: 1,
+///     chunk_size: 1024,
+///     compressed: true,
+///     first_sample: 1,
+/// };
+/// let mut header_bytes = Vec::new();
+/// header.write(&mut header_bytes).unwrap();
+/// writer.write_records(&header_bytes, 0).unwrap();
+/// // splitr 每批追加一个 zstd 帧
+/// writer.write_records(&encode_slots(vec![7u8; 32], true).unwrap(), 2).unwrap();
+/// writer.write_records(&encode_slots(vec![9u8; 16], true).unwrap(), 1).unwrap();
+/// writer.finish().unwrap();
+///
+/// let (read_header, mut reader) = open_chunk_file(&chunk_file).unwrap();
+/// assert_eq!(read_header, header);
+/// let mut slots = Vec::new();
+/// reader.read_to_end(&mut slots).unwrap();
+/// assert_eq!(slots, [vec![7u8; 32], vec![9u8; 16]].concat());
+/// std::fs::remove_file(&chunk_file).unwrap();
+/// ```
+pub fn open_chunk_file<P: AsRef<Path>>(
+    chunk_file: P,
+) -> io::Result<(ChunkHeader, Box<dyn Read + Send>)> {
+    let path = chunk_file.as_ref();
+    let (footer, reader) = open_with_footer(path)?;
+    let mut reader = BufReader::new(reader);
+    let header = ChunkHeader::read(&mut reader, path)?;
+    let slots: Box<dyn Read + Send> = if header.compressed {
+        // 解码器依次读出追加的多个帧
+        let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+        Box::new(FullReader(decoder))
+    } else {
+        Box::new(reader)
+    };
+    let counter = SlotCounter {
+        inner: slots,
+        bytes: 0,
+        footer,
+        path: path.to_path_buf(),
+    };
+    Ok((header, Box::new(counter)))
+}
@@ -0,0 +1,650 @@
+//! Classification of reads against a database loaded in memory
+//!
+//! [`Classifier`] loads all hash pages and the taxonomy of a database once, like the
+//! `direct` subcommand, and classifies any number of reads afterwards.
+//!
+//! ```no_run
+//! use kun_peng::classifier::{Classifier, ClassifierOptions};
+//!
+//! let classifier = Classifier::load("test_database", ClassifierOptions::default())?;
+//! let reads = vec![("read_1".to_string(), b"ACGTTGCA".to_vec())];
+//! let (calls, summary) = classifier.classify_reads(reads)?;
+//! for call in &calls {
+//!     println!("{}", call);
+//! }
+//! summary.write_report(&mut std::io::stdout(), classifier.taxonomy(), false, false)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::classify::process_hitgroup;
+use crate::compact_hash::{page_file_bytes, CHTable, Compact, HashConfig, Row};
+use crate::error::Error;
+use crate::input::CheckedReader;
+use crate::readcounts::{ReadCounter, TaxonCounters, TaxonCountersDash};
+use crate::report::{read_kraken_report, write_kraken_style};
+use crate::taxonomy::Taxonomy;
+use crate::utils::{find_and_sort_files, format_bytes, memory_budget, pipeline_threads};
+use crate::{HitGroup, IndexOptions};
+use seqkmer::{
+    read_parallel, Base, Meros, MinimizerIterator, OptionPair, Reader, SeqFormat, SeqHeader,
+};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of reads handed to one worker thread by [`Classifier::classify_reads`] and
+/// [`FastxStream`](crate::input::FastxStream)
+pub(crate) const BATCH_SIZE: usize = 10000;
+
+/// Options of a [`Classifier`]
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifierOptions {
+    /// Confidence score threshold
+    pub confidence_threshold: f64,
+    /// The minimum number of hit groups needed for a call
+    pub minimum_hit_groups: usize,
+    /// The number of threads used by one classification
+    pub num_threads: usize,
+}
+
+impl Default for ClassifierOptions {
+    fn default() -> Self {
+        Self {
+            confidence_threshold: 0.0,
+            minimum_hit_groups: 2,
+            num_threads: num_cpus::get(),
+        }
+    }
+}
+
+/// The call of one read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadCall {
+    /// The read ID
+    pub id: String,
+    /// Whether the read was assigned to a taxon
+    pub classified: bool,
+    /// External ID of the assigned taxon, 0 if unclassified
+    pub taxid: u64,
+    /// Length of the read, "len1|len2" for pairs
+    pub seq_len: String,
+    /// The k-mer mapping, e.g. "562:13 561:4 A:31"
+    pub hits: String,
+}
+
+impl fmt::Display for ReadCall {
+    /// Formats the call as a line of the Kraken output, without the newline
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::classifier::ReadCall;
+    ///
+    /// let call = ReadCall {
+    ///     id: "read_1".to_string(),
+    ///     classified: true,
+    ///     taxid: 562,
+    ///     seq_len: "150".to_string(),
+    ///     hits: "562:13 0:103".to_string(),
+    /// };
+    /// assert_eq!(call.to_string(), "C\tread_1\t562\t150\t562:13 0:103");
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            if self.classified { "C" } else { "U" },
+            self.id,
+            self.taxid,
+            self.seq_len,
+            self.hits
+        )
+    }
+}
+
+/// Read counts of one or more classifications
+#[derive(Default)]
+pub struct ClassifySummary {
+    /// Reads and minimizers per internal taxon ID
+    pub taxon_counts: TaxonCounters,
+    /// The number of reads
+    pub total_seqs: u64,
+    /// The number of unclassified reads
+    pub total_unclassified: u64,
+}
+
+impl ClassifySummary {
+    /// Reads the read counts back from a Kraken-style report written by [`Self::write_report`]
+    ///
+    /// Minimizer counts are not restored, so a report of merged summaries has none.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The report file
+    /// * `taxonomy` - The taxonomy of the database the report was written with
+    pub fn read_report<P: AsRef<Path>>(filename: P, taxonomy: &Taxonomy) -> io::Result<Self> {
+        let mut summary = Self::default();
+        for entry in read_kraken_report(filename)? {
+            summary.total_seqs += entry.taxon_reads;
+            if entry.taxid == 0 {
+                summary.total_unclassified += entry.taxon_reads;
+                continue;
+            }
+            let internal_id = taxonomy.get_internal_id(entry.taxid);
+            if internal_id == 0 || entry.taxon_reads == 0 {
+                continue;
+            }
+            summary
+                .taxon_counts
+                .entry(internal_id as u64)
+                .or_default()
+                .merge(&ReadCounter::new(entry.taxon_reads, 0))
+                .unwrap();
+        }
+        Ok(summary)
+    }
+
+    /// Adds the counts of another classification
+    pub fn merge(&mut self, other: &ClassifySummary) {
+        for (taxid, counter) in &other.taxon_counts {
+            self.taxon_counts
+                .entry(*taxid)
+                .or_default()
+                .merge(counter)
+                .unwrap();
+        }
+        self.total_seqs += other.total_seqs;
+        self.total_unclassified += other.total_unclassified;
+    }
+
+    /// Writes the Kraken-style report (kreport2) of the counts
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer of the report
+    /// * `taxonomy` - The taxonomy of the database, see [`Classifier::taxonomy`]
+    /// * `report_zeros` - Whether to report taxa with zero counts
+    /// * `report_kmer_data` - Whether to report minimizer counts
+    pub fn write_report<W: Write>(
+        &self,
+        writer: &mut W,
+        taxonomy: &Taxonomy,
+        report_zeros: bool,
+        report_kmer_data: bool,
+    ) -> io::Result<()> {
+        write_kraken_style(
+            writer,
+            report_zeros,
+            report_kmer_data,
+            taxonomy,
+            &self.taxon_counts,
+            self.total_seqs,
+            self.total_unclassified,
+        )
+    }
+}
+
+/// Hands reads kept in memory to `read_parallel` in batches
+struct VecReader {
+    records: Vec<Base<Vec<u8>>>,
+}
+
+impl Reader for VecReader {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        if self.records.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.records.split_off(self.records.len().min(BATCH_SIZE));
+        Ok(Some(std::mem::replace(&mut self.records, rest)))
+    }
+}
+
+/// Batch counts of the samples read by a [`SampleChain`]
+///
+/// The sample of a read is told by its `header.file_index`: sample `i` (from 0) is read with
+/// the file index `first_file_index + i + 1`. A consumer counting the batches it handled per
+/// sample learns from [`SampleProgress::is_complete`] when a sample is done.
+pub struct SampleProgress {
+    first_file_index: usize,
+    batches: Vec<AtomicUsize>,
+    read: Vec<AtomicBool>,
+}
+
+impl SampleProgress {
+    /// The progress of `samples` samples numbered after `first_file_index`
+    pub fn new(first_file_index: usize, samples: usize) -> Self {
+        Self {
+            first_file_index,
+            batches: (0..samples).map(|_| AtomicUsize::new(0)).collect(),
+            read: (0..samples).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// The number of samples
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// The file index of the reads of a sample
+    pub fn file_index(&self, sample: usize) -> usize {
+        self.first_file_index + sample + 1
+    }
+
+    /// The sample of a read, from its file index
+    pub fn sample(&self, file_index: usize) -> usize {
+        file_index - self.first_file_index - 1
+    }
+
+    /// Whether a sample is read in full and all its batches are handled
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - The sample
+    /// * `handled` - The number of batches of the sample the caller has handled
+    pub fn is_complete(&self, sample: usize, handled: usize) -> bool {
+        // 先确认已读完, 批次数随后不再变化
+        self.read[sample].load(Ordering::SeqCst)
+            && self.batches[sample].load(Ordering::SeqCst) == handled
+    }
+}
+
+/// Reads several samples one after the other as a single stream
+///
+/// The samples are opened one at a time, when the previous one is exhausted, so a run over
+/// many samples keeps one read pipeline busy instead of starting and draining one per sample.
+/// Every batch holds reads of a single sample, whose file index is set as told by
+/// [`SampleProgress`].
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::classifier::{SampleChain, SampleProgress};
+/// use kun_peng::input::FastxStream;
+/// use seqkmer::Reader;
+///
+/// // 第二个样本为空, 不产生批次
+/// let samples = [">a\nACGT\n>b\nACGA\n", "", ">c\nTTGA\n"];
+/// let progress = SampleProgress::new(0, samples.len());
+/// let mut chain = SampleChain::new(&progress, |sample| {
+///     Ok(FastxStream::new(samples[sample].as_bytes(), 0, 0))
+/// });
+/// let mut file_indexes = Vec::new();
+/// while let Some(batch) = chain.next().unwrap() {
+///     file_indexes.push((batch[0].header.file_index, batch.len()));
+/// }
+/// assert_eq!(file_indexes, vec![(1, 2), (3, 1)]);
+/// assert!(progress.is_complete(0, 1) && progress.is_complete(1, 0) && progress.is_complete(2, 1));
+/// ```
+pub struct SampleChain<'a, R, F> {
+    progress: &'a SampleProgress,
+    open: F,
+    current: Option<(usize, R)>,
+    next_sample: usize,
+}
+
+impl<'a, R, F> SampleChain<'a, R, F>
+where
+    R: Reader,
+    F: FnMut(usize) -> io::Result<R> + Send,
+{
+    /// Chains the samples of `progress`, opened by `open` with the sample number
+    pub fn new(progress: &'a SampleProgress, open: F) -> Self {
+        Self {
+            progress,
+            open,
+            current: None,
+            next_sample: 0,
+        }
+    }
+
+    fn next_batch(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        loop {
+            let (sample, reader) = match self.current.as_mut() {
+                Some((sample, reader)) => (*sample, reader),
+                None if self.next_sample < self.progress.len() => {
+                    let reader = (self.open)(self.next_sample)?;
+                    self.current = Some((self.next_sample, reader));
+                    self.next_sample += 1;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            match reader.next()? {
+                Some(mut records) if !records.is_empty() => {
+                    let file_index = self.progress.file_index(sample);
+                    for record in &mut records {
+                        record.header.file_index = file_index;
+                    }
+                    self.progress.batches[sample].fetch_add(1, Ordering::SeqCst);
+                    return Ok(Some(records));
+                }
+                Some(_) => continue,
+                None => {
+                    self.progress.read[sample].store(true, Ordering::SeqCst);
+                    self.current = None;
+                }
+            }
+        }
+    }
+}
+
+impl<R, F> Reader for SampleChain<'_, R, F>
+where
+    R: Reader,
+    F: FnMut(usize) -> io::Result<R> + Send,
+{
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        self.next_batch()
+    }
+}
+
+/// A database loaded in memory, ready to classify reads
+pub struct Classifier {
+    options: ClassifierOptions,
+    meros: Meros,
+    hash_config: HashConfig,
+    chtable: CHTable,
+    taxonomy: Taxonomy,
+}
+
+impl Classifier {
+    /// Loads the options, the taxonomy and all hash pages of a database
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - The database directory
+    /// * `options` - The classification options
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the classifier, an error if the hash pages are larger than
+    /// the memory budget
+    pub fn load<P: AsRef<Path>>(database: P, options: ClassifierOptions) -> io::Result<Self> {
+        let database = database.as_ref();
+        let idx_opts = IndexOptions::read_index_options(database.join("opts.k2d"))?;
+        let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+        let hash_config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+        if hash_config.hash_capacity == 0 {
+            return Err(Error::InvalidDatabase {
+                path: database.join("hash_config.k2d"),
+                reason: "hash_capacity is 0".to_string(),
+            }
+            .into());
+        }
+
+        let hash_files = find_and_sort_files(database, "hash", hash_config.page_suffix(), true)?;
+        if let Some(budget) = memory_budget() {
+            let mut table_bytes = 0;
+            for hash_file in &hash_files {
+                table_bytes += page_file_bytes(hash_file)?;
+            }
+            if table_bytes as usize > budget {
+                return Err(Error::Resources(format!(
+                    "the hash pages ({}) are larger than the memory budget of {}; use classify, which loads one page at a time",
+                    format_bytes(table_bytes as f64),
+                    format_bytes(budget as f64)
+                ))
+                .into());
+            }
+        }
+        let chtable = CHTable::from_hash_files(hash_config, &hash_files)?;
+
+        Ok(Self {
+            options,
+            meros: idx_opts.as_meros(),
+            hash_config,
+            chtable,
+            taxonomy,
+        })
+    }
+
+    /// The options of the classifier
+    pub fn options(&self) -> &ClassifierOptions {
+        &self.options
+    }
+
+    /// The hash table configuration of the database
+    pub fn hash_config(&self) -> &HashConfig {
+        &self.hash_config
+    }
+
+    /// The taxonomy of the database
+    pub fn taxonomy(&self) -> &Taxonomy {
+        &self.taxonomy
+    }
+
+    /// Looks up the minimizers of a read in the hash pages
+    ///
+    /// # Returns
+    ///
+    /// The hits of the read, used by the functions of [`crate::classify`]
+    pub fn hits(&self, record: &mut Base<MinimizerIterator>) -> HitGroup {
+        let hash_config = &self.hash_config;
+        let chunk_size = hash_config.hash_capacity;
+        let value_bits = hash_config.value_bits;
+        let rows: Vec<Row> = record.fold(|rows, m_iter, offset| {
+            for (sort, hash_key) in m_iter.by_ref() {
+                let (idx, compacted) = hash_config.compact(hash_key);
+                let partition_index = idx / chunk_size;
+                let index = idx % chunk_size;
+
+                let taxid = self
+                    .chtable
+                    .get_from_page(index, compacted, partition_index);
+                if taxid > 0 {
+                    let high = if hash_config.is_wide() {
+                        taxid
+                    } else {
+                        u32::combined(compacted, taxid, value_bits)
+                    };
+                    rows.push(Row::new(high, 0, sort as u32 + 1 + offset as u32));
+                }
+            }
+            m_iter.size + offset
+        });
+        HitGroup::new(rows, record.range())
+    }
+
+    /// Classifies one read
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The minimizers of the read
+    /// * `taxon_counts` - The counters the read is added to
+    /// * `classify_counter` - Incremented when the read is classified
+    pub fn classify_record(
+        &self,
+        record: &mut Base<MinimizerIterator>,
+        taxon_counts: &TaxonCountersDash,
+        classify_counter: &AtomicUsize,
+    ) -> ReadCall {
+        let hits = self.hits(record);
+        let required_score = hits.required_score(self.options.confidence_threshold);
+        let (classified, taxid, hit_string, counts) = process_hitgroup(
+            &hits,
+            &self.taxonomy,
+            classify_counter,
+            required_score,
+            self.options.minimum_hit_groups,
+            self.hash_config.value_mask,
+        );
+        counts.iter().for_each(|(key, value)| {
+            taxon_counts.entry(*key).or_default().merge(value).unwrap();
+        });
+
+        ReadCall {
+            id: record.header.id.clone(),
+            classified: classified == "C",
+            taxid,
+            seq_len: record.fmt_seq_size(),
+            hits: hit_string,
+        }
+    }
+
+    /// Classifies the reads of a reader, e.g. a `seqkmer::FastxReader`
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reads to classify
+    /// * `on_calls` - Called with the calls of every batch of reads, in no particular order
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the read counts of the classification, or the first error of
+    /// the reader
+    pub fn classify_reader<R, F>(
+        &self,
+        reader: &mut R,
+        mut on_calls: F,
+    ) -> io::Result<ClassifySummary>
+    where
+        R: Reader,
+        F: FnMut(Vec<ReadCall>) + Send,
+    {
+        let taxon_counts = TaxonCountersDash::new();
+        let seq_counter = AtomicUsize::new(0);
+        let classify_counter = AtomicUsize::new(0);
+
+        let mut reader = CheckedReader::new(reader);
+        read_parallel(
+            &mut reader,
+            pipeline_threads(self.options.num_threads),
+            &self.meros,
+            |seqs| {
+                seq_counter.fetch_add(seqs.len(), Ordering::SeqCst);
+                seqs.iter_mut()
+                    .map(|record| self.classify_record(record, &taxon_counts, &classify_counter))
+                    .collect::<Vec<_>>()
+            },
+            |dataset| {
+                while let Some(item) = dataset.next() {
+                    on_calls(item.unwrap());
+                }
+            },
+        )?;
+        reader.finish()?;
+
+        let total_seqs = seq_counter.load(Ordering::SeqCst) as u64;
+        Ok(ClassifySummary {
+            taxon_counts: taxon_counts.into_iter().collect(),
+            total_seqs,
+            total_unclassified: total_seqs - classify_counter.load(Ordering::SeqCst) as u64,
+        })
+    }
+
+    /// Classifies several samples through a single read pipeline
+    ///
+    /// The hash pages are loaded once and shared by all samples, so a batch of samples costs
+    /// about as much as their reads as one sample: there is no pipeline to start and drain per
+    /// sample. The samples stream through in the given order, see [`SampleChain`].
+    ///
+    /// # Arguments
+    ///
+    /// * `readers` - The reads of every sample
+    /// * `on_calls` - Called with the sample number (from 0) and the calls of every batch of
+    ///   reads, in no particular order
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the read counts of every sample, or the first error of the
+    /// readers
+    pub fn classify_samples<R, F>(
+        &self,
+        readers: Vec<R>,
+        mut on_calls: F,
+    ) -> io::Result<Vec<ClassifySummary>>
+    where
+        R: Reader,
+        F: FnMut(usize, Vec<ReadCall>) + Send,
+    {
+        let progress = SampleProgress::new(0, readers.len());
+        let counters: Vec<(TaxonCountersDash, AtomicUsize, AtomicUsize)> =
+            (0..readers.len()).map(|_| Default::default()).collect();
+        let mut readers = readers.into_iter();
+        let mut chain = SampleChain::new(&progress, |_| {
+            readers
+                .next()
+                .ok_or_else(|| io::Error::other("no reader left for the sample"))
+        });
+
+        let mut reader = CheckedReader::new(&mut chain);
+        read_parallel(
+            &mut reader,
+            pipeline_threads(self.options.num_threads),
+            &self.meros,
+            |seqs| {
+                let sample = progress.sample(seqs[0].header.file_index);
+                let (taxon_counts, seq_counter, classify_counter) = &counters[sample];
+                seq_counter.fetch_add(seqs.len(), Ordering::SeqCst);
+                let calls = seqs
+                    .iter_mut()
+                    .map(|record| self.classify_record(record, taxon_counts, classify_counter))
+                    .collect::<Vec<_>>();
+                (sample, calls)
+            },
+            |dataset| {
+                while let Some(item) = dataset.next() {
+                    let (sample, calls) = item.unwrap();
+                    on_calls(sample, calls);
+                }
+            },
+        )?;
+        reader.finish()?;
+
+        Ok(counters
+            .into_iter()
+            .map(|(taxon_counts, seq_counter, classify_counter)| {
+                let total_seqs = seq_counter.into_inner() as u64;
+                ClassifySummary {
+                    taxon_counts: taxon_counts.into_iter().collect(),
+                    total_seqs,
+                    total_unclassified: total_seqs - classify_counter.into_inner() as u64,
+                }
+            })
+            .collect())
+    }
+
+    /// Classifies reads given as IDs and sequences
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the calls, in no particular order, and the read counts
+    pub fn classify_reads<I>(&self, reads: I) -> io::Result<(Vec<ReadCall>, ClassifySummary)>
+    where
+        I: IntoIterator<Item = (String, Vec<u8>)>,
+    {
+        let records = reads
+            .into_iter()
+            .enumerate()
+            .map(|(reads_index, (id, seq))| {
+                let header = SeqHeader {
+                    id,
+                    file_index: 0,
+                    reads_index,
+                    format: SeqFormat::Fasta,
+                };
+                Base::new(header, OptionPair::Single(seq))
+            })
+            .collect();
+        self.classify_records(records)
+    }
+
+    /// Classifies records kept in memory, e.g. from [`parse_fastx`](crate::input::parse_fastx)
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the calls, in no particular order, and the read counts
+    pub fn classify_records(
+        &self,
+        records: Vec<Base<Vec<u8>>>,
+    ) -> io::Result<(Vec<ReadCall>, ClassifySummary)> {
+        let mut calls = Vec::new();
+        let summary =
+            self.classify_reader(&mut VecReader { records }, |batch| calls.extend(batch))?;
+        Ok((calls, summary))
+    }
+}
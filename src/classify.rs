@@ -167,3 +167,299 @@ pub fn process_hitgroup(
 
     (clasify.to_owned(), ext_call, hit_string, cur_taxon_counts)
 }
+
+/// Refines a species-level call to the best supported strain below it.
+///
+/// The read's hits that fall inside the species clade are re-scored against each
+/// direct child of the species. The child whose subtree collects the most hits is
+/// reported together with the fraction of the clade's hits that support it.
+///
+/// # Arguments
+///
+/// * `hits` - The HitGroup of the read.
+/// * `taxonomy` - The Taxonomy object representing the taxonomic hierarchy.
+/// * `call` - The internal ID of the taxon the read was assigned to.
+/// * `value_mask` - A mask used for processing hit values.
+///
+/// # Returns
+///
+/// Returns the internal ID of the best strain and its support fraction, or `None`
+/// if the call is not a species, no hit reaches below the species or the best
+/// strains are tied.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::classify::refine_strain;
+/// use kun_peng::compact_hash::Row;
+/// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+/// use kun_peng::HitGroup;
+/// use seqkmer::OptionPair;
+///
+/// // root (1) 之下是物种 A (2) 和 B (3), A 之下是菌株 A1 (4) 和 A2 (5)
+/// let mut taxo = Taxonomy::default();
+/// taxo.rank_data = b"no rank\0species\0strain\0".to_vec();
+/// taxo.nodes.push(TaxonomyNode::default());
+/// for (parent_id, first_child, child_count, rank_offset, external_id) in
+///     [(0, 2, 2, 0, 1), (1, 4, 2, 8, 100), (1, 0, 0, 8, 200), (2, 0, 0, 16, 101), (2, 0, 0, 16, 102)]
+/// {
+///     taxo.nodes.push(TaxonomyNode {
+///         parent_id,
+///         first_child,
+///         child_count,
+///         rank_offset,
+///         external_id,
+///         ..Default::default()
+///     });
+/// }
+/// taxo.generate_external_to_internal_id_map();
+/// taxo.build_path_cache();
+///
+/// // 每个 k-mer 一个命中, 值即内部 ID
+/// let hits = |taxa: &[u32]| {
+///     let rows = taxa.iter().zip(1..).map(|(&taxon, kmer_id)| Row::new(taxon, 0, kmer_id)).collect();
+///     HitGroup::new(rows, OptionPair::Single((0, taxa.len())))
+/// };
+/// let (strain, support) = refine_strain(&hits(&[4, 4, 4, 5, 2]), &taxo, 2, 0xFFFF).unwrap();
+/// assert_eq!((taxo.nodes[strain as usize].external_id, support), (101, 0.6));
+/// // 两个菌株得分相同, 停在物种
+/// assert_eq!(refine_strain(&hits(&[4, 5, 2]), &taxo, 2, 0xFFFF), None);
+/// ```
+pub fn refine_strain(
+    hits: &HitGroup,
+    taxonomy: &Taxonomy,
+    call: u32,
+    value_mask: usize,
+) -> Option<(u32, f64)> {
+    if call == 0 || taxonomy.rank_of(call) != "species" {
+        return None;
+    }
+
+    let mut clade_hits = 0u64;
+    let mut counts: HashMap<u32, u64> = HashMap::new();
+    for row in &hits.rows {
+        let taxon = row.value.right(value_mask);
+        if taxon == call || taxonomy.is_a_ancestor_of_b(call, taxon) {
+            clade_hits += 1;
+            *counts.entry(taxon).or_insert(0) += 1;
+        }
+    }
+
+    let scores: Vec<(u32, u64)> = taxonomy
+        .children_of(call)
+        .map(|strain| {
+            let score: u64 = counts
+                .iter()
+                .filter(|(&taxon, _)| taxon == strain || taxonomy.is_a_ancestor_of_b(strain, taxon))
+                .map(|(_, &count)| count)
+                .sum();
+            (strain, score)
+        })
+        .collect();
+    let &(best_strain, best_score) = scores.iter().max_by_key(|&&(_, score)| score)?;
+
+    // 并列最高的菌株无法区分, 停在物种
+    let tied = scores
+        .iter()
+        .filter(|&&(_, score)| score == best_score)
+        .count()
+        > 1;
+    if best_score == 0 || tied {
+        return None;
+    }
+
+    Some((best_strain, best_score as f64 / clade_hits as f64))
+}
+
+/// The taxonomic call of one window of a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowCall {
+    /// First k-mer position of the window (inclusive)
+    pub start: usize,
+    /// Last k-mer position of the window (exclusive)
+    pub end: usize,
+    /// Internal ID of the taxon the window resolves to
+    pub call: u32,
+}
+
+/// Detects reads whose windows resolve to incompatible taxa.
+///
+/// The read is cut into windows of `window` k-mer positions every `stride` positions,
+/// and each window with at least `minimum_hits` hits is resolved on its own. Two calls
+/// are incompatible when neither is an ancestor of the other. Breakpoints are placed
+/// halfway between the centers of adjacent windows with incompatible calls.
+///
+/// # Arguments
+///
+/// * `hits` - The HitGroup of the read.
+/// * `taxonomy` - The Taxonomy object representing the taxonomic hierarchy.
+/// * `value_mask` - A mask used for processing hit values.
+/// * `window` - The window size in k-mer positions.
+/// * `stride` - The distance between the starts of two windows.
+/// * `minimum_hits` - The minimum number of hits needed to call a window.
+///
+/// # Returns
+///
+/// Returns the called windows and the approximate breakpoints if the read looks chimeric.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::classify::detect_chimera;
+/// use kun_peng::compact_hash::Row;
+/// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+/// use kun_peng::HitGroup;
+/// use seqkmer::OptionPair;
+///
+/// // root (1) 之下是物种 A (2) 和 B (3), A 之下是菌株 A1 (4) 和 A2 (5)
+/// let mut taxo = Taxonomy::default();
+/// taxo.rank_data = b"no rank\0species\0strain\0".to_vec();
+/// taxo.nodes.push(TaxonomyNode::default());
+/// for (parent_id, first_child, child_count, rank_offset, external_id) in
+///     [(0, 2, 2, 0, 1), (1, 4, 2, 8, 100), (1, 0, 0, 8, 200), (2, 0, 0, 16, 101), (2, 0, 0, 16, 102)]
+/// {
+///     taxo.nodes.push(TaxonomyNode {
+///         parent_id,
+///         first_child,
+///         child_count,
+///         rank_offset,
+///         external_id,
+///         ..Default::default()
+///     });
+/// }
+/// taxo.generate_external_to_internal_id_map();
+/// taxo.build_path_cache();
+///
+/// // 20 个 k-mer 的 read, 每个 k-mer 一个命中, 值即内部 ID
+/// let hits = |first: u32, second: u32| {
+///     let rows = (1..=20).map(|kmer_id| Row::new(if kmer_id <= 10 { first } else { second }, 0, kmer_id)).collect();
+///     HitGroup::new(rows, OptionPair::Single((0, 20)))
+/// };
+/// // 菌株 A1 接物种 B: 不相容, 断点在两个窗口之间
+/// let (windows, breakpoints) = detect_chimera(&hits(4, 3), &taxo, 0xFFFF, 10, 10, 1).unwrap();
+/// assert_eq!(windows.iter().map(|w| w.call).collect::<Vec<_>>(), vec![4, 3]);
+/// assert_eq!(breakpoints, vec![10]);
+/// // 物种 A 接其菌株 A1: 同一谱系, 不是嵌合
+/// assert!(detect_chimera(&hits(2, 4), &taxo, 0xFFFF, 10, 10, 1).is_none());
+/// ```
+pub fn detect_chimera(
+    hits: &HitGroup,
+    taxonomy: &Taxonomy,
+    value_mask: usize,
+    window: usize,
+    stride: usize,
+    minimum_hits: usize,
+) -> Option<(Vec<WindowCall>, Vec<usize>)> {
+    let length = hits.capacity();
+    if window == 0 || stride == 0 || length <= window {
+        return None;
+    }
+
+    let mut windows = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let end = (start + window).min(length);
+        let mut counts: HashMap<u32, u64> = HashMap::new();
+        // kmer_id is 1-based
+        for row in hits
+            .rows
+            .iter()
+            .filter(|row| (row.kmer_id as usize) > start && (row.kmer_id as usize) <= end)
+        {
+            *counts.entry(row.value.right(value_mask)).or_insert(0) += 1;
+        }
+        let total: u64 = counts.values().sum();
+        if total as usize >= minimum_hits.max(1) {
+            let call = resolve_tree(&counts, taxonomy, 0);
+            if call > 0 {
+                windows.push(WindowCall { start, end, call });
+            }
+        }
+        if end == length {
+            break;
+        }
+        start += stride;
+    }
+
+    let compatible = |a: u32, b: u32| {
+        a == b || taxonomy.is_a_ancestor_of_b(a, b) || taxonomy.is_a_ancestor_of_b(b, a)
+    };
+
+    let breakpoints: Vec<usize> = windows
+        .windows(2)
+        .filter(|pair| !compatible(pair[0].call, pair[1].call))
+        .map(|pair| (pair[0].start + pair[0].end + pair[1].start + pair[1].end) / 4)
+        .collect();
+
+    if breakpoints.is_empty() {
+        None
+    } else {
+        Some((windows, breakpoints))
+    }
+}
+
+/// Formats the result of `detect_chimera` as a tab separated line
+///
+/// The columns are the read id, the calls of all windows as `start-end:taxid`,
+/// and the approximate breakpoints, both comma separated.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::classify::{detect_chimera, format_chimera};
+/// use kun_peng::compact_hash::Row;
+/// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+/// use kun_peng::HitGroup;
+/// use seqkmer::OptionPair;
+///
+/// // root (1) 之下是物种 A (2) 和 B (3), A 之下是菌株 A1 (4) 和 A2 (5)
+/// let mut taxo = Taxonomy::default();
+/// taxo.rank_data = b"no rank\0species\0strain\0".to_vec();
+/// taxo.nodes.push(TaxonomyNode::default());
+/// for (parent_id, first_child, child_count, rank_offset, external_id) in
+///     [(0, 2, 2, 0, 1), (1, 4, 2, 8, 100), (1, 0, 0, 8, 200), (2, 0, 0, 16, 101), (2, 0, 0, 16, 102)]
+/// {
+///     taxo.nodes.push(TaxonomyNode {
+///         parent_id,
+///         first_child,
+///         child_count,
+///         rank_offset,
+///         external_id,
+///         ..Default::default()
+///     });
+/// }
+/// taxo.generate_external_to_internal_id_map();
+/// taxo.build_path_cache();
+///
+/// let hits = |first: u32, second: u32| {
+///     let rows = (1..=20).map(|kmer_id| Row::new(if kmer_id <= 10 { first } else { second }, 0, kmer_id)).collect();
+///     HitGroup::new(rows, OptionPair::Single((0, 20)))
+/// };
+/// let (windows, breakpoints) = detect_chimera(&hits(4, 3), &taxo, 0xFFFF, 10, 10, 1).unwrap();
+/// assert_eq!(format_chimera("read1", &taxo, &windows, &breakpoints), "read1\t0-10:101,10-20:200\t10\n");
+/// // 相容的谱系没有嵌合行可写
+/// assert!(detect_chimera(&hits(5, 2), &taxo, 0xFFFF, 10, 10, 1).is_none());
+/// ```
+pub fn format_chimera(
+    id: &str,
+    taxonomy: &Taxonomy,
+    windows: &[WindowCall],
+    breakpoints: &[usize],
+) -> String {
+    let windows = windows
+        .iter()
+        .map(|w| {
+            format!(
+                "{}-{}:{}",
+                w.start, w.end, taxonomy.nodes[w.call as usize].external_id
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let breakpoints = breakpoints
+        .iter()
+        .map(|pos| pos.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{}\t{}\t{}\n", id, windows, breakpoints)
+}
@@ -1,6 +1,11 @@
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 #[cfg(target_endian = "little")]
 use bytemuck::cast_slice_mut;
+use crate::error::Error;
+use crate::numa::place;
+use crate::prefilter::Prefilter;
+use crate::read_ahead::{open_db_file, will_need};
+use memmap2::Mmap;
 use std::cmp::Ordering as CmpOrdering;
 use std::fmt::{self, Debug};
 use std::fs::File;
@@ -179,10 +184,40 @@ impl Row {
             kmer_id,
         }
     }
+
+    /// Bytes of a row in the sample bin files
+    pub const BYTES: usize = 12;
+
+    /// The bytes of the row in the sample bin files: `value`, `seq_id` and `kmer_id` as
+    /// little-endian u32 on every platform
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::Row;
+    ///
+    /// let row = Row::new(0x1234, 1, 2);
+    /// let bytes = row.to_le_bytes();
+    /// assert_eq!(bytes, [0x34, 0x12, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    /// assert_eq!(Row::from_le_bytes(&bytes), row);
+    /// ```
     #[inline]
-    pub fn as_slice(&self, row_size: usize) -> &[u8] {
-        let slot_ptr = self as *const Self as *const u8;
-        unsafe { std::slice::from_raw_parts(slot_ptr, row_size) }
+    pub fn to_le_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
+        bytes[0..4].copy_from_slice(&self.value.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seq_id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.kmer_id.to_le_bytes());
+        bytes
+    }
+
+    /// Reads a row written by [`Row::to_le_bytes`]
+    #[inline]
+    pub fn from_le_bytes(bytes: &[u8; Self::BYTES]) -> Self {
+        Self::new(
+            LittleEndian::read_u32(&bytes[0..4]),
+            LittleEndian::read_u32(&bytes[4..8]),
+            LittleEndian::read_u32(&bytes[8..12]),
+        )
     }
 }
 
@@ -228,15 +263,55 @@ where
     pub fn new(idx: usize, value: B) -> Self {
         Self { idx, value }
     }
+}
 
+impl Slot<u64> {
+    /// Bytes of a slot in the chunk files
+    pub const BYTES: usize = 16;
+
+    /// The bytes of the slot in the chunk files: `idx` and `value` as little-endian u64 on
+    /// every platform
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::Slot;
+    ///
+    /// let slot = Slot::<u64>::new(3, 0x0102);
+    /// let bytes = slot.to_le_bytes();
+    /// assert_eq!(bytes[..8], 3u64.to_le_bytes());
+    /// assert_eq!(bytes[8..], 0x0102u64.to_le_bytes());
+    /// assert!(Slot::<u64>::from_le_bytes(&bytes) == slot);
+    /// // 按内存布局读出的 slot 换成本机字节序
+    /// let raw = Slot::<u64>::new(usize::from_le(3), u64::from_le(0x0102));
+    /// assert!(Slot::from_le(raw) == slot);
+    /// ```
     #[inline]
-    pub fn as_slice(&self, slot_size: usize) -> &[u8] {
-        let slot_ptr = self as *const Self as *const u8;
-        unsafe { std::slice::from_raw_parts(slot_ptr, slot_size) }
+    pub fn to_le_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
+        bytes[0..8].copy_from_slice(&(self.idx as u64).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+
+    /// Reads a slot written by [`Slot::to_le_bytes`]
+    #[inline]
+    pub fn from_le_bytes(bytes: &[u8; Self::BYTES]) -> Self {
+        Self::new(
+            LittleEndian::read_u64(&bytes[0..8]) as usize,
+            LittleEndian::read_u64(&bytes[8..16]),
+        )
+    }
+
+    /// Converts a slot whose bytes were read from a chunk file as they are, e.g. by
+    /// `buffer_read_parallel`, to the byte order of the platform
+    ///
+    /// A no-op on little-endian platforms.
+    #[inline]
+    pub fn from_le(slot: Self) -> Self {
+        Self::new(usize::from_le(slot.idx), u64::from_le(slot.value))
     }
-}
 
-impl Slot<u64> {
     /// Returns the sequence ID (lower 32 bits) for a u64 Slot
     ///
     /// # Examples
@@ -272,6 +347,27 @@ where
     }
 }
 
+/// HashConfig version of databases whose hash pages store 64-bit cells
+///
+/// A wide cell holds the upper 32 bits of the minimizer hash as key and the internal
+/// taxid in the lower 32 bits, so the key does not shrink as the taxonomy grows.
+pub const WIDE_CELL_VERSION: usize = 2;
+
+/// Bits of a sample slot reserved for the file index when the database has wide cells
+pub const WIDE_SAMPLE_VALUE_BITS: usize = 16;
+
+/// Flag in the HashConfig version of databases whose hash pages are compressed with zstd
+///
+/// The pages are stored as `hash_{i}.k2d.zst` and decompressed when they are loaded.
+pub const ZSTD_PAGE_FLAG: usize = 0x100;
+
+/// Flag in the HashConfig version of databases whose hash pages are probed by double hashing
+///
+/// A key whose cell is taken steps on by a stride derived from the key (see [`probe_step`])
+/// instead of to the next cell, so collisions do not pile up into the long runs of linear
+/// probing. The probe sequence wraps around within the page, the pages have no overflow block.
+pub const DOUBLE_HASHING_FLAG: usize = 0x200;
+
 #[derive(Clone, Copy)]
 pub struct HashConfig {
     // value_mask = ((1 << value_bits) - 1);
@@ -369,6 +465,18 @@ impl HashConfig {
         let size = file.read_u64::<LittleEndian>()? as usize;
         let value_bits = file.read_u64::<LittleEndian>()? as usize;
 
+        // 未知的版本或标志位来自更新的 kun_peng
+        let base_version = version & !(ZSTD_PAGE_FLAG | DOUBLE_HASHING_FLAG);
+        if base_version > WIDE_CELL_VERSION {
+            return Err(Error::FormatVersion {
+                path: filename.as_ref().to_path_buf(),
+                found: base_version as u64,
+                supported: WIDE_CELL_VERSION as u64,
+                upgrade: String::new(),
+            }
+            .into());
+        }
+
         Ok(Self::new(
             version,
             capacity,
@@ -396,12 +504,127 @@ impl HashConfig {
         self.value_bits
     }
 
+    /// Whether the hash pages store 64-bit cells
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{HashConfig, WIDE_CELL_VERSION};
+    ///
+    /// assert!(!HashConfig::new(1, 1000, 16, 0, 10, 100).is_wide());
+    /// assert!(HashConfig::new(WIDE_CELL_VERSION, 1000, 32, 0, 10, 100).is_wide());
+    /// ```
+    pub fn is_wide(&self) -> bool {
+        self.base_version() >= WIDE_CELL_VERSION
+    }
+
+    /// The version without the `ZSTD_PAGE_FLAG` and `DOUBLE_HASHING_FLAG`, describing the
+    /// layout of the cells
+    pub fn base_version(&self) -> usize {
+        self.version & !(ZSTD_PAGE_FLAG | DOUBLE_HASHING_FLAG)
+    }
+
+    /// Whether the hash pages are probed by double hashing instead of linearly
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{HashConfig, DOUBLE_HASHING_FLAG, WIDE_CELL_VERSION};
+    ///
+    /// let config = HashConfig::new(WIDE_CELL_VERSION | DOUBLE_HASHING_FLAG, 1000, 32, 0, 10, 100);
+    /// assert!(config.is_double_hashing() && config.is_wide());
+    /// assert_eq!(config.base_version(), WIDE_CELL_VERSION);
+    /// assert!(!HashConfig::new(1, 1000, 16, 0, 10, 100).is_double_hashing());
+    /// ```
+    pub fn is_double_hashing(&self) -> bool {
+        self.version & DOUBLE_HASHING_FLAG != 0
+    }
+
+    /// Whether the hash pages are compressed with zstd
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{HashConfig, WIDE_CELL_VERSION, ZSTD_PAGE_FLAG};
+    ///
+    /// let config = HashConfig::new(WIDE_CELL_VERSION | ZSTD_PAGE_FLAG, 1000, 32, 0, 10, 100);
+    /// assert!(config.is_compressed() && config.is_wide());
+    /// assert_eq!(config.page_suffix(), ".k2d.zst");
+    /// assert_eq!(HashConfig::new(1, 1000, 16, 0, 10, 100).page_suffix(), ".k2d");
+    /// ```
+    pub fn is_compressed(&self) -> bool {
+        self.version & ZSTD_PAGE_FLAG != 0
+    }
+
+    /// Suffix of the hash page files, `hash_{i}.k2d` or `hash_{i}.k2d.zst`
+    pub fn page_suffix(&self) -> &'static str {
+        if self.is_compressed() {
+            ".k2d.zst"
+        } else {
+            ".k2d"
+        }
+    }
+
+    /// Number of value bits of the sample slots written by splitr, which hold the file index
+    pub fn sample_value_bits(&self) -> usize {
+        if self.is_wide() {
+            WIDE_SAMPLE_VALUE_BITS
+        } else {
+            self.value_bits
+        }
+    }
+
+    /// Mask of the value bits of the sample slots written by splitr
+    pub fn sample_value_mask(&self) -> usize {
+        (1 << self.sample_value_bits()) - 1
+    }
+
+    /// Number of samples the slots of one set of chunk files can tell apart
+    ///
+    /// The slots number the samples from 1 on, counted from the first sample of the chunk
+    /// files (see `ChunkHeader::first_sample`), so the number does not limit a whole run,
+    /// only the samples split before the chunk files are annotated.
+    pub fn max_chunk_samples(&self) -> usize {
+        self.sample_value_mask()
+    }
+
     pub fn index(&self, hash_key: u64) -> usize {
         hash_key as usize % self.capacity
     }
 
+    /// Returns the index and the key stored in the cell of a hash key
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{HashConfig, WIDE_CELL_VERSION};
+    ///
+    /// let config = HashConfig::new(1, 1000, 16, 0, 10, 100);
+    /// assert_eq!(config.compact(0x1234567890ABCDEF).1, 0x1234);
+    /// let config = HashConfig::new(WIDE_CELL_VERSION, 1000, 32, 0, 10, 100);
+    /// assert_eq!(config.compact(0x1234567890ABCDEF).1, 0x12345678);
+    /// ```
     pub fn compact(&self, hash_key: u64) -> (usize, u32) {
-        (self.index(hash_key), hash_key.left(self.value_bits) as u32)
+        let compacted = if self.is_wide() {
+            (hash_key >> 32) as u32
+        } else {
+            hash_key.left(self.value_bits) as u32
+        };
+        (self.index(hash_key), compacted)
+    }
+
+    /// Returns the 64-bit cell of a hash key and an internal taxid for databases with wide cells
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{HashConfig, WIDE_CELL_VERSION};
+    ///
+    /// let config = HashConfig::new(WIDE_CELL_VERSION, 1000, 32, 0, 10, 100);
+    /// assert_eq!(config.wide_cell(0x1234567890ABCDEF, 42), 0x12345678_0000002A);
+    /// ```
+    pub fn wide_cell(&self, hash_key: u64, taxid: u32) -> u64 {
+        (hash_key >> 32) << 32 | taxid as u64
     }
 
     pub fn slot(&self, hash_key: u64, taxid: u32) -> Slot<u32> {
@@ -411,17 +634,134 @@ impl HashConfig {
 
     pub fn slot_u64(&self, hash_key: u64, seq_id: u64) -> Slot<u64> {
         let idx = self.index(hash_key);
-        Slot::<u64>::new(idx, u64::hash_value(hash_key, self.value_bits, seq_id))
+        Slot::<u64>::new(
+            idx,
+            u64::hash_value(hash_key, self.sample_value_bits(), seq_id),
+        )
     }
 }
 
+/// Whether a hash page stores 64-bit cells, judged by the length of its content
+fn is_wide_page(len: usize, capacity: usize) -> bool {
+    capacity > 0 && len >= 16 + capacity * 8
+}
+
+/// Opens a hash page file and returns a reader of its content and the content length
+///
+/// Pages ending in `.zst` are decompressed while they are read. Their length is taken from
+/// the zstd frame header, which `compress_page_file` always writes.
+fn open_page_file<P: AsRef<Path>>(filename: P) -> Result<(Box<dyn Read>, usize)> {
+    let path = filename.as_ref();
+    let mut file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "zst") {
+        let mut header = [0u8; 18];
+        let mut filled = 0;
+        while filled < header.len() {
+            match file.read(&mut header[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        let len = zstd::zstd_safe::get_frame_content_size(&header[..filled])
+            .ok()
+            .flatten()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} has no content size in its zstd frame header", path),
+                )
+            })?;
+        // 按 --db-read-ahead 重新打开, 从头解压
+        return Ok((
+            Box::new(zstd::stream::read::Decoder::new(open_db_file(path)?)?),
+            len as usize,
+        ));
+    }
+    let len = file.metadata()?.len() as usize;
+    Ok((open_db_file(path)?, len))
+}
+
+/// Returns the memory a hash page file takes when loaded, the uncompressed size for `.zst` pages
+/// plus the size of its prefilter, if any
+pub fn page_file_bytes<P: AsRef<Path>>(filename: P) -> Result<u64> {
+    let prefilter = crate::prefilter::prefilter_path(filename.as_ref());
+    let prefilter_bytes = std::fs::metadata(prefilter).map_or(0, |meta| meta.len());
+    let (_, len) = open_page_file(filename)?;
+    Ok(len as u64 + prefilter_bytes)
+}
+
+/// Compresses a hash page file with zstd into `<file>.zst` and removes the original
+///
+/// # Arguments
+///
+/// * `filename` - The path of the `hash_{i}.k2d` file
+/// * `level` - The zstd compression level
+///
+/// # Returns
+///
+/// The size of the compressed file
+pub fn compress_page_file<P: AsRef<Path>>(filename: P, level: i32) -> Result<u64> {
+    let path = filename.as_ref();
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut zst_name = path.as_os_str().to_owned();
+    zst_name.push(".zst");
+    let zst_path = std::path::PathBuf::from(zst_name);
+
+    let mut encoder =
+        zstd::stream::write::Encoder::new(BufWriter::new(File::create(&zst_path)?), level)?;
+    encoder.include_contentsize(true)?;
+    encoder.set_pledged_src_size(Some(len))?;
+    std::io::copy(&mut file, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    std::fs::remove_file(path)?;
+    Ok(std::fs::metadata(&zst_path)?.len())
+}
+
+/// Reads 64-bit cells, splitting them into taxids (`data`) and keys
+fn read_wide_page_data<R: Read>(file: &mut R, data: &mut [u32], keys: &mut [u32]) -> Result<()> {
+    const CHUNK: usize = 4096;
+    let mut buf = vec![0u64; CHUNK];
+    let mut filled = 0usize;
+    while filled < data.len() {
+        let n = (data.len() - filled).min(CHUNK);
+        file.read_u64_into::<LittleEndian>(&mut buf[..n])?;
+        for (i, cell) in buf[..n].iter().enumerate() {
+            data[filled + i] = *cell as u32;
+            keys[filled + i] = (*cell >> 32) as u32;
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+/// Reads the leading run of non-empty 64-bit cells, including the first empty cell
+fn read_wide_first_block<R: Read>(file: &mut R, index: usize, capacity: usize) -> Result<Page> {
+    let mut data = Vec::new();
+    let mut keys = Vec::new();
+    let mut cell = [0u64; 1];
+    while data.len() < capacity {
+        file.read_u64_into::<LittleEndian>(&mut cell)?;
+        data.push(cell[0] as u32);
+        keys.push((cell[0] >> 32) as u32);
+        if cell[0] == 0 {
+            break;
+        }
+    }
+    let size = data.len();
+    Ok(Page::new_wide(index, size, data, keys))
+}
+
 fn read_first_block_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
-    let mut file = std::fs::File::open(filename)?;
+    let (mut file, len) = open_page_file(filename)?;
 
     let mut header = [0u8; 16];
     file.read_exact(&mut header)?;
     let index    = LittleEndian::read_u64(&header[0..8])  as usize;
     let capacity = LittleEndian::read_u64(&header[8..16]) as usize;
+    if is_wide_page(len, capacity) {
+        return read_wide_first_block(&mut file, index, capacity);
+    }
 
     let chunk_elems = 4096;
     let mut data = vec![0u32; capacity];
@@ -466,7 +806,7 @@ fn read_first_block_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
 
     if !found_zero {
         first_zero_end = capacity;
-        eprintln!("Warning: No zero value found in the data, using full capacity.");
+        log::warn!("No zero value found in the data, using full capacity.");
     }
 
     data.truncate(first_zero_end);
@@ -474,13 +814,13 @@ fn read_first_block_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
     Ok(Page::new(index, first_zero_end, data))
 }
 
-fn read_page_metadata(file: &mut File) -> Result<(usize, usize)> {
+fn read_page_metadata<R: Read>(file: &mut R) -> Result<(usize, usize)> {
     let index = file.read_u64::<LittleEndian>()? as usize;
     let capacity = file.read_u64::<LittleEndian>()? as usize;
     Ok((index, capacity))
 }
 
-fn read_page_data(file: &mut File, data: &mut [u32]) -> Result<()> {
+fn read_page_data<R: Read>(file: &mut R, data: &mut [u32]) -> Result<()> {
     #[cfg(target_endian = "little")]
     {
         // 零拷贝，安全，无端序转换（文件即小端）
@@ -506,17 +846,27 @@ fn read_page_data(file: &mut File, data: &mut [u32]) -> Result<()> {
     }
 }
 
-fn read_page_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
-    let mut file = std::fs::File::open(filename)?;
+/// Reads a single hash page file without the overflow block of the next page
+///
+/// Pages with 64-bit cells are split into taxids (`data`) and keys (`keys`).
+pub fn read_page_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
+    let (mut file, len) = open_page_file(filename)?;
     let (index, capacity) = read_page_metadata(&mut file)?;
     let mut data = vec![0u32; capacity];
+    place(&data);
+    if is_wide_page(len, capacity) {
+        let mut keys = vec![0u32; capacity];
+        place(&keys);
+        read_wide_page_data(&mut file, &mut data, &mut keys)?;
+        return Ok(Page::new_wide(index, capacity, data, keys));
+    }
     read_page_data(&mut file, &mut data)?;
 
     Ok(Page::new(index, capacity, data))
 }
 
 fn read_large_page_from_file<P: AsRef<Path>>(large_page: &mut Page, filename: P) -> Result<()> {
-    let mut file = File::open(filename)?;
+    let (mut file, len) = open_page_file(filename)?;
 
     let (index, capacity) = read_page_metadata(&mut file)?;
 
@@ -531,7 +881,15 @@ fn read_large_page_from_file<P: AsRef<Path>>(large_page: &mut Page, filename: P)
         large_page.data.shrink_to_fit(); // Free up excess memory
     }
 
-    read_page_data(&mut file, &mut large_page.data)?;
+    place(&large_page.data);
+    if is_wide_page(len, capacity) {
+        large_page.keys.resize(capacity, 0);
+        place(&large_page.keys);
+        read_wide_page_data(&mut file, &mut large_page.data, &mut large_page.keys)?;
+    } else {
+        large_page.keys.clear();
+        read_page_data(&mut file, &mut large_page.data)?;
+    }
 
     large_page.index = index;
     large_page.size = capacity;
@@ -548,6 +906,12 @@ pub fn read_next_page<P: AsRef<Path> + Debug>(
     let mut hash_file = &hash_sorted_files[page_index];
     let parition = config.partition;
     read_large_page_from_file(large_page, hash_file)?;
+    large_page.prefilter = Prefilter::load(hash_file.as_ref(), large_page.size)?;
+    large_page.double_hashing = config.is_double_hashing();
+    if large_page.double_hashing {
+        // 双重哈希的探测序列在页内回绕, 不需要溢出块
+        return Ok(());
+    }
 
     let next_page = if large_page.data.last().map_or(false, |&x| x != 0) {
         if config.version < 1 {
@@ -562,11 +926,114 @@ pub fn read_next_page<P: AsRef<Path> + Debug>(
     Ok(())
 }
 
+/// The key of a lookup at the resolution of the sample slots
+///
+/// Wide keys are looked up with `value_bits` of their lower bits dropped, the prefilter and the
+/// double-hashing stride use them with the [`WIDE_SAMPLE_VALUE_BITS`] of the sample slots dropped.
+fn sample_key(wide: bool, compacted_key: u32, value_bits: usize) -> u32 {
+    if wide {
+        let shift = WIDE_SAMPLE_VALUE_BITS.saturating_sub(value_bits) as u32;
+        compacted_key.checked_shr(shift).unwrap_or(0)
+    } else {
+        compacted_key
+    }
+}
+
+/// Whether a page may hold a key, by its prefilter if it has one
+fn may_contain(
+    prefilter: &Option<Prefilter>,
+    wide: bool,
+    index: usize,
+    compacted_key: u32,
+    value_bits: usize,
+) -> bool {
+    let Some(prefilter) = prefilter else {
+        return true;
+    };
+    prefilter.may_contain(index, sample_key(wide, compacted_key, value_bits))
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The stride of the double-hashing probe sequence of a key in a page of `capacity` cells
+///
+/// `key` is the key at the resolution of the sample slots, the upper bits of a narrow cell
+/// or the upper 16 bits of a wide one, so that `direct`, `classify` and `annotate` follow the
+/// same sequence. The stride is coprime with the capacity, the sequence visits every cell.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::compact_hash::probe_step;
+/// use std::collections::HashSet;
+///
+/// let capacity = 12;
+/// let step = probe_step(0xBEEF, capacity);
+/// let cells: HashSet<usize> = (0..capacity).map(|i| (5 + i * step) % capacity).collect();
+/// assert_eq!(cells.len(), capacity);
+/// assert_eq!(probe_step(0xBEEF, 1), 1);
+/// ```
+pub fn probe_step(key: u32, capacity: usize) -> usize {
+    if capacity < 3 {
+        return 1;
+    }
+    let h = ((key as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15) >> 32) as usize;
+    let mut step = 1 + h % (capacity - 1);
+    while gcd(step, capacity) != 1 {
+        step = step % (capacity - 1) + 1;
+    }
+    step
+}
+
+/// Follows the double-hashing probe sequence of a key through a page of `capacity` cells
+///
+/// `cell` gives the taxid and the key of a cell as [`MappedPage`] reads them.
+///
+/// # Returns
+///
+/// The taxid of the key, 0 if it is not in the page, and the number of cells probed
+fn probe_double_hashed<F>(
+    capacity: usize,
+    index: usize,
+    compacted_key: u32,
+    step_key: u32,
+    cell: F,
+) -> (u32, usize)
+where
+    F: Fn(usize) -> (u32, u32),
+{
+    let mut idx = index;
+    let mut step = 0;
+    for probes in 1..=capacity {
+        let (taxid, key) = cell(idx);
+        if taxid == 0 || key == compacted_key {
+            return (taxid, probes);
+        }
+        if step == 0 {
+            step = probe_step(step_key, capacity);
+        }
+        idx = (idx + step) % capacity;
+    }
+    (0, capacity)
+}
+
 #[derive(Clone)]
 pub struct Page {
     pub index: usize,
     pub size: usize,
+    /// The cells, or only the taxids of the cells for pages with 64-bit cells
     pub data: Vec<u32>,
+    /// The keys of the cells for pages with 64-bit cells, empty otherwise
+    pub keys: Vec<u32>,
+    /// The prefilter of the page, see [`crate::prefilter`]
+    pub prefilter: Option<Prefilter>,
+    /// Whether the page is probed by double hashing, see [`DOUBLE_HASHING_FLAG`]
+    pub double_hashing: bool,
 }
 
 impl Default for Page {
@@ -581,7 +1048,31 @@ impl Page {
     }
 
     pub fn new(index: usize, size: usize, data: Vec<u32>) -> Self {
-        Self { index, size, data }
+        Self {
+            index,
+            size,
+            data,
+            keys: Vec::new(),
+            prefilter: None,
+            double_hashing: false,
+        }
+    }
+
+    /// Creates a page with 64-bit cells from their taxids and keys
+    pub fn new_wide(index: usize, size: usize, data: Vec<u32>, keys: Vec<u32>) -> Self {
+        Self {
+            index,
+            size,
+            data,
+            keys,
+            prefilter: None,
+            double_hashing: false,
+        }
+    }
+
+    /// Whether the page was read from 64-bit cells
+    pub fn is_wide(&self) -> bool {
+        !self.keys.is_empty()
     }
 
     pub fn start(&self) -> usize {
@@ -598,9 +1089,17 @@ impl Page {
             self.data.reserve(new_size - self.data.len());
         }
         self.data.extend_from_slice(&other.data[..other.size]);
+        if other.is_wide() {
+            self.keys.extend_from_slice(&other.keys[..other.size]);
+        }
         self.size = new_size;
     }
 
+    /// Looks up the taxid of a key, starting at `index` and probing linearly, or by double
+    /// hashing on pages of a database with the [`DOUBLE_HASHING_FLAG`]
+    ///
+    /// For pages with 64-bit cells `compacted_key` is compared with the stored key shifted
+    /// right by `value_bits`, so callers holding only the upper bits of the key can look it up.
     pub fn find_index(
         &self,
         index: usize,
@@ -608,33 +1107,196 @@ impl Page {
         value_bits: usize,
         value_mask: usize,
     ) -> u32 {
-        let mut idx = index;
-        if idx >= self.size {
+        if index >= self.size {
+            return 0;
+        }
+        if !may_contain(&self.prefilter, self.is_wide(), index, compacted_key, value_bits) {
             return 0;
         }
+        self.probe(index, compacted_key, value_bits, value_mask).0
+    }
+
+    /// The taxid and the key of a cell, the key shifted right by `value_bits`
+    fn cell(&self, idx: usize, value_bits: usize, value_mask: usize) -> (u32, u32) {
+        if self.is_wide() {
+            let key = self.keys[idx].checked_shr(value_bits as u32).unwrap_or(0);
+            (self.data[idx], key)
+        } else {
+            let cell = self.data[idx];
+            (cell.right(value_mask), cell.left(value_bits))
+        }
+    }
+
+    /// Looks up a key like [`Page::find_index`], without consulting the prefilter
+    ///
+    /// # Returns
+    ///
+    /// The taxid of the key, 0 if it is not in the page, and the number of cells probed
+    pub fn probe(
+        &self,
+        index: usize,
+        compacted_key: u32,
+        value_bits: usize,
+        value_mask: usize,
+    ) -> (u32, usize) {
+        let size = self.size.min(self.data.len());
+        if index >= size {
+            return (0, 0);
+        }
+        let cell = |idx| self.cell(idx, value_bits, value_mask);
+        if self.double_hashing {
+            let step_key = sample_key(self.is_wide(), compacted_key, value_bits);
+            return probe_double_hashed(size, index, compacted_key, step_key, cell);
+        }
+        for idx in index..size {
+            let (taxid, key) = cell(idx);
+            if taxid == 0 || key == compacted_key {
+                return (taxid, idx - index + 1);
+            }
+        }
+        (0, size - index)
+    }
+}
+
+/// A hash page file mapped into memory
+///
+/// The cells are read from the mapping when they are looked up, so the OS page cache decides
+/// which parts of the page stay in memory. The overflow block of the next page is read into
+/// memory as in [`Page::merge`]. The file must not be changed while it is mapped.
+pub struct MappedPage {
+    map: Mmap,
+    capacity: usize,
+    wide: bool,
+    overflow: Page,
+    prefilter: Option<Prefilter>,
+    double_hashing: bool,
+}
+
+impl MappedPage {
+    /// Maps a `hash_{i}.k2d` file, without its overflow block
+    pub fn map<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        let path = filename.as_ref();
+        let file = File::open(path)?;
+        // 只读映射, 文件在映射期间被改写是未定义行为
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} {}", path, reason),
+            )
+        };
+        if map.len() < 16 {
+            return Err(invalid("is not a hash page file"));
+        }
+        let capacity = LittleEndian::read_u64(&map[8..16]) as usize;
+        let wide = is_wide_page(map.len(), capacity);
+        let cell_bytes = if wide { 8 } else { 4 };
+        if map.len() < 16 + capacity * cell_bytes {
+            return Err(invalid("is truncated"));
+        }
+        // 查询是随机访问, 预读只会浪费页缓存
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Random);
 
-        loop {
-            if let Some(cell) = self.data.get(idx) {
-                if cell.right(value_mask) == 0 || cell.left(value_bits) == compacted_key {
-                    return cell.right(value_mask);
-                }
+        Ok(Self {
+            map,
+            capacity,
+            wide,
+            overflow: Page::default(),
+            prefilter: Prefilter::load(path, capacity)?,
+            double_hashing: false,
+        })
+    }
 
-                idx = idx + 1;
-                if idx >= self.size {
-                    break;
-                }
+    /// The taxid and the key of a cell, the key shifted right by `value_bits`
+    fn cell(&self, idx: usize, value_bits: usize, value_mask: usize) -> (u32, u32) {
+        if idx >= self.capacity {
+            let idx = idx - self.capacity;
+            let cell = self.overflow.data[idx];
+            return if self.overflow.is_wide() {
+                let key = self.overflow.keys[idx];
+                (cell, key.checked_shr(value_bits as u32).unwrap_or(0))
             } else {
+                (cell.right(value_mask), cell.left(value_bits))
+            };
+        }
+        if self.wide {
+            let cell = LittleEndian::read_u64(&self.map[16 + idx * 8..]);
+            let key = (cell >> 32) as u32;
+            (cell as u32, key.checked_shr(value_bits as u32).unwrap_or(0))
+        } else {
+            let cell = LittleEndian::read_u32(&self.map[16 + idx * 4..]);
+            (cell.right(value_mask), cell.left(value_bits))
+        }
+    }
+
+    /// Whether the last cell is taken, so lookups may continue into the next page
+    fn spills_over(&self) -> bool {
+        self.capacity > 0 && self.cell(self.capacity - 1, 0, usize::MAX).0 != 0
+    }
+
+    /// Looks up the taxid of a key, see [`Page::find_index`]
+    pub fn find_index(
+        &self,
+        index: usize,
+        compacted_key: u32,
+        value_bits: usize,
+        value_mask: usize,
+    ) -> u32 {
+        if !may_contain(&self.prefilter, self.wide, index, compacted_key, value_bits) {
+            return 0;
+        }
+        if self.double_hashing {
+            if index >= self.capacity {
                 return 0;
             }
+            let step_key = sample_key(self.wide, compacted_key, value_bits);
+            let cell = |idx| self.cell(idx, value_bits, value_mask);
+            return probe_double_hashed(self.capacity, index, compacted_key, step_key, cell).0;
+        }
+        let size = self.capacity + self.overflow.size;
+        for idx in index..size {
+            let (taxid, key) = self.cell(idx, value_bits, value_mask);
+            if taxid == 0 || key == compacted_key {
+                return taxid;
+            }
         }
         0
     }
 }
 
+/// Loads the hash page `hash_sorted_files[i]` for lookups, with its prefilter and the overflow
+/// block of the next page
+pub fn load_page<P: AsRef<Path> + Debug>(
+    config: HashConfig,
+    hash_sorted_files: &[P],
+    i: usize,
+) -> Result<Page> {
+    let mut hash_file = &hash_sorted_files[i];
+    let mut page = read_page_from_file(hash_file)?;
+    page.prefilter = Prefilter::load(hash_file.as_ref(), page.size)?;
+    page.double_hashing = config.is_double_hashing();
+    if page.double_hashing {
+        return Ok(page);
+    }
+    let next_page = if page.data.last().is_some_and(|&x| x != 0) {
+        if config.version < 1 {
+            hash_file = &hash_sorted_files[(i + 1) % hash_sorted_files.len()]
+        }
+        read_first_block_from_file(hash_file)?
+    } else {
+        Page::default()
+    };
+    page.merge(next_page);
+    Ok(page)
+}
+
 #[allow(unused)]
 pub struct CHTable {
     pub config: HashConfig,
     pub pages: Vec<Page>,
+    /// The pages mapped by [`CHTable::map_hash_files`], used instead of `pages` if present
+    pub mapped: Vec<MappedPage>,
 }
 
 impl CHTable {
@@ -653,34 +1315,94 @@ impl CHTable {
         end: usize,
     ) -> Result<CHTable> {
         let mut pages = vec![Page::default(); start];
-        let parition = hash_sorted_files.len();
         for i in start..end {
-            let mut hash_file = &hash_sorted_files[i];
-            let mut page = read_page_from_file(&hash_file)?;
-            let next_page = if page.data.last().map_or(false, |&x| x != 0) {
-                if config.version < 1 {
-                    hash_file = &hash_sorted_files[(i + 1) % parition]
-                }
-                read_first_block_from_file(&hash_file)?
-            } else {
-                Page::default()
-            };
-            page.merge(next_page);
-            pages.push(page);
+            if i + 1 < end {
+                // 读取本页时让下一页进入页缓存
+                will_need(hash_sorted_files[i + 1].as_ref());
+            }
+            pages.push(load_page(config, hash_sorted_files, i)?);
         }
 
-        let chtm = CHTable { config, pages };
+        let chtm = CHTable {
+            config,
+            pages,
+            mapped: Vec::new(),
+        };
         Ok(chtm)
     }
 
+    /// Maps the hash pages into memory instead of reading them, see [`MappedPage`]
+    ///
+    /// Zstd-compressed pages cannot be mapped and must be loaded with [`CHTable::from_hash_files`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::{CHTable, HashConfig};
+    ///
+    /// // 容量为 4 的页: 单元 = key << 16 | taxid
+    /// let page_file = std::env::temp_dir().join("kun_peng_mapped_page_doctest.k2d");
+    /// let mut bytes = Vec::new();
+    /// for word in [1u64, 4] {
+    ///     bytes.extend_from_slice(&word.to_le_bytes());
+    /// }
+    /// for cell in [7u32 << 16 | 3, 9 << 16 | 5, 0, 0] {
+    ///     bytes.extend_from_slice(&cell.to_le_bytes());
+    /// }
+    /// std::fs::write(&page_file, bytes).unwrap();
+    ///
+    /// let config = HashConfig::new(1, 4, 16, 2, 1, 4);
+    /// let hash_files = vec![page_file.clone()];
+    /// let mapped = CHTable::map_hash_files(config, &hash_files).unwrap();
+    /// let loaded = CHTable::from_hash_files(config, &hash_files).unwrap();
+    /// for (index, key) in [(0, 7), (0, 9), (2, 9)] {
+    ///     assert_eq!(mapped.get_from_page(index, key, 0), loaded.get_from_page(index, key, 0));
+    /// }
+    /// assert_eq!(mapped.get_from_page(0, 9, 0), 5);
+    /// drop(mapped);
+    /// std::fs::remove_file(&page_file).unwrap();
+    /// ```
+    pub fn map_hash_files<P: AsRef<Path> + Debug>(
+        config: HashConfig,
+        hash_sorted_files: &[P],
+    ) -> Result<CHTable> {
+        let parition = hash_sorted_files.len();
+        let mut mapped = Vec::with_capacity(parition);
+        for (i, hash_file) in hash_sorted_files.iter().enumerate() {
+            let mut page = MappedPage::map(hash_file)?;
+            page.double_hashing = config.is_double_hashing();
+            if !page.double_hashing && page.spills_over() {
+                let next_file = if config.version < 1 {
+                    &hash_sorted_files[(i + 1) % parition]
+                } else {
+                    hash_file
+                };
+                page.overflow = read_first_block_from_file(next_file)?;
+            }
+            mapped.push(page);
+        }
+
+        Ok(CHTable {
+            config,
+            pages: Vec::new(),
+            mapped,
+        })
+    }
+
     pub fn get_from_page(&self, indx: usize, compacted: u32, page_index: usize) -> u32 {
+        // `compact` returns the whole 32-bit key for wide cells
+        let value_bits = if self.config.is_wide() {
+            0
+        } else {
+            self.config.value_bits
+        };
+        if !self.mapped.is_empty() {
+            return self.mapped.get(page_index).map_or(0, |page| {
+                page.find_index(indx, compacted, value_bits, self.config.value_mask)
+            });
+        }
         if let Some(page) = self.pages.get(page_index) {
-            page.find_index(
-                indx,
-                compacted,
-                self.config.value_bits,
-                self.config.value_mask,
-            )
+            page.find_index(indx, compacted, value_bits, self.config.value_mask)
         } else {
             0
         }
@@ -1,16 +1,25 @@
-use crate::compact_hash::{Compact, HashConfig, Slot};
+use crate::compact_hash::{
+    probe_step, read_page_from_file, Compact, HashConfig, Slot, WIDE_SAMPLE_VALUE_BITS,
+};
+use crate::external_sort::{remove_sorted_runs, sort_chunk_file, SortedCells};
+use crate::prefilter::{prefilter_path, remove_prefilter, Prefilter};
 // use crate::mmscanner::MinimizerScanner;
-use crate::taxonomy::{NCBITaxonomy, Taxonomy};
+use crate::taxonomy::{
+    find_gtdb_taxonomy_files, read_pseudo_taxa, NCBITaxonomy, TaxidChanges, Taxonomy,
+    PSEUDO_TAXA_FILENAME, TAXID_REMAP_FILENAME,
+};
 use seqkmer::{read_parallel, BufferFastaReader, Meros};
 
-use crate::utils::open_file;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::utils::{open_file, pipeline_threads};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use memmap2::MmapMut;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Result as IOResult, Write};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Result as IOResult, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 // Define the number of Cells processed per batch
 const BATCH_SIZE: usize = 81920;
@@ -22,21 +31,29 @@ const BATCH_SIZE: usize = 81920;
 /// * `taxonomy` - The taxonomy used for LCA calculations
 /// * `page` - The page of AtomicU32 cells
 /// * `item` - The Slot item to be set
-/// * `page_size` - The size of the page
+/// * `double_hashing` - Probe by double hashing instead of linearly, see `DOUBLE_HASHING_FLAG`
 /// * `value_bits` - The number of bits used for the value
 /// * `value_mask` - The mask used to extract the value
+/// * `counters` - Counts the LCA promotions and probing collisions
 fn set_page_cell(
     taxonomy: &Taxonomy,
     page: &[AtomicU32],
     item: &Slot<u32>,
-    page_size: usize,
+    double_hashing: bool,
     value_bits: usize,
     value_mask: usize,
+    counters: &PageCounters,
 ) {
+    let page_size = page.len();
     let mut idx = item.idx % page_size;
     let item_taxid: u32 = item.value.right(value_mask).to_u32();
     let compact_key = item.value.left(value_bits);
     let first_idx = idx;
+    let step = if double_hashing {
+        probe_step(compact_key, page_size)
+    } else {
+        1
+    };
 
     loop {
         let result = page[idx].fetch_update(Ordering::SeqCst, Ordering::Relaxed, |current| {
@@ -54,15 +71,23 @@ fn set_page_cell(
         });
 
         match result {
-            Ok(_) => {
+            Ok(previous) => {
                 // `fetch_update` 成功 (我们写入了数据或LCA)
                 // 任务完成，退出循环
+                let previous_taxid = previous.right(value_mask).to_u32();
+                if previous_taxid != 0
+                    && previous.left(value_bits) == compact_key
+                    && taxonomy.lca(item_taxid, previous_taxid) != previous_taxid
+                {
+                    counters.lca_promotions.fetch_add(1, Ordering::Relaxed);
+                }
                 break;
             }
             Err(_) => {
+                counters.collisions.fetch_add(1, Ordering::Relaxed);
                 // `fetch_update` 失败 (返回 None)，意味着 slot 被
-                // 另一个 *不同的 key* 占用了。我们必须进行线性探测。
-                idx = (idx + 1) % page_size;
+                // 另一个 *不同的 key* 占用了。我们必须继续探测 (线性或双重哈希)。
+                idx = (idx + step) % page_size;
                 if idx == first_idx {
                     // 我们已经绕了完整的一圈，没有找到空位
                     // TODO: 在这里添加哈希页已满的日志或错误处理
@@ -74,6 +99,59 @@ fn set_page_cell(
     }
 }
 
+/// Sets a 64-bit cell in the page of a database with wide cells, see `set_page_cell`
+///
+/// The key is the upper half of the cell and the internal taxid the lower half.
+fn set_wide_page_cell(
+    taxonomy: &Taxonomy,
+    page: &[AtomicU64],
+    item: &Slot<u64>,
+    double_hashing: bool,
+    counters: &PageCounters,
+) {
+    let page_size = page.len();
+    let mut idx = item.idx % page_size;
+    let item_taxid = item.value as u32;
+    let compact_key = item.value >> 32;
+    let first_idx = idx;
+    // 步长只取样本 slot 保留的 key 高位, annotate 才能沿同一序列查找
+    let step = if double_hashing {
+        probe_step((compact_key >> WIDE_SAMPLE_VALUE_BITS) as u32, page_size)
+    } else {
+        1
+    };
+
+    loop {
+        let result = page[idx].fetch_update(Ordering::SeqCst, Ordering::Relaxed, |current| {
+            let current_taxid = current as u32;
+            if current == 0 || current_taxid == 0 {
+                Some(item.value)
+            } else if current >> 32 == compact_key {
+                let new_taxid = taxonomy.lca(item_taxid, current_taxid);
+                Some(compact_key << 32 | new_taxid as u64)
+            } else {
+                None
+            }
+        });
+
+        if let Ok(previous) = result {
+            let previous_taxid = previous as u32;
+            if previous_taxid != 0
+                && previous >> 32 == compact_key
+                && taxonomy.lca(item_taxid, previous_taxid) != previous_taxid
+            {
+                counters.lca_promotions.fetch_add(1, Ordering::Relaxed);
+            }
+            break;
+        }
+        counters.collisions.fetch_add(1, Ordering::Relaxed);
+        idx = (idx + step) % page_size;
+        if idx == first_idx {
+            break;
+        }
+    }
+}
+
 /// Writes the hash table to a file
 ///
 /// # Arguments
@@ -87,7 +165,7 @@ fn set_page_cell(
 ///
 /// The number of non-zero items written to the file
 fn write_hashtable_to_file(
-    page: &Vec<AtomicU32>,
+    page: &[AtomicU32],
     file_path: &PathBuf,
     page_index: u64,
     capacity: u64,
@@ -112,45 +190,771 @@ fn write_hashtable_to_file(
     Ok(count)
 }
 
+/// Writes a hash table with 64-bit cells to a file, see `write_hashtable_to_file`
+fn write_wide_hashtable_to_file(
+    page: &[AtomicU64],
+    file_path: &Path,
+    page_index: u64,
+    capacity: u64,
+) -> IOResult<usize> {
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    let mut count = 0;
+    writer.write_u64::<LittleEndian>(page_index)?;
+    writer.write_u64::<LittleEndian>(capacity)?;
+
+    for item in page {
+        let value = item.load(Ordering::Relaxed);
+        if value != 0 {
+            count += 1;
+        }
+        writer.write_u64::<LittleEndian>(value)?;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// A hash page file mapped into memory, its cells set in place
+///
+/// The file is created with the header and `capacity` zeroed cells. The cells are set through
+/// the mapping, so the kernel writes the page back as it fills instead of the build holding
+/// the whole page in memory and serializing it afterwards.
+struct MappedPageFile {
+    map: MmapMut,
+    capacity: usize,
+}
+
+impl MappedPageFile {
+    /// Creates the page file of `capacity` cells of `cell_bytes` bytes and maps it
+    fn create(
+        path: &Path,
+        page_index: usize,
+        capacity: usize,
+        cell_bytes: usize,
+    ) -> IOResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // 稀疏文件, 未写入的单元读出为 0
+        file.set_len((16 + capacity * cell_bytes) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        LittleEndian::write_u64(&mut map[0..8], page_index as u64);
+        LittleEndian::write_u64(&mut map[8..16], capacity as u64);
+        Ok(Self { map, capacity })
+    }
+
+    /// The 32-bit cells of the page
+    fn narrow_cells(&mut self) -> &[AtomicU32] {
+        // 映射按页对齐, 单元从偏移 16 开始; 仅在小端平台使用, 与文件的字节序一致
+        unsafe {
+            let cells = self.map[16..].as_mut_ptr() as *const AtomicU32;
+            std::slice::from_raw_parts(cells, self.capacity)
+        }
+    }
+
+    /// The 64-bit cells of the page
+    fn wide_cells(&mut self) -> &[AtomicU64] {
+        unsafe {
+            let cells = self.map[16..].as_mut_ptr() as *const AtomicU64;
+            std::slice::from_raw_parts(cells, self.capacity)
+        }
+    }
+
+    /// Writes the page back before the next page is built, so the dirty pages do not pile up
+    fn finish(self) -> IOResult<()> {
+        self.map.flush()
+    }
+}
+
+/// Name of the file in library/ recording the file hash and seqid of every added sequence
+pub const LIBRARY_PROVENANCE_FILENAME: &str = "added.seqids";
+
+/// Default upper bound for the occupancy of a single hash page
+pub const DEFAULT_MAX_PAGE_LOAD: f64 = 0.9;
+
+/// Capacity a page has to grow to when it holds more than `max_page_load` of its cells
+///
+/// Minimizers are not spread perfectly evenly over the pages, so a page can fill up far
+/// beyond the average load factor of the database and slow down its probe sequences.
+/// The grown page keeps the home slots of its keys, the extra cells only take the probe
+/// overflow at the end of the page.
+fn grown_page_capacity(
+    page_index: usize,
+    count: usize,
+    capacity: usize,
+    max_page_load: f64,
+) -> Option<usize> {
+    if max_page_load <= 0.0 || count as f64 <= capacity as f64 * max_page_load {
+        return None;
+    }
+    let target = (count as f64 / (max_page_load * 0.9)).ceil() as usize;
+    let grown = target.max(capacity + capacity / 8 + 1);
+    log::info!(
+        "hash page {} holds {} of {} cells ({:.2}), growing it to {} cells",
+        page_index,
+        count,
+        capacity,
+        count as f64 / capacity as f64,
+        grown
+    );
+    Some(grown)
+}
+
+/// Name of the file in the database directory holding the minimizer sample of `estimate`
+pub const MINIMIZER_SAMPLE_FILENAME: &str = "minimizer_sample.json";
+
+/// Name of the file in the database directory holding the planned capacity of every page
+pub const PAGE_CAPACITY_FILENAME: &str = "page_capacity.tsv";
+
+/// Default number of hashes kept by a `MinimizerSample`
+pub const DEFAULT_SAMPLE_LIMIT: usize = 1 << 16;
+
+/// Bottom-k sample of the distinct minimizer hashes of a library
+///
+/// Only the `limit` smallest hashes are kept, so the memory does not grow with the library.
+/// The kept hashes are a uniform sample of the distinct minimizers, which gives the share of
+/// the minimizers falling into each hash page for any capacity chosen later.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::db::MinimizerSample;
+///
+/// let mut sample = MinimizerSample::new(2);
+/// for hash in [30, 10, 20, 10] {
+///     sample.insert(hash);
+/// }
+/// assert_eq!(sample.hashes.iter().copied().collect::<Vec<_>>(), vec![10, 20]);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MinimizerSample {
+    pub limit: usize,
+    pub hashes: BTreeSet<u64>,
+}
+
+impl MinimizerSample {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            hashes: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a hash, dropping the largest one once the sample is full
+    pub fn insert(&mut self, hash: u64) {
+        if self.hashes.len() < self.limit {
+            self.hashes.insert(hash);
+        } else if self.hashes.last().is_some_and(|&last| hash < last) && self.hashes.insert(hash) {
+            self.hashes.pop_last();
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for &hash in &other.hashes {
+            self.insert(hash);
+        }
+    }
+
+    /// Planned capacity of every page of a hash table
+    ///
+    /// A page keeps its share of `config.capacity` unless the sample shows that more
+    /// minimizers fall into it, then it gets proportionally more cells. The share is taken at
+    /// two standard deviations below the sampled count, so sampling noise alone does not
+    /// grow a page.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::compact_hash::HashConfig;
+    /// use kun_peng::db::MinimizerSample;
+    ///
+    /// let config = HashConfig::new(1, 1000, 16, 0, 2, 500);
+    /// let mut sample = MinimizerSample::new(1000);
+    /// // every sampled minimizer falls into the first page
+    /// for i in 0..400 {
+    ///     sample.insert(i * 1000 + i % 500);
+    /// }
+    /// let capacities = sample.page_capacities(&config);
+    /// assert!(capacities[0] > 500);
+    /// assert_eq!(capacities[1], 500);
+    /// ```
+    pub fn page_capacities(&self, config: &HashConfig) -> Vec<usize> {
+        let mut counts = vec![0usize; config.partition];
+        for &hash in &self.hashes {
+            if let Some(count) = counts.get_mut(config.index(hash) / config.hash_capacity) {
+                *count += 1;
+            }
+        }
+        let total = self.hashes.len().max(1) as f64;
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| {
+                let range = config
+                    .hash_capacity
+                    .min(config.capacity - i * config.hash_capacity);
+                let lower = (count as f64 - 2.0 * (count as f64).sqrt()).max(0.0);
+                range.max((lower / total * config.capacity as f64).ceil() as usize)
+            })
+            .collect()
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, file_path: P) -> IOResult<()> {
+        let writer = BufWriter::new(File::create(file_path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> IOResult<Self> {
+        let reader = BufReader::new(open_file(file_path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+/// Writes the planned capacity of every page, one `page\tcapacity` line per page
+pub fn write_page_capacities(database: &Path, capacities: &[usize]) -> IOResult<()> {
+    let mut writer = BufWriter::new(File::create(database.join(PAGE_CAPACITY_FILENAME))?);
+    for (i, capacity) in capacities.iter().enumerate() {
+        writeln!(writer, "{}\t{}", i + 1, capacity)?;
+    }
+    writer.flush()
+}
+
+/// Reads the planned capacity of a page, `None` if the pages were not planned individually
+pub fn read_page_capacity(database: &Path, page_index: usize) -> IOResult<Option<usize>> {
+    let path = database.join(PAGE_CAPACITY_FILENAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Some((page, capacity)) = line.split_once('\t') {
+            if page.parse::<usize>().ok() == Some(page_index) {
+                return Ok(capacity.parse().ok());
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Name of the file in the database directory holding the insertion statistics of every page
+pub const BUILD_STATS_FILENAME: &str = "build_stats.tsv";
+
+/// Counters updated while the cells of a chunk file are inserted into a page
+#[derive(Default)]
+struct PageCounters {
+    lca_promotions: AtomicUsize,
+    collisions: AtomicUsize,
+}
+
+/// Insertion statistics of a built page, one line of `BUILD_STATS_FILENAME`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageBuildStats {
+    pub page: usize,
+    pub capacity: usize,
+    /// Occupied cells of the page
+    pub cells: usize,
+    /// Insertions that replaced the taxid of a key with a higher LCA
+    pub lca_promotions: usize,
+    /// Probe steps past slots held by other keys
+    pub collisions: usize,
+}
+
+impl PageBuildStats {
+    fn new(page: usize, capacity: usize, cells: usize, counters: &PageCounters) -> Self {
+        Self {
+            page,
+            capacity,
+            cells,
+            lca_promotions: counters.lca_promotions.load(Ordering::Relaxed),
+            collisions: counters.collisions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Reads the statistics of the built pages, empty if none were recorded
+pub fn read_build_stats(database: &Path) -> IOResult<Vec<PageBuildStats>> {
+    let path = database.join(BUILD_STATS_FILENAME);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut stats = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<usize> = line.split('\t').filter_map(|f| f.parse().ok()).collect();
+        if let [page, capacity, cells, lca_promotions, collisions] = fields[..] {
+            stats.push(PageBuildStats {
+                page,
+                capacity,
+                cells,
+                lca_promotions,
+                collisions,
+            });
+        }
+    }
+    Ok(stats)
+}
+
+/// Records the statistics of a page, replacing those of an earlier build of the page
+fn write_page_build_stats(database: &Path, page_stats: PageBuildStats) -> IOResult<()> {
+    let mut stats = read_build_stats(database)?;
+    stats.retain(|item| item.page != page_stats.page);
+    stats.push(page_stats);
+    stats.sort_by_key(|item| item.page);
+
+    let mut writer = BufWriter::new(File::create(database.join(BUILD_STATS_FILENAME))?);
+    writeln!(writer, "page\tcapacity\tcells\tlca_promotions\tcollisions")?;
+    for item in &stats {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            item.page, item.capacity, item.cells, item.lca_promotions, item.collisions
+        )?;
+    }
+    writer.flush()
+}
+
 /// Processes a k2 file and updates the hash table
 ///
+/// The page starts at the capacity planned by `chunk` (see `PAGE_CAPACITY_FILENAME`). A page
+/// whose occupancy exceeds `max_page_load` is rebuilt with more cells, so no page of the
+/// database is more than `max_page_load` full. The LCA promotions and probing collisions of
+/// the page are recorded in `BUILD_STATS_FILENAME`.
+///
 /// # Arguments
 ///
 /// * `config` - The HashConfig for the process
 /// * `database` - The path to the database
 /// * `chunk_file` - The path to the chunk file
 /// * `taxonomy` - The taxonomy used for processing
-/// * `page_size` - The size of each page
 /// * `page_index` - The index of the current page
+/// * `deterministic` - Insert the cells in sorted order so that the page content does not depend on thread scheduling
+/// * `max_page_load` - The highest occupancy allowed for the page, 0 disables growing pages
+/// * `mmap` - Build the page in a mapping of its file instead of in memory, see `MappedPageFile`
 ///
 /// # Returns
 ///
 /// The number of items processed
+#[allow(clippy::too_many_arguments)]
 pub fn process_k2file(
     config: HashConfig,
-    database: &PathBuf,
-    chunk_file: &PathBuf,
+    database: &Path,
+    chunk_file: &Path,
     taxonomy: &Taxonomy,
-    page_size: usize,
     page_index: usize,
+    deterministic: bool,
+    max_page_load: f64,
+    mmap: bool,
 ) -> IOResult<usize> {
-    let total_counter = AtomicUsize::new(0);
+    let page_size = config.hash_capacity;
+    let start_index = (page_index - 1) * page_size;
+    let end_index = std::cmp::min(page_index * page_size, config.capacity);
+
+    let planned = read_page_capacity(database, page_index)?.unwrap_or(0);
+    let mut capacity = planned.max(end_index - start_index);
+    let page_file = database.join(format!("hash_{}.k2d", page_index));
+    // 旧的 prefilter 不含新页的 key, 留着会漏掉命中
+    remove_prefilter(&page_file)?;
+    // 页文件按小端存储, 只有小端平台能直接映射原子单元
+    let mmap = mmap && cfg!(target_endian = "little");
 
-    let value_mask = config.value_mask;
-    let value_bits = config.value_bits;
+    loop {
+        let counters = PageCounters::default();
+        let count = if config.is_wide() {
+            let mut mapped = if mmap {
+                Some(MappedPageFile::create(&page_file, page_index, capacity, 8)?)
+            } else {
+                None
+            };
+            let owned: Vec<AtomicU64> = match mapped {
+                Some(_) => Vec::new(),
+                None => (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            };
+            let page = match mapped.as_mut() {
+                Some(mapped) => mapped.wide_cells(),
+                None => &owned,
+            };
+            fill_page(chunk_file, deterministic, |item| {
+                set_wide_page_cell(taxonomy, page, item, config.is_double_hashing(), &counters)
+            })?;
+            let count = page
+                .iter()
+                .filter(|cell| cell.load(Ordering::Relaxed) != 0)
+                .count();
+            if let Some(grown) = grown_page_capacity(page_index, count, capacity, max_page_load) {
+                capacity = grown;
+                continue;
+            }
+            match mapped {
+                Some(mapped) => {
+                    mapped.finish()?;
+                    count
+                }
+                None => write_wide_hashtable_to_file(
+                    &owned,
+                    &page_file,
+                    page_index as u64,
+                    capacity as u64,
+                )?,
+            }
+        } else {
+            let mut mapped = if mmap {
+                Some(MappedPageFile::create(&page_file, page_index, capacity, 4)?)
+            } else {
+                None
+            };
+            let owned: Vec<AtomicU32> = match mapped {
+                Some(_) => Vec::new(),
+                None => (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            };
+            let page = match mapped.as_mut() {
+                Some(mapped) => mapped.narrow_cells(),
+                None => &owned,
+            };
+            fill_page(chunk_file, deterministic, |item| {
+                set_narrow_page_cell(config, taxonomy, page, item, &counters)
+            })?;
+            let count = page
+                .iter()
+                .filter(|cell| cell.load(Ordering::Relaxed) != 0)
+                .count();
+            if let Some(grown) = grown_page_capacity(page_index, count, capacity, max_page_load) {
+                capacity = grown;
+                continue;
+            }
+            match mapped {
+                Some(mapped) => {
+                    mapped.finish()?;
+                    count
+                }
+                None => {
+                    write_hashtable_to_file(&owned, &page_file, page_index as u64, capacity as u64)?
+                }
+            }
+        };
+        write_page_build_stats(
+            database,
+            PageBuildStats::new(page_index, capacity, count, &counters),
+        )?;
+        return Ok(count);
+    }
+}
 
+/// Calls `f` with the home index, the cell and the number of chunk cells of every key in
+/// sorted chunk cells
+///
+/// Cells of the same key are adjacent in the sorted stream, their taxids are merged with
+/// the LCA like `set_page_cell` does. Keys without a known taxon are dropped.
+fn for_each_sorted_key<F>(
+    config: HashConfig,
+    taxonomy: &Taxonomy,
+    cells: SortedCells,
+    counters: &PageCounters,
+    mut f: F,
+) -> IOResult<()>
+where
+    F: FnMut(usize, u64, usize) -> IOResult<()>,
+{
+    let (value_bits, value_mask) = if config.is_wide() {
+        (32, u32::MAX as u64)
+    } else {
+        (config.value_bits, config.value_mask as u64)
+    };
+    let mut current: Option<(usize, u64, u32, usize)> = None;
+    for cell in cells {
+        let cell = cell?;
+        let key = cell.value >> value_bits;
+        let taxid = (cell.value & value_mask) as u32;
+        match current {
+            Some((idx, current_key, current_taxid, occurrences))
+                if idx == cell.idx && current_key == key =>
+            {
+                let lca = taxonomy.lca(current_taxid, taxid);
+                if current_taxid != 0 && lca != current_taxid {
+                    counters.lca_promotions.fetch_add(1, Ordering::Relaxed);
+                }
+                current = Some((idx, key, lca, occurrences + 1));
+            }
+            _ => {
+                if let Some((idx, key, taxid, occurrences)) = current.filter(|item| item.2 != 0) {
+                    f(idx, key << value_bits | taxid as u64, occurrences)?;
+                }
+                current = Some((cell.idx, key, taxid, 1));
+            }
+        }
+    }
+    if let Some((idx, key, taxid, occurrences)) = current.filter(|item| item.2 != 0) {
+        f(idx, key << value_bits | taxid as u64, occurrences)?;
+    }
+    Ok(())
+}
+
+/// Writes a hash page from cells ordered by their home index
+///
+/// Every cell goes to the first free slot at or after its home index, which is where linear
+/// probing puts it when the cells are inserted in this order. Cells running past the end of
+/// the page wrap around to the first free slots, which are patched in afterwards.
+///
+/// # Returns
+///
+/// The statistics of the page
+fn write_sorted_page(
+    config: HashConfig,
+    taxonomy: &Taxonomy,
+    run_files: &[PathBuf],
+    page_file: &Path,
+    page_index: usize,
+    capacity: usize,
+) -> IOResult<PageBuildStats> {
+    let counters = &PageCounters::default();
+    let cell_bytes = if config.is_wide() { 8 } else { 4 };
+    let mut writer = BufWriter::new(File::create(page_file)?);
+    writer.write_u64::<LittleEndian>(page_index as u64)?;
+    writer.write_u64::<LittleEndian>(capacity as u64)?;
+
+    let write_cell = |writer: &mut BufWriter<File>, cell: u64| {
+        if cell_bytes == 8 {
+            writer.write_u64::<LittleEndian>(cell)
+        } else {
+            writer.write_u32::<LittleEndian>(cell as u32)
+        }
+    };
+    let mut position = 0;
+    let mut count = 0;
+    let mut wrapped = Vec::new();
+    for_each_sorted_key(
+        config,
+        taxonomy,
+        SortedCells::open(run_files)?,
+        counters,
+        |idx, cell, occurrences| {
+            let slot = idx.max(position);
+            if slot >= capacity {
+                wrapped.push((idx, cell, occurrences));
+                return Ok(());
+            }
+            // 同一 key 的每个 chunk cell 都沿相同的探测序列找到它
+            counters
+                .collisions
+                .fetch_add((slot - idx) * occurrences, Ordering::Relaxed);
+            for _ in position..slot {
+                write_cell(&mut writer, 0)?;
+            }
+            write_cell(&mut writer, cell)?;
+            position = slot + 1;
+            count += 1;
+            Ok(())
+        },
+    )?;
+    for _ in position..capacity {
+        write_cell(&mut writer, 0)?;
+    }
+    writer.flush()?;
+    drop(writer);
+
+    // 超出页尾的 cell 和线性探测一样从页首的空位继续放
+    if !wrapped.is_empty() {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(page_file)?;
+        let mut buffer = vec![0u8; cell_bytes];
+        let mut wrapped = wrapped.into_iter().peekable();
+        for slot in 0..capacity {
+            let Some(&(idx, cell, occurrences)) = wrapped.peek() else {
+                break;
+            };
+            let offset = 16 + (slot * cell_bytes) as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            if buffer.iter().all(|&byte| byte == 0) {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&cell.to_le_bytes()[..cell_bytes])?;
+                wrapped.next();
+                counters
+                    .collisions
+                    .fetch_add((capacity - idx + slot) * occurrences, Ordering::Relaxed);
+                count += 1;
+            }
+        }
+    }
+    Ok(PageBuildStats::new(page_index, capacity, count, counters))
+}
+
+/// Processes a k2 file like `process_k2file` without holding the page in memory
+///
+/// The chunk file is sorted externally in runs of at most `sort_buffer` bytes, and the page
+/// is written in one pass over the merged runs. The cells end up where a `deterministic`
+/// build puts them, and the same statistics are recorded in `BUILD_STATS_FILENAME`.
+///
+/// The single pass relies on linear probing, databases probed by double hashing (see
+/// `DOUBLE_HASHING_FLAG`) are refused.
+///
+/// # Arguments
+///
+/// * `config` - The HashConfig for the process
+/// * `database` - The path to the database
+/// * `chunk_file` - The path to the chunk file
+/// * `taxonomy` - The taxonomy used for processing
+/// * `page_index` - The index of the current page
+/// * `max_page_load` - The highest occupancy allowed for the page, 0 disables growing pages
+/// * `sort_buffer` - The number of bytes of chunk cells sorted in memory at a time
+///
+/// # Returns
+///
+/// The number of items processed
+pub fn process_sorted_k2file(
+    config: HashConfig,
+    database: &Path,
+    chunk_file: &Path,
+    taxonomy: &Taxonomy,
+    page_index: usize,
+    max_page_load: f64,
+    sort_buffer: usize,
+) -> IOResult<usize> {
+    if config.is_double_hashing() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "pages probed by double hashing cannot be built from sorted chunk files",
+        ));
+    }
+    let page_size = config.hash_capacity;
     let start_index = (page_index - 1) * page_size;
     let end_index = std::cmp::min(page_index * page_size, config.capacity);
 
-    let capacity = end_index - start_index;
+    let cell_size = std::mem::size_of::<Slot<u64>>();
+    let run_files = sort_chunk_file(chunk_file, sort_buffer / cell_size)?;
+
+    // 先数出 key 的个数, 一次确定页面容量
+    let mut count = 0;
+    for_each_sorted_key(
+        config,
+        taxonomy,
+        SortedCells::open(&run_files)?,
+        &PageCounters::default(),
+        |_, _, _| {
+            count += 1;
+            Ok(())
+        },
+    )?;
+    let planned = read_page_capacity(database, page_index)?.unwrap_or(0);
+    let mut capacity = planned.max(end_index - start_index);
+    while let Some(grown) = grown_page_capacity(page_index, count, capacity, max_page_load) {
+        capacity = grown;
+    }
+
     let page_file = database.join(format!("hash_{}.k2d", page_index));
+    remove_prefilter(&page_file)?;
+    let page_stats = write_sorted_page(
+        config, taxonomy, &run_files, &page_file, page_index, capacity,
+    )?;
+    remove_sorted_runs(chunk_file)?;
+    write_page_build_stats(database, page_stats)?;
+    Ok(page_stats.cells)
+}
 
-    let page: Vec<AtomicU32> = (0..capacity).map(|_| AtomicU32::new(0)).collect();
+/// Merges the cells of a k2 chunk file into an existing hash page
+///
+/// Cells already stored in `hash_{page_index}.k2d` are kept and the new cells are
+/// inserted with the same LCA rule as `process_k2file`. A prefilter of the page gets the
+/// new cells as well.
+///
+/// # Arguments
+///
+/// * `config` - The HashConfig of the database
+/// * `database` - The path to the database
+/// * `chunk_file` - The path to the chunk file with the new cells
+/// * `taxonomy` - The taxonomy used for processing
+/// * `page_index` - The 1-based index of the page
+/// * `deterministic` - Insert the cells in sorted order
+///
+/// # Returns
+///
+/// The number of non-zero cells in the updated page
+pub fn update_k2file(
+    config: HashConfig,
+    database: &Path,
+    chunk_file: &Path,
+    taxonomy: &Taxonomy,
+    page_index: usize,
+    deterministic: bool,
+) -> IOResult<usize> {
+    let page_file = database.join(format!("hash_{}.k2d", page_index));
+    let old_page = read_page_from_file(&page_file)?;
+    let capacity = old_page.size;
+    let prefilter_file = prefilter_path(&page_file);
+    if prefilter_file.exists() {
+        let mut prefilter = Prefilter::read(&prefilter_file)?;
+        prefilter.insert_chunk_file(&config, chunk_file)?;
+        prefilter.write(&prefilter_file)?;
+    }
+
+    if old_page.is_wide() {
+        let page: Vec<AtomicU64> = old_page
+            .data
+            .iter()
+            .zip(&old_page.keys)
+            .map(|(&taxid, &key)| AtomicU64::new((key as u64) << 32 | taxid as u64))
+            .collect();
+        let double_hashing = config.is_double_hashing();
+        fill_page(chunk_file, deterministic, |item| {
+            set_wide_page_cell(
+                taxonomy,
+                &page,
+                item,
+                double_hashing,
+                &PageCounters::default(),
+            )
+        })?;
+        return write_wide_hashtable_to_file(&page, &page_file, page_index as u64, capacity as u64);
+    }
+
+    let page: Vec<AtomicU32> = old_page.data.into_iter().map(AtomicU32::new).collect();
+    fill_page(chunk_file, deterministic, |item| {
+        set_narrow_page_cell(config, taxonomy, &page, item, &PageCounters::default())
+    })?;
+
+    write_hashtable_to_file(&page, &page_file, page_index as u64, capacity as u64)
+}
+
+/// Sets a chunk cell in a page with 32-bit cells
+fn set_narrow_page_cell(
+    config: HashConfig,
+    taxonomy: &Taxonomy,
+    page: &[AtomicU32],
+    item: &Slot<u64>,
+    counters: &PageCounters,
+) {
+    let cell = Slot::new(item.idx, item.value as u32);
+    set_page_cell(
+        taxonomy,
+        page,
+        &cell,
+        config.is_double_hashing(),
+        config.value_bits,
+        config.value_mask,
+        counters,
+    );
+}
+
+/// Inserts all cells of a k2 chunk file into a page with `insert`
+///
+/// The LCA of a key does not depend on the insertion order, but the slot a key ends up in
+/// after probing does. With `deterministic` the whole chunk is sorted by index and
+/// value and inserted by a single thread, otherwise batches are inserted in parallel.
+fn fill_page<F>(chunk_file: &Path, deterministic: bool, insert: F) -> IOResult<()>
+where
+    F: Fn(&Slot<u64>) + Sync,
+{
+    let mut sorted_cells: Vec<Slot<u64>> = Vec::new();
 
     let file = open_file(&chunk_file)?;
     let mut reader = BufReader::new(file);
 
-    let cell_size = std::mem::size_of::<Slot<u32>>();
+    let cell_size = Slot::<u64>::BYTES;
     let batch_buffer_size = cell_size * BATCH_SIZE;
     let mut batch_buffer = vec![0u8; batch_buffer_size];
 
@@ -162,27 +966,32 @@ pub fn process_k2file(
         // Process the read data batch
         let cells_in_batch = bytes_read / cell_size;
 
-        let cells = unsafe {
-            std::slice::from_raw_parts(batch_buffer.as_ptr() as *const Slot<u32>, cells_in_batch)
-        };
-        cells.par_iter().for_each(|item| {
-            set_page_cell(taxonomy, &page, item, capacity, value_bits, value_mask);
-        });
-        total_counter.fetch_add(cells.len(), Ordering::SeqCst);
+        let cells: Vec<Slot<u64>> = batch_buffer[..cells_in_batch * cell_size]
+            .chunks_exact(cell_size)
+            .map(|bytes| Slot::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        if deterministic {
+            sorted_cells.extend(cells);
+        } else {
+            cells.par_iter().for_each(&insert);
+        }
     }
 
-    let size_count =
-        write_hashtable_to_file(&page, &page_file, page_index as u64, capacity as u64)?;
-    Ok(size_count)
+    if deterministic {
+        sorted_cells.par_sort_unstable_by_key(|item| (item.idx, item.value));
+        sorted_cells.iter().for_each(insert);
+    }
+    Ok(())
 }
 
 /// Generates a taxonomy tree file
 ///
 /// # Arguments
 ///
-/// * `ncbi_taxonomy_directory` - The directory containing NCBI taxonomy files
+/// * `ncbi_taxonomy_directory` - The directory containing NCBI taxonomy files or GTDB taxonomy files
 /// * `taxonomy_filename` - The output filename for the generated taxonomy
-/// * `id_map` - A map of string IDs to u64 IDs
+/// * `id_map` - A map of string IDs to u64 IDs; taxids merged since it was written are remapped
+///   and those deleted or unknown removed, as reported in `taxid_remap.tsv` next to the taxonomy
 ///
 /// # Returns
 ///
@@ -190,13 +999,44 @@ pub fn process_k2file(
 pub fn generate_taxonomy(
     ncbi_taxonomy_directory: &PathBuf,
     taxonomy_filename: &PathBuf,
-    id_map: &HashMap<String, u64>,
+    id_map: &mut HashMap<String, u64>,
 ) -> IOResult<Taxonomy> {
     let nodes_filename = ncbi_taxonomy_directory.join("nodes.dmp");
     let names_filename = ncbi_taxonomy_directory.join("names.dmp");
-    let mut ncbi = NCBITaxonomy::from_ncbi(nodes_filename, names_filename)?;
+    // an NCBI (or GTDB-style) taxdump takes precedence over GTDB taxonomy files
+    let gtdb_files = if nodes_filename.exists() {
+        Vec::new()
+    } else {
+        find_gtdb_taxonomy_files(ncbi_taxonomy_directory)?
+    };
+    let mut ncbi = if gtdb_files.is_empty() {
+        NCBITaxonomy::from_ncbi(nodes_filename, names_filename)?
+    } else {
+        NCBITaxonomy::from_gtdb(&gtdb_files)?.0
+    };
 
-    for (_, id) in id_map.into_iter() {
+    // user-defined pseudo-taxa, e.g. spike-in controls
+    let pseudo_taxa_filename = ncbi_taxonomy_directory.join(PSEUDO_TAXA_FILENAME);
+    if pseudo_taxa_filename.exists() {
+        let pseudo_taxa = read_pseudo_taxa(&pseudo_taxa_filename)?;
+        ncbi.add_pseudo_taxa(&pseudo_taxa)?;
+        // keep a copy next to the taxonomy so classification can report recovery rates
+        if let Some(database) = taxonomy_filename.parent() {
+            std::fs::copy(&pseudo_taxa_filename, database.join(PSEUDO_TAXA_FILENAME))?;
+        }
+    }
+
+    // seqid2taxid.map 可能引用已被 NCBI 合并或删除的 taxid
+    let remap = TaxidChanges::from_directory(ncbi_taxonomy_directory)?
+        .remap(id_map, |taxid| ncbi.contains(taxid));
+    if !remap.is_empty() {
+        remap.log_summary();
+        if let Some(database) = taxonomy_filename.parent() {
+            remap.write_to_file(database.join(TAXID_REMAP_FILENAME))?;
+        }
+    }
+
+    for (_, id) in id_map.iter() {
         ncbi.mark_node(*id);
     }
     let mut taxo = ncbi.convert_to_kraken_taxonomy();
@@ -241,7 +1081,7 @@ pub fn get_bits_for_taxid(
 /// * `taxonomy` - The taxonomy used for processing
 /// * `id_to_taxon_map` - A map of string IDs to taxon IDs
 /// * `hash_config` - The HashConfig for the process
-/// * `writers` - A vector of BufWriters for output
+/// * `writers` - One BufWriter per partition; cells of partitions without a writer are dropped
 /// * `chunk_size` - The size of each chunk
 /// * `threads` - The number of threads to use for processing
 pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
@@ -250,17 +1090,16 @@ pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
     taxonomy: &Taxonomy,
     id_to_taxon_map: &HashMap<String, u64>,
     hash_config: HashConfig,
-    writers: &mut Vec<BufWriter<File>>,
+    writers: &mut [Option<BufWriter<File>>],
     chunk_size: usize,
     threads: usize,
 ) {
     let mut reader = BufferFastaReader::from_path(fna_file, 1).unwrap();
     let value_bits = hash_config.value_bits;
-    let cell_size = std::mem::size_of::<Slot<u32>>();
 
     read_parallel(
         &mut reader,
-        threads,
+        pipeline_threads(threads),
         &meros,
         |seqs| {
             let mut k2_cell_list = Vec::new();
@@ -270,13 +1109,18 @@ pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
                 record.body.apply_mut(|m_iter| {
                     if let Some(ext_taxid) = id_to_taxon_map.get(&header.id) {
                         let taxid = taxonomy.get_internal_id(*ext_taxid);
-                        let k2_cell: Vec<(usize, Slot<u32>)> = m_iter
+                        let k2_cell: Vec<(usize, Slot<u64>)> = m_iter
                             .map(|(_, hash_key)| {
                                 let index: usize = hash_config.index(hash_key);
                                 let idx = index % chunk_size;
                                 let partition_index = index / chunk_size;
-                                let cell =
-                                    Slot::new(idx, u32::hash_value(hash_key, value_bits, taxid));
+                                // chunk cells are always 64-bit, pages with 32-bit cells use the lower half
+                                let value = if hash_config.is_wide() {
+                                    hash_config.wide_cell(hash_key, taxid)
+                                } else {
+                                    u32::hash_value(hash_key, value_bits, taxid) as u64
+                                };
+                                let cell = Slot::new(idx, value);
                                 (partition_index, cell)
                             })
                             .collect();
@@ -293,8 +1137,8 @@ pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
                 let k2_cell_map = data.unwrap();
                 for cell in k2_cell_map {
                     let partition_index = cell.0;
-                    if let Some(writer) = writers.get_mut(partition_index) {
-                        writer.write_all(&cell.1.as_slice(cell_size)).unwrap();
+                    if let Some(Some(writer)) = writers.get_mut(partition_index) {
+                        writer.write_all(&cell.1.to_le_bytes()).unwrap();
                     }
                 }
             }
@@ -302,3 +1146,56 @@ pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
     )
     .expect("failed");
 }
+
+/// Collects the hash partitions that the minimizers of the given sequences fall into
+///
+/// # Arguments
+///
+/// * `fna_file` - The input FNA file path
+/// * `meros` - The Meros instance for k-mer processing
+/// * `id_to_taxon_map` - The sequences to scan, other sequences of the file are skipped
+/// * `hash_config` - The HashConfig of the database
+/// * `chunk_size` - The size of each chunk
+/// * `threads` - The number of threads to use for processing
+///
+/// # Returns
+///
+/// The 0-based indexes of the partitions
+pub fn collect_partitions<P: AsRef<Path>>(
+    fna_file: P,
+    meros: Meros,
+    id_to_taxon_map: &HashMap<String, u64>,
+    hash_config: HashConfig,
+    chunk_size: usize,
+    threads: usize,
+) -> HashSet<usize> {
+    let mut reader = BufferFastaReader::from_path(fna_file, 1).unwrap();
+    let mut partitions = HashSet::new();
+
+    read_parallel(
+        &mut reader,
+        pipeline_threads(threads),
+        &meros,
+        |seqs| {
+            let mut batch = HashSet::new();
+            for record in seqs {
+                if id_to_taxon_map.contains_key(&record.header.id) {
+                    record.body.apply_mut(|m_iter| {
+                        batch.extend(
+                            m_iter.map(|(_, hash_key)| hash_config.index(hash_key) / chunk_size),
+                        );
+                    });
+                }
+            }
+            batch
+        },
+        |record_sets| {
+            while let Some(data) = record_sets.next() {
+                partitions.extend(data.unwrap());
+            }
+        },
+    )
+    .expect("failed");
+
+    partitions
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+/// Default window length of the DUST low-complexity filter
+pub const DUST_WINDOW: usize = 64;
+/// Default score threshold of the DUST low-complexity filter
+pub const DUST_THRESHOLD: usize = 20;
+
+fn base_code(base: u8) -> Option<u8> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' | b'U' => Some(3),
+        _ => None,
+    }
+}
+
+/// Masks low-complexity regions of a sequence with `N`
+///
+/// Each window of `window` bases is scored by its triplet composition as in DUST
+/// (Morgulis et al. 2006): the sum of `c * (c - 1) / 2` over the counts `c` of the
+/// `l = window - 2` triplets, divided by `l - 1`. All bases of windows scoring above
+/// `threshold` are masked. Line breaks are skipped, so wrapped FASTA sequences can be
+/// masked in place.
+///
+/// # Arguments
+///
+/// * `seq` - The sequence to mask
+/// * `window` - The window length in bases
+/// * `threshold` - The score above which a window is masked
+///
+/// # Returns
+///
+/// The number of bases that were masked
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::dust::{dust_mask, DUST_THRESHOLD, DUST_WINDOW};
+///
+/// let complex = b"GATTACAGCTTGCAAGTCCGATGCATCGTAGCTAGGCTAACGTTGCCAGTCAGGTACCATGAC";
+/// let mut seq = complex.to_vec();
+/// assert_eq!(dust_mask(&mut seq, DUST_WINDOW, DUST_THRESHOLD), 0);
+///
+/// let mut seq = [complex.to_vec(), vec![b'A'; 100], complex.to_vec()].concat();
+/// assert!(dust_mask(&mut seq, DUST_WINDOW, DUST_THRESHOLD) >= 100);
+/// assert!(seq[64..164].iter().all(|&b| b == b'N'));
+/// assert_eq!(&seq[..20], &complex[..20]);
+/// ```
+pub fn dust_mask(seq: &mut [u8], window: usize, threshold: usize) -> usize {
+    let l = window.saturating_sub(2).max(2);
+    let limit = threshold * (l - 1);
+
+    let mut counts = [0usize; 64];
+    let mut score = 0;
+    // triplet code (None if it spans an ambiguous base) and the position of its first base
+    let mut triplets: VecDeque<(Option<usize>, usize)> = VecDeque::with_capacity(l + 1);
+    let mut recent = [0usize; 3];
+    let mut code = 0usize;
+    let mut run = 0;
+    let mut seen = 0;
+    let mut masked_until = 0;
+    let mut masked = 0;
+
+    for i in 0..seq.len() {
+        if seq[i] == b'\n' || seq[i] == b'\r' {
+            continue;
+        }
+        recent = [recent[1], recent[2], i];
+        seen += 1;
+        match base_code(seq[i]) {
+            Some(c) => {
+                code = ((code << 2) | c as usize) & 0x3f;
+                run += 1;
+            }
+            None => run = 0,
+        }
+        if seen < 3 {
+            continue;
+        }
+
+        let triplet = (run >= 3).then_some(code);
+        if let Some(t) = triplet {
+            score += counts[t];
+            counts[t] += 1;
+        }
+        triplets.push_back((triplet, recent[0]));
+        if triplets.len() > l {
+            if let Some((Some(t), _)) = triplets.pop_front() {
+                counts[t] -= 1;
+                score -= counts[t];
+            }
+        }
+
+        if triplets.len() == l && score > limit {
+            let start = triplets[0].1.max(masked_until);
+            for base in &mut seq[start..=i] {
+                if *base != b'\n' && *base != b'\r' && *base != b'N' {
+                    *base = b'N';
+                    masked += 1;
+                }
+            }
+            masked_until = i + 1;
+        }
+    }
+
+    masked
+}
+
+/// Masks low-complexity regions of a FASTA record, leaving its header line untouched
+///
+/// # Arguments
+///
+/// * `record` - A FASTA record, optionally starting with its `>` header line
+///
+/// # Returns
+///
+/// The number of bases that were masked
+pub fn mask_fasta_record(record: &mut [u8]) -> usize {
+    let seq_start = if record.first() == Some(&b'>') {
+        record
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(record.len(), |pos| pos + 1)
+    } else {
+        0
+    };
+    dust_mask(&mut record[seq_start..], DUST_WINDOW, DUST_THRESHOLD)
+}
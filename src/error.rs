@@ -0,0 +1,186 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Exit code of failures without a more specific code
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of invalid arguments or input files, the same as for command-line errors
+pub const EXIT_INVALID_INPUT: i32 = 2;
+/// Exit code of a missing input file or database file
+pub const EXIT_NOT_FOUND: i32 = 3;
+/// Exit code of a database that is incomplete, corrupt or of an unsupported version
+pub const EXIT_INVALID_DATABASE: i32 = 4;
+/// Exit code of a run that does not fit the memory budget or the free disk space
+pub const EXIT_RESOURCES: i32 = 5;
+/// Exit code of other failures to read or write a file
+pub const EXIT_IO: i32 = 6;
+
+/// Errors of Kun-peng that say what went wrong and what to do about it
+///
+/// Functions that return `io::Result` carry these errors inside an `io::Error`
+/// (see `From<Error> for io::Error`), so [`exit_code`] finds them again at the top.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A database file is incomplete, corrupt or of an unsupported version
+    #[error("invalid database file {path:?}: {reason}; rebuild the database or check --db")]
+    InvalidDatabase { path: PathBuf, reason: String },
+    /// A file written by an older or a newer kun_peng, with the format version of the file,
+    /// the version this kun_peng reads and how to upgrade an older file
+    #[error("{}", format_version_message(.path, *.found, *.supported, .upgrade))]
+    FormatVersion {
+        path: PathBuf,
+        found: u64,
+        supported: u64,
+        upgrade: String,
+    },
+    /// Arguments or input files that cannot be processed
+    #[error("{0}")]
+    InvalidInput(String),
+    /// More samples in the chunk files than their slots can tell apart
+    #[error("{files} samples are more than the {max} the chunk files can tell apart; annotate the chunk files before splitting more, or run classify, which splits the samples into batches")]
+    TooManyFiles { files: usize, max: usize },
+    /// An output that would overwrite existing data
+    #[error("{0:?} already exists; remove it or choose another output directory")]
+    AlreadyExists(PathBuf),
+    /// Not enough memory or disk space for the run
+    #[error("{0}")]
+    Resources(String),
+    /// A file that could not be created, opened or written
+    #[error("{path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+/// The message of [`Error::FormatVersion`]
+fn format_version_message(path: &Path, found: u64, supported: u64, upgrade: &str) -> String {
+    if found < supported {
+        format!(
+            "{:?} was written by an older kun_peng (format version {}, this kun_peng reads version {}); {}",
+            path, found, supported, upgrade
+        )
+    } else {
+        format!(
+            "{:?} was written by a newer kun_peng (format version {}, this kun_peng reads up to version {}); use that kun_peng or a newer one",
+            path, found, supported
+        )
+    }
+}
+
+impl Error {
+    /// Wraps an I/O error with the file it happened on
+    pub fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// The process exit code of the error
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::error::{Error, EXIT_INVALID_INPUT};
+    ///
+    /// let error = Error::TooManyFiles { files: 40, max: 32 };
+    /// assert_eq!(error.exit_code(), EXIT_INVALID_INPUT);
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "40 samples are more than the 32 the chunk files can tell apart; annotate the chunk files before splitting more, or run classify, which splits the samples into batches"
+    /// );
+    /// ```
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidDatabase { .. } | Error::FormatVersion { .. } => EXIT_INVALID_DATABASE,
+            Error::InvalidInput(_) | Error::TooManyFiles { .. } | Error::AlreadyExists(_) => {
+                EXIT_INVALID_INPUT
+            }
+            Error::Resources(_) => EXIT_RESOURCES,
+            Error::Io { source, .. } => io_exit_code(source),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::InvalidDatabase { .. } | Error::FormatVersion { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            Error::InvalidInput(_) | Error::TooManyFiles { .. } => io::ErrorKind::InvalidInput,
+            Error::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            Error::Resources(_) => io::ErrorKind::OutOfMemory,
+            Error::Io { source, .. } => source.kind(),
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Turns the failure to read a file of a database into [`Error::InvalidDatabase`]
+///
+/// A missing or unreadable database file means the database is incomplete. Errors that
+/// already say what is wrong, e.g. [`Error::FormatVersion`], are kept.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::error::{database_error, exit_code, EXIT_INVALID_DATABASE};
+/// use std::io;
+///
+/// let missing = io::Error::new(io::ErrorKind::NotFound, "No such file or directory");
+/// let error = database_error("db/hash_config.k2d", missing);
+/// assert_eq!(exit_code(&error), EXIT_INVALID_DATABASE);
+/// assert!(error.to_string().starts_with("invalid database file \"db/hash_config.k2d\""));
+/// ```
+pub fn database_error<P: Into<PathBuf>>(path: P, source: io::Error) -> io::Error {
+    if source.get_ref().is_some_and(|e| e.is::<Error>()) {
+        return source;
+    }
+    Error::InvalidDatabase {
+        path: path.into(),
+        reason: source.to_string(),
+    }
+    .into()
+}
+
+fn io_exit_code(error: &io::Error) -> i32 {
+    if let Some(error) = error.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        return error.exit_code();
+    }
+    match error.kind() {
+        io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        io::ErrorKind::InvalidInput => EXIT_INVALID_INPUT,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EXIT_INVALID_DATABASE,
+        io::ErrorKind::OutOfMemory | io::ErrorKind::StorageFull => EXIT_RESOURCES,
+        _ => EXIT_IO,
+    }
+}
+
+/// The process exit code of an error returned by a subcommand
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::error::{exit_code, Error, EXIT_FAILURE, EXIT_INVALID_DATABASE, EXIT_NOT_FOUND};
+/// use std::io;
+///
+/// let missing = io::Error::new(io::ErrorKind::NotFound, "taxo.k2d");
+/// assert_eq!(exit_code(&missing), EXIT_NOT_FOUND);
+///
+/// let corrupt: io::Error = Error::InvalidDatabase {
+///     path: "hash_config.k2d".into(),
+///     reason: "hash_capacity is 0".to_string(),
+/// }
+/// .into();
+/// assert_eq!(exit_code(&corrupt), EXIT_INVALID_DATABASE);
+///
+/// let other: Box<dyn std::error::Error> = "unknown".into();
+/// assert_eq!(exit_code(other.as_ref()), EXIT_FAILURE);
+/// ```
+pub fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        error.exit_code()
+    } else if let Some(error) = error.downcast_ref::<io::Error>() {
+        io_exit_code(error)
+    } else {
+        EXIT_FAILURE
+    }
+}
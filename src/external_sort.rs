@@ -0,0 +1,149 @@
+use crate::compact_hash::Slot;
+use crate::utils::{find_files, open_file};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of a chunk cell on disk
+const CELL_SIZE: usize = Slot::<u64>::BYTES;
+
+/// File name prefix of the sorted runs of a chunk file, e.g. `chunk_1.k2.run`
+fn run_prefix(chunk_file: &Path) -> String {
+    format!(
+        "{}.run",
+        chunk_file.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// Removes the sorted runs of a chunk file, e.g. those left by an interrupted build
+pub fn remove_sorted_runs(chunk_file: &Path) -> Result<()> {
+    let dir = chunk_file.parent().unwrap_or(Path::new("."));
+    for run_file in find_files(dir, &run_prefix(chunk_file), "") {
+        fs::remove_file(run_file)?;
+    }
+    Ok(())
+}
+
+/// Reads the next cell of a chunk or run file, `None` at the end of the file
+fn read_cell<R: Read>(reader: &mut R) -> Result<Option<Slot<u64>>> {
+    let mut buffer = [0u8; CELL_SIZE];
+    match reader.read_exact(&mut buffer) {
+        Ok(()) => Ok(Some(Slot::from_le_bytes(&buffer))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Splits a chunk file into runs sorted by index and value
+///
+/// At most `run_cells` cells are held in memory at a time. The runs are written next to the
+/// chunk file as `<chunk file>.run<n>`; runs of an earlier, interrupted sort are removed first.
+///
+/// # Arguments
+///
+/// * `chunk_file` - The chunk file with the unsorted cells of one page
+/// * `run_cells` - The number of cells sorted in memory at a time
+///
+/// # Returns
+///
+/// The run files, empty for an empty chunk file
+pub fn sort_chunk_file(chunk_file: &Path, run_cells: usize) -> Result<Vec<PathBuf>> {
+    remove_sorted_runs(chunk_file)?;
+    let run_cells = run_cells.max(1);
+    let mut reader = BufReader::new(open_file(chunk_file)?);
+    let mut run_files = Vec::new();
+    let mut cells: Vec<Slot<u64>> = Vec::with_capacity(run_cells.min(1 << 24));
+    loop {
+        cells.clear();
+        while cells.len() < run_cells {
+            match read_cell(&mut reader)? {
+                Some(cell) => cells.push(cell),
+                None => break,
+            }
+        }
+        if cells.is_empty() {
+            break;
+        }
+        cells.par_sort_unstable_by_key(|cell| (cell.idx, cell.value));
+
+        let run_file =
+            chunk_file.with_file_name(format!("{}{}", run_prefix(chunk_file), run_files.len()));
+        let mut writer = BufWriter::new(File::create(&run_file)?);
+        for cell in &cells {
+            writer.write_all(&cell.to_le_bytes())?;
+        }
+        writer.flush()?;
+        run_files.push(run_file);
+        if cells.len() < run_cells {
+            break;
+        }
+    }
+    Ok(run_files)
+}
+
+/// The cells of sorted runs, merged into one stream ordered by index and value
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::compact_hash::Slot;
+/// use kun_peng::external_sort::{sort_chunk_file, SortedCells};
+/// use std::io::Write;
+///
+/// let dir = std::env::temp_dir().join("kun_peng_sorted_cells_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let chunk_file = dir.join("chunk_1.k2");
+/// let mut file = std::fs::File::create(&chunk_file).unwrap();
+/// for (idx, value) in [(5, 1), (2, 9), (5, 0), (1, 3), (2, 4)] {
+///     file.write_all(&Slot::<u64>::new(idx, value).to_le_bytes()).unwrap();
+/// }
+/// drop(file);
+///
+/// // two cells per run: three runs
+/// let run_files = sort_chunk_file(&chunk_file, 2).unwrap();
+/// assert_eq!(run_files.len(), 3);
+/// let cells: Vec<(usize, u64)> = SortedCells::open(&run_files)
+///     .unwrap()
+///     .map(|cell| cell.map(|cell| (cell.idx, cell.value)))
+///     .collect::<std::io::Result<_>>()
+///     .unwrap();
+/// assert_eq!(cells, vec![(1, 3), (2, 4), (2, 9), (5, 0), (5, 1)]);
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct SortedCells {
+    runs: Vec<BufReader<File>>,
+    heap: BinaryHeap<Reverse<(usize, u64, usize)>>,
+}
+
+impl SortedCells {
+    /// Opens the runs written by `sort_chunk_file`
+    pub fn open(run_files: &[PathBuf]) -> Result<Self> {
+        let mut runs = Vec::with_capacity(run_files.len());
+        let mut heap = BinaryHeap::with_capacity(run_files.len());
+        for (run, run_file) in run_files.iter().enumerate() {
+            let mut reader = BufReader::new(open_file(run_file)?);
+            if let Some(cell) = read_cell(&mut reader)? {
+                heap.push(Reverse((cell.idx, cell.value, run)));
+            }
+            runs.push(reader);
+        }
+        Ok(Self { runs, heap })
+    }
+}
+
+impl Iterator for SortedCells {
+    type Item = Result<Slot<u64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((idx, value, run)) = self.heap.pop()?;
+        match read_cell(&mut self.runs[run]) {
+            Ok(Some(cell)) => self.heap.push(Reverse((cell.idx, cell.value, run))),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(Slot::new(idx, value)))
+    }
+}
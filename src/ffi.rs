@@ -0,0 +1,256 @@
+//! C API of the classifier, exported by the `kun_peng` shared library
+//!
+//! The declarations for C and C++ are in `include/kun_peng.h`. A failing call returns
+//! NULL and leaves a message for [`kun_peng_last_error`].
+use crate::classifier::{Classifier, ClassifierOptions, ClassifySummary, ReadCall};
+use crate::input::parse_fastx;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    CString::new(s.replace('\0', " "))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// Runs an API call, turning errors and panics into NULL and the last error
+fn guard<T, F>(f: F) -> *mut T
+where
+    F: FnOnce() -> Result<*mut T, String>,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_last_error(message);
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_last_error("kun_peng panicked".to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The call of one read
+#[repr(C)]
+pub struct KunPengCall {
+    /// The read ID
+    pub id: *mut c_char,
+    /// 1 if the read was assigned to a taxon, 0 otherwise
+    pub classified: c_int,
+    /// External ID of the assigned taxon, 0 if unclassified
+    pub taxid: u64,
+    /// Length of the read
+    pub seq_len: *mut c_char,
+    /// The k-mer mapping, e.g. "562:13 561:4 A:31"
+    pub hits: *mut c_char,
+}
+
+impl From<ReadCall> for KunPengCall {
+    fn from(call: ReadCall) -> Self {
+        Self {
+            id: to_c_string(call.id),
+            classified: call.classified as c_int,
+            taxid: call.taxid,
+            seq_len: to_c_string(call.seq_len),
+            hits: to_c_string(call.hits),
+        }
+    }
+}
+
+/// The calls and read counts of one classified buffer
+#[repr(C)]
+pub struct KunPengResults {
+    /// The calls, `len` of them
+    pub calls: *mut KunPengCall,
+    pub len: usize,
+    /// The number of reads
+    pub total_seqs: u64,
+    /// The number of unclassified reads
+    pub total_unclassified: u64,
+    summary: *mut ClassifySummary,
+}
+
+/// Returns the message of the last failed call on this thread, or NULL
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn kun_peng_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Loads a database, returns NULL on failure
+///
+/// # Arguments
+///
+/// * `database` - The database directory, a NUL-terminated UTF-8 path
+/// * `num_threads` - The number of threads of each classification, 0 for all CPUs
+/// * `confidence_threshold` - Confidence score threshold
+/// * `minimum_hit_groups` - The minimum number of hit groups needed for a call
+///
+/// # Safety
+///
+/// `database` must be a valid NUL-terminated string. The classifier must be released
+/// with [`kun_peng_close`].
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_open(
+    database: *const c_char,
+    num_threads: usize,
+    confidence_threshold: f64,
+    minimum_hit_groups: usize,
+) -> *mut Classifier {
+    guard(|| {
+        if database.is_null() {
+            return Err("database is NULL".to_string());
+        }
+        let database = PathBuf::from(
+            CStr::from_ptr(database)
+                .to_str()
+                .map_err(|e| e.to_string())?,
+        );
+        let mut options = ClassifierOptions {
+            confidence_threshold,
+            minimum_hit_groups,
+            ..Default::default()
+        };
+        if num_threads > 0 {
+            options.num_threads = num_threads;
+        }
+        let classifier = Classifier::load(&database, options)
+            .map_err(|e| format!("failed to load {:?}: {}", database, e))?;
+        Ok(Box::into_raw(Box::new(classifier)))
+    })
+}
+
+/// Releases a classifier
+///
+/// # Safety
+///
+/// `classifier` must come from [`kun_peng_open`] and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_close(classifier: *mut Classifier) {
+    if !classifier.is_null() {
+        drop(Box::from_raw(classifier));
+    }
+}
+
+/// Classifies the reads of a FASTA or FASTQ text, returns NULL on failure
+///
+/// # Arguments
+///
+/// * `classifier` - The loaded database
+/// * `data` - The FASTA/FASTQ text, `len` bytes, not compressed
+/// * `minimum_quality_score` - FASTQ bases below this quality are masked
+///
+/// # Safety
+///
+/// `classifier` must come from [`kun_peng_open`] and `data` must point to `len` readable
+/// bytes. The results must be released with [`kun_peng_results_free`].
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_classify(
+    classifier: *const Classifier,
+    data: *const u8,
+    len: usize,
+    minimum_quality_score: c_int,
+) -> *mut KunPengResults {
+    guard(|| {
+        let classifier = classifier.as_ref().ok_or("classifier is NULL")?;
+        let data = if len == 0 {
+            &[][..]
+        } else if data.is_null() {
+            return Err("data is NULL".to_string());
+        } else {
+            std::slice::from_raw_parts(data, len)
+        };
+        let records = parse_fastx(data, minimum_quality_score, false).map_err(|e| e.to_string())?;
+        let (calls, summary) = classifier
+            .classify_records(records)
+            .map_err(|e| e.to_string())?;
+
+        let calls: Box<[KunPengCall]> = calls.into_iter().map(KunPengCall::from).collect();
+        let len = calls.len();
+        Ok(Box::into_raw(Box::new(KunPengResults {
+            calls: Box::into_raw(calls) as *mut KunPengCall,
+            len,
+            total_seqs: summary.total_seqs,
+            total_unclassified: summary.total_unclassified,
+            summary: Box::into_raw(Box::new(summary)),
+        })))
+    })
+}
+
+/// Writes the Kraken-style report (kreport2) of classified reads, returns NULL on failure
+///
+/// # Safety
+///
+/// `classifier` must be the one that produced `results`. The string must be released with
+/// [`kun_peng_string_free`].
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_report(
+    classifier: *const Classifier,
+    results: *const KunPengResults,
+    report_zero_counts: c_int,
+) -> *mut c_char {
+    guard(|| {
+        let classifier = classifier.as_ref().ok_or("classifier is NULL")?;
+        let results = results.as_ref().ok_or("results is NULL")?;
+        let mut report = Vec::new();
+        (*results.summary)
+            .write_report(
+                &mut report,
+                classifier.taxonomy(),
+                report_zero_counts != 0,
+                false,
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(to_c_string(String::from_utf8_lossy(&report).into_owned()))
+    })
+}
+
+/// Releases the results of [`kun_peng_classify`]
+///
+/// # Safety
+///
+/// `results` must come from [`kun_peng_classify`] and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_results_free(results: *mut KunPengResults) {
+    if results.is_null() {
+        return;
+    }
+    let results = Box::from_raw(results);
+    let calls = Box::from_raw(ptr::slice_from_raw_parts_mut(results.calls, results.len));
+    for call in calls.iter() {
+        kun_peng_string_free(call.id);
+        kun_peng_string_free(call.seq_len);
+        kun_peng_string_free(call.hits);
+    }
+    drop(Box::from_raw(results.summary));
+}
+
+/// Releases a string returned by the library
+///
+/// # Safety
+///
+/// `s` must come from this library and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn kun_peng_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
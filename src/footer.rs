@@ -0,0 +1,284 @@
+//! Footers of the intermediate files of classify
+//!
+//! splitr ends every chunk file `sample_{i}.k2` and annotate every sample bin file
+//! `sample_file_{i}_{j}.bin` with a footer of three little-endian u64: the number of records
+//! in the file, the xxHash64 of all bytes before the footer and [`FOOTER_MAGIC`]. annotate and
+//! resolve check the footer as they read the files, so a file cut short or damaged on a flaky
+//! scratch filesystem fails the run instead of quietly dropping reads from the reports.
+use crate::error::Error;
+use crate::utils::open_file;
+use std::fs::{File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::path::{Path, PathBuf};
+use twox_hash::XxHash64;
+
+/// The last word of a footer
+pub const FOOTER_MAGIC: u64 = u64::from_le_bytes(*b"KPFOOTR1");
+
+/// Bytes of a footer
+pub const FOOTER_BYTES: u64 = 24;
+
+/// The number of records of a file and the checksum of its bytes before the footer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub records: u64,
+    pub checksum: u64,
+}
+
+impl Footer {
+    pub fn to_bytes(&self) -> [u8; FOOTER_BYTES as usize] {
+        let mut bytes = [0u8; FOOTER_BYTES as usize];
+        bytes[0..8].copy_from_slice(&self.records.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[16..24].copy_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a footer, `None` if the bytes do not end with [`FOOTER_MAGIC`]
+    pub fn from_bytes(bytes: &[u8; FOOTER_BYTES as usize]) -> Option<Self> {
+        let word = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        (word(16) == FOOTER_MAGIC).then(|| Self {
+            records: word(0),
+            checksum: word(8),
+        })
+    }
+
+    /// Reads the footer at the end of a file
+    ///
+    /// # Returns
+    ///
+    /// The footer and the number of bytes before it, or an error naming the file if it does
+    /// not end with a footer: it was cut short or never completely written
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<(Self, u64)> {
+        let path = path.as_ref();
+        let mut file = open_file(path)?;
+        let len = file.metadata()?.len();
+        let mut bytes = [0u8; FOOTER_BYTES as usize];
+        if len >= FOOTER_BYTES {
+            file.seek(SeekFrom::Start(len - FOOTER_BYTES))?;
+            file.read_exact(&mut bytes)?;
+        }
+        match Self::from_bytes(&bytes) {
+            Some(footer) if len >= FOOTER_BYTES => Ok((footer, len - FOOTER_BYTES)),
+            _ => Err(damaged(
+                path,
+                "it has no footer; it was cut short or not completely written".to_string(),
+            )),
+        }
+    }
+}
+
+/// The error of an intermediate file that is truncated or corrupt
+///
+/// The file can only be written again: the message asks to rerun the stage.
+pub fn damaged(path: &Path, reason: String) -> io::Error {
+    Error::io(
+        path,
+        io::Error::other(format!(
+            "{}; the scratch filesystem may have lost data, rerun classify from splitr",
+            reason
+        )),
+    )
+    .into()
+}
+
+/// Appends records to a file and ends it with their footer
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::footer::{open_with_footer, FooterWriter};
+/// use std::io::Read;
+///
+/// let path = std::env::temp_dir().join("kun_peng_footer_doctest.bin");
+/// let mut writer = FooterWriter::new(std::fs::File::create(&path).unwrap());
+/// writer.write_records(&[1u8; 24], 2).unwrap();
+/// writer.write_records(&[2u8; 12], 1).unwrap();
+/// writer.finish().unwrap();
+///
+/// let (footer, mut reader) = open_with_footer(&path).unwrap();
+/// assert_eq!(footer.records, 3);
+/// let mut bytes = Vec::new();
+/// reader.read_to_end(&mut bytes).unwrap();
+/// assert_eq!(bytes, [vec![1u8; 24], vec![2u8; 12]].concat());
+///
+/// // 截断的文件没有 footer
+/// let len = std::fs::metadata(&path).unwrap().len();
+/// std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 30).unwrap();
+/// assert!(open_with_footer(&path).is_err());
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct FooterWriter<W: Write> {
+    inner: W,
+    hasher: XxHash64,
+    records: u64,
+}
+
+impl<W: Write> FooterWriter<W> {
+    /// Starts writing an empty file
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: XxHash64::with_seed(0),
+            records: 0,
+        }
+    }
+
+    /// Writes bytes holding `records` records
+    pub fn write_records(&mut self, bytes: &[u8], records: u64) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.hasher.write(bytes);
+        self.records += records;
+        Ok(())
+    }
+
+    /// Reopens a file ended with a footer to append more records through `inner`, a writer
+    /// appending to the file
+    ///
+    /// The bytes of the file are checked against the footer and hashed again, and the footer
+    /// removed; [`FooterWriter::finish`] writes the footer of all records.
+    pub fn append<P: AsRef<Path>>(path: P, inner: W) -> io::Result<Self> {
+        let path = path.as_ref();
+        let (footer, mut reader) = open_with_footer(path)?;
+        let mut hasher = XxHash64::with_seed(0);
+        io::copy(&mut reader, &mut HashWriter(&mut hasher))?;
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(reader.len)?;
+        Ok(Self {
+            inner,
+            hasher,
+            records: footer.records,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Writes the footer of the records written so far and flushes the file
+    pub fn finish(mut self) -> io::Result<W> {
+        let footer = Footer {
+            records: self.records,
+            checksum: self.hasher.finish(),
+        };
+        self.inner.write_all(&footer.to_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Ends a file of `record_size` byte records with their footer, unless it already has one
+///
+/// A file whose size is not a whole number of records was cut short and fails.
+pub fn seal_file<P: AsRef<Path>>(path: P, record_size: usize) -> io::Result<()> {
+    let path = path.as_ref();
+    let len = std::fs::metadata(path)?.len();
+    if let Ok((footer, data_len)) = Footer::read_from_file(path) {
+        // 续跑时已完成的文件不再追加第二个 footer
+        if footer.records * record_size as u64 == data_len {
+            return Ok(());
+        }
+    }
+    if len % record_size as u64 != 0 {
+        return Err(damaged(
+            path,
+            format!(
+                "{} bytes are not whole records of {} bytes",
+                len, record_size
+            ),
+        ));
+    }
+    let mut hasher = XxHash64::with_seed(0);
+    let mut reader = BufReader::new(open_file(path)?);
+    io::copy(&mut reader, &mut HashWriter(&mut hasher))?;
+    let footer = Footer {
+        records: len / record_size as u64,
+        checksum: hasher.finish(),
+    };
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&footer.to_bytes())?;
+    file.flush()
+}
+
+/// Feeds the bytes written to it to a hasher
+struct HashWriter<'a>(&'a mut XxHash64);
+
+impl Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the bytes of a file before its footer and checks them against it at the end
+///
+/// The read that reaches the footer fails if the checksum differs.
+pub struct FooterReader<R: Read> {
+    inner: Take<R>,
+    hasher: XxHash64,
+    footer: Footer,
+    path: PathBuf,
+    /// Bytes before the footer
+    len: u64,
+    checked: bool,
+}
+
+impl<R: Read> FooterReader<R> {
+    /// Bytes of the file before the footer
+    pub fn data_len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<R: Read> Read for FooterReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write(&buf[..n]);
+        if n == 0 && !buf.is_empty() && !self.checked {
+            self.checked = true;
+            if self.inner.limit() > 0 {
+                return Err(damaged(&self.path, "it ends before its footer".to_string()));
+            }
+            if self.hasher.finish() != self.footer.checksum {
+                return Err(damaged(
+                    &self.path,
+                    "its checksum does not match its footer".to_string(),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Opens a file ended with a footer for reading the bytes before it
+///
+/// # Returns
+///
+/// The footer and a reader of the bytes before it that fails at the end if they do not match
+/// the checksum of the footer
+pub fn open_with_footer<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<(Footer, FooterReader<BufReader<File>>)> {
+    let path = path.as_ref();
+    let (footer, len) = Footer::read_from_file(path)?;
+    let reader = FooterReader {
+        inner: BufReader::new(open_file(path)?).take(len),
+        hasher: XxHash64::with_seed(0),
+        footer,
+        path: path.to_path_buf(),
+        len,
+        checked: false,
+    };
+    Ok((footer, reader))
+}
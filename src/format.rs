@@ -0,0 +1,204 @@
+//! Versions of the on-disk formats
+//!
+//! The binary files whose layout a later kun_peng may change carry the version of their format:
+//!
+//! - taxo.k2d ends with a trailer of [`TAXONOMY_TRAILER_MAGIC`] and its version after the
+//!   layout of Kraken 2, which does not read past the rank data; files without it are version 0.
+//! - opts.k2d holds its version in the `db_version` field Kraken 2 reserved for it, 0 in
+//!   Kraken 2 databases.
+//! - the chunk files `sample_*.k2` start with `CHUNK_MAGIC` and their version.
+//! - hash_config.k2d describes the layout of the hash pages in its `version` field; layouts
+//!   this kun_peng does not know are rejected.
+//!
+//! Readers fail with [`Error::FormatVersion`] on files of a newer version. taxo.k2d and
+//! opts.k2d of an older version, such as those of Kraken 2, are read as they are; `kun_peng
+//! migrate-db` can upgrade them in place ([`migrate_database`]). Chunk files of an older
+//! version have another layout and are written again by splitr.
+use crate::compact_hash::HashConfig;
+use crate::error::Error;
+use crate::manifest::{DbManifest, MANIFEST_FILENAME};
+use crate::taxonomy::Taxonomy;
+use crate::utils::file_md5;
+use crate::IndexOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Format version of taxo.k2d written by this kun_peng
+pub const TAXONOMY_FORMAT_VERSION: u64 = 1;
+
+/// Format version of opts.k2d written by this kun_peng
+pub const OPTIONS_FORMAT_VERSION: u64 = 1;
+
+/// Format version of the chunk files written by this kun_peng
+pub const CHUNK_FORMAT_VERSION: u64 = 1;
+
+/// The first word of the trailer of taxo.k2d, followed by its format version
+pub const TAXONOMY_TRAILER_MAGIC: u64 = u64::from_le_bytes(*b"KPTAXVER");
+
+/// Checks the format version of a database file against the version this kun_peng reads
+///
+/// Files of an older version, and files without a version such as those of Kraken 2, are
+/// compatible: only files of a newer kun_peng are rejected.
+///
+/// # Arguments
+///
+/// * `path` - The file, named in the error
+/// * `found` - The version of the file, 0 for files without a version
+/// * `supported` - The version this kun_peng reads and writes
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::format::check_format_version;
+/// use std::path::Path;
+///
+/// let path = Path::new("db/taxo.k2d");
+/// assert!(check_format_version(path, 1, 1).is_ok());
+/// assert!(check_format_version(path, 0, 1).is_ok());
+/// let newer = check_format_version(path, 2, 1).unwrap_err();
+/// assert!(newer.to_string().contains("newer kun_peng"));
+/// ```
+pub fn check_format_version(path: &Path, found: u64, supported: u64) -> io::Result<()> {
+    if found <= supported {
+        return Ok(());
+    }
+    Err(Error::FormatVersion {
+        path: path.to_path_buf(),
+        found,
+        supported,
+        upgrade: String::new(),
+    }
+    .into())
+}
+
+/// The format version of a database file and whether this kun_peng reads it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileVersion {
+    pub path: PathBuf,
+    pub found: u64,
+    pub supported: u64,
+}
+
+impl FileVersion {
+    /// Whether the file can be upgraded to the version this kun_peng reads
+    pub fn is_older(&self) -> bool {
+        self.found < self.supported
+    }
+
+    pub fn is_newer(&self) -> bool {
+        self.found > self.supported
+    }
+}
+
+/// The format versions of taxo.k2d and opts.k2d of a database directory
+///
+/// hash_config.k2d is only checked: this kun_peng reads all its older layouts.
+pub fn database_versions(database: &Path) -> io::Result<Vec<FileVersion>> {
+    HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    let taxo_file = database.join("taxo.k2d");
+    let opts_file = database.join("opts.k2d");
+    Ok(vec![
+        FileVersion {
+            found: Taxonomy::format_version(&taxo_file)?,
+            path: taxo_file,
+            supported: TAXONOMY_FORMAT_VERSION,
+        },
+        FileVersion {
+            found: IndexOptions::format_version(&opts_file)?,
+            path: opts_file,
+            supported: OPTIONS_FORMAT_VERSION,
+        },
+    ])
+}
+
+/// Writes the taxo.k2d and opts.k2d of a database directory in the layouts of Kraken 2
+///
+/// taxo.k2d loses its version trailer and the `db_version` of opts.k2d is set back to 0, the
+/// files Kraken 2 itself writes.
+///
+/// # Arguments
+///
+/// * `database` - The database directory
+/// * `output_dir` - The directory of the Kraken 2 database, another one than `database`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::format::{export_kraken2_files, TAXONOMY_FORMAT_VERSION};
+/// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+/// use kun_peng::IndexOptions;
+///
+/// let database = std::env::temp_dir().join("kun_peng_export_doctest_db");
+/// let output_dir = std::env::temp_dir().join("kun_peng_export_doctest_kraken2");
+/// std::fs::create_dir_all(&database).unwrap();
+/// std::fs::create_dir_all(&output_dir).unwrap();
+/// let mut taxo = Taxonomy::default();
+/// taxo.nodes.push(TaxonomyNode::default());
+/// taxo.nodes.push(TaxonomyNode { external_id: 1, ..Default::default() });
+/// taxo.name_data = b"root\0".to_vec();
+/// taxo.rank_data = b"no rank\0".to_vec();
+/// taxo.write_to_disk(database.join("taxo.k2d")).unwrap();
+/// IndexOptions::new(35, 31, 0, 0, true, 0).write_to_file(database.join("opts.k2d")).unwrap();
+/// assert_eq!(Taxonomy::format_version(database.join("taxo.k2d")).unwrap(), TAXONOMY_FORMAT_VERSION);
+///
+/// export_kraken2_files(&database, &output_dir).unwrap();
+/// let taxo_file = output_dir.join("taxo.k2d");
+/// assert_eq!(Taxonomy::format_version(&taxo_file).unwrap(), 0);
+/// assert_eq!(
+///     std::fs::metadata(&taxo_file).unwrap().len(),
+///     std::fs::metadata(database.join("taxo.k2d")).unwrap().len() - 16
+/// );
+/// assert_eq!(Taxonomy::from_file(&taxo_file).unwrap().name_of(1), "root");
+/// let opts = IndexOptions::read_index_options(output_dir.join("opts.k2d")).unwrap();
+/// assert_eq!((opts.k, opts.l, opts.db_version), (35, 31, 0));
+/// std::fs::remove_dir_all(&database).unwrap();
+/// std::fs::remove_dir_all(&output_dir).unwrap();
+/// ```
+pub fn export_kraken2_files(database: &Path, output_dir: &Path) -> io::Result<()> {
+    let taxo = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    taxo.write_kraken2_file(output_dir.join("taxo.k2d"))?;
+    let mut opts = IndexOptions::read_index_options(database.join("opts.k2d"))?;
+    opts.db_version = 0;
+    opts.write_to_file(output_dir.join("opts.k2d"))
+}
+
+/// Upgrades the taxo.k2d and opts.k2d of a database directory to the formats of this kun_peng
+///
+/// Every file is written next to the old one and renamed over it, so an interrupted migration
+/// leaves each file either old or upgraded; running it again finishes the job. The checksum of
+/// taxo.k2d in `db_manifest.json` is updated with it. Files of a newer kun_peng fail the
+/// migration before anything is written.
+///
+/// # Arguments
+///
+/// * `database` - The database directory
+///
+/// # Returns
+///
+/// The files that were upgraded
+pub fn migrate_database(database: &Path) -> io::Result<Vec<FileVersion>> {
+    let versions = database_versions(database)?;
+    if let Some(newer) = versions.iter().find(|version| version.is_newer()) {
+        return Err(Error::FormatVersion {
+            path: newer.path.clone(),
+            found: newer.found,
+            supported: newer.supported,
+            upgrade: String::new(),
+        }
+        .into());
+    }
+    let older: Vec<FileVersion> = versions.into_iter().filter(FileVersion::is_older).collect();
+    for version in &older {
+        if version.path.ends_with("taxo.k2d") {
+            Taxonomy::upgrade_file(&version.path)?;
+            if database.join(MANIFEST_FILENAME).exists() {
+                let mut manifest = DbManifest::from_file(database)?;
+                manifest.taxo_md5 = file_md5(&version.path)?;
+                manifest.write_to_file(database)?;
+            }
+        } else {
+            IndexOptions::upgrade_file(&version.path)?;
+        }
+    }
+    Ok(older)
+}
@@ -0,0 +1,861 @@
+//! Reading the FASTA/FASTQ inputs of a run
+//!
+//! [`open_fastx`] opens the input of a sample, local files or `s3://`/`gs://` objects (see
+//! [`crate::remote`]). Uncompressed local FASTA files are read by the seqkmer file readers;
+//! everything else is read record by record here:
+//!
+//! - gzip-compressed inputs, so that a truncated or corrupt file is reported with the byte and
+//!   read it breaks at;
+//! - FASTQ inputs, whose records are checked as they are read (see [`FastxStream`]);
+//! - the two files of paired-end reads, whose mates are compared as they are paired (see
+//!   [`MismatchedPairs`]).
+//!
+//! [`parse_fastx`] parses a FASTA or FASTQ text held in memory the same way, e.g. the body of
+//! a `serve` request. How the inputs are read is given by the [`InputOptions`] of the run.
+use crate::classifier::BATCH_SIZE;
+use crate::error::Error;
+use crate::remote::{is_remote, open_remote, url_of};
+use flate2::read::MultiGzDecoder;
+use log::warn;
+use seqkmer::{Base, FastxReader, OptionPair, Reader, SeqFormat, SeqHeader};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How the FASTA/FASTQ inputs of a run are read, set by the global options of `kun_peng`
+///
+/// # Examples
+///
+/// ```
+/// use flate2::{write::GzEncoder, Compression};
+/// use kun_peng::input::{open_fastx, InputOptions};
+/// use seqkmer::{OptionPair, Reader};
+/// use std::io::Write;
+///
+/// let dir = std::env::temp_dir().join("kun_peng_input_options_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let path = dir.join("reads.fq.gz");
+/// let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+/// encoder.write_all(b"@a\nACGT\n+\nIIII\n@b\nACGT\n+\nIIII\n").unwrap();
+/// let gz = encoder.finish().unwrap();
+/// // 截掉 gzip 流的结尾
+/// std::fs::write(&path, &gz[..gz.len() - 12]).unwrap();
+///
+/// let options = InputOptions::default();
+/// let mut reader = open_fastx(OptionPair::Single(&path), 1, 0, &options).unwrap();
+/// assert!(reader.next().is_err());
+///
+/// let options = InputOptions {
+///     keep_truncated: true,
+///     ..InputOptions::default()
+/// };
+/// let mut reader = open_fastx(OptionPair::Single(&path), 1, 0, &options).unwrap();
+/// assert!(reader.next().unwrap().is_some_and(|reads| !reads.is_empty()));
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputOptions {
+    /// What to do with paired-end files whose mates are out of sync, `--mismatched-pairs`
+    pub mismatched_pairs: MismatchedPairs,
+    /// Keep the reads before a truncated or corrupt input and end the file there, instead of
+    /// failing the read, `--keep-truncated-input`
+    pub keep_truncated: bool,
+    /// Fail the read at the first malformed FASTQ record instead of skipping it, `--strict`
+    pub strict: bool,
+}
+
+/// The magic bytes a gzip file starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Whether a local file is gzip-compressed or FASTQ, both read by [`InputStream`]
+fn is_streamed_file(path: &Path) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(2);
+    File::open(path)?.take(2).read_to_end(&mut magic)?;
+    Ok(magic == GZIP_MAGIC || magic.starts_with(b"@"))
+}
+
+/// Counts the bytes of an input consumed by the reader on top of it
+struct CountingReader<R: BufRead> {
+    inner: R,
+    consumed: Arc<AtomicU64>,
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.consumed.fetch_add(amt as u64, Ordering::Relaxed);
+    }
+}
+
+/// The records of an input file or object, read by [`FastxStream`]
+///
+/// A read error of the data, a gzip stream that ends early or is corrupt or a truncated FASTQ
+/// record, names the file, the byte of the file and the read it happened at. It fails the
+/// read, or with [`InputOptions::keep_truncated`] ends the file after the reads before it.
+///
+/// Malformed FASTQ records are skipped and counted in a warning at the end of the file, or
+/// with [`InputOptions::strict`] fail the read with the file, record and line.
+struct InputStream {
+    stream: FastxStream<Box<dyn BufRead + Send>>,
+    path: String,
+    compressed: bool,
+    /// Bytes of the file consumed, by the gzip decoder if compressed
+    consumed: Arc<AtomicU64>,
+    keep_truncated: bool,
+    truncated: bool,
+    ended: bool,
+}
+
+impl InputStream {
+    /// Opens an input file or an `s3://`/`gs://` object, decompressing gzip
+    fn open(
+        path: &Path,
+        file_index: usize,
+        quality_score: i32,
+        options: &InputOptions,
+    ) -> io::Result<Self> {
+        let source: Box<dyn Read + Send> = if is_remote(path) {
+            Box::new(open_remote(&url_of(path))?)
+        } else {
+            Box::new(File::open(path)?)
+        };
+        let mut reader = BufReader::with_capacity(1 << 20, source);
+        // 流不能回退, 只查看缓冲区中的前两个字节判断是否 gzip
+        let compressed = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+        let consumed = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            inner: reader,
+            consumed: consumed.clone(),
+        };
+        let reader: Box<dyn BufRead + Send> = if compressed {
+            Box::new(BufReader::with_capacity(
+                1 << 20,
+                MultiGzDecoder::new(reader),
+            ))
+        } else {
+            Box::new(reader)
+        };
+        Ok(Self {
+            stream: FastxStream::new(reader, file_index, quality_score).strict(options.strict),
+            path: path.display().to_string(),
+            compressed,
+            consumed,
+            keep_truncated: options.keep_truncated,
+            truncated: false,
+            ended: false,
+        })
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Base<Vec<u8>>>> {
+        if self.truncated || self.ended {
+            return Ok(None);
+        }
+        match self.stream.next_record() {
+            Ok(None) => {
+                self.ended = true;
+                if let Some(summary) = self.stream.skipped_summary() {
+                    warn!("{}: {}", self.path, summary);
+                }
+                Ok(None)
+            }
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<MalformedRecord>()) => {
+                Err(Error::InvalidInput(format!("{}: {}", self.path, e)).into())
+            }
+            Err(e) if is_data_error(&e) => {
+                let reads = self.stream.reads();
+                let message = format!(
+                    "{} is truncated or corrupt at byte {}{}, around read {}: {}",
+                    self.path,
+                    self.consumed.load(Ordering::Relaxed),
+                    if self.compressed {
+                        " of the compressed file"
+                    } else {
+                        ""
+                    },
+                    reads + 1,
+                    e
+                );
+                if self.keep_truncated {
+                    warn!("{}; keeping the {} reads before it", message, reads);
+                    self.truncated = true;
+                    return Ok(None);
+                }
+                Err(Error::InvalidInput(format!(
+                    "{}; pass --keep-truncated-input to keep the reads before it",
+                    message
+                ))
+                .into())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether an error reading an input is one of its data rather than of the file or the download
+fn is_data_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof
+    )
+}
+
+impl Reader for InputStream {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        // 逐条读取, 截断前读到的 read 留在批次中
+        let mut records = Vec::new();
+        while records.len() < BATCH_SIZE {
+            match self.next_record()? {
+                Some(record) => records.push(record),
+                None => break,
+            }
+        }
+        Ok((!records.is_empty()).then_some(records))
+    }
+}
+
+/// What to do with paired-end files whose mates are out of sync, given as
+/// `--mismatched-pairs error|warn`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::MismatchedPairs;
+///
+/// let mode: MismatchedPairs = "warn".parse().unwrap();
+/// assert_eq!(mode, MismatchedPairs::Warn);
+/// assert_eq!(MismatchedPairs::Error.to_string(), "error");
+/// assert!("ignore".parse::<MismatchedPairs>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MismatchedPairs {
+    /// Fail at the first pair of reads with different IDs or the first read without a mate
+    #[default]
+    Error,
+    /// Log the mismatches and classify the pairs as they are; the reads without a mate are
+    /// skipped
+    Warn,
+}
+
+impl FromStr for MismatchedPairs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            _ => Err(format!(
+                "invalid mismatched pairs mode '{}', expected error or warn",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MismatchedPairs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+        })
+    }
+}
+
+/// The read ID shared by both mates, without a `/1` or `/2` suffix
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::mate_id;
+///
+/// assert_eq!(mate_id("SRR1234.5/1"), "SRR1234.5");
+/// assert_eq!(mate_id("SRR1234.5/2"), "SRR1234.5");
+/// assert_eq!(mate_id("SRR1234.5"), "SRR1234.5");
+/// assert_eq!(mate_id("read/3"), "read/3");
+/// ```
+pub fn mate_id(id: &str) -> &str {
+    id.strip_suffix("/1")
+        .or_else(|| id.strip_suffix("/2"))
+        .unwrap_or(id)
+}
+
+/// The mates of paired-end reads from two streams
+///
+/// The IDs of the mates are compared as they are paired; the first mismatch, or the end of
+/// one file before the other, fails the read or is logged as `mode` says.
+struct PairedStream {
+    first: InputStream,
+    second: InputStream,
+    mode: MismatchedPairs,
+    /// The two files, named in the messages
+    paths: [String; 2],
+    /// The pairs read so far
+    records: usize,
+    mismatches: usize,
+    ended: bool,
+}
+
+impl PairedStream {
+    fn new(first: InputStream, second: InputStream, mode: MismatchedPairs) -> Self {
+        let paths = [first.path.clone(), second.path.clone()];
+        Self {
+            first,
+            second,
+            mode,
+            paths,
+            records: 0,
+            mismatches: 0,
+            ended: false,
+        }
+    }
+
+    /// Fails or warns about mates that are out of sync
+    fn mismatch(&mut self, message: String) -> io::Result<()> {
+        self.mismatches += 1;
+        match self.mode {
+            MismatchedPairs::Error => Err(Error::InvalidInput(format!(
+                "{}; check that the paired-end files belong together or pass --mismatched-pairs warn",
+                message
+            ))
+            .into()),
+            MismatchedPairs::Warn => {
+                // 只报告第一处, 总数在读完时报告
+                if self.mismatches == 1 {
+                    warn!("{}", message);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Logs the number of mismatched mates at the end of the files
+    fn finish(&mut self) {
+        self.ended = true;
+        if self.mismatches > 1 {
+            warn!(
+                "{} of {} pairs of {} and {} have mismatched mates",
+                self.mismatches, self.records, self.paths[0], self.paths[1]
+            );
+        }
+    }
+}
+
+impl Reader for PairedStream {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let (first, second) = match (self.first.next()?, self.second.next()?) {
+            (None, None) => {
+                self.finish();
+                return Ok(None);
+            }
+            (first, second) => (first.unwrap_or_default(), second.unwrap_or_default()),
+        };
+        // 两个文件的批次同样大小, 长度不同说明其中一个文件先结束
+        let shorter = (first.len() != second.len()).then(|| first.len().min(second.len()));
+        let shorter_file = usize::from(second.len() < first.len());
+        let mut batch = Vec::with_capacity(first.len().min(second.len()));
+        for (first, second) in first.into_iter().zip(second) {
+            self.records += 1;
+            if mate_id(&first.header.id) != mate_id(&second.header.id) {
+                let message = format!(
+                    "read {} of {} is {} but its mate in {} is {}",
+                    self.records, self.paths[0], first.header.id, self.paths[1], second.header.id
+                );
+                self.mismatch(message)?;
+            }
+            let body = match (first.body, second.body) {
+                (OptionPair::Single(seq1), OptionPair::Single(seq2)) => {
+                    OptionPair::Pair(seq1, seq2)
+                }
+                (body, _) => body,
+            };
+            batch.push(Base::new(first.header, body));
+        }
+        if shorter.is_some() && (self.first.truncated || self.second.truncated) {
+            // 截断的文件已经报告过, 只配对截断前的 read
+            self.finish();
+        } else if shorter.is_some() {
+            let message = format!(
+                "{} ends after read {} but {} has more reads",
+                self.paths[shorter_file],
+                self.records,
+                self.paths[1 - shorter_file]
+            );
+            self.mismatch(message)?;
+            // 多出的 read 没有配对, 跳过
+            self.finish();
+        }
+        Ok((!batch.is_empty()).then_some(batch))
+    }
+}
+
+/// Opens the FASTA/FASTQ input of one sample, local files or `s3://`/`gs://` objects
+///
+/// An uncompressed local FASTA file is read by the seqkmer file readers, an object is streamed
+/// as it downloads. Gzip-compressed files are read record by record, so that a truncated or
+/// corrupt file is reported with the byte and read it breaks at, and so are FASTQ files, whose
+/// malformed records are skipped or fail the read (see [`FastxStream`]), and the two files of
+/// paired-end reads, whose mates are compared, see [`MismatchedPairs`].
+///
+/// # Arguments
+///
+/// * `paths` - The file, or the two files of paired-end reads
+/// * `file_index` - The number of the sample
+/// * `quality_score` - FASTQ bases below this quality are masked
+/// * `options` - How the inputs of the run are read
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::{open_fastx, InputOptions};
+/// use seqkmer::{OptionPair, Reader};
+///
+/// let dir = std::env::temp_dir().join("kun_peng_open_fastx_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let (r1, r2) = (dir.join("r1.fq"), dir.join("r2.fq"));
+/// std::fs::write(&r1, "@a/1\nACGT\n+\nIIII\n@b/1\nACGT\n+\nIIII\n").unwrap();
+/// std::fs::write(&r2, "@a/2\nTTGA\n+\nIIII\n@c/2\nTTGA\n+\nIIII\n").unwrap();
+///
+/// let options = InputOptions::default();
+/// let mut reader = open_fastx(OptionPair::Pair(&r1, &r2), 1, 0, &options).unwrap();
+/// let error = reader.next().err().unwrap();
+/// assert!(error.to_string().starts_with("read 2 of"));
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn open_fastx<P: AsRef<Path>>(
+    paths: OptionPair<P>,
+    file_index: usize,
+    quality_score: i32,
+    options: &InputOptions,
+) -> io::Result<Box<dyn Reader + Send>> {
+    let streamed = match &paths {
+        OptionPair::Single(path) => is_remote(path) || is_streamed_file(path.as_ref())?,
+        OptionPair::Pair(..) => true,
+    };
+    if !streamed {
+        return Ok(Box::new(FastxReader::from_paths(
+            paths,
+            file_index,
+            quality_score,
+        )?));
+    }
+    Ok(match paths {
+        OptionPair::Single(path) => Box::new(InputStream::open(
+            path.as_ref(),
+            file_index,
+            quality_score,
+            options,
+        )?),
+        // 成对的文件逐条配对, 以便检查两端的 ID
+        OptionPair::Pair(path1, path2) => Box::new(PairedStream::new(
+            InputStream::open(path1.as_ref(), file_index, quality_score, options)?,
+            InputStream::open(path2.as_ref(), file_index, quality_score, options)?,
+            options.mismatched_pairs,
+        )),
+    })
+}
+
+/// Keeps the first error of a reader until its read pipeline is done
+///
+/// The read pipelines of seqkmer (`read_parallel` and friends) stop at the first error of
+/// their reader and drop it, as if the input had ended. A reader read through a
+/// `CheckedReader` ends the same way, and [`CheckedReader::finish`] returns the error after the
+/// pipeline, so that a truncated input, a malformed record with `--strict` or mismatched mates
+/// fail the run instead of cutting it short.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::{CheckedReader, FastxStream};
+/// use seqkmer::Reader;
+///
+/// let mut stream = FastxStream::new(&b"ACGT\n"[..], 0, 0);
+/// let mut reader = CheckedReader::new(&mut stream);
+/// assert!(reader.next().unwrap().is_none());
+/// assert_eq!(
+///     reader.finish().unwrap_err().to_string(),
+///     "the input is neither FASTA nor FASTQ"
+/// );
+/// ```
+pub struct CheckedReader<'a, R> {
+    inner: &'a mut R,
+    error: Option<io::Error>,
+}
+
+impl<'a, R: Reader> CheckedReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self { inner, error: None }
+    }
+
+    /// The first error of the reader, once the pipeline reading it is done
+    pub fn finish(self) -> io::Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+impl<R: Reader> Reader for CheckedReader<'_, R> {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        if self.error.is_some() {
+            return Ok(None);
+        }
+        match self.inner.next() {
+            Err(e) => {
+                self.error = Some(e);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Parses a FASTA or FASTQ text held in memory into records
+///
+/// FASTQ bases below `quality_score` are masked as in the file readers.
+///
+/// # Arguments
+///
+/// * `data` - The FASTA or FASTQ text
+/// * `quality_score` - FASTQ bases below this quality are masked
+/// * `strict` - Fail on malformed FASTQ records instead of skipping them, see [`FastxStream`]
+///
+/// # Returns
+///
+/// An io::Result containing the records, an `InvalidData` error if the text is neither
+/// FASTA nor FASTQ, a FASTQ record is truncated or, if `strict`, malformed; otherwise
+/// malformed FASTQ records are skipped with a warning
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::parse_fastx;
+/// use seqkmer::OptionPair;
+///
+/// let records = parse_fastx(b"@r1 sample\nACGT\n+\nII#I\n", 10, false).unwrap();
+/// assert_eq!(records[0].header.id, "r1");
+/// assert!(matches!(&records[0].body, OptionPair::Single(seq) if seq == b"ACxT"));
+///
+/// let records = parse_fastx(b">c1\nACG\nTTA\n>c2\nGG\n", 0, false).unwrap();
+/// assert_eq!(records.len(), 2);
+/// assert!(matches!(&records[0].body, OptionPair::Single(seq) if seq == b"ACGTTA"));
+/// assert!(parse_fastx(b"ACGT\n", 0, false).is_err());
+/// assert!(parse_fastx(b"@r1\nACGT\n+\nIII\n", 0, false).unwrap().is_empty());
+/// assert!(parse_fastx(b"@r1\nACGT\n+\nIII\n", 0, true).is_err());
+/// ```
+pub fn parse_fastx(
+    data: &[u8],
+    quality_score: i32,
+    strict: bool,
+) -> io::Result<Vec<Base<Vec<u8>>>> {
+    let mut stream = FastxStream::new(data, 0, quality_score).strict(strict);
+    let mut records = Vec::new();
+    while let Some(record) = stream.next_record()? {
+        records.push(record);
+    }
+    if let Some(summary) = stream.skipped_summary() {
+        warn!("{}", summary);
+    }
+    Ok(records)
+}
+
+/// A FASTQ record of four lines that do not make a record, with its coordinates in the input
+///
+/// With [`FastxStream::strict`] it is the error of [`FastxStream::next_record`], wrapped in an
+/// `InvalidData` io::Error; otherwise the record is skipped and counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedRecord {
+    /// The 1-based number of the record in the input, skipped records included
+    pub record: usize,
+    /// The 1-based line of its header
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for MalformedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed FASTQ record {} at line {}: {}",
+            self.record, self.line, self.reason
+        )
+    }
+}
+
+impl std::error::Error for MalformedRecord {}
+
+/// Reads FASTA or FASTQ records one at a time from any buffered reader, e.g. a pipe
+///
+/// Unlike the file readers of seqkmer it never seeks, so it reads streams that can only be
+/// read once. The format is taken from the first line.
+///
+/// FASTQ records are checked as they are read: a header starting with `@` and naming the
+/// read, a separator starting with `+` and as many quality characters as bases. A record that
+/// fails is skipped as the four lines it takes and counted, see [`FastxStream::skipped`], or
+/// with [`FastxStream::strict`] fails the read with a [`MalformedRecord`].
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::input::FastxStream;
+///
+/// let data = b"@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIII\n@r3\nAC\n+\nII\n";
+/// let mut stream = FastxStream::new(&data[..], 0, 0);
+/// assert_eq!(stream.next_record().unwrap().unwrap().header.id, "r1");
+/// // r2 的质量行少一个字符, 被跳过
+/// assert_eq!(stream.next_record().unwrap().unwrap().header.id, "r3");
+/// assert!(stream.next_record().unwrap().is_none());
+/// assert_eq!(stream.skipped(), 1);
+/// let first = stream.first_skipped().unwrap();
+/// assert_eq!((first.record, first.line), (2, 5));
+///
+/// // --strict
+/// let mut stream = FastxStream::new(&data[..], 0, 0).strict(true);
+/// stream.next_record().unwrap();
+/// assert_eq!(
+///     stream.next_record().unwrap_err().to_string(),
+///     "malformed FASTQ record 2 at line 5: the quality line has 3 characters for 4 bases"
+/// );
+/// ```
+pub struct FastxStream<R: BufRead + Send> {
+    reader: R,
+    file_index: usize,
+    quality_score: i32,
+    format: Option<SeqFormat>,
+    /// The header of the next FASTA record, read at the end of the previous one
+    next_header: Option<Vec<u8>>,
+    reads_index: usize,
+    /// The lines read so far, empty lines included
+    lines: usize,
+    strict: bool,
+    skipped: usize,
+    first_skipped: Option<MalformedRecord>,
+}
+
+impl<R: BufRead + Send> FastxStream<R> {
+    /// Creates a stream of the records of input file `file_index`
+    ///
+    /// FASTQ bases below `quality_score` are masked as in the file readers.
+    pub fn new(reader: R, file_index: usize, quality_score: i32) -> Self {
+        Self {
+            reader,
+            file_index,
+            quality_score,
+            format: None,
+            next_header: None,
+            reads_index: 0,
+            lines: 0,
+            strict: false,
+            skipped: 0,
+            first_skipped: None,
+        }
+    }
+
+    /// Fail the read at the first malformed FASTQ record, instead of skipping it and counting
+    /// it, `false` by default
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The number of records read so far
+    pub fn reads(&self) -> usize {
+        self.reads_index
+    }
+
+    /// The number of malformed FASTQ records skipped so far
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// The first malformed FASTQ record skipped
+    pub fn first_skipped(&self) -> Option<&MalformedRecord> {
+        self.first_skipped.as_ref()
+    }
+
+    /// The number of skipped records and the first of them, `None` if no record was skipped
+    pub fn skipped_summary(&self) -> Option<String> {
+        self.first_skipped.as_ref().map(|first| {
+            format!(
+                "skipped {} malformed FASTQ records, the first record {} at line {}: {}",
+                self.skipped, first.record, first.line, first.reason
+            )
+        })
+    }
+
+    /// The next non-empty line without its line ending
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(None);
+            }
+            self.lines += 1;
+            while matches!(line.last(), Some(b'\n' | b'\r')) {
+                line.pop();
+            }
+            if !line.is_empty() {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    /// Reads the next record
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the record or None at the end of the stream, an
+    /// `InvalidData` error if the text is neither FASTA nor FASTQ, a FASTQ record is truncated
+    /// or, if [`strict`](Self::strict), malformed
+    pub fn next_record(&mut self) -> io::Result<Option<Base<Vec<u8>>>> {
+        loop {
+            match self.read_record()? {
+                NextRecord::Record(record) => return Ok(Some(record)),
+                NextRecord::Skipped => {}
+                NextRecord::End => return Ok(None),
+            }
+        }
+    }
+
+    /// Skips a malformed FASTQ record, or fails with it if strict
+    fn malformed(&mut self, line: usize, reason: String) -> io::Result<()> {
+        let record = MalformedRecord {
+            record: self.reads_index + self.skipped + 1,
+            line,
+            reason,
+        };
+        if self.strict {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, record));
+        }
+        self.skipped += 1;
+        self.first_skipped.get_or_insert(record);
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> io::Result<NextRecord> {
+        let header = match self.next_header.take() {
+            Some(header) => header,
+            None => match self.read_line()? {
+                Some(header) => header,
+                None => return Ok(NextRecord::End),
+            },
+        };
+        let header_line = self.lines;
+        let format = match (self.format, header[0]) {
+            (Some(format), _) => format,
+            (None, b'>') => SeqFormat::Fasta,
+            (None, b'@') => SeqFormat::Fastq,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the input is neither FASTA nor FASTQ",
+                ))
+            }
+        };
+        self.format = Some(format);
+
+        let id = String::from_utf8_lossy(&header[1..])
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let mut seq = Vec::new();
+        match format {
+            SeqFormat::Fasta => {
+                while let Some(line) = self.read_line()? {
+                    if line[0] == b'>' {
+                        self.next_header = Some(line);
+                        break;
+                    }
+                    seq.extend_from_slice(&line);
+                }
+            }
+            SeqFormat::Fastq => {
+                let (Some(line), Some(separator), Some(qual)) =
+                    (self.read_line()?, self.read_line()?, self.read_line()?)
+                else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("truncated FASTQ record {}", id),
+                    ));
+                };
+                let reason = if header[0] != b'@' {
+                    Some("the header does not start with '@'".to_string())
+                } else if id.is_empty() {
+                    Some("the header has no read ID".to_string())
+                } else if separator[0] != b'+' {
+                    Some("the separator line does not start with '+'".to_string())
+                } else if qual.len() != line.len() {
+                    Some(format!(
+                        "the quality line has {} characters for {} bases",
+                        qual.len(),
+                        line.len()
+                    ))
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    // 按四行跳过整条记录
+                    self.malformed(header_line, reason)?;
+                    return Ok(NextRecord::Skipped);
+                }
+                seq = line;
+                if self.quality_score > 0 {
+                    for (base, &score) in seq.iter_mut().zip(&qual) {
+                        if (score as i32 - '!' as i32) < self.quality_score {
+                            *base = b'x';
+                        }
+                    }
+                }
+            }
+        }
+        let header = SeqHeader {
+            id,
+            file_index: self.file_index,
+            reads_index: self.reads_index,
+            format,
+        };
+        self.reads_index += 1;
+        Ok(NextRecord::Record(Base::new(
+            header,
+            OptionPair::Single(seq),
+        )))
+    }
+}
+
+/// What [`FastxStream`] read from its input
+enum NextRecord {
+    Record(Base<Vec<u8>>),
+    /// A malformed FASTQ record that was skipped
+    Skipped,
+    End,
+}
+
+impl<R: BufRead + Send> Reader for FastxStream<R> {
+    fn next(&mut self) -> io::Result<Option<Vec<Base<Vec<u8>>>>> {
+        let mut records = Vec::new();
+        while records.len() < BATCH_SIZE {
+            match self.next_record()? {
+                Some(record) => records.push(record),
+                None => break,
+            }
+        }
+        Ok((!records.is_empty()).then_some(records))
+    }
+}
@@ -1,11 +1,13 @@
 use crate::compact_hash::Row;
+use crate::error::Error;
+use crate::format::{check_format_version, OPTIONS_FORMAT_VERSION};
 use crate::utils::open_file;
+use byteorder::{ByteOrder, LittleEndian};
 use seqkmer::Meros;
 use seqkmer::OptionPair;
 use seqkmer::CURRENT_REVCOM_VERSION;
 use std::fs::File;
 use std::io::{Read, Result as IoResult, Write};
-use std::mem;
 use std::path::Path;
 
 /// Parses a binary string into a u64
@@ -30,19 +32,30 @@ pub fn parse_binary(src: &str) -> Result<u64, std::num::ParseIntError> {
 ///
 /// # Returns
 ///
-/// A String representing the constructed seed template
-pub fn construct_seed_template(minimizer_len: usize, minimizer_spaces: usize) -> String {
+/// An IoResult containing the seed template, an `InvalidInput` error if the minimizer has
+/// more spaces than a quarter of its length
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::construct_seed_template;
+///
+/// assert_eq!(construct_seed_template(8, 2).unwrap(), "11110101");
+/// assert!(construct_seed_template(8, 3).is_err());
+/// ```
+pub fn construct_seed_template(minimizer_len: usize, minimizer_spaces: usize) -> IoResult<String> {
     if minimizer_len / 4 < minimizer_spaces {
-        panic!(
+        return Err(Error::InvalidInput(format!(
             "number of minimizer spaces ({}) exceeds max for minimizer len ({}); max: {}",
             minimizer_spaces,
             minimizer_len,
             minimizer_len / 4
-        );
+        ))
+        .into());
     }
     let core = "1".repeat(minimizer_len - 2 * minimizer_spaces);
     let spaces = "01".repeat(minimizer_spaces);
-    format!("{}{}", core, spaces)
+    Ok(format!("{}{}", core, spaces))
 }
 
 /// Converts a u64 value to Option<u64>, filtering out zero values
@@ -93,7 +106,7 @@ pub struct IndexOptions {
     pub dna_db: bool,
     pub minimum_acceptable_hash_value: u64,
     pub revcom_version: i32, // Throws an error if equal to 0
-    pub db_version: i32,     // Reserved for future database structure changes
+    pub db_version: i32,     // Format version of opts.k2d, 0 in Kraken 2 databases
     pub db_type: i32,        // Reserved for future use of other data structures
 }
 
@@ -115,7 +128,7 @@ impl IndexOptions {
             dna_db,
             minimum_acceptable_hash_value,
             revcom_version: CURRENT_REVCOM_VERSION as i32,
-            db_version: 0,
+            db_version: OPTIONS_FORMAT_VERSION as i32,
             db_type: 0,
         }
     }
@@ -130,22 +143,62 @@ impl IndexOptions {
     ///
     /// An IoResult containing the read IndexOptions
     pub fn read_index_options<P: AsRef<Path>>(file_path: P) -> IoResult<Self> {
-        let mut file = open_file(file_path)?;
-        let mut buffer = vec![0; std::mem::size_of::<Self>()];
-        file.read_exact(&mut buffer)?;
-
-        let idx_opts = unsafe {
-            // Ensure this conversion is safe, depending on the exact layout and source of the data
-            std::ptr::read(buffer.as_ptr() as *const Self)
-        };
+        let path = file_path.as_ref().to_path_buf();
+        let idx_opts = Self::read_layout(&path)?;
+        check_format_version(&path, idx_opts.db_version as u64, OPTIONS_FORMAT_VERSION)?;
         if idx_opts.revcom_version != CURRENT_REVCOM_VERSION as i32 {
-            // Trigger a panic if the version is 0
-            panic!("Unsupported version (revcom_version == 0)");
+            return Err(Error::InvalidDatabase {
+                path,
+                reason: format!(
+                    "unsupported reverse complement version {}",
+                    idx_opts.revcom_version
+                ),
+            }
+            .into());
         }
 
         Ok(idx_opts)
     }
 
+    /// Reads the options of a file, whatever its format version
+    fn read_layout(path: &Path) -> IoResult<Self> {
+        let mut file = open_file(path)?;
+        let mut buffer = [0u8; Self::BYTES];
+        file.read_exact(&mut buffer)?;
+        Ok(Self::from_le_bytes(&buffer))
+    }
+
+    /// The format version of an opts.k2d file, 0 for Kraken 2 databases
+    pub fn format_version<P: AsRef<Path>>(file_path: P) -> IoResult<u64> {
+        Ok(Self::read_layout(file_path.as_ref())?.db_version as u64)
+    }
+
+    /// Rewrites an opts.k2d file of an older format version in the current format
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::IndexOptions;
+    ///
+    /// let path = std::env::temp_dir().join("kun_peng_opts_upgrade_doctest.k2d");
+    /// let mut opts = IndexOptions::new(35, 31, 0, 0, true, 0);
+    /// // Kraken 2 写出的 opts.k2d
+    /// opts.db_version = 0;
+    /// opts.write_to_file(&path).unwrap();
+    /// assert_eq!(IndexOptions::read_index_options(&path).unwrap().k, 35);
+    /// IndexOptions::upgrade_file(&path).unwrap();
+    /// assert_eq!(IndexOptions::format_version(&path).unwrap(), 1);
+    /// std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn upgrade_file<P: AsRef<Path>>(file_path: P) -> IoResult<()> {
+        let path = file_path.as_ref();
+        let mut idx_opts = Self::read_layout(path)?;
+        idx_opts.db_version = OPTIONS_FORMAT_VERSION as i32;
+        let tmp_path = path.with_extension("k2d.tmp");
+        idx_opts.write_to_file(&tmp_path)?;
+        std::fs::rename(tmp_path, path)
+    }
+
     /// Writes IndexOptions to a file
     ///
     /// # Arguments
@@ -157,21 +210,65 @@ impl IndexOptions {
     /// An IoResult indicating success or failure of the write operation
     pub fn write_to_file<P: AsRef<Path>>(&self, file_path: P) -> IoResult<()> {
         let mut file = File::create(file_path)?;
-
-        // Convert the struct to a byte slice. This is an unsafe operation as we're
-        // forcing memory content to be interpreted as bytes. This requires IndexOptions
-        // to be #[repr(C)], and all fields must be safely copyable in their raw memory representation.
-        let bytes: &[u8] = unsafe {
-            std::slice::from_raw_parts(
-                (self as *const IndexOptions) as *const u8,
-                mem::size_of::<IndexOptions>(),
-            )
-        };
-
-        file.write_all(bytes)?;
+        file.write_all(&self.to_le_bytes())?;
         Ok(())
     }
 
+    /// Bytes of opts.k2d
+    pub const BYTES: usize = 64;
+
+    /// The bytes of opts.k2d: the fields at the offsets of the `#[repr(C)]` layout of a 64-bit
+    /// platform, as written by Kraken 2, in little-endian on every platform
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::IndexOptions;
+    ///
+    /// let opts = IndexOptions::new(35, 31, 0b1101, 0xabcd, true, 7);
+    /// let bytes = opts.to_le_bytes();
+    /// assert_eq!(bytes[0..8], 35u64.to_le_bytes());
+    /// assert_eq!(bytes[24..32], 0xabcdu64.to_le_bytes());
+    /// assert_eq!(bytes[32], 1);
+    /// assert_eq!(bytes[40..48], 7u64.to_le_bytes());
+    ///
+    /// let path = std::env::temp_dir().join("kun_peng_opts_doctest.k2d");
+    /// opts.write_to_file(&path).unwrap();
+    /// let read = IndexOptions::read_index_options(&path).unwrap();
+    /// assert_eq!((read.k, read.l, read.spaced_seed_mask), (35, 31, 0b1101));
+    /// assert_eq!((read.toggle_mask, read.dna_db, read.minimum_acceptable_hash_value), (0xabcd, true, 7));
+    /// assert_eq!(read.revcom_version, opts.revcom_version);
+    /// std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn to_le_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
+        LittleEndian::write_u64(&mut bytes[0..8], self.k as u64);
+        LittleEndian::write_u64(&mut bytes[8..16], self.l as u64);
+        LittleEndian::write_u64(&mut bytes[16..24], self.spaced_seed_mask);
+        LittleEndian::write_u64(&mut bytes[24..32], self.toggle_mask);
+        bytes[32] = self.dna_db as u8;
+        LittleEndian::write_u64(&mut bytes[40..48], self.minimum_acceptable_hash_value);
+        LittleEndian::write_i32(&mut bytes[48..52], self.revcom_version);
+        LittleEndian::write_i32(&mut bytes[52..56], self.db_version);
+        LittleEndian::write_i32(&mut bytes[56..60], self.db_type);
+        bytes
+    }
+
+    /// Reads the options written by [`IndexOptions::to_le_bytes`]
+    pub fn from_le_bytes(bytes: &[u8; Self::BYTES]) -> Self {
+        Self {
+            k: LittleEndian::read_u64(&bytes[0..8]) as usize,
+            l: LittleEndian::read_u64(&bytes[8..16]) as usize,
+            spaced_seed_mask: LittleEndian::read_u64(&bytes[16..24]),
+            toggle_mask: LittleEndian::read_u64(&bytes[24..32]),
+            dna_db: bytes[32] != 0,
+            minimum_acceptable_hash_value: LittleEndian::read_u64(&bytes[40..48]),
+            revcom_version: LittleEndian::read_i32(&bytes[48..52]),
+            db_version: LittleEndian::read_i32(&bytes[52..56]),
+            db_type: LittleEndian::read_i32(&bytes[56..60]),
+        }
+    }
+
     /// Creates IndexOptions from a Meros instance
     pub fn from_meros(meros: Meros) -> Self {
         Self::new(
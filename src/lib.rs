@@ -6,10 +6,32 @@ pub mod taxonomy;
 pub mod utils;
 
 pub mod db;
+pub mod dust;
 pub use kr2r_data::*;
 pub use kv_store::*;
 pub use readcounts::TaxonCounts;
+pub use classifier::Classifier;
 
+pub mod anonymize;
 pub mod args;
+pub mod checkpoint;
+pub mod chunk_stream;
+pub mod classifier;
 pub mod classify;
 pub mod compact_hash;
+pub mod error;
+pub mod external_sort;
+pub mod ffi;
+pub mod footer;
+pub mod format;
+pub mod input;
+pub mod logging;
+pub mod manifest;
+pub mod numa;
+pub mod page_cache;
+pub mod plan;
+pub mod prefilter;
+pub mod progress;
+pub mod read_ahead;
+pub mod remote;
+pub mod summary;
@@ -0,0 +1,151 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::logging::format_timestamp;
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+/// assert_eq!(format_timestamp(time), "2023-11-14T22:13:20.123Z");
+/// ```
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // 由 1970-01-01 起的天数换算公历日期
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Formats a log record as one line of text or one JSON object
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::logging::format_line;
+/// use log::Level;
+///
+/// let ts = "2023-11-14T22:13:20.123Z";
+/// assert_eq!(
+///     format_line(ts, Level::Info, "kun_peng::annotate", "annotate start...", false),
+///     "[2023-11-14T22:13:20.123Z INFO ] annotate start..."
+/// );
+/// assert_eq!(
+///     format_line(ts, Level::Warn, "kun_peng::annotate", "say \"hi\"", true),
+///     r#"{"ts":"2023-11-14T22:13:20.123Z","level":"WARN","target":"kun_peng::annotate","msg":"say \"hi\""}"#
+/// );
+/// ```
+pub fn format_line(ts: &str, level: Level, target: &str, message: &str, json: bool) -> String {
+    if json {
+        let quote = |value: &str| serde_json::Value::from(value).to_string();
+        format!(
+            "{{\"ts\":{},\"level\":{},\"target\":{},\"msg\":{}}}",
+            quote(ts),
+            quote(level.as_str()),
+            quote(target),
+            quote(message)
+        )
+    } else {
+        format!("[{} {:<5}] {}", ts, level.as_str(), message)
+    }
+}
+
+/// Whether stderr carries JSON log lines, set by [`init`]
+static JSON_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Whether text for people, such as the summary of a run, may be printed to stderr
+///
+/// It may unless the log level is below info (`--quiet`) or stderr carries JSON log lines.
+pub fn human_output() -> bool {
+    log::max_level() >= LevelFilter::Info && !JSON_STDERR.load(Ordering::Relaxed)
+}
+
+/// Writes the log records to stderr or a log file, as text or JSON lines
+struct Logger {
+    level: LevelFilter,
+    json: bool,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(
+            &format_timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            &record.args().to_string(),
+            self.json,
+        );
+        match &self.file {
+            Some(file) => {
+                let _ = writeln!(file.lock().unwrap(), "{}", line);
+            }
+            None => {
+                let _ = writeln!(io::stderr().lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Installs the logger of the `kun_peng` command
+///
+/// # Arguments
+///
+/// * `level` - The most verbose level that is written
+/// * `log_file` - Append the records to this file instead of writing them to stderr
+/// * `json` - Write one JSON object per line instead of text
+pub fn init(level: LevelFilter, log_file: Option<&Path>, json: bool) -> io::Result<()> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+    JSON_STDERR.store(json && file.is_none(), Ordering::Relaxed);
+    log::set_boxed_logger(Box::new(Logger { level, json, file }))
+        .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
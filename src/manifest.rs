@@ -0,0 +1,254 @@
+use crate::compact_hash::HashConfig;
+use crate::taxonomy::find_gtdb_taxonomy_files;
+use crate::utils::{file_md5, find_library_fna_files};
+use crate::IndexOptions;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the manifest file in the database directory
+pub const MANIFEST_FILENAME: &str = "db_manifest.json";
+
+/// Checksum of a file the database was built from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileChecksum {
+    /// File name relative to the database directory
+    pub file: String,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch
+    pub modified: u64,
+    pub md5: String,
+}
+
+/// Description of a database directory, written as `db_manifest.json` at build time
+///
+/// Records the minimizer options, the hash table layout and checksums of the inputs, so a
+/// database directory can still be identified long after it was built.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbManifest {
+    /// Version of Kun-peng that built the database
+    pub builder_version: String,
+    /// Build time in seconds since the Unix epoch
+    pub created: u64,
+    pub k: usize,
+    pub l: usize,
+    pub spaced_seed_mask: u64,
+    pub toggle_mask: u64,
+    pub minimum_acceptable_hash_value: u64,
+    pub hash_version: usize,
+    pub capacity: usize,
+    pub size: usize,
+    pub load_factor: f64,
+    pub partition: usize,
+    pub hash_capacity: usize,
+    pub value_bits: usize,
+    /// Checksum of taxo.k2d
+    pub taxo_md5: String,
+    /// Checksums of the taxonomy files taxo.k2d was generated from
+    pub taxonomy: Vec<FileChecksum>,
+    /// Checksums of the library files the hash tables were built from
+    pub library: Vec<FileChecksum>,
+}
+
+/// Checksums of the given files, reusing those of `previous` for unchanged files
+fn checksums(
+    database: &Path,
+    files: &[PathBuf],
+    previous: &HashMap<String, FileChecksum>,
+) -> Result<Vec<FileChecksum>> {
+    files
+        .par_iter()
+        .map(|path| {
+            let file = path
+                .strip_prefix(database)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .to_string();
+            let metadata = fs::metadata(path)?;
+            let size = metadata.len();
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            if let Some(checksum) = previous.get(&file) {
+                if checksum.size == size && checksum.modified == modified {
+                    return Ok(checksum.clone());
+                }
+            }
+            Ok(FileChecksum {
+                file,
+                size,
+                modified,
+                md5: file_md5(path)?,
+            })
+        })
+        .collect()
+}
+
+impl DbManifest {
+    /// Describes a database from its option, config, taxonomy and library files
+    ///
+    /// Checksums recorded in an existing manifest are reused for files whose size and
+    /// modification time did not change.
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - The database directory
+    /// * `library_dir` - The directory holding the library files of the database
+    ///
+    /// # Returns
+    ///
+    /// The manifest of the database
+    pub fn collect(database: &Path, library_dir: &Path) -> Result<Self> {
+        let idx_opts = IndexOptions::read_index_options(database.join("opts.k2d"))?;
+        let config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+        let previous: HashMap<String, FileChecksum> = Self::from_file(database)
+            .map(|manifest| {
+                manifest
+                    .taxonomy
+                    .into_iter()
+                    .chain(manifest.library)
+                    .map(|checksum| (checksum.file.clone(), checksum))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let taxonomy_dir = database.join("taxonomy");
+        let mut taxonomy_files: Vec<PathBuf> = ["names.dmp", "nodes.dmp"]
+            .iter()
+            .map(|name| taxonomy_dir.join(name))
+            .filter(|path| path.exists())
+            .collect();
+        if taxonomy_dir.is_dir() {
+            taxonomy_files.extend(find_gtdb_taxonomy_files(&taxonomy_dir)?);
+        }
+        let library_files = find_library_fna_files(library_dir);
+
+        Ok(Self {
+            builder_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            k: idx_opts.k,
+            l: idx_opts.l,
+            spaced_seed_mask: idx_opts.spaced_seed_mask,
+            toggle_mask: idx_opts.toggle_mask,
+            minimum_acceptable_hash_value: idx_opts.minimum_acceptable_hash_value,
+            hash_version: config.version,
+            capacity: config.capacity,
+            size: config.size,
+            load_factor: config.size as f64 / config.capacity.max(1) as f64,
+            partition: config.partition,
+            hash_capacity: config.hash_capacity,
+            value_bits: config.value_bits,
+            taxo_md5: file_md5(&database.join("taxo.k2d"))?,
+            taxonomy: checksums(database, &taxonomy_files, &previous)?,
+            library: checksums(database, &library_files, &previous)?,
+        })
+    }
+
+    /// Collects the manifest of a database and writes it to `db_manifest.json`
+    pub fn update(database: &Path, library_dir: &Path) -> Result<Self> {
+        let manifest = Self::collect(database, library_dir)?;
+        manifest.write_to_file(database)?;
+        Ok(manifest)
+    }
+
+    pub fn write_to_file(&self, database: &Path) -> Result<()> {
+        let writer = BufWriter::new(File::create(database.join(MANIFEST_FILENAME))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn from_file(database: &Path) -> Result<Self> {
+        let reader = BufReader::new(File::open(database.join(MANIFEST_FILENAME))?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Differences between the manifest and the option and config files of the database
+    pub fn mismatches(&self, idx_opts: &IndexOptions, config: &HashConfig) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let mut check = |name: &str, expected: u64, found: u64| {
+            if expected != found {
+                mismatches.push(format!("{}: manifest {}, found {}", name, expected, found));
+            }
+        };
+        check("k", self.k as u64, idx_opts.k as u64);
+        check("l", self.l as u64, idx_opts.l as u64);
+        check(
+            "spaced_seed_mask",
+            self.spaced_seed_mask,
+            idx_opts.spaced_seed_mask,
+        );
+        check("toggle_mask", self.toggle_mask, idx_opts.toggle_mask);
+        check(
+            "minimum_acceptable_hash_value",
+            self.minimum_acceptable_hash_value,
+            idx_opts.minimum_acceptable_hash_value,
+        );
+        check("capacity", self.capacity as u64, config.capacity as u64);
+        check("partition", self.partition as u64, config.partition as u64);
+        check(
+            "hash_capacity",
+            self.hash_capacity as u64,
+            config.hash_capacity as u64,
+        );
+        check(
+            "value_bits",
+            self.value_bits as u64,
+            config.value_bits as u64,
+        );
+        mismatches
+    }
+}
+
+/// Prints the manifest of a database and checks it against opts.k2d and hash_config.k2d
+///
+/// Databases built before manifests were introduced are accepted with a note.
+///
+/// # Arguments
+///
+/// * `database` - The database directory
+///
+/// # Returns
+///
+/// An error if the manifest does not match the database files
+pub fn validate_manifest(database: &Path) -> Result<()> {
+    if !database.join(MANIFEST_FILENAME).exists() {
+        log::info!(
+            "no {} in {:?}, skipping validation",
+            MANIFEST_FILENAME, database
+        );
+        return Ok(());
+    }
+    let manifest = DbManifest::from_file(database)?;
+    log::info!(
+        "database built by kun_peng {}: k={}, l={}, capacity {} ({} pages), load factor {:.2}, {} library files",
+        manifest.builder_version,
+        manifest.k,
+        manifest.l,
+        manifest.capacity,
+        manifest.partition,
+        manifest.load_factor,
+        manifest.library.len()
+    );
+
+    let idx_opts = IndexOptions::read_index_options(database.join("opts.k2d"))?;
+    let config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    let mismatches = manifest.mismatches(&idx_opts, &config);
+    if !mismatches.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} does not match the database files: {}",
+                MANIFEST_FILENAME,
+                mismatches.join("; ")
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! NUMA placement of the worker threads and hash pages
+//!
+//! On dual-socket nodes a lookup into a hash page held by the memory of the other socket is
+//! markedly slower than a local one, and where a page lands depends on the thread that first
+//! touched it. The global `--numa` option makes the placement explicit:
+//!
+//! - `local` pins the run to the CPUs of one node and prefers that node for the hash pages.
+//!   A page larger than the free memory of the node is interleaved instead.
+//! - `interleave` spreads the hash pages over all nodes, so threads on every socket see the
+//!   same average cost.
+//!
+//! Placement works on Linux only; elsewhere, and on machines with a single node, `--numa`
+//! has no effect.
+use log::warn;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Placement of the threads and hash pages, given as `--numa off|local|interleave`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::numa::NumaPolicy;
+///
+/// let policy: NumaPolicy = "interleave".parse().unwrap();
+/// assert_eq!(policy, NumaPolicy::Interleave);
+/// assert_eq!(NumaPolicy::Local.to_string(), "local");
+/// assert!("spread".parse::<NumaPolicy>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumaPolicy {
+    /// Leave the placement to the OS
+    #[default]
+    Off,
+    /// Pin the threads to one node and allocate the hash pages there
+    Local,
+    /// Spread the hash pages over all nodes
+    Interleave,
+}
+
+impl FromStr for NumaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "local" => Ok(Self::Local),
+            "interleave" => Ok(Self::Interleave),
+            _ => Err(format!(
+                "invalid NUMA policy '{}', expected off, local or interleave",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NumaPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Off => "off",
+            Self::Local => "local",
+            Self::Interleave => "interleave",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A NUMA node and its CPUs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+/// Where the hash pages go, chosen by [`set_numa_policy`]
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone)]
+enum Placement {
+    /// Prefer node `id`, interleave over `nodes` what does not fit it
+    Node { id: usize, nodes: Vec<usize> },
+    /// Interleave over these nodes
+    Interleave(Vec<usize>),
+}
+
+/// Page placement of the process, set by the global `--numa` option
+#[cfg(target_os = "linux")]
+static PLACEMENT: OnceLock<Placement> = OnceLock::new();
+
+/// Parses a Linux CPU list such as `0-3,8-11`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::numa::parse_cpu_list;
+///
+/// assert_eq!(parse_cpu_list("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+/// assert!(parse_cpu_list("").is_empty());
+/// ```
+pub fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse::<usize>()) {
+            cpus.extend(first..=last);
+        }
+    }
+    cpus
+}
+
+/// Get the NUMA nodes of the machine, from `/sys/devices/system/node`.
+///
+/// # Returns
+///
+/// The nodes ordered by ID, empty if they couldn't be read or the OS is not Linux
+pub fn numa_nodes() -> Vec<NumaNode> {
+    let mut nodes = Vec::new();
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") else {
+        return nodes;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name();
+        let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        let cpus = std::fs::read_to_string(entry.path().join("cpulist"))
+            .map(|list| parse_cpu_list(&list))
+            .unwrap_or_default();
+        nodes.push(NumaNode { id, cpus });
+    }
+    nodes.sort_by_key(|node| node.id);
+    nodes
+}
+
+/// Get the free memory of a NUMA node in bytes, from its `meminfo`.
+#[cfg(target_os = "linux")]
+fn node_free_memory(id: usize) -> Option<usize> {
+    let meminfo =
+        std::fs::read_to_string(format!("/sys/devices/system/node/node{}/meminfo", id)).ok()?;
+    // 格式: "Node 0 MemFree:  123456 kB"
+    let line = meminfo.lines().find(|line| line.contains("MemFree:"))?;
+    let kb: usize = line.split_whitespace().rev().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// The node of the CPU the calling thread runs on
+#[cfg(target_os = "linux")]
+fn current_node(nodes: &[NumaNode]) -> Option<&NumaNode> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return nodes.first();
+    }
+    nodes
+        .iter()
+        .find(|node| node.cpus.contains(&(cpu as usize)))
+        .or(nodes.first())
+}
+
+/// Pins the calling thread, and the threads it starts from then on, to `cpus`
+#[cfg(target_os = "linux")]
+fn pin_to_cpus(cpus: &[usize]) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the NUMA placement of the process.
+///
+/// Call it at startup, before any worker thread starts: `local` pins the calling thread and
+/// the threads started later inherit its CPUs. If `threads` (the `--threads` budget) exceeds
+/// the CPUs of the node, `local` falls back to `interleave`.
+///
+/// # Returns
+///
+/// An io::Result, an error if the threads could not be pinned
+#[cfg(target_os = "linux")]
+pub fn set_numa_policy(policy: NumaPolicy, threads: Option<usize>) -> io::Result<()> {
+    if policy == NumaPolicy::Off {
+        return Ok(());
+    }
+    let nodes = numa_nodes();
+    if nodes.len() < 2 {
+        log::info!("single NUMA node, --numa {} has no effect", policy);
+        return Ok(());
+    }
+    let ids: Vec<usize> = nodes.iter().map(|node| node.id).collect();
+    let placement = match (policy, current_node(&nodes)) {
+        (NumaPolicy::Local, Some(node)) if threads.is_none_or(|t| t <= node.cpus.len()) => {
+            pin_to_cpus(&node.cpus)?;
+            log::info!(
+                "pinned to the {} CPUs of NUMA node {}",
+                node.cpus.len(),
+                node.id
+            );
+            Placement::Node {
+                id: node.id,
+                nodes: ids,
+            }
+        }
+        (NumaPolicy::Local, _) => {
+            warn!("the threads do not fit one NUMA node, interleaving the hash pages instead");
+            Placement::Interleave(ids)
+        }
+        _ => Placement::Interleave(ids),
+    };
+    let _ = PLACEMENT.set(placement);
+    Ok(())
+}
+
+/// Sets the NUMA placement of the process, a no-op on this OS.
+#[cfg(not(target_os = "linux"))]
+pub fn set_numa_policy(policy: NumaPolicy, _threads: Option<usize>) -> io::Result<()> {
+    if policy != NumaPolicy::Off {
+        warn!("--numa is only supported on Linux, ignoring it");
+    }
+    Ok(())
+}
+
+// mbind(2) 的策略与标志, libc 中没有定义
+#[cfg(target_os = "linux")]
+const MPOL_PREFERRED: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const MPOL_INTERLEAVE: libc::c_int = 3;
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Applies the NUMA placement of the process to the memory of `data`, e.g. a hash page.
+///
+/// Call it on a new buffer before filling it; memory already touched is migrated. Does
+/// nothing without `--numa`. A failure is logged and otherwise ignored, the data then stays
+/// where the OS put it.
+#[cfg(target_os = "linux")]
+pub fn place<T>(data: &[T]) {
+    let Some(placement) = PLACEMENT.get() else {
+        return;
+    };
+    let len = std::mem::size_of_val(data);
+    if len == 0 {
+        return;
+    }
+    let (mode, nodes) = match placement {
+        Placement::Node { id, .. } if node_free_memory(*id).is_none_or(|free| len <= free) => {
+            (MPOL_PREFERRED, std::slice::from_ref(id))
+        }
+        // 本节点放不下时交错分布到所有节点
+        Placement::Node { nodes, .. } | Placement::Interleave(nodes) => {
+            (MPOL_INTERLEAVE, nodes.as_slice())
+        }
+    };
+
+    const WORD_BITS: usize = libc::c_ulong::BITS as usize;
+    let max_node = nodes.iter().max().map_or(0, |&id| id + 1);
+    let mut mask = vec![0 as libc::c_ulong; max_node.div_ceil(WORD_BITS)];
+    for &id in nodes {
+        mask[id / WORD_BITS] |= 1 << (id % WORD_BITS);
+    }
+    // mbind 需要页对齐的地址
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize;
+    let start = data.as_ptr() as usize;
+    let aligned = start - start % page_size;
+    let len = start + len - aligned;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            aligned as *mut libc::c_void,
+            len as libc::c_ulong,
+            mode,
+            mask.as_ptr(),
+            (mask.len() * WORD_BITS) as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+    if ret != 0 {
+        log::debug!("mbind failed: {}", io::Error::last_os_error());
+    }
+}
+
+/// Applies the NUMA placement of the process to `data`, a no-op on this OS.
+#[cfg(not(target_os = "linux"))]
+pub fn place<T>(_data: &[T]) {}
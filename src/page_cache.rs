@@ -0,0 +1,206 @@
+//! A least-recently-used cache of hash pages for databases larger than the memory budget
+//!
+//! `direct` holds all hash pages in memory. When they do not fit the memory budget, it keeps
+//! as many pages as fit in a [`PageCache`] instead and looks up the minimizers of a batch of
+//! reads page by page: the pages that are cached first, then the others, each loaded once for
+//! the whole batch and evicting the pages used longest ago. The larger the share of the
+//! database that fits, the fewer pages a batch has to load.
+use crate::compact_hash::{load_page, page_file_bytes, HashConfig, Page};
+use crate::error::Error;
+use crate::utils::format_bytes;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A cached page, its memory and when it was last used
+struct CachedPage {
+    page: Arc<Page>,
+    bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    pages: HashMap<usize, CachedPage>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl CacheState {
+    /// The cached page, marked as used now
+    fn touch(&mut self, index: usize) -> Option<Arc<Page>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.pages.get_mut(&index).map(|cached| {
+            cached.last_used = tick;
+            cached.page.clone()
+        })
+    }
+
+    /// Evicts the pages used longest ago until `bytes` more fit `capacity`
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted pages
+    fn make_room(&mut self, bytes: usize, capacity: usize) -> usize {
+        let mut evicted = 0;
+        while self.bytes + bytes > capacity {
+            let Some(&oldest) = self
+                .pages
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(index, _)| index)
+            else {
+                break;
+            };
+            // 仍在使用该页的线程持有 Arc, 用完后释放
+            if let Some(cached) = self.pages.remove(&oldest) {
+                self.bytes -= cached.bytes;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+}
+
+/// The hash pages of a database, loaded on demand and evicted least recently used first
+///
+/// Pages still in use by a lookup when they are evicted are freed once the lookup is done, so
+/// the memory can briefly exceed the capacity by a page per worker.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::compact_hash::HashConfig;
+/// use kun_peng::page_cache::PageCache;
+///
+/// // 两个容量为 4 的页, 每页 32 字节
+/// let dir = std::env::temp_dir().join("kun_peng_page_cache_doctest");
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let mut hash_files = Vec::new();
+/// for index in 1..=2u64 {
+///     let mut bytes = Vec::new();
+///     for word in [index, 4] {
+///         bytes.extend_from_slice(&word.to_le_bytes());
+///     }
+///     for cell in [7u32 << 16 | index as u32, 0, 0, 0] {
+///         bytes.extend_from_slice(&cell.to_le_bytes());
+///     }
+///     let hash_file = dir.join(format!("hash_{}.k2d", index));
+///     std::fs::write(&hash_file, bytes).unwrap();
+///     hash_files.push(hash_file);
+/// }
+///
+/// let config = HashConfig::new(1, 8, 16, 2, 2, 4);
+/// let cache = PageCache::new(config, hash_files, 40).unwrap();
+/// assert_eq!(cache.get(0).unwrap().find_index(0, 7, 16, 0xffff), 1);
+/// // 第二页放不下两页, 换出第一页
+/// assert_eq!(cache.get(1).unwrap().find_index(0, 7, 16, 0xffff), 2);
+/// assert!(!cache.contains(0) && cache.contains(1));
+/// assert_eq!(cache.stats(), (2, 1));
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct PageCache<P> {
+    config: HashConfig,
+    hash_files: Vec<P>,
+    page_bytes: Vec<usize>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+    /// Serializes the loads of each page, so that a page is loaded once for all workers
+    loading: Vec<Mutex<()>>,
+    loads: AtomicUsize,
+    evictions: AtomicUsize,
+}
+
+impl<P: AsRef<Path> + Debug> PageCache<P> {
+    /// Creates an empty cache of the hash pages holding at most `capacity` bytes of pages
+    ///
+    /// Fails if a single page does not fit the capacity.
+    pub fn new(config: HashConfig, hash_files: Vec<P>, capacity: usize) -> Result<Self> {
+        let page_bytes = hash_files
+            .iter()
+            .map(|hash_file| page_file_bytes(hash_file).map(|bytes| bytes as usize))
+            .collect::<Result<Vec<usize>>>()?;
+        if let Some(&largest) = page_bytes.iter().max() {
+            if largest > capacity {
+                return Err(Error::Resources(format!(
+                    "a hash page of {} does not fit the page cache of {}",
+                    format_bytes(largest as f64),
+                    format_bytes(capacity as f64)
+                ))
+                .into());
+            }
+        }
+        let loading = hash_files.iter().map(|_| Mutex::new(())).collect();
+        Ok(Self {
+            config,
+            hash_files,
+            page_bytes,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            loading,
+            loads: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        })
+    }
+
+    /// The number of hash pages
+    pub fn len(&self) -> usize {
+        self.hash_files.len()
+    }
+
+    /// Whether the database has no hash pages
+    pub fn is_empty(&self) -> bool {
+        self.hash_files.is_empty()
+    }
+
+    /// Whether page `index` is cached
+    pub fn contains(&self, index: usize) -> bool {
+        self.state.lock().unwrap().pages.contains_key(&index)
+    }
+
+    /// Gets page `index`, loading it if it is not cached
+    pub fn get(&self, index: usize) -> Result<Arc<Page>> {
+        if let Some(page) = self.state.lock().unwrap().touch(index) {
+            return Ok(page);
+        }
+        let _loading = self.loading[index].lock().unwrap();
+        // 等待期间可能已由其他线程加载
+        if let Some(page) = self.state.lock().unwrap().touch(index) {
+            return Ok(page);
+        }
+        let bytes = self.page_bytes[index];
+        let evicted = self.state.lock().unwrap().make_room(bytes, self.capacity);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+
+        let page = Arc::new(load_page(self.config, &self.hash_files, index)?);
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        // 加载期间其他页可能已占用空间
+        let evicted = state.make_room(bytes, self.capacity);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        state.bytes += bytes;
+        state.tick += 1;
+        let last_used = state.tick;
+        state.pages.insert(
+            index,
+            CachedPage {
+                page: page.clone(),
+                bytes,
+                last_used,
+            },
+        );
+        Ok(page)
+    }
+
+    /// The number of page loads and evictions so far
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.loads.load(Ordering::Relaxed),
+            self.evictions.load(Ordering::Relaxed),
+        )
+    }
+}
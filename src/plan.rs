@@ -0,0 +1,413 @@
+use crate::args::{Build, ClassifyArgs};
+use crate::compact_hash::HashConfig;
+use crate::db::read_page_capacity;
+use crate::utils::{format_bytes, get_available_disk_space, memory_budget};
+use std::fmt;
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+/// Rough chunk directory usage per input byte: splitr writes a 16-byte cell for about every
+/// third base and annotate writes the hits next to them
+pub const CHUNK_BYTES_PER_INPUT_BYTE: u64 = 4;
+
+/// Assumed expansion of gzipped input files
+pub const GZIP_EXPANSION: u64 = 4;
+
+/// Rough size of the Kraken output per input byte: one line with the hit list per read
+/// takes about half the size of the read
+const INPUT_BYTES_PER_OUTPUT_BYTE: u64 = 2;
+
+/// Size of a chunk cell (index and value) on disk
+const CHUNK_CELL_BYTES: u64 = 16;
+
+/// Adds the memory budget to a plan, if there is one
+fn budget_fact(plan: &mut Plan) {
+    if let Some(budget) = memory_budget() {
+        plan.fact("memory budget", &format_bytes(budget as f64));
+    }
+}
+
+/// One stage of a planned run
+struct Stage {
+    name: String,
+    memory: u64,
+    disk: u64,
+    note: String,
+}
+
+/// The resources a `build` or `classify` run is expected to need, printed by `--dry-run`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::plan::Plan;
+///
+/// let mut plan = Plan::new("classify plan");
+/// plan.fact("hash pages", "2");
+/// plan.stage("annotate", 4 << 30, 0, "one hash page at a time");
+/// assert_eq!(
+///     plan.to_string(),
+///     "classify plan\n\
+///      \x20 hash pages  2\n\
+///      \n\
+///      \x20 stage     memory      disk\n\
+///      \x20 annotate  4.00GB      -           one hash page at a time\n"
+/// );
+/// ```
+pub struct Plan {
+    title: String,
+    facts: Vec<(String, String)>,
+    stages: Vec<Stage>,
+}
+
+impl Plan {
+    /// Creates an empty plan with a title line
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            facts: Vec::new(),
+            stages: Vec::new(),
+        }
+    }
+
+    /// Adds a line with a name and a value, e.g. the number of hash pages
+    pub fn fact(&mut self, name: &str, value: &str) {
+        self.facts.push((name.to_string(), value.to_string()));
+    }
+
+    /// Adds a stage with its peak memory and the disk space it leaves behind, 0 for none
+    pub fn stage(&mut self, name: &str, memory: u64, disk: u64, note: &str) {
+        self.stages.push(Stage {
+            name: name.to_string(),
+            memory,
+            disk,
+            note: note.to_string(),
+        });
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = |size: u64| {
+            if size == 0 {
+                "-".to_string()
+            } else {
+                format_bytes(size as f64)
+            }
+        };
+        writeln!(f, "{}", self.title)?;
+        let name_width = self
+            .facts
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0);
+        for (name, value) in &self.facts {
+            writeln!(f, "  {:<width$}  {}", name, value, width = name_width)?;
+        }
+        if self.stages.is_empty() {
+            return Ok(());
+        }
+        let stage_width = self
+            .stages
+            .iter()
+            .map(|stage| stage.name.len())
+            .chain(Some("stage".len()))
+            .max()
+            .unwrap_or(0);
+        writeln!(f)?;
+        writeln!(
+            f,
+            "  {:<width$}  {:<10}  disk",
+            "stage",
+            "memory",
+            width = stage_width
+        )?;
+        for stage in &self.stages {
+            writeln!(
+                f,
+                "  {:<width$}  {:<10}  {:<10}  {}",
+                stage.name,
+                bytes(stage.memory),
+                bytes(stage.disk),
+                stage.note,
+                width = stage_width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the input files of classify, a single `.txt` input is read as a file list like splitr does
+pub fn list_input_files(input_files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    match input_files {
+        [list] if list.is_file() && list.extension().is_some_and(|ext| ext == "txt") => {
+            Ok(fs::read_to_string(list)?
+                .lines()
+                .map(|line| PathBuf::from(line.trim()))
+                .collect())
+        }
+        files => Ok(files.to_vec()),
+    }
+}
+
+/// Estimated uncompressed size of a file, gzipped files count `GZIP_EXPANSION` times their size
+pub fn estimated_file_bytes(file: &Path) -> u64 {
+    let size = fs::metadata(file).map_or(0, |m| m.len());
+    if file.extension().is_some_and(|ext| ext == "gz") {
+        size * GZIP_EXPANSION
+    } else {
+        size
+    }
+}
+
+/// Estimated chunk directory usage of classifying the input files
+pub fn estimated_chunk_bytes(input_files: &[PathBuf]) -> u64 {
+    input_files
+        .iter()
+        .map(|file| estimated_file_bytes(file) * CHUNK_BYTES_PER_INPUT_BYTE)
+        .sum()
+}
+
+/// Free space of a directory for the plan, "unknown" if it cannot be read
+fn free_space(dir: &Path) -> String {
+    // 目录尚未创建时查看最近的已存在上级目录
+    let existing = dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    get_available_disk_space(existing)
+        .map_or("unknown".to_string(), |free| format_bytes(free as f64))
+}
+
+/// Bytes per hash cell of a database
+fn cell_bytes(hash_config: &HashConfig) -> u64 {
+    if hash_config.is_wide() {
+        8
+    } else {
+        4
+    }
+}
+
+/// Plans a classify run from the database hash_config.k2d and the sizes of the input files
+///
+/// # Arguments
+///
+/// * `args` - The classify arguments
+/// * `chunk_dir` - The chunk directory the run would use
+pub fn plan_classify(args: &ClassifyArgs, chunk_dir: &Path) -> Result<Plan> {
+    let hash_config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let mut page_cells = hash_config.hash_capacity;
+    for page_index in 1..=hash_config.partition {
+        if let Some(capacity) = read_page_capacity(&args.database, page_index)? {
+            page_cells = page_cells.max(capacity);
+        }
+    }
+    let page_bytes = page_cells as u64 * cell_bytes(&hash_config);
+
+    let input_files = list_input_files(&args.input_files)?;
+    let file_bytes: Vec<u64> = input_files
+        .iter()
+        .map(|file| estimated_file_bytes(file))
+        .collect();
+    let input_bytes: u64 = file_bytes.iter().sum();
+    // 双端模式下两个文件为一个样本
+    let files_per_sample = if args.paired_end_processing { 2 } else { 1 };
+    let samples = file_bytes.chunks(files_per_sample).count();
+    let largest_sample = file_bytes
+        .chunks(files_per_sample)
+        .map(|sizes| sizes.iter().sum::<u64>())
+        .max()
+        .unwrap_or(0);
+    let chunk_bytes = estimated_chunk_bytes(&input_files);
+    // --buffer-size 为每批读取的 cell 数
+    let read_buffers = (args.num_threads * args.buffer_size) as u64 * CHUNK_CELL_BYTES;
+    let taxonomy_bytes = fs::metadata(args.database.join("taxo.k2d")).map_or(0, |m| m.len());
+    // 未指定 --output-dir 时输出写到 stdout, 不占磁盘
+    let output_bytes = match args.output_dir {
+        Some(_) => input_bytes / INPUT_BYTES_PER_OUTPUT_BYTE,
+        None => 0,
+    };
+
+    let mut plan = Plan::new("classify plan (estimates)");
+    plan.fact(
+        "inputs",
+        &format!(
+            "{} files in {} samples, about {} uncompressed",
+            input_files.len(),
+            samples,
+            format_bytes(input_bytes as f64)
+        ),
+    );
+    plan.fact(
+        "hash pages",
+        &format!(
+            "{}, the largest {} ({} cells)",
+            hash_config.partition,
+            format_bytes(page_bytes as f64),
+            page_cells
+        ),
+    );
+    let max_samples = hash_config.max_chunk_samples().max(1);
+    if samples > max_samples {
+        plan.fact(
+            "batches",
+            &format!(
+                "{} of up to {} samples, each split and annotated before the next",
+                samples.div_ceil(max_samples),
+                max_samples
+            ),
+        );
+    }
+    plan.fact(
+        "chunk dir",
+        &format!(
+            "{}, needs about {}, {} free",
+            chunk_dir.display(),
+            format_bytes(chunk_bytes as f64),
+            free_space(chunk_dir)
+        ),
+    );
+    budget_fact(&mut plan);
+    match &args.output_dir {
+        Some(output_dir) => plan.fact(
+            "output dir",
+            &format!(
+                "{}, about {} of output, {} free",
+                output_dir.display(),
+                format_bytes(output_bytes as f64),
+                free_space(output_dir)
+            ),
+        ),
+        None => plan.fact("output", "Kraken output to stdout"),
+    }
+    plan.stage(
+        "splitr",
+        read_buffers,
+        chunk_bytes,
+        &format!(
+            "{} sample chunk files, one per hash page",
+            hash_config.partition
+        ),
+    );
+    plan.stage(
+        "annotate",
+        page_bytes + read_buffers,
+        chunk_bytes,
+        "loads one hash page at a time, the chunk files become hit files",
+    );
+    plan.stage(
+        "resolve",
+        taxonomy_bytes + largest_sample * CHUNK_BYTES_PER_INPUT_BYTE / args.batch_size as u64,
+        output_bytes,
+        &format!("one sample and 1/{} of its hits at a time", args.batch_size),
+    );
+    Ok(plan)
+}
+
+/// Plans a database build from the size of the library
+///
+/// Without a known `capacity` it is estimated from the number of minimizers in the library,
+/// an upper bound for libraries with many similar genomes; `estimate` gives the exact figure.
+///
+/// # Arguments
+///
+/// * `build` - The build arguments
+/// * `library_bytes` - The size of the library sequences
+/// * `capacity` - The hash table capacity if it is already known
+/// * `hash_capacity` - The capacity of a hash page
+/// * `load_factor` - The proportion of the hash table to be populated
+/// * `merge` - Whether the downloaded genomes are merged into the library first
+pub fn plan_build(
+    build: &Build,
+    library_bytes: u64,
+    capacity: Option<usize>,
+    hash_capacity: usize,
+    load_factor: f64,
+    merge: bool,
+) -> Plan {
+    // 窗口 w = k - l + 1 内的最小化子密度约为 2 / (w + 1)
+    let window = (build.klmt.k_mer + 1)
+        .saturating_sub(build.klmt.l_mer as u64)
+        .max(1);
+    let minimizers = library_bytes as f64 * 2.0 / (window + 1) as f64;
+    let estimated = capacity.is_none();
+    let capacity = capacity.unwrap_or((minimizers / load_factor) as usize);
+    let hash_capacity = hash_capacity.max(1);
+    let partition = capacity.div_ceil(hash_capacity).max(1);
+    let cell_bytes = if build.wide_cells { 8 } else { 4 };
+    // 只有一页时页的大小为总容量
+    let page_cells = hash_capacity.min(capacity.max(1));
+    let page_bytes = (page_cells * cell_bytes) as u64;
+    let chunk_bytes = (minimizers as u64) * CHUNK_CELL_BYTES;
+    let seqid_map_bytes =
+        fs::metadata(build.database.join("seqid2taxid.map")).map_or(0, |m| m.len());
+
+    let mut plan = Plan::new("build plan (estimates)");
+    plan.fact(
+        "library",
+        &format!("about {}", format_bytes(library_bytes as f64)),
+    );
+    plan.fact(
+        "capacity",
+        &format!(
+            "{}{}",
+            capacity,
+            if estimated {
+                " (upper bound, run estimate for the exact figure)"
+            } else {
+                ""
+            }
+        ),
+    );
+    plan.fact(
+        "hash pages",
+        &format!(
+            "{}, the largest {} ({} cells)",
+            partition,
+            format_bytes(page_bytes as f64),
+            page_cells
+        ),
+    );
+    plan.fact(
+        "database",
+        &format!(
+            "{}, {} free",
+            build.database.display(),
+            free_space(&build.database)
+        ),
+    );
+    budget_fact(&mut plan);
+    if merge {
+        plan.stage(
+            "merge-fna",
+            0,
+            library_bytes,
+            "library_*.fna and seqid2taxid.map",
+        );
+    }
+    plan.stage(
+        "estimate",
+        0,
+        0,
+        &format!("reads the library once ({} threads)", build.threads),
+    );
+    plan.stage(
+        "chunk",
+        seqid_map_bytes * 4,
+        chunk_bytes,
+        &format!("{} chunk files, removed as the pages are built", partition),
+    );
+    // --sort-buffer 时页不再整页驻留内存
+    let build_memory = build
+        .sort_buffer
+        .map_or(page_bytes, |sort_buffer| sort_buffer as u64);
+    plan.stage(
+        "build",
+        build_memory,
+        (capacity.max(1) * cell_bytes) as u64,
+        "builds one hash page at a time into hash_*.k2d",
+    );
+    plan
+}
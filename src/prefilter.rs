@@ -0,0 +1,251 @@
+//! Per-page prefilter of the minimizers absent from a database
+//!
+//! Most minimizers of a diverse environmental sample are not in the database, and every miss
+//! still probes the hash page at a random cell, a cache or page-cache miss on a large page.
+//! `build --prefilter` writes a blocked Bloom filter `hash_{i}.k2f` next to each page. It
+//! holds the home index and the key of every cell, so a lookup first reads one cache line of
+//! the much smaller filter and only probes the page if the key may be there.
+//!
+//! The filter never misses a stored key. Keys are kept at the resolution of the sample slots
+//! written by splitr, so `direct`, `classify` and `annotate` all consult the same filter.
+use crate::compact_hash::{HashConfig, Slot, WIDE_SAMPLE_VALUE_BITS};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Bits of filter per key; with [`PREFILTER_HASHES`] about 1-2% of absent keys pass
+pub const PREFILTER_BITS_PER_KEY: usize = 10;
+
+/// Bits set per key, all within one block
+pub const PREFILTER_HASHES: usize = 6;
+
+/// Words of a block, one 64-byte cache line
+const BLOCK_WORDS: usize = 8;
+
+/// The finalizer of MurmurHash3
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// The prefilter file of a hash page file, `hash_{i}.k2f` for `hash_{i}.k2d[.zst]`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::prefilter::prefilter_path;
+/// use std::path::Path;
+///
+/// assert_eq!(prefilter_path(Path::new("db/hash_3.k2d")), Path::new("db/hash_3.k2f"));
+/// assert_eq!(prefilter_path(Path::new("db/hash_3.k2d.zst")), Path::new("db/hash_3.k2f"));
+/// ```
+pub fn prefilter_path(hash_file: &Path) -> PathBuf {
+    let page_file = if hash_file.extension().is_some_and(|ext| ext == "zst") {
+        hash_file.with_extension("")
+    } else {
+        hash_file.to_path_buf()
+    };
+    page_file.with_extension("k2f")
+}
+
+/// A blocked Bloom filter of the cells of a hash page
+///
+/// A key is the home index of a cell within the page and the upper bits of its key, as many
+/// as the sample slots keep (see [`HashConfig::sample_value_bits`]).
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::prefilter::Prefilter;
+///
+/// let mut prefilter = Prefilter::new(1000, 1 << 20);
+/// for idx in 0..1000 {
+///     prefilter.insert(idx * 7, idx as u32);
+/// }
+/// assert!((0..1000).all(|idx| prefilter.may_contain(idx * 7, idx as u32)));
+/// let passed = (0..1000).filter(|&idx| prefilter.may_contain(idx * 7 + 1, 42)).count();
+/// assert!(passed < 100);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Prefilter {
+    /// Capacity of the page the filter was built for
+    capacity: usize,
+    blocks: Vec<u64>,
+}
+
+impl Prefilter {
+    /// Creates an empty filter sized for `keys` keys of a page with `capacity` cells
+    pub fn new(keys: usize, capacity: usize) -> Self {
+        let block_bits = BLOCK_WORDS * 64;
+        let count = (keys * PREFILTER_BITS_PER_KEY).div_ceil(block_bits).max(1);
+        Self {
+            capacity,
+            blocks: vec![0; count * BLOCK_WORDS],
+        }
+    }
+
+    /// Capacity of the page the filter was built for
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Size of the filter in bytes
+    pub fn bytes(&self) -> usize {
+        self.blocks.len() * 8
+    }
+
+    /// The first word of the block of a key and the bit positions in the block
+    fn locate(&self, idx: usize, key: u32) -> (usize, u64) {
+        let h = mix(mix(idx as u64) ^ key as u64);
+        let count = (self.blocks.len() / BLOCK_WORDS) as u64;
+        let block = (((h >> 32) * count) >> 32) as usize;
+        (block * BLOCK_WORDS, mix(h))
+    }
+
+    /// Adds a key
+    pub fn insert(&mut self, idx: usize, key: u32) {
+        let (start, mut bits) = self.locate(idx, key);
+        for _ in 0..PREFILTER_HASHES {
+            let bit = (bits & 511) as usize;
+            self.blocks[start + bit / 64] |= 1 << (bit % 64);
+            bits >>= 9;
+        }
+    }
+
+    /// Whether the key may be in the page, `false` only if it is certainly not
+    pub fn may_contain(&self, idx: usize, key: u32) -> bool {
+        let (start, mut bits) = self.locate(idx, key);
+        for _ in 0..PREFILTER_HASHES {
+            let bit = (bits & 511) as usize;
+            if self.blocks[start + bit / 64] & (1 << (bit % 64)) == 0 {
+                return false;
+            }
+            bits >>= 9;
+        }
+        true
+    }
+
+    /// Adds the cells of a k2 chunk file, the input of the page build
+    pub fn insert_chunk_file(&mut self, config: &HashConfig, chunk_file: &Path) -> Result<()> {
+        let mut reader = BufReader::new(File::open(chunk_file)?);
+        let mut bytes = [0u8; Slot::<u64>::BYTES];
+        loop {
+            match reader.read_exact(&mut bytes) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let Slot { idx, value } = Slot::<u64>::from_le_bytes(&bytes);
+            let key = if config.is_wide() {
+                (value >> (32 + WIDE_SAMPLE_VALUE_BITS)) as u32
+            } else {
+                value as u32 >> config.value_bits
+            };
+            self.insert(idx % self.capacity.max(1), key);
+        }
+        Ok(())
+    }
+
+    /// Builds the filter of a page from its k2 chunk file
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The HashConfig of the database
+    /// * `chunk_file` - The chunk file the page was built from
+    /// * `capacity` - The capacity of the built page
+    /// * `keys` - The number of cells of the built page
+    pub fn from_chunk_file(
+        config: &HashConfig,
+        chunk_file: &Path,
+        capacity: usize,
+        keys: usize,
+    ) -> Result<Self> {
+        let mut prefilter = Self::new(keys, capacity);
+        prefilter.insert_chunk_file(config, chunk_file)?;
+        Ok(prefilter)
+    }
+
+    /// Writes the filter: the page capacity, the number of words and the words
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_u64::<LittleEndian>(self.capacity as u64)?;
+        writer.write_u64::<LittleEndian>(self.blocks.len() as u64)?;
+        for word in &self.blocks {
+            writer.write_u64::<LittleEndian>(*word)?;
+        }
+        writer.flush()
+    }
+
+    /// Reads a filter written by [`Prefilter::write`]
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let capacity = reader.read_u64::<LittleEndian>()? as usize;
+        let words = reader.read_u64::<LittleEndian>()? as usize;
+        if words == 0 || !words.is_multiple_of(BLOCK_WORDS) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} is not a prefilter file", path),
+            ));
+        }
+        let mut blocks = vec![0u64; words];
+        reader.read_u64_into::<LittleEndian>(&mut blocks)?;
+        Ok(Self { capacity, blocks })
+    }
+
+    /// Loads the filter of a hash page file, if it has one
+    ///
+    /// # Returns
+    ///
+    /// The filter, or `None` if there is no filter or it was built for a page with another
+    /// capacity, i.e. the page was rebuilt without it
+    pub fn load(hash_file: &Path, capacity: usize) -> Result<Option<Self>> {
+        let path = prefilter_path(hash_file);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let prefilter = Self::read(&path)?;
+        if prefilter.capacity != capacity {
+            log::warn!("{:?} does not match its hash page, ignoring it", path);
+            return Ok(None);
+        }
+        Ok(Some(prefilter))
+    }
+}
+
+/// Builds and writes the filter of a freshly built, uncompressed hash page
+///
+/// # Arguments
+///
+/// * `config` - The HashConfig of the database
+/// * `chunk_file` - The chunk file the page was built from
+/// * `page_file` - The `hash_{i}.k2d` file of the page
+/// * `keys` - The number of cells of the page
+///
+/// # Returns
+///
+/// The size of the filter in bytes
+pub fn write_prefilter(
+    config: &HashConfig,
+    chunk_file: &Path,
+    page_file: &Path,
+    keys: usize,
+) -> Result<usize> {
+    let mut header = [0u8; 16];
+    File::open(page_file)?.read_exact(&mut header)?;
+    let capacity = u64::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+    let prefilter = Prefilter::from_chunk_file(config, chunk_file, capacity, keys)?;
+    prefilter.write(&prefilter_path(page_file))?;
+    Ok(prefilter.bytes())
+}
+
+/// Removes the filter of a hash page file before the page is rewritten
+pub fn remove_prefilter(hash_file: &Path) -> Result<()> {
+    match std::fs::remove_file(prefilter_path(hash_file)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time between two progress lines on a terminal
+const TERMINAL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between two progress lines when stderr goes to a log file
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Width of the progress bar in characters
+const BAR_WIDTH: usize = 30;
+
+/// Formats a duration as `h:mm:ss`
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Formats one progress line
+///
+/// With a known `total`, the line has a bar, the percentage and the estimated time left;
+/// with a `total` of 0 it has the count and the rate.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::progress::format_progress;
+/// use std::time::Duration;
+///
+/// assert_eq!(
+///     format_progress("annotate", "chunk files", 1, 4, Duration::from_secs(60)),
+///     "annotate [#######-----------------------]  25% 1/4 chunk files, elapsed 0:01:00, ETA 0:03:00"
+/// );
+/// assert_eq!(
+///     format_progress("splitr", "reads", 3000, 0, Duration::from_secs(2)),
+///     "splitr 3000 reads (1500 reads/s), elapsed 0:00:02"
+/// );
+/// ```
+pub fn format_progress(
+    label: &str,
+    unit: &str,
+    done: u64,
+    total: u64,
+    elapsed: Duration,
+) -> String {
+    if total == 0 {
+        let rate = done as f64 / elapsed.as_secs_f64().max(1e-3);
+        return format!(
+            "{} {} {} ({:.0} {}/s), elapsed {}",
+            label,
+            done,
+            unit,
+            rate,
+            unit,
+            format_duration(elapsed)
+        );
+    }
+
+    let done = done.min(total);
+    let fraction = done as f64 / total as f64;
+    let filled = (fraction * BAR_WIDTH as f64) as usize;
+    let eta = if done == 0 {
+        "?".to_string()
+    } else {
+        format_duration(elapsed.mul_f64((total - done) as f64 / done as f64))
+    };
+    format!(
+        "{} [{}{}] {:>3}% {}/{} {}, elapsed {}, ETA {}",
+        label,
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        (fraction * 100.0) as u64,
+        done,
+        total,
+        unit,
+        format_duration(elapsed),
+        eta
+    )
+}
+
+/// Progress of one stage, printed to stderr when enabled
+///
+/// On a terminal the line is redrawn in place; otherwise, e.g. under a workflow manager,
+/// it is logged at most every 30 seconds.
+pub struct Progress {
+    label: String,
+    unit: &'static str,
+    total: u64,
+    done: AtomicU64,
+    start: Instant,
+    last_print: Mutex<Option<Instant>>,
+    enabled: bool,
+    terminal: bool,
+}
+
+impl Progress {
+    /// Creates the progress of a stage
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The stage name printed at the start of the line
+    /// * `unit` - What is counted, e.g. "reads"
+    /// * `total` - The expected count, 0 if unknown
+    /// * `enabled` - Whether anything is printed, never below the info log level (`--quiet`)
+    pub fn new(label: &str, unit: &'static str, total: u64, enabled: bool) -> Self {
+        Self {
+            label: label.to_string(),
+            unit,
+            total,
+            done: AtomicU64::new(0),
+            start: Instant::now(),
+            last_print: Mutex::new(None),
+            enabled: enabled && log::max_level() >= log::LevelFilter::Info,
+            terminal: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Adds `n` to the count and prints the line if it is due
+    pub fn inc(&self, n: u64) {
+        let done = self.done.fetch_add(n, Ordering::Relaxed) + n;
+        if !self.enabled {
+            return;
+        }
+        let interval = if self.terminal {
+            TERMINAL_INTERVAL
+        } else {
+            LOG_INTERVAL
+        };
+        let mut last_print = self.last_print.lock().unwrap();
+        if last_print.is_some_and(|last| last.elapsed() < interval) {
+            return;
+        }
+        *last_print = Some(Instant::now());
+        self.print(done);
+    }
+
+    /// Prints the final line of the stage
+    pub fn finish(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.print(self.done.load(Ordering::Relaxed));
+        if self.terminal {
+            eprintln!();
+        }
+    }
+
+    fn print(&self, done: u64) {
+        let line = format_progress(
+            &self.label,
+            self.unit,
+            done,
+            self.total,
+            self.start.elapsed(),
+        );
+        if self.terminal {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r{}\x1b[K", line);
+            let _ = stderr.flush();
+        } else {
+            log::info!("{}", line);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Read-ahead hints for the hash page files
+//!
+//! A cold read of a hash page from Lustre or NFS is dominated by how far ahead the client
+//! fetches. The global `--db-read-ahead` option tells the kernel how the pages are read:
+//!
+//! - `sequential` marks every page file as read sequentially, which widens the read-ahead
+//!   window, and asks for the page that is loaded next (`WILLNEED`) while the current one is
+//!   read or used.
+//! - `direct` reads the page files with `O_DIRECT`, past the page cache. A page is read once
+//!   per run and then held in memory, so caching it only evicts other data; on filesystems
+//!   without `O_DIRECT` the files are read as with `sequential`.
+//!
+//! The hints work on Linux only; elsewhere `--db-read-ahead` has no effect.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// How the hash page files are read, given as `--db-read-ahead off|sequential|direct`
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::read_ahead::ReadAhead;
+///
+/// let mode: ReadAhead = "sequential".parse().unwrap();
+/// assert_eq!(mode, ReadAhead::Sequential);
+/// assert_eq!(ReadAhead::Direct.to_string(), "direct");
+/// assert!("random".parse::<ReadAhead>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadAhead {
+    /// Leave the read-ahead to the OS
+    #[default]
+    Off,
+    /// Sequential read-ahead of the page files and `WILLNEED` for the next page
+    Sequential,
+    /// `O_DIRECT` reads of the page files
+    Direct,
+}
+
+impl FromStr for ReadAhead {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "sequential" => Ok(Self::Sequential),
+            "direct" => Ok(Self::Direct),
+            _ => Err(format!(
+                "invalid read-ahead mode '{}', expected off, sequential or direct",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReadAhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Off => "off",
+            Self::Sequential => "sequential",
+            Self::Direct => "direct",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Read-ahead mode of the process, set by the global `--db-read-ahead` option
+static READ_AHEAD: OnceLock<ReadAhead> = OnceLock::new();
+
+/// Sets how the hash page files are read for the rest of the run.
+pub fn set_read_ahead(mode: ReadAhead) {
+    let _ = READ_AHEAD.set(mode);
+}
+
+/// Get the read-ahead mode of the process, `off` unless set.
+pub fn read_ahead() -> ReadAhead {
+    READ_AHEAD.get().copied().unwrap_or_default()
+}
+
+/// Block size of the `O_DIRECT` reads; buffers, offsets and lengths are multiples of it
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGN: usize = 4096;
+
+/// Bytes read from the file per `O_DIRECT` read
+#[cfg(target_os = "linux")]
+const DIRECT_BUFFER: usize = 4 << 20;
+
+/// Reads a file opened with `O_DIRECT` through an aligned buffer
+///
+/// Every read of the file asks for [`DIRECT_BUFFER`] bytes at an aligned offset. A read that
+/// returns less than a whole number of blocks reached the end of the file.
+#[cfg(target_os = "linux")]
+struct DirectReader {
+    file: File,
+    buffer: Vec<u8>,
+    /// Offset of the aligned part of `buffer`
+    start: usize,
+    pos: usize,
+    filled: usize,
+    eof: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectReader {
+    fn new(file: File) -> Self {
+        let buffer = vec![0u8; DIRECT_BUFFER + DIRECT_ALIGN];
+        let start = buffer.as_ptr().align_offset(DIRECT_ALIGN);
+        Self {
+            file,
+            buffer,
+            start,
+            pos: 0,
+            filled: 0,
+            eof: false,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled {
+            if self.eof {
+                return Ok(0);
+            }
+            let aligned = &mut self.buffer[self.start..self.start + DIRECT_BUFFER];
+            let n = loop {
+                match self.file.read(aligned) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    result => break result?,
+                }
+            };
+            // 不足整块说明到了文件末尾, 之后的偏移也不再对齐
+            self.eof = n == 0 || n % DIRECT_ALIGN != 0;
+            self.pos = 0;
+            self.filled = n;
+        }
+        let n = buf.len().min(self.filled - self.pos);
+        let from = self.start + self.pos;
+        buf[..n].copy_from_slice(&self.buffer[from..from + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Passes an access pattern of the whole file to the kernel, ignoring failures
+#[cfg(target_os = "linux")]
+fn advise(file: &File, advice: libc::c_int) {
+    use std::os::unix::io::AsRawFd;
+    // 提示失败不影响读取, 例如不支持 fadvise 的文件系统
+    let _ = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+}
+
+/// Opens a hash page file for reading it from start to end, as the read-ahead mode asks
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::read_ahead::open_db_file;
+/// use std::io::Read;
+///
+/// let path = std::env::temp_dir().join("kun_peng_read_ahead_doctest.k2d");
+/// std::fs::write(&path, vec![5u8; 10_000]).unwrap();
+/// let mut bytes = Vec::new();
+/// open_db_file(&path).unwrap().read_to_end(&mut bytes).unwrap();
+/// assert_eq!(bytes, vec![5u8; 10_000]);
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn open_db_file(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        match read_ahead() {
+            ReadAhead::Off => {}
+            ReadAhead::Sequential => {
+                let file = File::open(path)?;
+                advise(&file, libc::POSIX_FADV_SEQUENTIAL);
+                return Ok(Box::new(file));
+            }
+            ReadAhead::Direct => {
+                match std::fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(path)
+                {
+                    Ok(file) => return Ok(Box::new(DirectReader::new(file))),
+                    Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                        static FALLBACK: std::sync::Once = std::sync::Once::new();
+                        FALLBACK.call_once(|| {
+                            log::warn!(
+                                "{:?} does not support O_DIRECT, reading the hash pages with sequential read-ahead",
+                                path
+                            )
+                        });
+                        let file = File::open(path)?;
+                        advise(&file, libc::POSIX_FADV_SEQUENTIAL);
+                        return Ok(Box::new(file));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(Box::new(File::open(path)?))
+}
+
+/// Asks the kernel to read a hash page file into the page cache in the background
+///
+/// Only with `--db-read-ahead sequential`: `direct` reads do not go through the page cache.
+/// The hint is sent from a short-lived thread, as on some filesystems it waits for the reads
+/// to be queued; a missing file or a failed hint is ignored.
+pub fn will_need(path: &Path) {
+    #[cfg(target_os = "linux")]
+    if read_ahead() == ReadAhead::Sequential {
+        let path = path.to_path_buf();
+        let _ = std::thread::Builder::new()
+            .name("read-ahead".to_string())
+            .spawn(move || {
+                if let Ok(file) = File::open(&path) {
+                    advise(&file, libc::POSIX_FADV_WILLNEED);
+                }
+            });
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = path;
+}
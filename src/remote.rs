@@ -0,0 +1,244 @@
+//! Streaming reads and writes of objects in S3 (`s3://`) and Google Cloud Storage (`gs://`)
+//!
+//! Objects are streamed through the cloud CLIs, `aws s3 cp` and `gcloud storage cp`, with
+//! `-` as the source or destination, so they use the credentials and settings of the
+//! machine (instance roles, `AWS_PROFILE`, `gcloud auth`). Downloads are read as they
+//! arrive and uploads are multipart uploads done by the CLI; neither touches the local disk.
+//!
+//! The FASTA/FASTQ inputs streamed from here are parsed in [`crate::input`].
+use log::error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Uploads that failed after their writer was dropped, see [`check_uploads`]
+static FAILED_UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a path is an `s3://` or `gs://` URL
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::remote::is_remote;
+/// use std::path::Path;
+///
+/// assert!(is_remote("s3://bucket/run1/reads.fq.gz"));
+/// assert!(is_remote(Path::new("gs://bucket/out").join("output_1.txt")));
+/// assert!(!is_remote("reads/s3:/reads.fq"));
+/// ```
+pub fn is_remote<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref().to_string_lossy();
+    path.starts_with("s3://") || path.starts_with("gs://")
+}
+
+/// The URL of a remote path
+///
+/// On Windows, [`Path::join`] appends with `\`, which is turned back into the `/` of URLs.
+pub(crate) fn url_of(path: &Path) -> String {
+    let url = path.to_string_lossy();
+    if cfg!(windows) {
+        url.replace('\\', "/")
+    } else {
+        url.into_owned()
+    }
+}
+
+/// The CLI command copying `source` to `destination`, one of them `-`
+fn copy_command(url: &str, source: &str, destination: &str) -> Command {
+    let mut command = if url.starts_with("s3://") {
+        let mut command = Command::new("aws");
+        command.args(["s3", "cp", "--only-show-errors"]);
+        command
+    } else {
+        // Windows 上 gcloud 是批处理脚本 gcloud.cmd
+        let mut command = Command::new(if cfg!(windows) {
+            "gcloud.cmd"
+        } else {
+            "gcloud"
+        });
+        command.args(["storage", "cp"]);
+        command
+    };
+    command.args([source, destination]);
+    command
+}
+
+fn spawn(mut command: Command, url: &str) -> io::Result<Child> {
+    command.stderr(Stdio::inherit()).spawn().map_err(|e| {
+        let program = command.get_program().to_string_lossy().into_owned();
+        if e.kind() == io::ErrorKind::NotFound {
+            io::Error::new(
+                e.kind(),
+                format!("{} not found; install it to access {}", program, url),
+            )
+        } else {
+            io::Error::new(
+                e.kind(),
+                format!("failed to run {} for {}: {}", program, url, e),
+            )
+        }
+    })
+}
+
+fn transfer_error(action: &str, url: &str, status: ExitStatus) -> io::Error {
+    io::Error::other(format!("failed to {} {}: {}", action, url, status))
+}
+
+/// A download of an object, read as it arrives
+pub struct RemoteReader {
+    url: String,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        // 下载结束时检查 CLI 是否成功, 否则中断的下载会被当作完整的文件
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(transfer_error("download", &self.url, status));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for RemoteReader {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Starts the download of an `s3://` or `gs://` object
+pub fn open_remote(url: &str) -> io::Result<RemoteReader> {
+    let mut command = copy_command(url, url, "-");
+    command.stdin(Stdio::null()).stdout(Stdio::piped());
+    let mut child = spawn(command, url)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(RemoteReader {
+        url: url.to_string(),
+        child,
+        stdout,
+    })
+}
+
+/// An upload of an object, complete once [`RemoteWriter::finish`] returns
+///
+/// A writer dropped without `finish` still waits for the upload; a failure is logged and
+/// reported by [`check_uploads`].
+pub struct RemoteWriter {
+    url: String,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+}
+
+impl RemoteWriter {
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::other(format!("upload of {} already finished", self.url)))
+    }
+
+    /// Ends the upload and waits for the object to be stored
+    pub fn finish(mut self) -> io::Result<()> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> io::Result<()> {
+        drop(self.stdin.take());
+        let Some(mut child) = self.child.take() else {
+            return Ok(());
+        };
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(transfer_error("upload", &self.url, status));
+        }
+        Ok(())
+    }
+}
+
+impl Write for RemoteWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin()?.flush()
+    }
+}
+
+impl Drop for RemoteWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.wait() {
+            error!("{}", e);
+            FAILED_UPLOADS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Starts the upload of an `s3://` or `gs://` object
+pub fn create_remote(url: &str) -> io::Result<RemoteWriter> {
+    let mut command = copy_command(url, "-", url);
+    command.stdin(Stdio::piped()).stdout(Stdio::null());
+    let mut child = spawn(command, url)?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    Ok(RemoteWriter {
+        url: url.to_string(),
+        child: Some(child),
+        stdin: Some(stdin),
+    })
+}
+
+/// Returns an error if an upload failed after its writer was dropped
+///
+/// Commands writing to an `s3://` or `gs://` output directory call it before they exit,
+/// so a failed upload fails the run.
+pub fn check_uploads() -> io::Result<()> {
+    match FAILED_UPLOADS.load(Ordering::SeqCst) {
+        0 => Ok(()),
+        failed => Err(io::Error::other(format!("{} uploads failed", failed))),
+    }
+}
+
+/// Creates an output file, or starts its upload if `path` is an `s3://` or `gs://` URL
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::remote::create_output;
+/// use std::io::Write;
+///
+/// let path = std::env::temp_dir().join("kun_peng_create_output_doctest.txt");
+/// let mut output = create_output(&path).unwrap();
+/// writeln!(output, "C\tread_1\t562\t150\t562:116").unwrap();
+/// drop(output);
+/// assert_eq!(std::fs::read_to_string(&path).unwrap(), "C\tread_1\t562\t150\t562:116\n");
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn create_output<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Write + Send>> {
+    let path = path.as_ref();
+    if is_remote(path) {
+        Ok(Box::new(create_remote(&url_of(path))?))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
+
+/// Copies a local file to an output path, which may be an `s3://` or `gs://` URL
+pub fn copy_to_output<P: AsRef<Path>, Q: AsRef<Path>>(source: P, destination: Q) -> io::Result<()> {
+    let destination = destination.as_ref();
+    if !is_remote(destination) {
+        std::fs::copy(source, destination)?;
+        return Ok(());
+    }
+    let mut writer = create_remote(&url_of(destination))?;
+    io::copy(&mut File::open(source)?, &mut writer)?;
+    writer.finish()
+}
@@ -1,10 +1,11 @@
 use crate::readcounts::{ReadCounter, TaxonCounters};
-use crate::taxonomy::Taxonomy;
-use std::collections::HashMap;
+use crate::remote::{create_output, is_remote};
+use crate::taxonomy::{read_pseudo_taxa, Taxonomy, PSEUDO_TAXA_FILENAME};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use std::fs::File;
+use std::fs::{create_dir_all, File};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Calculates clade counts based on the taxonomy and call counts
 ///
@@ -125,7 +126,7 @@ fn mpa_report_dfs(
     let rank = extract_string_from_offset(&taxonomy.rank_data, node.rank_offset as usize);
 
     let rank_code = match rank {
-        "superkingdom" => 'd',
+        "superkingdom" | "domain" => 'd',
         "kingdom" => 'k',
         "phylum" => 'p',
         "class" => 'c',
@@ -219,35 +220,53 @@ pub fn report_mpa_style<P: AsRef<Path>>(
     )
 }
 
+/// What a Kraken-style report is written from and what it shows
+pub struct ReportOptions<'a> {
+    /// Whether to report zero counts
+    pub report_zeros: bool,
+    /// Whether to report k-mer data
+    pub report_kmer_data: bool,
+    /// The taxonomy structure
+    pub taxonomy: &'a Taxonomy,
+    /// A HashMap of taxon IDs to their ReadCounters
+    pub call_counters: &'a HashMap<u64, ReadCounter>,
+    /// The total number of sequences
+    pub total_seqs: u64,
+}
+
+/// A taxon as printed on a line of a Kraken-style report
+pub struct ReportLine<'a> {
+    /// The rank string
+    pub rank: &'a str,
+    /// The taxon ID
+    pub taxid: u32,
+    /// The scientific name
+    pub name: &'a str,
+    /// The depth in the taxonomy tree
+    pub depth: usize,
+}
+
 /// Prints a line in Kraken-style report format
 ///
 /// # Arguments
 ///
 /// * `file` - The file to write to
-/// * `report_kmer_data` - Whether to report k-mer data
-/// * `total_seqs` - The total number of sequences
+/// * `options` - The report settings and total number of sequences
 /// * `clade_counter` - The ReadCounter for the clade
 /// * `taxon_counter` - The ReadCounter for the taxon
-/// * `rank_str` - The rank string
-/// * `taxid` - The taxon ID
-/// * `sci_name` - The scientific name
-/// * `depth` - The depth in the taxonomy tree
+/// * `line` - The taxon of the line
 ///
 /// # Returns
 ///
 /// An io::Result indicating success or failure of the write operation
-pub fn print_kraken_style_report_line(
-    file: &mut File,
-    report_kmer_data: bool,
-    total_seqs: u64,
+pub fn print_kraken_style_report_line<W: Write>(
+    file: &mut W,
+    options: &ReportOptions,
     clade_counter: &mut ReadCounter,
     taxon_counter: &ReadCounter,
-    rank_str: &str,
-    taxid: u32,
-    sci_name: &str,
-    depth: usize,
+    line: &ReportLine,
 ) -> io::Result<()> {
-    let pct = 100.0 * clade_counter.read_count() as f64 / total_seqs as f64;
+    let pct = 100.0 * clade_counter.read_count() as f64 / options.total_seqs as f64;
     let pct_str = format!("{:6.2}", pct);
 
     write!(
@@ -258,7 +277,7 @@ pub fn print_kraken_style_report_line(
         taxon_counter.read_count()
     )?;
 
-    if report_kmer_data {
+    if options.report_kmer_data {
         write!(
             file,
             "\t{}\t{}",
@@ -267,13 +286,13 @@ pub fn print_kraken_style_report_line(
         )?;
     }
 
-    write!(file, "\t{}\t{}\t", rank_str, taxid)?;
+    write!(file, "\t{}\t{}\t", line.rank, line.taxid)?;
 
-    for _ in 0..depth {
+    for _ in 0..line.depth {
         write!(file, "  ")?;
     }
 
-    writeln!(file, "{}", sci_name)
+    writeln!(file, "{}", line.name)
 }
 
 /// Performs a depth-first search to generate a Kraken-style report
@@ -282,12 +301,8 @@ pub fn print_kraken_style_report_line(
 ///
 /// * `taxid` - The current taxon ID
 /// * `file` - The file to write the report to
-/// * `report_zeros` - Whether to report zero counts
-/// * `report_kmer_data` - Whether to report k-mer data
-/// * `taxonomy` - The taxonomy structure
+/// * `options` - The report settings, taxonomy, call counts and total number of sequences
 /// * `clade_counters` - A mutable reference to TaxonCounters for clade counts
-/// * `call_counters` - A reference to TaxonCounters for call counts
-/// * `total_seqs` - The total number of sequences
 /// * `rank_code` - The current rank code
 /// * `rank_depth` - The current rank depth
 /// * `depth` - The current depth in the taxonomy tree
@@ -295,20 +310,17 @@ pub fn print_kraken_style_report_line(
 /// # Returns
 ///
 /// An io::Result indicating success or failure of the operation
-pub fn kraken_report_dfs(
+pub fn kraken_report_dfs<W: Write>(
     taxid: u64,
-    file: &mut File,
-    report_zeros: bool,
-    report_kmer_data: bool,
-    taxonomy: &Taxonomy,
+    file: &mut W,
+    options: &ReportOptions,
     clade_counters: &mut HashMap<u64, ReadCounter>,
-    call_counters: &HashMap<u64, ReadCounter>,
-    total_seqs: u64,
     rank_code: char,
     rank_depth: i32,
     depth: usize,
 ) -> io::Result<()> {
-    if !report_zeros && clade_counters.get(&taxid).map_or(0, |c| c.read_count()) == 0 {
+    let taxonomy = options.taxonomy;
+    if !options.report_zeros && clade_counters.get(&taxid).map_or(0, |c| c.read_count()) == 0 {
         return Ok(());
     }
 
@@ -320,7 +332,7 @@ pub fn kraken_report_dfs(
         .unwrap_or("");
 
     let (new_rank_code, new_rank_depth) = match rank {
-        "superkingdom" => ('D', 0),
+        "superkingdom" | "domain" => ('D', 0),
         "kingdom" => ('K', 0),
         "phylum" => ('P', 0),
         "class" => ('C', 0),
@@ -349,14 +361,18 @@ pub fn kraken_report_dfs(
 
     print_kraken_style_report_line(
         file,
-        report_kmer_data,
-        total_seqs,
+        options,
         &mut clade_counter,
-        call_counters.get(&taxid).unwrap_or(&ReadCounter::default()),
-        &rank_str,
-        node.external_id as u32,
-        name,
-        depth,
+        options
+            .call_counters
+            .get(&taxid)
+            .unwrap_or(&ReadCounter::default()),
+        &ReportLine {
+            rank: &rank_str,
+            taxid: node.external_id as u32,
+            name,
+            depth,
+        },
     )?;
 
     let mut children: Vec<u64> = (0..node.child_count)
@@ -374,12 +390,8 @@ pub fn kraken_report_dfs(
         kraken_report_dfs(
             child_taxid,
             file,
-            report_zeros,
-            report_kmer_data,
-            taxonomy,
+            options,
             clade_counters,
-            call_counters,
-            total_seqs,
             new_rank_code,
             new_rank_depth,
             depth + 1,
@@ -413,39 +425,401 @@ pub fn report_kraken_style<P: AsRef<Path>>(
     total_seqs: u64,
     total_unclassified: u64,
 ) -> io::Result<()> {
-    let mut clade_counters = get_clade_counters(taxonomy, call_counters);
+    let mut file = create_output(filename)?;
+    write_kraken_style(
+        &mut file,
+        report_zeros,
+        report_kmer_data,
+        taxonomy,
+        call_counters,
+        total_seqs,
+        total_unclassified,
+    )
+}
 
-    let mut file = File::create(filename)?;
+/// Writes a Kraken-style report to a writer
+///
+/// # Arguments
+///
+/// * `file` - The writer of the report
+/// * `report_zeros` - Whether to report zero counts
+/// * `report_kmer_data` - Whether to report k-mer data
+/// * `taxonomy` - The taxonomy structure
+/// * `call_counters` - A HashMap of taxon IDs to their ReadCounters
+/// * `total_seqs` - The total number of sequences
+/// * `total_unclassified` - The total number of unclassified sequences
+///
+/// # Returns
+///
+/// An io::Result indicating success or failure of the operation
+pub fn write_kraken_style<W: Write>(
+    file: &mut W,
+    report_zeros: bool,
+    report_kmer_data: bool,
+    taxonomy: &Taxonomy,
+    call_counters: &HashMap<u64, ReadCounter>,
+    total_seqs: u64,
+    total_unclassified: u64,
+) -> io::Result<()> {
+    let mut clade_counters = get_clade_counters(taxonomy, call_counters);
+    let options = ReportOptions {
+        report_zeros,
+        report_kmer_data,
+        taxonomy,
+        call_counters,
+        total_seqs,
+    };
 
     // Handle the special case for unclassified sequences
     if total_unclassified != 0 || report_zeros {
         let mut rc = ReadCounter::new(total_unclassified, 0);
         let trc = ReadCounter::new(total_unclassified, 0);
-        print_kraken_style_report_line(
-            &mut file,
-            report_kmer_data,
-            total_seqs,
-            &mut rc,
-            &trc,
-            "U",
-            0,
-            "unclassified",
-            0,
-        )?;
+        let line = ReportLine {
+            rank: "U",
+            taxid: 0,
+            name: "unclassified",
+            depth: 0,
+        };
+        print_kraken_style_report_line(file, &options, &mut rc, &trc, &line)?;
     }
 
     // Traverse the taxonomy tree using DFS
-    kraken_report_dfs(
-        1,
-        &mut file,
-        report_zeros,
-        report_kmer_data,
-        taxonomy,
-        &mut clade_counters,
-        call_counters,
-        total_seqs,
-        'R',
-        -1,
-        0,
-    )
+    kraken_report_dfs(1, file, &options, &mut clade_counters, 'R', -1, 0)
+}
+
+/// Reads the expected read counts of the pseudo-taxa defined for a database
+///
+/// # Arguments
+///
+/// * `database` - The database directory
+///
+/// # Returns
+///
+/// An io::Result containing a map from external pseudo-taxids to expected reads,
+/// empty when the database has no pseudo-taxa
+pub fn read_pseudo_taxa_expectations<P: AsRef<Path>>(database: P) -> io::Result<HashMap<u64, u64>> {
+    let filename = database.as_ref().join(PSEUDO_TAXA_FILENAME);
+    if !filename.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(read_pseudo_taxa(filename)?
+        .into_iter()
+        .filter_map(|taxon| taxon.expected_reads.map(|reads| (taxon.taxid, reads)))
+        .collect())
+}
+
+/// Generates a recovery report for the user-defined pseudo-taxa, e.g. spike-in controls
+///
+/// No file is written when the taxonomy has no pseudo-taxa.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file to write the report to
+/// * `taxonomy` - The taxonomy structure
+/// * `call_counters` - A HashMap of taxon IDs to their ReadCounters
+/// * `total_seqs` - The total number of sequences
+/// * `expected_reads` - Expected reads per external pseudo-taxid
+///
+/// # Returns
+///
+/// An io::Result indicating success or failure of the operation
+pub fn report_pseudo_taxa<P: AsRef<Path>>(
+    filename: P,
+    taxonomy: &Taxonomy,
+    call_counters: &HashMap<u64, ReadCounter>,
+    total_seqs: u64,
+    expected_reads: &HashMap<u64, u64>,
+) -> io::Result<()> {
+    let pseudo_taxa: Vec<u32> = (1..taxonomy.node_count() as u32)
+        .filter(|&internal_id| taxonomy.is_pseudo_taxon(internal_id))
+        .collect();
+    if pseudo_taxa.is_empty() {
+        return Ok(());
+    }
+
+    let clade_counters = get_clade_counters(taxonomy, call_counters);
+    let mut file = create_output(filename)?;
+    writeln!(
+        file,
+        "taxid\tname\treads\tfraction\texpected_reads\trecovery"
+    )?;
+    for internal_id in pseudo_taxa {
+        let external_id = taxonomy.nodes[internal_id as usize].external_id;
+        let reads = clade_counters
+            .get(&(internal_id as u64))
+            .map_or(0, |counter| counter.read_count());
+        let fraction = if total_seqs > 0 {
+            reads as f64 / total_seqs as f64
+        } else {
+            0.0
+        };
+        let (expected, recovery) = match expected_reads.get(&external_id) {
+            Some(&expected) if expected > 0 => (
+                expected.to_string(),
+                format!("{:.4}", reads as f64 / expected as f64),
+            ),
+            _ => ("-".to_string(), "-".to_string()),
+        };
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{:.6}\t{}\t{}",
+            external_id,
+            taxonomy.name_of(internal_id),
+            reads,
+            fraction,
+            expected,
+            recovery
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A single line of a Kraken-style report
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    /// Reads assigned to the clade rooted at this taxon
+    pub clade_reads: u64,
+    /// Reads assigned directly to this taxon
+    pub taxon_reads: u64,
+    pub rank: String,
+    /// External taxon ID, 0 for the unclassified line
+    pub taxid: u64,
+    pub name: String,
+}
+
+/// Reads a Kraken-style report, with or without minimizer columns
+///
+/// # Arguments
+///
+/// * `filename` - The path to the report file
+///
+/// # Returns
+///
+/// An io::Result containing the report entries in file order
+pub fn read_kraken_report<P: AsRef<Path>>(filename: P) -> io::Result<Vec<ReportEntry>> {
+    let content = std::fs::read_to_string(filename)?;
+    let mut entries = Vec::new();
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        // pct, clade, taxon, [minimizers, distinct,] rank, taxid, name
+        let offset = match fields.len() {
+            6 => 0,
+            8 => 2,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed report line: {}", line),
+                ))
+            }
+        };
+        let parse = |s: &str| {
+            s.trim().parse::<u64>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed report line: {}", line),
+                )
+            })
+        };
+        entries.push(ReportEntry {
+            clade_reads: parse(fields[1])?,
+            taxon_reads: parse(fields[2])?,
+            rank: fields[3 + offset].to_string(),
+            taxid: parse(fields[4 + offset])?,
+            name: fields[5 + offset].trim().to_string(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Name of the summary of a run with per-sample output directories
+pub const SAMPLE_SUMMARY_FILENAME: &str = "summary.tsv";
+
+/// Name of the report of all samples of a run with per-sample output directories
+pub const COMBINED_REPORT_FILENAME: &str = "combined.kreport2";
+
+/// Removes the first matching suffix, ignoring case
+fn strip_suffix_ignore_case<'a>(name: &'a str, suffixes: &[&str]) -> &'a str {
+    for suffix in suffixes {
+        if name.len() > suffix.len() && name.to_lowercase().ends_with(suffix) {
+            return &name[..name.len() - suffix.len()];
+        }
+    }
+    name
+}
+
+/// Names the per-sample output directories after the input files
+///
+/// The name is the file name of the first input without its compression and FASTA/FASTQ
+/// extensions and, for read pairs, without the read suffix (`_R1`, `_1`, ...). Characters
+/// other than letters, digits, `.`, `-` and `_` become `_`, and a name that is already taken
+/// gets the sample index appended.
+///
+/// # Arguments
+///
+/// * `samples` - The input files of every sample, comma separated as in `sample_file.map`
+///
+/// # Returns
+///
+/// The directory name of every sample
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report::sample_dir_names;
+/// use std::collections::BTreeMap;
+///
+/// let samples = BTreeMap::from([
+///     (1, "/data/S1_R1.fastq.gz,/data/S1_R2.fastq.gz".to_string()),
+///     (2, "run1/S2.fa".to_string()),
+///     (3, "run2/S2.fa".to_string()),
+/// ]);
+/// let names = sample_dir_names(&samples);
+/// assert_eq!(names[&1], "S1");
+/// assert_eq!(names[&2], "S2");
+/// assert_eq!(names[&3], "S2_3");
+/// ```
+pub fn sample_dir_names(samples: &BTreeMap<usize, String>) -> HashMap<usize, String> {
+    let mut taken = HashSet::new();
+    let mut names = HashMap::new();
+    for (index, files) in samples {
+        let first = files.split(',').next().unwrap_or_default();
+        let file_name = Path::new(first)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut name = strip_suffix_ignore_case(&file_name, &[".gz", ".bz2", ".zst"]);
+        name = strip_suffix_ignore_case(name, &[".fastq", ".fq", ".fasta", ".fa", ".fna", ".fas"]);
+        if files.contains(',') {
+            name = strip_suffix_ignore_case(name, &["_r1_001", "_r1", ".r1", "_1", ".1"]);
+        }
+        let mut name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if name.is_empty() || name.starts_with('.') {
+            name = format!("sample_{}", index);
+        }
+        if !taken.insert(name.clone()) {
+            name = format!("{}_{}", name, index);
+            taken.insert(name.clone());
+        }
+        names.insert(*index, name);
+    }
+    names
+}
+
+/// The output files of one sample, named `output_{i}.*` in the output directory or kept in a
+/// directory of their own (`<sample>/output.txt`, `<sample>/report.kreport2`, ...)
+pub struct SampleOutputs {
+    dir: PathBuf,
+    index: Option<usize>,
+}
+
+impl SampleOutputs {
+    /// Creates the outputs of sample `index`, in the directory `name` of `output_dir` if given
+    pub fn new(output_dir: &Path, index: usize, name: Option<&str>) -> Self {
+        match name {
+            Some(name) => Self {
+                dir: output_dir.join(name),
+                index: None,
+            },
+            None => Self {
+                dir: output_dir.to_path_buf(),
+                index: Some(index),
+            },
+        }
+    }
+
+    /// Creates the directory of the outputs, object stores need none
+    pub fn create_dir(&self) -> io::Result<()> {
+        if is_remote(&self.dir) {
+            return Ok(());
+        }
+        create_dir_all(&self.dir)
+    }
+
+    fn file(&self, flat_prefix: &str, own_name: &str, extension: &str) -> PathBuf {
+        match self.index {
+            Some(index) => self
+                .dir
+                .join(format!("{}_{}.{}", flat_prefix, index, extension)),
+            None => self.dir.join(format!("{}.{}", own_name, extension)),
+        }
+    }
+
+    /// The Kraken output of the reads
+    pub fn output(&self) -> PathBuf {
+        self.file("output", "output", "txt")
+    }
+
+    /// The Kraken-style report
+    pub fn report(&self) -> PathBuf {
+        self.file("output", "report", "kreport2")
+    }
+
+    /// The recovery report of the pseudo-taxa
+    pub fn spikein(&self) -> PathBuf {
+        self.file("output", "report", "spikein.tsv")
+    }
+
+    /// The reads detected as chimeras
+    pub fn chimera(&self) -> PathBuf {
+        self.file("chimera", "chimera", "txt")
+    }
+
+    /// All output files of the sample that exist
+    pub fn files(&self) -> Vec<PathBuf> {
+        [self.output(), self.report(), self.spikein(), self.chimera()]
+            .into_iter()
+            .filter(|file| file.is_file())
+            .collect()
+    }
+}
+
+/// Read counts of one sample, a row of the run summary
+pub struct SampleSummary {
+    pub name: String,
+    pub files: String,
+    pub reads: u64,
+    pub unclassified: u64,
+}
+
+/// Writes the summary of a run with per-sample output directories, one line per sample
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file to write the summary to
+/// * `samples` - The read counts of every sample
+pub fn write_sample_summary<P: AsRef<Path>>(
+    filename: P,
+    samples: &[SampleSummary],
+) -> io::Result<()> {
+    let mut file = create_output(filename)?;
+    writeln!(
+        file,
+        "sample\tfiles\treads\tclassified\tunclassified\tclassified_pct"
+    )?;
+    for sample in samples {
+        let classified = sample.reads - sample.unclassified;
+        let pct = if sample.reads > 0 {
+            100.0 * classified as f64 / sample.reads as f64
+        } else {
+            0.0
+        };
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{:.2}",
+            sample.name, sample.files, sample.reads, classified, sample.unclassified, pct
+        )?;
+    }
+    Ok(())
 }
@@ -0,0 +1,189 @@
+//! The summary printed at the end of `classify`
+use crate::classifier::ClassifySummary;
+use crate::progress::format_duration;
+use crate::report::get_clade_counts;
+use crate::taxonomy::Taxonomy;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::time::Duration;
+
+/// Number of taxa listed by the summary
+pub const TOP_TAXA: usize = 10;
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// A taxon of the summary with its reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopTaxon {
+    /// External taxon ID
+    pub taxid: u64,
+    pub name: String,
+    /// Reads of the clade rooted at the taxon
+    pub reads: u64,
+}
+
+/// Read counts, the most abundant taxa and the wall time of each stage of a run
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    /// The number of reads
+    pub total_seqs: u64,
+    /// The number of unclassified reads
+    pub total_unclassified: u64,
+    /// The species with the most reads, most abundant first
+    pub top_taxa: Vec<TopTaxon>,
+    /// The stages of the run and their wall time, in run order
+    pub stages: Vec<(String, Duration)>,
+}
+
+impl RunSummary {
+    /// Summarizes the read counts of a classification
+    ///
+    /// The top taxa are the species with the most reads in their clade. Without any read
+    /// at species rank, e.g. with a custom taxonomy, they are the taxa with the most reads
+    /// assigned directly.
+    pub fn new(summary: &ClassifySummary, taxonomy: &Taxonomy) -> Self {
+        let call_counts: HashMap<u64, u64> = summary
+            .taxon_counts
+            .iter()
+            .map(|(&taxid, counter)| (taxid, counter.read_count()))
+            .collect();
+        let clade_counts = get_clade_counts(taxonomy, &call_counts);
+        let mut ranked: Vec<(u64, u64)> = clade_counts
+            .into_iter()
+            .filter(|&(taxid, _)| taxonomy.rank_of(taxid as u32) == "species")
+            .collect();
+        if ranked.is_empty() {
+            ranked = call_counts.into_iter().collect();
+        }
+        // 读数相同时按 taxid 排序, 输出稳定
+        ranked.retain(|&(_, reads)| reads > 0);
+        ranked.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let top_taxa = ranked
+            .into_iter()
+            .take(TOP_TAXA)
+            .map(|(taxid, reads)| TopTaxon {
+                taxid: taxonomy.nodes[taxid as usize].external_id,
+                name: taxonomy.name_of(taxid as u32).to_string(),
+                reads,
+            })
+            .collect();
+
+        Self {
+            total_seqs: summary.total_seqs,
+            total_unclassified: summary.total_unclassified,
+            top_taxa,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Records the wall time of a stage
+    pub fn add_stage(&mut self, stage: &str, duration: Duration) {
+        self.stages.push((stage.to_string(), duration));
+    }
+
+    /// Formats the summary, with ANSI colors if `color` is set
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::summary::{RunSummary, TopTaxon};
+    /// use std::time::Duration;
+    ///
+    /// let mut summary = RunSummary {
+    ///     total_seqs: 1000,
+    ///     total_unclassified: 250,
+    ///     top_taxa: vec![TopTaxon { taxid: 562, name: "Escherichia coli".to_string(), reads: 600 }],
+    ///     stages: Vec::new(),
+    /// };
+    /// summary.add_stage("splitr", Duration::from_secs(62));
+    /// summary.add_stage("resolve", Duration::from_secs(5));
+    /// assert_eq!(
+    ///     summary.format(false),
+    ///     "Classification summary
+    ///   reads         1000
+    ///   classified    750 (75.00%)
+    ///   unclassified  250 (25.00%)
+    /// Top taxa
+    ///    1. Escherichia coli (562)  600 (60.00%)
+    /// Wall time
+    ///   splitr        0:01:02
+    ///   resolve       0:00:05
+    ///   total         0:01:07
+    /// "
+    /// );
+    /// ```
+    pub fn format(&self, color: bool) -> String {
+        let paint = |code: &str, text: String| {
+            if color {
+                format!("{}{}{}", code, text, RESET)
+            } else {
+                text
+            }
+        };
+        let percent = |reads: u64| 100.0 * reads as f64 / self.total_seqs.max(1) as f64;
+        let classified = self.total_seqs - self.total_unclassified;
+
+        let mut text = paint(BOLD, "Classification summary".to_string()) + "\n";
+        text += &format!("  {:<12}  {}\n", "reads", self.total_seqs);
+        text += &format!(
+            "  {:<12}  {}\n",
+            "classified",
+            paint(
+                GREEN,
+                format!("{} ({:.2}%)", classified, percent(classified))
+            )
+        );
+        text += &format!(
+            "  {:<12}  {}\n",
+            "unclassified",
+            paint(
+                YELLOW,
+                format!(
+                    "{} ({:.2}%)",
+                    self.total_unclassified,
+                    percent(self.total_unclassified)
+                )
+            )
+        );
+
+        if !self.top_taxa.is_empty() {
+            text += &(paint(BOLD, "Top taxa".to_string()) + "\n");
+            let labels: Vec<String> = self
+                .top_taxa
+                .iter()
+                .map(|taxon| format!("{} ({})", taxon.name, taxon.taxid))
+                .collect();
+            let width = labels.iter().map(String::len).max().unwrap_or(0);
+            for (i, (taxon, label)) in self.top_taxa.iter().zip(&labels).enumerate() {
+                text += &format!(
+                    "  {:>2}. {:<width$}  {} ({:.2}%)\n",
+                    i + 1,
+                    label,
+                    taxon.reads,
+                    percent(taxon.reads),
+                    width = width
+                );
+            }
+        }
+
+        if !self.stages.is_empty() {
+            text += &(paint(BOLD, "Wall time".to_string()) + "\n");
+            for (stage, duration) in &self.stages {
+                text += &format!("  {:<12}  {}\n", stage, format_duration(*duration));
+            }
+            let total: Duration = self.stages.iter().map(|(_, duration)| *duration).sum();
+            text += &format!("  {:<12}  {}\n", "total", format_duration(total));
+        }
+        text
+    }
+
+    /// Prints the summary to stderr, colored on a terminal unless `NO_COLOR` is set
+    pub fn print(&self) {
+        let stderr = std::io::stderr();
+        let color = stderr.is_terminal() && std::env::var_os("NO_COLOR").is_none();
+        eprint!("{}", self.format(color));
+    }
+}
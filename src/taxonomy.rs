@@ -1,10 +1,109 @@
+use crate::format::{check_format_version, TAXONOMY_FORMAT_VERSION, TAXONOMY_TRAILER_MAGIC};
 use crate::utils::open_file;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 use std::path::Path;
 
+/// External IDs at or above this value are reserved for user-defined pseudo-taxa
+pub const PSEUDO_TAXID_START: u64 = 2_000_000_000;
+
+/// Rank assigned to user-defined pseudo-taxa
+pub const PSEUDO_TAXON_RANK: &str = "spike-in";
+
+/// Name of the pseudo-taxa definition file in the taxonomy and database directories
+pub const PSEUDO_TAXA_FILENAME: &str = "spikein.tsv";
+
+/// Name of the genome accession to taxid map written for GTDB taxonomies
+pub const GTDB_ACCESSION_MAP_FILENAME: &str = "gtdb.accession2taxid";
+
+/// Name of the report of the seqid2taxid taxids that were merged, deleted or unknown
+pub const TAXID_REMAP_FILENAME: &str = "taxid_remap.tsv";
+
+/// A user-defined taxon outside the NCBI ID range, e.g. a synthetic spike-in control
+#[derive(Debug, Clone)]
+pub struct PseudoTaxon {
+    pub taxid: u64,
+    pub name: String,
+    /// External ID of the parent node, the root by default
+    pub parent: u64,
+    /// Number of reads expected in a sample, used to report recovery rates
+    pub expected_reads: Option<u64>,
+}
+
+/// Parse a pseudo-taxa definition file
+///
+/// Each non-comment line holds tab separated `taxid`, `name` and the optional
+/// `parent_taxid` and `expected_reads` columns.
+///
+/// # Arguments
+///
+/// * `filename` - Path to the definition file
+///
+/// # Returns
+///
+/// A Result containing the pseudo-taxa in file order
+pub fn read_pseudo_taxa<P: AsRef<Path>>(filename: P) -> Result<Vec<PseudoTaxon>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut taxa = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(|field| field.trim()).collect();
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid pseudo-taxon line: {}", line),
+            )
+        };
+        if fields.len() < 2 {
+            return Err(invalid());
+        }
+        let taxid = fields[0].parse::<u64>().map_err(|_| invalid())?;
+        if taxid < PSEUDO_TAXID_START {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "pseudo-taxid {} must not be lower than {}",
+                    taxid, PSEUDO_TAXID_START
+                ),
+            ));
+        }
+        let parent = match fields.get(2).filter(|field| !field.is_empty()) {
+            Some(field) => field.parse::<u64>().map_err(|_| invalid())?,
+            None => 1,
+        };
+        let expected_reads = match fields.get(3).filter(|field| !field.is_empty()) {
+            Some(field) => Some(field.parse::<u64>().map_err(|_| invalid())?),
+            None => None,
+        };
+        taxa.push(PseudoTaxon {
+            taxid,
+            name: fields[1].to_string(),
+            parent,
+            expected_reads,
+        });
+    }
+
+    Ok(taxa)
+}
+
+/// Read a NUL-terminated string from the name or rank data block
+fn str_at_offset(data: &[u8], offset: usize) -> &str {
+    if offset >= data.len() {
+        return "";
+    }
+    let end = data[offset..]
+        .iter()
+        .position(|&c| c == b'\0')
+        .map_or(data.len(), |pos| pos + offset);
+    std::str::from_utf8(&data[offset..end]).unwrap_or("")
+}
+
 /// Parse the NCBI taxonomy nodes file
 ///
 /// # Arguments
@@ -112,6 +211,277 @@ pub fn parse_names_file<P: AsRef<Path>>(names_filename: P) -> Result<HashMap<u64
     Ok(name_map)
 }
 
+/// Parse the NCBI taxonomy merged.dmp file
+///
+/// # Arguments
+///
+/// * `merged_filename` - Path to the merged file
+///
+/// # Returns
+///
+/// A HashMap of the old taxid to the taxid it was merged into
+pub fn parse_merged_file<P: AsRef<Path>>(merged_filename: P) -> Result<HashMap<u64, u64>> {
+    let reader = BufReader::new(open_file(merged_filename)?);
+    let mut merged = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split('|').map(str::trim).collect();
+        if fields.len() < 2 {
+            continue;
+        }
+        let (Ok(old_id), Ok(new_id)) = (fields[0].parse::<u64>(), fields[1].parse::<u64>()) else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid merged.dmp line: {}", line),
+            ));
+        };
+        merged.insert(old_id, new_id);
+    }
+
+    Ok(merged)
+}
+
+/// Parse the NCBI taxonomy delnodes.dmp file
+///
+/// # Arguments
+///
+/// * `delnodes_filename` - Path to the delnodes file
+///
+/// # Returns
+///
+/// A HashSet of the deleted taxids
+pub fn parse_delnodes_file<P: AsRef<Path>>(delnodes_filename: P) -> Result<HashSet<u64>> {
+    let reader = BufReader::new(open_file(delnodes_filename)?);
+    let mut deleted = HashSet::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let field = line.split('|').next().unwrap_or("").trim();
+        let taxid = field.parse::<u64>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid delnodes.dmp line: {}", line),
+            )
+        })?;
+        deleted.insert(taxid);
+    }
+
+    Ok(deleted)
+}
+
+/// The taxids NCBI has merged into others or deleted since a seqid2taxid map was written
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::taxonomy::TaxidChanges;
+/// use std::collections::HashMap;
+///
+/// let changes = TaxidChanges {
+///     merged: HashMap::from([(10, 11), (11, 12)]),
+///     deleted: [20].into_iter().collect(),
+/// };
+/// let mut id_map = HashMap::from([
+///     ("a".to_string(), 10),
+///     ("b".to_string(), 12),
+///     ("c".to_string(), 20),
+///     ("d".to_string(), 30),
+/// ]);
+/// // 当前 taxonomy 中只有 12
+/// let remap = changes.remap(&mut id_map, |taxid| taxid == 12);
+/// assert_eq!(id_map, HashMap::from([("a".to_string(), 12), ("b".to_string(), 12)]));
+/// assert_eq!(remap.merged[&10], (12, 1));
+/// assert_eq!(remap.deleted[&20], 1);
+/// assert_eq!(remap.unknown[&30], 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct TaxidChanges {
+    /// Old taxid to the taxid it was merged into
+    pub merged: HashMap<u64, u64>,
+    pub deleted: HashSet<u64>,
+}
+
+impl TaxidChanges {
+    /// Read merged.dmp and delnodes.dmp of a taxonomy directory
+    ///
+    /// Missing files, e.g. of GTDB taxonomies, leave the changes empty.
+    pub fn from_directory(taxonomy_directory: &Path) -> Result<Self> {
+        let merged_filename = taxonomy_directory.join("merged.dmp");
+        let delnodes_filename = taxonomy_directory.join("delnodes.dmp");
+        let merged = if merged_filename.exists() {
+            parse_merged_file(merged_filename)?
+        } else {
+            HashMap::new()
+        };
+        let deleted = if delnodes_filename.exists() {
+            parse_delnodes_file(delnodes_filename)?
+        } else {
+            HashSet::new()
+        };
+        Ok(Self { merged, deleted })
+    }
+
+    /// The taxid a merged taxid lives on as, following merges of merged taxa
+    fn current_taxid(&self, taxid: u64, known: &impl Fn(u64) -> bool) -> Option<u64> {
+        let mut current = taxid;
+        // merged.dmp 通常已指向最终 taxid, 限制步数以防循环
+        for _ in 0..16 {
+            current = *self.merged.get(&current)?;
+            if known(current) {
+                return Some(current);
+            }
+        }
+        None
+    }
+
+    /// Remap the taxids of a seqid2taxid map that are not in the taxonomy
+    ///
+    /// Merged taxids are replaced by the taxids they were merged into; sequences of deleted
+    /// or unknown taxids are removed from the map, as they cannot be placed in the tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `id_map` - The sequence id to taxid map
+    /// * `known` - Whether a taxid is in the taxonomy
+    ///
+    /// # Returns
+    ///
+    /// What was remapped and removed
+    pub fn remap(
+        &self,
+        id_map: &mut HashMap<String, u64>,
+        known: impl Fn(u64) -> bool,
+    ) -> TaxidRemap {
+        let mut remap = TaxidRemap::default();
+        id_map.retain(|_, taxid| {
+            if known(*taxid) {
+                return true;
+            }
+            if let Some(current) = self.current_taxid(*taxid, &known) {
+                remap.merged.entry(*taxid).or_insert((current, 0)).1 += 1;
+                *taxid = current;
+                true
+            } else if self.deleted.contains(taxid) {
+                *remap.deleted.entry(*taxid).or_insert(0) += 1;
+                false
+            } else {
+                *remap.unknown.entry(*taxid).or_insert(0) += 1;
+                false
+            }
+        });
+        remap
+    }
+}
+
+/// The taxids of a seqid2taxid map that were not in the taxonomy, with their sequence counts
+#[derive(Debug, Default)]
+pub struct TaxidRemap {
+    /// Merged taxid to the taxid it was remapped to and the number of sequences
+    pub merged: BTreeMap<u64, (u64, usize)>,
+    /// Deleted taxid to the number of removed sequences
+    pub deleted: BTreeMap<u64, usize>,
+    /// Taxid neither in the taxonomy nor in merged.dmp or delnodes.dmp to the number of removed sequences
+    pub unknown: BTreeMap<u64, usize>,
+}
+
+impl TaxidRemap {
+    pub fn is_empty(&self) -> bool {
+        self.merged.is_empty() && self.deleted.is_empty() && self.unknown.is_empty()
+    }
+
+    /// Log a summary of the remapped and removed sequences
+    pub fn log_summary(&self) {
+        if !self.merged.is_empty() {
+            log::info!(
+                "{} merged taxids of {} sequences remapped to their current taxids",
+                self.merged.len(),
+                self.merged.values().map(|&(_, count)| count).sum::<usize>()
+            );
+        }
+        if !self.deleted.is_empty() {
+            log::warn!(
+                "{} deleted taxids, their {} sequences are skipped",
+                self.deleted.len(),
+                self.deleted.values().sum::<usize>()
+            );
+        }
+        if !self.unknown.is_empty() {
+            log::warn!(
+                "{} taxids not in the taxonomy, their {} sequences are skipped",
+                self.unknown.len(),
+                self.unknown.values().sum::<usize>()
+            );
+        }
+    }
+
+    /// Write the report as TSV with the columns `taxid`, `current_taxid` (0 if removed),
+    /// `status` (merged, deleted or unknown) and `sequences`
+    pub fn write_to_file<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
+        let mut writer = std::io::BufWriter::new(File::create(filename)?);
+        writeln!(writer, "taxid\tcurrent_taxid\tstatus\tsequences")?;
+        for (taxid, (current, count)) in &self.merged {
+            writeln!(writer, "{}\t{}\tmerged\t{}", taxid, current, count)?;
+        }
+        for (taxid, count) in &self.deleted {
+            writeln!(writer, "{}\t0\tdeleted\t{}", taxid, count)?;
+        }
+        for (taxid, count) in &self.unknown {
+            writeln!(writer, "{}\t0\tunknown\t{}", taxid, count)?;
+        }
+        writer.flush()
+    }
+}
+
+/// GTDB rank prefixes and the ranks they stand for
+const GTDB_RANKS: [(&str, &str); 7] = [
+    ("d__", "domain"),
+    ("p__", "phylum"),
+    ("c__", "class"),
+    ("o__", "order"),
+    ("f__", "family"),
+    ("g__", "genus"),
+    ("s__", "species"),
+];
+
+/// Get the rank of a prefixed GTDB name such as `g__Escherichia`
+fn gtdb_rank(name: &str) -> Option<&'static str> {
+    GTDB_RANKS
+        .iter()
+        .find(|(prefix, _)| name.starts_with(prefix))
+        .map(|&(_, rank)| rank)
+}
+
+/// Check whether a taxonomy directory holds GTDB taxonomy files instead of an NCBI taxdump
+///
+/// # Arguments
+///
+/// * `directory` - The taxonomy directory
+///
+/// # Returns
+///
+/// The GTDB taxonomy files (e.g. bac120_taxonomy.tsv, ar53_taxonomy.tsv), sorted by name
+pub fn find_gtdb_taxonomy_files(directory: &Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut files: Vec<_> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.contains("_taxonomy") && name.ends_with(".tsv"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
 /// Represents a node in the taxonomy
 #[derive(Debug)]
 pub struct TaxonomyNode {
@@ -177,6 +547,119 @@ impl NCBITaxonomy {
         })
     }
 
+    /// Create a new NCBITaxonomy from GTDB taxonomy files
+    ///
+    /// Every line maps a genome accession to a lineage such as
+    /// `d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli`. Taxids are assigned to the
+    /// lineage names in sorted order, so the same files always give the same taxids.
+    ///
+    /// # Arguments
+    ///
+    /// * `filenames` - Paths to the GTDB taxonomy files
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new NCBITaxonomy and a map of genome accession to taxid
+    pub fn from_gtdb<P: AsRef<Path>>(filenames: &[P]) -> Result<(Self, HashMap<String, u64>)> {
+        // prefixed name -> prefixed parent name, the root is the empty name
+        let mut parents: HashMap<String, String> = HashMap::new();
+        let mut genomes = Vec::new();
+
+        for filename in filenames {
+            let reader = BufReader::new(open_file(filename)?);
+            for line in reader.lines() {
+                let line = line?;
+                let Some((accession, lineage)) = line.split_once('\t') else {
+                    continue;
+                };
+                let mut parent = String::new();
+                // empty ranks are written as a bare prefix, e.g. `s__`
+                for name in lineage.trim().split(';').map(str::trim) {
+                    if gtdb_rank(name).is_none() {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid GTDB lineage: {}", line),
+                        ));
+                    }
+                    if name.len() <= 3 {
+                        continue;
+                    }
+                    parents
+                        .entry(name.to_string())
+                        .or_insert_with(|| parent.clone());
+                    parent = name.to_string();
+                }
+                if !parent.is_empty() {
+                    let accession = accession.trim();
+                    let accession = accession
+                        .strip_prefix("RS_")
+                        .or_else(|| accession.strip_prefix("GB_"))
+                        .unwrap_or(accession);
+                    genomes.push((accession.to_string(), parent));
+                }
+            }
+        }
+
+        let mut names: Vec<&String> = parents.keys().collect();
+        names.sort_unstable();
+        let mut ids: HashMap<&str, u64> = HashMap::new();
+        ids.insert("", 1);
+        for (i, name) in names.iter().enumerate() {
+            ids.insert(name.as_str(), i as u64 + 2);
+        }
+
+        let mut parent_map = HashMap::new();
+        let mut name_map = HashMap::new();
+        let mut rank_map = HashMap::new();
+        let mut child_map: HashMap<u64, HashSet<u64>> = HashMap::new();
+        let mut known_ranks = HashSet::new();
+
+        parent_map.insert(1, 0);
+        child_map.entry(0).or_default().insert(1);
+        name_map.insert(1, "root".to_string());
+        rank_map.insert(1, "no rank".to_string());
+        known_ranks.insert("no rank".to_string());
+
+        for (name, parent) in &parents {
+            let id = ids[name.as_str()];
+            let parent_id = ids[parent.as_str()];
+            let rank = gtdb_rank(name).unwrap_or("no rank");
+            parent_map.insert(id, parent_id);
+            child_map.entry(parent_id).or_default().insert(id);
+            name_map.insert(id, name[3..].to_string());
+            rank_map.insert(id, rank.to_string());
+            known_ranks.insert(rank.to_string());
+        }
+
+        let accession_map = genomes
+            .into_iter()
+            .map(|(accession, name)| {
+                let id = ids[name.as_str()];
+                (accession, id)
+            })
+            .collect();
+
+        let mut marked_nodes = HashSet::new();
+        marked_nodes.insert(1);
+
+        Ok((
+            NCBITaxonomy {
+                parent_map,
+                name_map,
+                rank_map,
+                child_map,
+                known_ranks,
+                marked_nodes,
+            },
+            accession_map,
+        ))
+    }
+
+    /// Whether a taxid is a node of the taxonomy
+    pub fn contains(&self, taxid: u64) -> bool {
+        self.parent_map.contains_key(&taxid)
+    }
+
     /// Mark a node and all its ancestors in the taxonomy
     ///
     /// # Arguments
@@ -194,6 +677,43 @@ impl NCBITaxonomy {
         }
     }
 
+    /// Add user-defined pseudo-taxa below their parent nodes
+    ///
+    /// Pseudo-taxa are marked like regular nodes once sequences are assigned to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `taxa` - The pseudo-taxa to add
+    pub fn add_pseudo_taxa(&mut self, taxa: &[PseudoTaxon]) -> Result<()> {
+        for taxon in taxa {
+            if self.parent_map.contains_key(&taxon.taxid) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("pseudo-taxid {} is already defined", taxon.taxid),
+                ));
+            }
+            if !self.parent_map.contains_key(&taxon.parent) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "parent {} of pseudo-taxid {} not found",
+                        taxon.parent, taxon.taxid
+                    ),
+                ));
+            }
+            self.parent_map.insert(taxon.taxid, taxon.parent);
+            self.child_map
+                .entry(taxon.parent)
+                .or_default()
+                .insert(taxon.taxid);
+            self.name_map.insert(taxon.taxid, taxon.name.clone());
+            self.rank_map
+                .insert(taxon.taxid, PSEUDO_TAXON_RANK.to_string());
+            self.known_ranks.insert(PSEUDO_TAXON_RANK.to_string());
+        }
+        Ok(())
+    }
+
     /// Get rank offset data for the taxonomy
     ///
     /// # Returns
@@ -251,7 +771,11 @@ impl NCBITaxonomy {
             node.rank_offset = *rank_offsets.get(&self.rank_map[&external_node_id]).unwrap();
             node.name_offset = name_data.len() as u64;
 
-            let name = self.name_map.get(&external_node_id).unwrap();
+            // names.dmp 可能缺少个别节点的学名
+            let name = self
+                .name_map
+                .get(&external_node_id)
+                .map_or("", String::as_str);
             name_data.push_str(name);
             name_data.push('\0');
 
@@ -313,6 +837,88 @@ impl Taxonomy {
     ///
     /// A Result containing the new Taxonomy or an error
     pub fn from_file<P: AsRef<Path> + Debug>(filename: P) -> Result<Taxonomy> {
+        let path = filename.as_ref();
+        let version = Self::format_version(path)?;
+        check_format_version(path, version, TAXONOMY_FORMAT_VERSION)?;
+        Self::read_layout(filename)
+    }
+
+    /// The format version of a taxonomy file, 0 for files without a version trailer
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::format::TAXONOMY_FORMAT_VERSION;
+    /// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+    ///
+    /// let mut taxo = Taxonomy::default();
+    /// taxo.nodes.push(TaxonomyNode::default());
+    /// taxo.nodes.push(TaxonomyNode { external_id: 1, ..Default::default() });
+    /// taxo.name_data = b"root\0".to_vec();
+    /// taxo.rank_data = b"no rank\0".to_vec();
+    /// let path = std::env::temp_dir().join("kun_peng_taxo_version_doctest.k2d");
+    /// taxo.write_to_disk(&path).unwrap();
+    /// assert_eq!(Taxonomy::format_version(&path).unwrap(), TAXONOMY_FORMAT_VERSION);
+    ///
+    /// // 去掉 trailer 即是 Kraken 2 和旧版本写出的布局
+    /// let len = std::fs::metadata(&path).unwrap().len();
+    /// std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 16).unwrap();
+    /// assert_eq!(Taxonomy::format_version(&path).unwrap(), 0);
+    /// assert_eq!(Taxonomy::from_file(&path).unwrap().name_of(1), "root");
+    /// Taxonomy::upgrade_file(&path).unwrap();
+    /// assert_eq!(Taxonomy::format_version(&path).unwrap(), TAXONOMY_FORMAT_VERSION);
+    /// assert_eq!(Taxonomy::from_file(&path).unwrap().name_of(1), "root");
+    /// std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn format_version<P: AsRef<Path>>(filename: P) -> Result<u64> {
+        let path = filename.as_ref();
+        let mut file = open_file(path)?;
+        let len = file.metadata()?.len();
+        let malformed = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed taxonomy file {:?}", path),
+            )
+        };
+
+        let mut header = [0u8; 32];
+        file.read_exact(&mut header).map_err(|_| malformed())?;
+        if &header[0..8] != Self::MAGIC {
+            return Err(malformed());
+        }
+        let word = |bytes: &[u8], i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let layout_len = 32u64
+            .saturating_add(word(&header, 8).saturating_mul(56))
+            .saturating_add(word(&header, 16))
+            .saturating_add(word(&header, 24));
+        if len == layout_len {
+            return Ok(0);
+        }
+
+        // trailer 总在文件末尾, 新版本的布局可能不同
+        let mut trailer = [0u8; 16];
+        if len < 48 {
+            return Err(malformed());
+        }
+        file.seek(SeekFrom::Start(len - 16))?;
+        file.read_exact(&mut trailer)?;
+        if word(&trailer, 0) != TAXONOMY_TRAILER_MAGIC {
+            return Err(malformed());
+        }
+        Ok(word(&trailer, 8))
+    }
+
+    /// Rewrites a taxonomy file of an older format version in the current format
+    pub fn upgrade_file<P: AsRef<Path>>(filename: P) -> Result<()> {
+        let path = filename.as_ref();
+        let taxo = Self::read_layout(path)?;
+        let tmp_path = path.with_extension("k2d.tmp");
+        taxo.write_to_disk(&tmp_path)?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    /// Reads the nodes, names and ranks of a taxonomy file, whatever its format version
+    fn read_layout<P: AsRef<Path> + Debug>(filename: P) -> Result<Taxonomy> {
         let mut file = open_file(&filename)?;
 
         let mut magic = vec![0; Self::MAGIC.len()];
@@ -433,38 +1039,67 @@ impl Taxonomy {
     }
 
     /// Build the path cache for efficient ancestor lookups
+    ///
+    /// The tree is walked with an explicit stack, so taxonomies with very deep lineages, e.g.
+    /// of heavily strain-expanded custom trees, do not overflow the stack.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::taxonomy::{Taxonomy, TaxonomyNode};
+    ///
+    /// // 一条 2000 层的链, 在 32 KiB 的栈上构建
+    /// let depth = 2000u64;
+    /// let mut taxo = Taxonomy::default();
+    /// taxo.nodes.push(TaxonomyNode::default());
+    /// for id in 1..=depth {
+    ///     taxo.nodes.push(TaxonomyNode {
+    ///         parent_id: id - 1,
+    ///         first_child: id + 1,
+    ///         child_count: (id < depth) as u64,
+    ///         external_id: id,
+    ///         ..Default::default()
+    ///     });
+    /// }
+    /// taxo.generate_external_to_internal_id_map();
+    /// let taxo = std::thread::Builder::new()
+    ///     .stack_size(32 * 1024)
+    ///     .spawn(move || {
+    ///         taxo.build_path_cache();
+    ///         taxo
+    ///     })
+    ///     .unwrap()
+    ///     .join()
+    ///     .unwrap();
+    /// assert_eq!(taxo.path_cache[&(depth as u32)].len(), depth as usize);
+    /// assert!(taxo.is_a_ancestor_of_b(1, depth as u32));
+    /// assert_eq!(taxo.lca(depth as u32, 1500), 1500);
+    /// ```
     pub fn build_path_cache(&mut self) {
         let mut cache: HashMap<u32, Vec<u32>> = HashMap::new();
         let root_external_id = 1u64;
         if let Some(&root_internal_id) = self.external_to_internal_id_map.get(&root_external_id) {
             // Start traversing from the root node
-            self.build_path_for_node(root_internal_id, &mut cache, Vec::new());
+            cache.insert(root_internal_id, vec![root_internal_id]);
+            let mut stack = vec![root_internal_id];
+            while let Some(node_id) = stack.pop() {
+                let node = &self.nodes[node_id as usize];
+                let first_child_id = node.first_child as u32;
+                let child_count = node.child_count as u32;
+                let path = cache[&node_id].clone();
+
+                // Children have consecutive internal IDs
+                for child_internal_id in first_child_id..first_child_id + child_count {
+                    let mut child_path = path.clone();
+                    child_path.push(child_internal_id);
+                    cache.insert(child_internal_id, child_path);
+                    stack.push(child_internal_id);
+                }
+            }
         }
         self.path_cache = cache;
     }
 
-    fn build_path_for_node(
-        &self,
-        node_id: u32,
-        path_cache: &mut HashMap<u32, Vec<u32>>,
-        mut current_path: Vec<u32>,
-    ) {
-        current_path.push(node_id); // Add the current node to the path
-                                    // Store the current node's path
-        path_cache.insert(node_id, current_path.clone());
-
-        // Get the current node's information
-        let node = &self.nodes[node_id as usize];
-        let first_child_id = node.first_child as u32;
-        let child_count = node.child_count as u32;
-
-        // Traverse all children
-        for i in 0..child_count {
-            let child_internal_id = first_child_id + i; // Assume child IDs are consecutive
-            self.build_path_for_node(child_internal_id, path_cache, current_path.clone());
-        }
-    }
-
     /// Get the number of nodes in the taxonomy
     ///
     /// # Returns
@@ -474,6 +1109,58 @@ impl Taxonomy {
         self.nodes.len()
     }
 
+    /// Get the scientific name of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `internal_id` - The internal ID of the node
+    ///
+    /// # Returns
+    ///
+    /// The name of the node, or an empty string if the node does not exist
+    pub fn name_of(&self, internal_id: u32) -> &str {
+        self.nodes
+            .get(internal_id as usize)
+            .map(|node| str_at_offset(&self.name_data, node.name_offset as usize))
+            .unwrap_or("")
+    }
+
+    /// Get the rank of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `internal_id` - The internal ID of the node
+    ///
+    /// # Returns
+    ///
+    /// The rank of the node, or an empty string if the node does not exist
+    pub fn rank_of(&self, internal_id: u32) -> &str {
+        self.nodes
+            .get(internal_id as usize)
+            .map(|node| str_at_offset(&self.rank_data, node.rank_offset as usize))
+            .unwrap_or("")
+    }
+
+    /// Check whether a node is a user-defined pseudo-taxon
+    pub fn is_pseudo_taxon(&self, internal_id: u32) -> bool {
+        self.nodes
+            .get(internal_id as usize)
+            .is_some_and(|node| node.external_id >= PSEUDO_TAXID_START)
+    }
+
+    /// Get the internal IDs of the direct children of a node
+    ///
+    /// Children are stored consecutively starting at `first_child`.
+    pub fn children_of(&self, internal_id: u32) -> std::ops::Range<u32> {
+        match self.nodes.get(internal_id as usize) {
+            Some(node) if node.child_count > 0 => {
+                let first = node.first_child as u32;
+                first..first + node.child_count as u32
+            }
+            _ => 0..0,
+        }
+    }
+
     /// Get the internal ID for a given external ID
     ///
     /// # Arguments
@@ -512,7 +1199,23 @@ impl Taxonomy {
     /// A Result indicating success or failure
     pub fn write_to_disk<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
         let mut file = File::create(filename)?;
+        self.write_layout(&mut file)?;
+
+        // Kraken 2 stops reading before the version trailer
+        file.write_all(&TAXONOMY_TRAILER_MAGIC.to_le_bytes())?;
+        file.write_all(&TAXONOMY_FORMAT_VERSION.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    /// Write the taxonomy to disk in the layout of Kraken 2, without the version trailer
+    pub fn write_kraken2_file<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
+        let mut file = File::create(filename)?;
+        self.write_layout(&mut file)
+    }
 
+    /// Writes the nodes, names and ranks in the layout of Kraken 2
+    fn write_layout<W: Write>(&self, file: &mut W) -> Result<()> {
         // Write file magic
         file.write_all(Taxonomy::MAGIC)?;
 
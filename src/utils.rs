@@ -1,7 +1,10 @@
-use std::collections::{BTreeMap as Map, HashMap};
+use crate::error::Error;
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeMap as Map, HashMap, HashSet};
 use std::fs::{self, create_dir_all, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Result};
+use std::io::{self, BufRead, BufReader, BufWriter, Result, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 use walkdir::WalkDir;
 
 /// Reads the seqid2taxid.map file to create a mapping for trimming the NCBI taxonomy tree.
@@ -37,6 +40,212 @@ pub fn read_id_to_taxon_map<P: AsRef<Path>>(filename: P) -> Result<HashMap<Strin
     Ok(id_map)
 }
 
+/// Reads NCBI accession2taxid files (e.g. nucl_gb.accession2taxid.gz), keeping only the wanted accessions.
+///
+/// The files have the columns `accession`, `accession.version`, `taxid` and `gi` below a header line;
+/// gzip compressed files are detected by their `.gz` extension. Only the entries of `accessions` are
+/// kept, so the multi-gigabyte NCBI files never have to fit into memory.
+///
+/// # Arguments
+///
+/// * `filenames` - The accession2taxid files.
+/// * `accessions` - The accessions to look up, with or without version.
+///
+/// # Returns
+///
+/// Returns a `Result` containing a `HashMap<String, u64>` from the requested accessions to their taxon IDs.
+pub fn read_accession_to_taxid_map<P: AsRef<Path>>(
+    filenames: &[P],
+    accessions: &HashSet<String>,
+) -> Result<HashMap<String, u64>> {
+    let mut accession_map = HashMap::new();
+
+    for filename in filenames {
+        let file = open_file(filename)?;
+        let reader: Box<dyn BufRead> =
+            if filename.as_ref().extension().and_then(|s| s.to_str()) == Some("gz") {
+                Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(file))))
+            } else {
+                Box::new(BufReader::new(file))
+            };
+
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            // the header line has no numeric taxid and is skipped here
+            let Ok(taxid) = fields[2].trim().parse::<u64>() else {
+                continue;
+            };
+            for accession in &fields[..2] {
+                if accessions.contains(*accession) {
+                    accession_map.insert(accession.to_string(), taxid);
+                }
+            }
+        }
+    }
+
+    Ok(accession_map)
+}
+
+/// Finds the library files of a library directory, including the kraken2-build layout
+/// where every library has its own `library/<name>/library.fna`.
+pub fn find_library_fna_files<P: AsRef<Path>>(library_dir: P) -> Vec<PathBuf> {
+    find_files(library_dir, "library", ".fna")
+}
+
+/// Writes the seqid to taxid map of a kraken2-build library from its `prelim_map.txt` files.
+///
+/// kraken2-build records `TAXID<TAB>seqid<TAB>taxid` for headers with a `kraken:taxid` tag and
+/// `ACCNUM<TAB>seqid<TAB>accession` for all other headers. The accessions are resolved with
+/// the given accession2taxid files; unresolved sequences are left out of the map.
+///
+/// # Arguments
+///
+/// * `library_dir` - The library directory holding `<name>/prelim_map.txt` files.
+/// * `accession2taxid` - accession2taxid files used for `ACCNUM` entries.
+/// * `map_file` - The seqid2taxid.map file to write.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the number of entries written and the number of unresolved accessions.
+pub fn summary_prelim_map_files<P: AsRef<Path>, Q: AsRef<Path>>(
+    library_dir: P,
+    accession2taxid: &[PathBuf],
+    map_file: Q,
+) -> Result<(usize, usize)> {
+    let prelim_files = find_files(library_dir, "prelim_map", ".txt");
+
+    let mut taxid_entries = Vec::new();
+    let mut accession_entries = Vec::new();
+    for prelim_file in &prelim_files {
+        for line in BufReader::new(open_file(prelim_file)?).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            let (seq_id, value) = (fields[1].to_string(), fields[2].trim().to_string());
+            match fields[0] {
+                "TAXID" => taxid_entries.push((seq_id, value)),
+                "ACCNUM" => accession_entries.push((seq_id, value)),
+                _ => {}
+            }
+        }
+    }
+
+    let accessions: HashSet<String> = accession_entries
+        .iter()
+        .map(|(_, accession)| accession.clone())
+        .collect();
+    let accession_map = if accessions.is_empty() {
+        HashMap::new()
+    } else {
+        read_accession_to_taxid_map(accession2taxid, &accessions)?
+    };
+
+    let mut writer = BufWriter::new(File::create(map_file)?);
+    let mut count = 0;
+    for (seq_id, taxid) in &taxid_entries {
+        writeln!(writer, "{}\t{}", seq_id, taxid)?;
+        count += 1;
+    }
+    let mut unresolved = 0;
+    for (seq_id, accession) in &accession_entries {
+        match accession_map.get(accession) {
+            Some(taxid) => {
+                writeln!(writer, "{}\t{}", seq_id, taxid)?;
+                count += 1;
+            }
+            None => unresolved += 1,
+        }
+    }
+    writer.flush()?;
+    Ok((count, unresolved))
+}
+
+/// Adds plain multi-FASTA files and their seqid to taxid map to a database directory.
+///
+/// The sequences are looked up by the first word of their headers, so no `>taxid|...` header
+/// convention is needed. Every FASTA file is hard linked (or copied, across file systems) to
+/// `library/library_<name>.fna` and the map is copied to `seqid2taxid.map`. Files that are
+/// already in place are kept, so an interrupted build can be rerun with the same arguments.
+///
+/// # Arguments
+///
+/// * `database` - The database directory.
+/// * `fna_files` - Uncompressed multi-FASTA files.
+/// * `map_file` - The map of the sequence ids of `fna_files` to taxids, `None` to keep the current `seqid2taxid.map`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the number of sequences in `fna_files` and the number of those missing from the map.
+pub fn import_library_files<P: AsRef<Path>>(
+    database: P,
+    fna_files: &[PathBuf],
+    map_file: Option<&Path>,
+) -> Result<(usize, usize)> {
+    let database = database.as_ref();
+    let id_map_file = database.join("seqid2taxid.map");
+    if let Some(map_file) = map_file {
+        if id_map_file.exists() && fs::read(&id_map_file)? != fs::read(map_file)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists with other entries", id_map_file),
+            ));
+        }
+        if !id_map_file.exists() {
+            create_dir_all(database)?;
+            fs::copy(map_file, &id_map_file)?;
+        }
+    }
+    let id_to_taxon_map = read_id_to_taxon_map(&id_map_file)?;
+
+    let library_dir = database.join("library");
+    create_dir_all(&library_dir)?;
+    let mut sequences = 0;
+    let mut unmapped = 0;
+    for fna_file in fna_files {
+        let name = fna_file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        if name.ends_with(".gz") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is compressed, decompress it first", fna_file),
+            ));
+        }
+        let stem = name.strip_suffix(".fna").unwrap_or(name);
+        let stem = stem.strip_prefix("library_").unwrap_or(stem);
+        let target = library_dir.join(format!("library_{}.fna", stem));
+        if target.exists() {
+            if fs::metadata(&target)?.len() != fs::metadata(fna_file)?.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} already exists with other content", target),
+                ));
+            }
+        } else if fs::hard_link(fna_file, &target).is_err() {
+            fs::copy(fna_file, &target)?;
+        }
+
+        for line in BufReader::new(open_file(&target)?).lines() {
+            let line = line?;
+            if let Some(header) = line.strip_prefix('>') {
+                sequences += 1;
+                let seq_id = header.split_whitespace().next().unwrap_or("");
+                if !id_to_taxon_map.contains_key(seq_id) {
+                    unmapped += 1;
+                }
+            }
+        }
+    }
+    Ok((sequences, unmapped))
+}
+
 /// Expands a spaced seed mask based on the given bit expansion factor.
 ///
 /// # Examples
@@ -115,6 +324,14 @@ pub fn format_bytes(size: f64) -> String {
     format!("{:.2}{}", size, current_suffix)
 }
 
+/// Computes the md5 checksum of a file as a lowercase hex string
+pub fn file_md5(file_path: &Path) -> Result<String> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = md5::Context::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 #[cfg(unix)]
 extern crate libc;
 
@@ -148,8 +365,8 @@ pub fn get_file_limit() -> usize {
         // If successful, return the current soft limit converted to usize
         limits.rlim_cur as usize
     } else {
-        // If failed, print an error and return 0
-        eprintln!("Failed to get file limit");
+        // If failed, log an error and return 0
+        log::error!("Failed to get file limit");
         0
     }
 }
@@ -168,9 +385,13 @@ pub fn set_fd_limit(new_limit: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// Get the number of files the process may open at once.
+///
+/// Files are Win32 handles on Windows, which have no per-process limit short of the
+/// 16 million handles of the kernel, so the partition writers never run out of them.
 #[cfg(windows)]
 pub fn get_file_limit() -> usize {
-    8192
+    1 << 24
 }
 
 #[cfg(windows)]
@@ -178,39 +399,552 @@ pub fn set_fd_limit(_new_limit: u64) -> io::Result<()> {
     Ok(())
 }
 
-pub fn create_partition_files(partition: usize, base_path: &PathBuf, prefix: &str) -> Vec<PathBuf> {
-    create_dir_all(&base_path).expect(&format!("create dir error {:?}", base_path));
+/// Get the memory available for new processes in bytes.
+///
+/// Reads `MemAvailable` from `/proc/meminfo` on Linux and falls back to the physical
+/// memory reported by `sysconf` on other Unix-like systems. On Windows it is the available
+/// physical memory reported by `GlobalMemoryStatusEx`.
+///
+/// # Returns
+///
+/// The available memory, or `None` if it couldn't be detected.
+#[cfg(unix)]
+pub fn get_available_memory() -> Option<usize> {
+    if let Ok(meminfo) = std::fs::read_to_string("/proc/meminfo") {
+        if let Some(line) = meminfo
+            .lines()
+            .find(|line| line.starts_with("MemAvailable:"))
+        {
+            let kb = line
+                .trim_start_matches("MemAvailable:")
+                .trim()
+                .trim_end_matches("kB")
+                .trim();
+            return kb.parse::<usize>().ok().map(|kb| kb * 1024);
+        }
+    }
+
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages > 0 && page_size > 0 {
+        Some(pages as usize * page_size as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+pub fn get_available_memory() -> Option<usize> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return None;
+    }
+    Some(status.ullAvailPhys as usize)
+}
+
+/// Threads of a read pipeline (seqkmer `read_parallel` and friends) besides its workers:
+/// the thread reading the input and the thread writing the results
+pub const PIPELINE_IO_THREADS: usize = 2;
+
+/// Thread budget of the process, set by the global `--threads` option
+static THREAD_BUDGET: OnceLock<usize> = OnceLock::new();
+
+/// Sets the thread budget of the process and sizes the rayon global pool to it.
+///
+/// Call it before any rayon work starts, the global pool can only be sized once.
+pub fn set_thread_budget(threads: usize) -> Result<()> {
+    let threads = threads.max(1);
+    let _ = THREAD_BUDGET.set(threads);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(io::Error::other)
+}
+
+/// Get the thread budget of the process, the `--threads` value if set.
+pub fn thread_budget() -> Option<usize> {
+    THREAD_BUDGET.get().copied()
+}
+
+/// Get the number of worker threads of a read pipeline.
+///
+/// With a thread budget, the workers, the reader and the writer stay within it: the
+/// `requested` workers are capped at the budget minus [`PIPELINE_IO_THREADS`], and at least
+/// one worker runs. Without a budget the `requested` workers run.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::pipeline_workers;
+///
+/// // 未设置 --threads 时不限制
+/// assert_eq!(pipeline_workers(16), 16);
+/// ```
+pub fn pipeline_workers(requested: usize) -> usize {
+    match thread_budget() {
+        Some(budget) => requested
+            .min(budget.saturating_sub(PIPELINE_IO_THREADS))
+            .max(1),
+        None => requested.max(1),
+    }
+}
+
+/// Get the number of threads of a read pipeline, the `n_threads` of seqkmer `read_parallel`
+/// and friends.
+///
+/// These are the [`pipeline_workers`] plus the [`PIPELINE_IO_THREADS`], so at least three.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::pipeline_threads;
+///
+/// // 未设置 --threads 时, 16 个工作线程加上读写线程
+/// assert_eq!(pipeline_threads(16), 18);
+/// assert_eq!(pipeline_threads(1), 3);
+/// ```
+pub fn pipeline_threads(requested: usize) -> usize {
+    pipeline_workers(requested) + PIPELINE_IO_THREADS
+}
+
+/// Buffers handed back by the writer of a read pipeline for the workers to reuse
+///
+/// A worker takes the buffers of a batch with [`BufferPool::take`], fills them and passes them
+/// on; once the batch is written, the writer returns them with [`BufferPool::give`]. The pool
+/// holds about as many buffers as batches are in flight, so after the first batches the
+/// workers no longer allocate them.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::BufferPool;
+///
+/// let pool: BufferPool<String> = BufferPool::default();
+/// let mut buffer = pool.take();
+/// buffer.push_str("read_1\t9606\n");
+/// let capacity = buffer.capacity();
+/// buffer.clear();
+/// pool.give(buffer);
+/// assert_eq!(pool.take().capacity(), capacity);
+/// ```
+#[derive(Debug, Default)]
+pub struct BufferPool<T>(Mutex<Vec<T>>);
+
+impl<T: Default> BufferPool<T> {
+    /// Takes buffers from the pool, new ones if it is empty
+    pub fn take(&self) -> T {
+        self.0.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Returns buffers to the pool; the caller empties them
+    pub fn give(&self, buffers: T) {
+        self.0.lock().unwrap().push(buffers);
+    }
+}
+
+/// Memory budget of the process, set by the global `--max-memory` option
+static MEMORY_BUDGET: OnceLock<usize> = OnceLock::new();
+
+/// Sets the memory budget honored by splitr, annotate, build and direct.
+pub fn set_memory_budget(max_memory: usize) {
+    let _ = MEMORY_BUDGET.set(max_memory);
+}
+
+/// Get the memory budget of the process in bytes.
+///
+/// # Returns
+///
+/// The `--max-memory` value, or else the memory limit of the cgroup the process runs in
+/// (e.g. a Slurm or Kubernetes job), or `None` if there is no limit.
+pub fn memory_budget() -> Option<usize> {
+    MEMORY_BUDGET
+        .get()
+        .copied()
+        .or_else(get_cgroup_memory_limit)
+}
+
+/// Get the memory limit of the cgroup of the process in bytes.
+///
+/// Reads `memory.max` (cgroup v2) or `memory.limit_in_bytes` (cgroup v1).
+///
+/// # Returns
+///
+/// The limit, or `None` if the cgroup has no limit or it couldn't be read.
+#[cfg(target_os = "linux")]
+pub fn get_cgroup_memory_limit() -> Option<usize> {
+    let mut limit_files = Vec::new();
+    if let Ok(cgroup) = std::fs::read_to_string("/proc/self/cgroup") {
+        for line in cgroup.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (Some(_), Some(controllers), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if controllers.is_empty() {
+                limit_files.push(format!("/sys/fs/cgroup{}/memory.max", path));
+            } else if controllers.split(',').any(|c| c == "memory") {
+                limit_files.push(format!(
+                    "/sys/fs/cgroup/memory{}/memory.limit_in_bytes",
+                    path
+                ));
+            }
+        }
+    }
+    // 容器中 cgroup 命名空间的根即为自身的 cgroup
+    limit_files.push("/sys/fs/cgroup/memory.max".to_string());
+    limit_files.push("/sys/fs/cgroup/memory/memory.limit_in_bytes".to_string());
+
+    limit_files.iter().find_map(|file| {
+        // cgroup v2 的 "max" 无法解析, v1 用接近 i64::MAX 的值表示不限制
+        let limit = std::fs::read_to_string(file)
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        (limit < 1 << 60).then_some(limit as usize)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_cgroup_memory_limit() -> Option<usize> {
+    None
+}
+
+/// Get the free disk space in bytes of the file system holding `path`.
+///
+/// # Returns
+///
+/// The space available to unprivileged users, or `None` if it couldn't be detected.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn get_available_disk_space<P: AsRef<Path>>(path: P) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn get_available_disk_space<P: AsRef<Path>>(path: P) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path
+        .as_ref()
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut available = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(available)
+}
+
+/// A uniquely named directory under the system temp directory (`TMPDIR`), removed with all its
+/// files when dropped.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::TempChunkDir;
+///
+/// let chunk_dir = TempChunkDir::create().unwrap();
+/// let path = chunk_dir.path().to_path_buf();
+/// std::fs::write(path.join("sample_1.k2"), b"cells").unwrap();
+/// assert!(path.starts_with(std::env::temp_dir()));
+/// drop(chunk_dir);
+/// assert!(!path.exists());
+/// ```
+#[derive(Debug)]
+pub struct TempChunkDir {
+    path: PathBuf,
+}
+
+impl TempChunkDir {
+    /// Creates the directory, named `kun_peng_chunk_<pid>_<time>_<n>`
+    ///
+    /// The directories left by killed runs of this machine are removed first, see
+    /// [`remove_stale_chunk_dirs`].
+    pub fn create() -> Result<Self> {
+        let temp_dir = std::env::temp_dir();
+        remove_stale_chunk_dirs(&temp_dir);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        for n in 0..100 {
+            let path = temp_dir.join(format!(
+                "kun_peng_chunk_{}_{}_{}",
+                std::process::id(),
+                nanos,
+                n
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "no unique chunk directory could be created in {:?}",
+                temp_dir
+            ),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempChunkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!("failed to remove chunk directory {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Removes the chunk directories in `temp_dir` left by classify runs that were killed
+///
+/// Only directories whose lock names a process of this machine that has exited are removed.
+///
+/// # Returns
+///
+/// The number of directories removed
+pub fn remove_stale_chunk_dirs(temp_dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(temp_dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_chunk_dir = path.is_dir()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("kun_peng_chunk_");
+        if !is_chunk_dir {
+            continue;
+        }
+        let Ok(Some(owner)) = LockOwner::read(&path.join(CHUNK_LOCK_FILENAME)) else {
+            continue;
+        };
+        if owner.is_gone() && fs::remove_dir_all(&path).is_ok() {
+            log::info!(
+                "removed chunk directory {:?} of interrupted run {}",
+                path,
+                owner.pid
+            );
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Name of the file that marks a chunk directory in use by a classify run
+pub const CHUNK_LOCK_FILENAME: &str = "kun_peng.lock";
+
+/// The host name of this machine, empty if unknown
+fn host_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Whether a process of this machine is running
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    // 信号 0 只检查进程是否存在; EPERM 表示进程存在但属于其他用户
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) == 0 };
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_running(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        // 拒绝访问表示进程存在但属于其他用户
+        return io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+    }
+    let mut exit_code = 0u32;
+    let ok = unsafe { GetExitCodeProcess(handle, &mut exit_code) };
+    unsafe { CloseHandle(handle) };
+    ok == 0 || exit_code == STILL_ACTIVE as u32
+}
+
+/// The process that holds a chunk directory lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+}
+
+impl LockOwner {
+    /// The current process
+    pub fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: host_name(),
+        }
+    }
+
+    /// Reads the owner of a lock file, `<pid><TAB><host>`
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the owner, or None if there is no lock file or it cannot be parsed
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (pid, host) = content
+            .trim_end()
+            .split_once('\t')
+            .unwrap_or((&content, ""));
+        Ok(pid.trim().parse().ok().map(|pid| Self {
+            pid,
+            host: host.to_string(),
+        }))
+    }
+
+    /// Whether the owner has exited, which can only be told for processes of this machine
+    pub fn is_gone(&self) -> bool {
+        self.host == host_name() && !process_running(self.pid)
+    }
+}
+
+/// Marks a chunk directory as used by this process until dropped
+///
+/// A run that is killed leaves its lock behind. The next run on the same machine sees that
+/// the owner has exited and knows the files of the chunk directory are leftovers.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::{ChunkDirLock, LockOwner, CHUNK_LOCK_FILENAME};
+///
+/// let chunk_dir = std::env::temp_dir().join("kun_peng_chunk_lock_doctest");
+/// std::fs::create_dir_all(&chunk_dir).unwrap();
+/// let lock = ChunkDirLock::acquire(&chunk_dir).unwrap();
+/// assert!(!lock.stale());
+/// let owner = LockOwner::read(&chunk_dir.join(CHUNK_LOCK_FILENAME)).unwrap().unwrap();
+/// assert_eq!(owner, LockOwner::current());
+/// drop(lock);
+/// assert!(!chunk_dir.join(CHUNK_LOCK_FILENAME).exists());
+/// std::fs::remove_dir(&chunk_dir).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ChunkDirLock {
+    path: PathBuf,
+    stale: bool,
+}
+
+impl ChunkDirLock {
+    /// Takes the lock of a chunk directory
+    ///
+    /// A lock of a process of another machine cannot be checked and is taken over with a warning.
+    ///
+    /// # Returns
+    ///
+    /// An io::Result containing the lock, an error if a running process of this machine holds it
+    pub fn acquire(chunk_dir: &Path) -> Result<Self> {
+        let path = chunk_dir.join(CHUNK_LOCK_FILENAME);
+        let current = LockOwner::current();
+        let mut stale = false;
+        if let Some(owner) = LockOwner::read(&path)? {
+            if owner.is_gone() {
+                stale = true;
+            } else if owner.host != current.host {
+                log::warn!(
+                    "chunk directory {:?} was in use by process {} on {}; make sure that run is over",
+                    chunk_dir,
+                    owner.pid,
+                    owner.host
+                );
+            } else if owner.pid != current.pid {
+                return Err(Error::InvalidInput(format!(
+                    "chunk directory {:?} is in use by process {}; wait for it or use another --chunk-dir",
+                    chunk_dir, owner.pid
+                ))
+                .into());
+            }
+        }
+        // 先写临时文件再改名, 被杀时不会留下空的锁文件
+        let tmp_path = path.with_extension("lock.tmp");
+        fs::write(&tmp_path, format!("{}\t{}\n", current.pid, current.host))?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(Self { path, stale })
+    }
+
+    /// Whether the previous lock was left by a run that was killed on this machine
+    pub fn stale(&self) -> bool {
+        self.stale
+    }
+}
+
+impl Drop for ChunkDirLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn create_partition_files(
+    partition: usize,
+    base_path: &PathBuf,
+    prefix: &str,
+) -> Result<Vec<PathBuf>> {
+    create_dir_all(base_path).map_err(|e| Error::io(base_path, e))?;
     let file_path = base_path.clone();
-    (1..=partition)
-        .into_iter()
+    Ok((1..=partition)
         .map(|item| file_path.join(format!("{}_{}.k2", prefix, item)))
-        .collect()
+        .collect())
 }
 
-pub fn create_partition_writers(partition_files: &Vec<PathBuf>) -> Vec<BufWriter<File>> {
+pub fn create_partition_writers(partition_files: &Vec<PathBuf>) -> Result<Vec<BufWriter<File>>> {
     partition_files
         .into_iter()
-        .map(|item| {
-            // Try to create the file, return an error if failed
-            let file = OpenOptions::new()
-                .write(true)
-                .append(true) // Ensure opening the file in append mode
-                .create(true) // Create the file if it doesn't exist
-                .open(item)
-                .unwrap();
-            BufWriter::new(file)
-        })
+        .map(create_sample_file)
         .collect()
 }
 
-pub fn create_sample_file<P: AsRef<Path>>(filename: P) -> BufWriter<File> {
+pub fn create_sample_file<P: AsRef<Path>>(filename: P) -> Result<BufWriter<File>> {
     let file = OpenOptions::new()
         .write(true)
         .append(true) // Ensure opening the file in append mode
         .create(true) // Create the file if it doesn't exist
-        .open(filename)
-        .unwrap();
-    BufWriter::new(file)
+        .open(&filename)
+        .map_err(|e| Error::io(filename.as_ref(), e))?;
+    Ok(BufWriter::new(file))
 }
 
 use regex::Regex;